@@ -0,0 +1,85 @@
+//! The smallest tray app: shares a file, or receives into a directory, and takes the commands a
+//! tray menu would have from stdin. Writes the QR code to `rustbelt-qr.png`, where a real tray app
+//! would show it in a window.
+//!
+//! ```text
+//! cargo run --example tray -- report.pdf
+//! cargo run --example tray -- --receive ~/Downloads
+//! ```
+
+use rustbelt::embed::{Event, ShareBuilder};
+use std::env;
+use std::error;
+use std::fs;
+use std::io::{self, BufRead};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const QR_PATH: &str = "rustbelt-qr.png";
+const MENU: &str = "Commands: urls, qr, clients, revoke [IP], quit";
+
+fn main() -> Result<(), Box<dyn error::Error>> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let builder = match args.as_slice() {
+        [flag, dir] if flag == "--receive" => ShareBuilder::receive(dir),
+        [path] => ShareBuilder::send(path),
+        _ => return Err("Usage: tray FILE | tray --receive DIR".into()),
+    };
+    let share = builder.start()?;
+    println!("Sharing at {}", share.get_urls()[0]);
+    fs::write(QR_PATH, share.get_qr_png()?)?;
+    println!("QR code in {}", QR_PATH);
+    println!("{}", MENU);
+
+    // A tray app gets clicks from its event loop, this one gets lines from stdin.
+    let (commands, clicks) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if commands.send(line.unwrap_or_default()).is_err() {
+                break;
+            }
+        }
+    });
+    loop {
+        for event in share.events().try_iter() {
+            match event {
+                Event::Message(message) => println!("* {}", message),
+                Event::Stopped(error) => {
+                    println!("Stopped: {}", error.as_deref().unwrap_or("done"));
+                    return Ok(());
+                }
+            }
+        }
+        let command = match clicks.recv_timeout(Duration::from_millis(100)) {
+            Ok(command) => command,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => String::from("quit"),
+        };
+        let words = command.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["urls"] => share.get_urls().iter().for_each(|url| println!("{}", url)),
+            ["qr"] => {
+                fs::write(QR_PATH, share.get_qr_png()?)?;
+                println!("QR code in {}", QR_PATH);
+            }
+            ["clients"] => {
+                for client in share.get_clients() {
+                    let revoked = if client.revoked { ", revoked" } else { "" };
+                    println!("{} ({} requests{})", client.name, client.requests, revoked);
+                }
+            }
+            ["revoke"] => share.revoke(),
+            ["revoke", ip] => match ip.parse() {
+                Ok(ip) if share.revoke_client(ip) => println!("Revoked {}", ip),
+                Ok(ip) => println!("No client at {}", ip),
+                Err(e) => println!("{}: {}", ip, e),
+            },
+            ["quit"] => break,
+            [] => {}
+            _ => println!("{}", MENU),
+        }
+    }
+    share.stop();
+    Ok(())
+}
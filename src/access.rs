@@ -0,0 +1,285 @@
+//! PIN protection of single paths inside a shared directory
+//!
+//! Rules are given as `PATTERN:PIN`, either with `--protect` or line by line in a
+//! `.rustbelt-access` file in the shared directory. Patterns are `/` separated, `*` and `?` match
+//! within a path segment and `**` matches any number of segments. A path matched by a rule is
+//! only served if its PIN is given as basic authentication password or as `pin` query parameter.
+//! The access file itself is never served.
+
+use hyper::{header, Body, Request, Response, StatusCode};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const ACCESS_FILE: &str = ".rustbelt-access";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessRule {
+    pattern: String,
+    pin: String,
+}
+
+impl FromStr for AccessRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AccessRule, String> {
+        match s.rfind(':') {
+            Some(i) if i > 0 && i + 1 < s.len() => Ok(AccessRule {
+                pattern: s[..i].trim_matches('/').to_string(),
+                pin: s[i + 1..].to_string(),
+            }),
+            _ => Err(format!("Expected PATTERN:PIN: {}", s)),
+        }
+    }
+}
+
+impl AccessRule {
+    pub fn get_pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn get_pin(&self) -> &str {
+        &self.pin
+    }
+}
+
+/// Matches `items` against `pattern`, in which the items `is_star` accepts stand for any number of
+/// items and all others are compared with `matches`. Only the last star is ever backtracked to, so
+/// this takes at most `pattern.len() * items.len()` steps.
+fn match_glob<P, T>(
+    pattern: &[P],
+    items: &[T],
+    is_star: impl Fn(&P) -> bool,
+    matches: impl Fn(&P, &T) -> bool,
+) -> bool {
+    let (mut p, mut i) = (0, 0);
+    let mut star = None;
+    while i < items.len() {
+        if p < pattern.len() && is_star(&pattern[p]) {
+            star = Some((p, i));
+            p += 1;
+        } else if p < pattern.len() && matches(&pattern[p], &items[i]) {
+            p += 1;
+            i += 1;
+        } else if let Some((star_p, star_i)) = star {
+            // Let the last star take one more item and try again after it.
+            star = Some((star_p, star_i + 1));
+            p = star_p + 1;
+            i = star_i + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(is_star)
+}
+
+/// Matches a single path segment against a pattern segment containing `*` and `?`.
+fn match_segment(pattern: &[char], segment: &[char]) -> bool {
+    match_glob(pattern, segment, |p| *p == '*', |p, c| *p == '?' || p == c)
+}
+
+fn match_segments(pattern: &[&str], segments: &[&str]) -> bool {
+    match_glob(
+        pattern,
+        segments,
+        |p| *p == "**",
+        |p, s| {
+            let p = p.chars().collect::<Vec<_>>();
+            let s = s.chars().collect::<Vec<_>>();
+            match_segment(&p, &s)
+        },
+    )
+}
+
+/// Whether the `/` separated `path` matches the glob `pattern`.
+pub fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let split = |s: &str| {
+        s.split('/')
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+    let (pattern, path) = (split(pattern), split(path));
+    let pattern = pattern.iter().map(String::as_str).collect::<Vec<_>>();
+    let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+    match_segments(&pattern, &path)
+}
+
+/// The rules applying to one directory
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessRules {
+    rules: Vec<AccessRule>,
+}
+
+impl AccessRules {
+    pub fn new(rules: Vec<AccessRule>) -> AccessRules {
+        AccessRules { rules }
+    }
+
+    /// Adds the rules of the access file in `root`, if there is one.
+    pub fn load(mut self, root: &Path) -> io::Result<AccessRules> {
+        let content = match fs::read_to_string(root.join(ACCESS_FILE)) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(self),
+            Err(e) => return Err(e),
+        };
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = line
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.rules.push(rule);
+        }
+        Ok(self)
+    }
+
+    /// Returns the PIN protecting `path`, if any. The first matching rule wins.
+    fn get_pin(&self, path: &str) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|r| matches_pattern(&r.pattern, path))
+            .map(|r| r.pin.as_str())
+    }
+
+    /// Whether `path` is only served with a PIN, or not at all
+    pub fn is_protected(&self, path: &str) -> bool {
+        path.split('/').any(|s| s == ACCESS_FILE) || self.get_pin(path).is_some()
+    }
+
+    /// Checks access to `path`, which is relative to the directory the rules belong to. Returns
+    /// the response to send instead if access is denied.
+    pub fn check(&self, path: &str, req: &Request<Body>) -> Option<Response<Body>> {
+        if path.split('/').any(|s| s == ACCESS_FILE) {
+            return Some(crate::create_status_response(
+                StatusCode::NOT_FOUND,
+                "Not found",
+            ));
+        }
+        let pin = self.get_pin(path)?;
+        if get_given_pins(req).iter().any(|p| is_pin(p, pin)) {
+            return None;
+        }
+        Some(
+            Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(
+                    header::WWW_AUTHENTICATE,
+                    "Basic realm=\"PIN protected\", charset=\"UTF-8\"",
+                )
+                .body(Body::from("This path requires a PIN"))
+                .unwrap(),
+        )
+    }
+}
+
+/// Compares `given` with `pin` in a time that doesn't depend on where they differ.
+fn is_pin(given: &str, pin: &str) -> bool {
+    given.len() == pin.len()
+        && given
+            .bytes()
+            .zip(pin.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Collects the basic authentication password and the `pin` query parameter.
+fn get_given_pins(req: &Request<Body>) -> Vec<String> {
+    let mut pins = Vec::new();
+    let password = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|a| a.to_str().ok())
+        .and_then(|a| a.strip_prefix("Basic "))
+        .and_then(|a| base64::decode(a.trim()).ok())
+        .and_then(|d| String::from_utf8(d).ok())
+        .and_then(|d| d.split_once(':').map(|(_, p)| p.to_string()));
+    pins.extend(password);
+    for pair in req.uri().query().unwrap_or_default().split('&') {
+        if let Some(pin) = pair.strip_prefix("pin=") {
+            pins.extend(crate::paths::percent_decode(pin));
+        }
+    }
+    pins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_pattern_matches_itself(path in "[a-z]{1,5}(/[a-z]{1,5}){0,4}") {
+            prop_assert!(matches_pattern(&path, &path));
+            prop_assert!(matches_pattern("**", &path));
+            let pattern = format!("{}/**", path);
+            let nested = format!("{}/x", path);
+            prop_assert!(matches_pattern(&pattern, &nested));
+        }
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("secret/**", "secret"));
+        assert!(matches_pattern("secret/**", "secret/a/b.txt"));
+        assert!(!matches_pattern("secret/**", "secrets/a"));
+        assert!(matches_pattern("**/*.key", "a/b/c.key"));
+        assert!(matches_pattern("*.key", "c.key"));
+        assert!(!matches_pattern("*.key", "a/c.key"));
+        assert!(matches_pattern("file?.txt", "file1.txt"));
+        assert!(!matches_pattern("file?.txt", "file.txt"));
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(!matches_pattern("a*b*c", "aXbYbZ"));
+        assert!(matches_pattern("**/x/**/y", "x/a/x/b/y"));
+        assert!(!matches_pattern("**/x/**/y", "x/a/y/b"));
+        let slow = format!("{}b", "*a".repeat(30));
+        assert!(!matches_pattern(&slow, &"a".repeat(60)));
+    }
+
+    #[test]
+    fn test_is_pin() {
+        assert!(is_pin("1234", "1234"));
+        assert!(!is_pin("1235", "1234"));
+        assert!(!is_pin("123", "1234"));
+        assert!(!is_pin("", "1234"));
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(ACCESS_FILE), "# comment\nprivate/**:1234\n").unwrap();
+        let rules = AccessRules::new(vec!["*.key:9".parse().unwrap()])
+            .load(dir.path())
+            .unwrap();
+        let request = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        assert!(rules.check("open.txt", &request("/open.txt")).is_none());
+        assert!(rules.check("private/a", &request("/private/a")).is_some());
+        assert!(rules
+            .check("private/a", &request("/private/a?pin=1234"))
+            .is_none());
+        assert!(rules.check("a.key", &request("/a.key?pin=1234")).is_some());
+        let authorized = Request::get("/a.key")
+            .header(
+                header::AUTHORIZATION,
+                format!("Basic {}", base64::encode("any:9")),
+            )
+            .body(Body::empty())
+            .unwrap();
+        assert!(rules.check("a.key", &authorized).is_none());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            rules.check(ACCESS_FILE, &request("/")).unwrap().status()
+        );
+    }
+
+    #[test]
+    fn test_is_protected() {
+        let rules = AccessRules::new(vec!["private/**:1234".parse().unwrap()]);
+        assert!(rules.is_protected("private/a"));
+        assert!(rules.is_protected(&format!("sub/{}", ACCESS_FILE)));
+        assert!(!rules.is_protected("open.txt"));
+    }
+}
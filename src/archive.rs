@@ -0,0 +1,380 @@
+//! Serving the content of a zip or tar.gz archive as a virtual directory
+//!
+//! Entries are decompressed on the fly in a blocking task and streamed to the recipient, so only
+//! the requested entry has to be read. Ranges of an entry are cut from the decompressed stream.
+//! `/SHA256SUMS` lists the checksums of all entries.
+
+use crate::source::{self, ContentSource, ContentStream, SourceFuture, SourceInfo};
+use crate::{create_status_response, help, html, manifest, paths, serve_text};
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::sink::SinkExt;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveKind {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveKind {
+    /// Guesses the archive format from a file name.
+    pub fn from_path(path: &Path) -> Option<ArchiveKind> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// A file inside an archive
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: u64,
+}
+
+/// An archive whose entries are offered individually
+pub struct ArchiveShare {
+    path: PathBuf,
+    kind: ArchiveKind,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl ArchiveShare {
+    /// Opens the archive at `path` and reads the list of its entries.
+    pub fn new(path: PathBuf, kind: ArchiveKind) -> io::Result<ArchiveShare> {
+        let entries = list_entries(&path, kind)?;
+        Ok(ArchiveShare {
+            path,
+            kind,
+            entries,
+        })
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn list_entries(path: &Path, kind: ArchiveKind) -> io::Result<Vec<ArchiveEntry>> {
+    let file = File::open(path)?;
+    let mut entries = Vec::new();
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(file).map_err(io::Error::other)?;
+            for i in 0..archive.len() {
+                let entry = archive.by_index(i).map_err(io::Error::other)?;
+                if entry.is_file() {
+                    entries.push(ArchiveEntry {
+                        name: entry.name().to_string(),
+                        size: entry.size(),
+                    });
+                }
+            }
+        }
+        ArchiveKind::TarGz => {
+            let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+            for entry in archive.entries()? {
+                let entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    entries.push(ArchiveEntry {
+                        name: entry.path()?.to_string_lossy().into_owned(),
+                        size: entry.header().size()?,
+                    });
+                }
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Reads the entry `name` from the archive and passes its content to `sink` in chunks.
+fn read_entry<F>(path: &Path, kind: ArchiveKind, name: &str, mut sink: F) -> io::Result<()>
+where
+    F: FnMut(Bytes) -> io::Result<()>,
+{
+    let file = File::open(path)?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut copy = |reader: &mut dyn Read| -> io::Result<()> {
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                return Ok(());
+            }
+            sink(Bytes::copy_from_slice(&buffer[..n]))?;
+        }
+    };
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(file).map_err(io::Error::other)?;
+            let mut entry = archive.by_name(name).map_err(io::Error::other)?;
+            copy(&mut entry)
+        }
+        ArchiveKind::TarGz => {
+            let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if entry.path()?.to_string_lossy() == name {
+                    return copy(&mut entry);
+                }
+            }
+            Err(io::Error::new(io::ErrorKind::NotFound, name.to_string()))
+        }
+    }
+}
+
+/// What a request of a shared archive is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Listing,
+    Sums,
+    Entry,
+}
+
+/// The requests a shared archive answers
+pub fn get_routes() -> Vec<help::Route<Route>> {
+    use help::Route as R;
+    vec![
+        R::new(
+            Method::GET,
+            "/",
+            "Lists the entries of the archive",
+            Route::Listing,
+        ),
+        R::new(
+            Method::GET,
+            "/SHA256SUMS",
+            "Checksums of all entries",
+            Route::Sums,
+        ),
+        R::new(
+            Method::GET,
+            "/<entry>",
+            "Downloads an entry, Range requests continue an interrupted download",
+            Route::Entry,
+        ),
+    ]
+}
+
+pub async fn handle_request(
+    share: Arc<ArchiveShare>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let route = match help::find_route(&get_routes(), &req) {
+        Ok(route) => route,
+        Err(response) => return Ok(response),
+    };
+    if route == Route::Listing {
+        let links = share
+            .entries
+            .iter()
+            .map(|e| {
+                (
+                    format!("/{}", paths::percent_encode_path(&e.name)),
+                    e.name.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let title = share.path.file_name().unwrap_or_default().to_string_lossy();
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(html::create_listing_page(&title, &links)))
+            .unwrap());
+    }
+
+    if route == Route::Sums {
+        return Ok(match create_sha256sums(share).await {
+            Ok(sums) => serve_text("SHA256SUMS", sums, "text/plain; charset=utf-8", &req).await,
+            Err(_) => {
+                create_status_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not hash files")
+            }
+        });
+    }
+
+    let name = paths::percent_decode(req.uri().path().trim_start_matches('/'));
+    let entry = match share
+        .entries
+        .iter()
+        .find(|e| Some(&e.name) == name.as_ref())
+    {
+        Some(e) => e.clone(),
+        None => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+
+    let source = Arc::new(EntrySource {
+        archive: share.clone(),
+        entry,
+    });
+    Ok(source::serve(source, &req).await)
+}
+
+/// An entry of the archive as the content of a download
+struct EntrySource {
+    archive: Arc<ArchiveShare>,
+    entry: ArchiveEntry,
+}
+
+impl ContentSource for EntrySource {
+    fn get_info(&self) -> SourceFuture<'_, SourceInfo> {
+        let name = self
+            .entry
+            .name
+            .rsplit('/')
+            .next()
+            .unwrap_or(&self.entry.name);
+        let info = SourceInfo {
+            name: name.to_string(),
+            size: Some(self.entry.size),
+            version: None,
+            content_type: None,
+        };
+        Box::pin(async move { Ok(info) })
+    }
+
+    fn open(self: Arc<Self>, range: Option<(u64, u64)>) -> SourceFuture<'static, ContentStream> {
+        let (mut sender, receiver) = mpsc::channel::<io::Result<Bytes>>(4);
+        tokio::task::spawn_blocking(move || {
+            let result = read_entry(
+                &self.archive.path,
+                self.archive.kind,
+                &self.entry.name,
+                |chunk| block_on(sender.send(Ok(chunk))).map_err(io::Error::other),
+            );
+            if let Err(e) = result {
+                let _ = block_on(sender.send(Err(e)));
+            }
+        });
+        let body: ContentStream = match range {
+            Some((start, end)) => source::slice_stream(Box::pin(receiver), start, end - start + 1),
+            None => Box::pin(receiver),
+        };
+        Box::pin(async move { Ok(body) })
+    }
+}
+
+/// Hashes every entry, decompressing them one after another.
+async fn create_sha256sums(share: Arc<ArchiveShare>) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in &share.entries {
+        let source = Arc::new(EntrySource {
+            archive: share.clone(),
+            entry: entry.clone(),
+        });
+        entries.push(manifest::Entry {
+            path: entry.name.clone(),
+            size: entry.size,
+            modified: 0,
+            sha256: source::hash_source(source).await?,
+        });
+    }
+    Ok(manifest::create_sha256sums(&entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn create_test_archives(dir: &Path) -> (PathBuf, PathBuf) {
+        let zip_path = dir.join("test.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        writer
+            .start_file("a/b.txt", zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(b"zip content").unwrap();
+        writer.finish().unwrap();
+
+        let tar_path = dir.join("test.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(&tar_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(11);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "a/b.txt", &b"tar content"[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        (zip_path, tar_path)
+    }
+
+    #[test]
+    fn test_archive_kind() {
+        assert_eq!(
+            Some(ArchiveKind::Zip),
+            ArchiveKind::from_path(Path::new("x/a.ZIP"))
+        );
+        assert_eq!(
+            Some(ArchiveKind::TarGz),
+            ArchiveKind::from_path(Path::new("a.tgz"))
+        );
+        assert_eq!(None, ArchiveKind::from_path(Path::new("a.tar")));
+    }
+
+    #[test]
+    fn test_list_and_read_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (zip_path, tar_path) = create_test_archives(dir.path());
+        for (path, kind, content) in &[
+            (zip_path, ArchiveKind::Zip, &b"zip content"[..]),
+            (tar_path, ArchiveKind::TarGz, &b"tar content"[..]),
+        ] {
+            let entries = list_entries(path, *kind).unwrap();
+            assert_eq!(
+                vec![ArchiveEntry {
+                    name: String::from("a/b.txt"),
+                    size: content.len() as u64
+                }],
+                entries
+            );
+            let mut read = Vec::new();
+            read_entry(path, *kind, "a/b.txt", |chunk| {
+                read.extend_from_slice(&chunk);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(*content, &read[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_entry_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, tar_path) = create_test_archives(dir.path());
+        let share = Arc::new(ArchiveShare::new(tar_path, ArchiveKind::TarGz).unwrap());
+        let req = Request::get("/a/b.txt")
+            .header(header::RANGE, "bytes=4-")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle_request(share.clone(), req).await.unwrap();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&b"content"[..], &body[..]);
+
+        let req = Request::get("/SHA256SUMS").body(Body::empty()).unwrap();
+        let response = handle_request(share, req).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            &b"294c0b7e0ee8c497d5c1516988a27d78bb8f8583c42a7e211ebee7cbeb5c537b  a/b.txt\n"[..],
+            &body[..]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Advertising the share URL as an Eddystone-URL Bluetooth beacon
+//!
+//! Phones nearby show the URL without scanning a QR code. The advertisement is registered with
+//! BlueZ through `btmgmt`, which usually needs root or the `CAP_NET_ADMIN` capability. Eddystone
+//! leaves only 17 bytes for the compressed URL, which fits an address, a port and a short path.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::process::{Command, Stdio};
+
+/// The advertising instance used by rustbelt
+const INSTANCE: &str = "1";
+/// 16 bit UUID of the Eddystone service, little endian
+const EDDYSTONE_UUID: [u8; 2] = [0xaa, 0xfe];
+const FRAME_TYPE_URL: u8 = 0x10;
+/// Calibrated transmission power at 0 m in dBm
+const TX_POWER: i8 = -20;
+const MAX_ENCODED_URL: usize = 17;
+
+const SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+const EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/", ".com", ".org", ".edu", ".net",
+    ".info", ".biz", ".gov",
+];
+
+#[derive(Debug)]
+pub enum BeaconError {
+    UnsupportedUrl(String),
+    UrlTooLong(String),
+    Command(String),
+}
+
+impl error::Error for BeaconError {}
+
+impl fmt::Display for BeaconError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BeaconError::UnsupportedUrl(url) => {
+                write!(f, "{} can't be advertised as Eddystone-URL", url)
+            }
+            BeaconError::UrlTooLong(url) => write!(
+                f,
+                "{} is too long for a beacon, it has to fit into {} bytes",
+                url, MAX_ENCODED_URL
+            ),
+            BeaconError::Command(message) => write!(f, "btmgmt failed: {}", message),
+        }
+    }
+}
+
+/// Compresses `url` as defined by Eddystone-URL, the scheme byte followed by the URL with common
+/// domain endings replaced by single bytes.
+pub fn encode_url(url: &str) -> Result<Vec<u8>, BeaconError> {
+    let (scheme, rest) = SCHEMES
+        .iter()
+        .enumerate()
+        .find_map(|(i, s)| url.strip_prefix(s).map(|rest| (i as u8, rest)))
+        .ok_or_else(|| BeaconError::UnsupportedUrl(url.to_string()))?;
+    let mut encoded = vec![scheme];
+    let mut rest = rest;
+    while let Some(c) = rest.chars().next() {
+        match EXPANSIONS.iter().position(|e| rest.starts_with(e)) {
+            Some(code) => {
+                encoded.push(code as u8);
+                rest = &rest[EXPANSIONS[code].len()..];
+            }
+            None if c.is_ascii_graphic() => {
+                encoded.push(c as u8);
+                rest = &rest[1..];
+            }
+            None => return Err(BeaconError::UnsupportedUrl(url.to_string())),
+        }
+    }
+    if encoded.len() > MAX_ENCODED_URL + 1 {
+        return Err(BeaconError::UrlTooLong(url.to_string()));
+    }
+    Ok(encoded)
+}
+
+/// The advertising data announcing `url`, without the flags the kernel adds itself.
+pub fn create_advertising_data(url: &str) -> Result<Vec<u8>, BeaconError> {
+    let encoded = encode_url(url)?;
+    let mut data = vec![3, 0x03, EDDYSTONE_UUID[0], EDDYSTONE_UUID[1]];
+    data.push(5 + encoded.len() as u8);
+    data.extend_from_slice(&[0x16, EDDYSTONE_UUID[0], EDDYSTONE_UUID[1]]);
+    data.extend_from_slice(&[FRAME_TYPE_URL, TX_POWER as u8]);
+    data.extend(encoded);
+    Ok(data)
+}
+
+fn run_btmgmt(args: &[&str]) -> Result<(), BeaconError> {
+    let output = Command::new("btmgmt")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => BeaconError::Command(String::from("is BlueZ installed?")),
+            _ => BeaconError::Command(e.to_string()),
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Err(BeaconError::Command(message))
+    }
+}
+
+/// Starts advertising `url` until `stop` is called.
+pub fn start(url: &str) -> Result<(), BeaconError> {
+    let data = create_advertising_data(url)?
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    run_btmgmt(&["add-adv", "-g", "-d", &data, INSTANCE])
+}
+
+pub fn stop() -> Result<(), BeaconError> {
+    run_btmgmt(&["rm-adv", INSTANCE])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_encoded_length(ip in any::<[u8; 4]>(), port in any::<u16>()) {
+            let url = format!("http://{}.{}.{}.{}:{}/", ip[0], ip[1], ip[2], ip[3], port);
+            match encode_url(&url) {
+                Ok(encoded) => prop_assert_eq!(url.len() - "http://".len() + 1, encoded.len()),
+                Err(BeaconError::UrlTooLong(_)) => prop_assert!(url.len() - "http://".len() > 17),
+                Err(e) => prop_assert!(false, "{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_url() {
+        assert_eq!(
+            vec![0x02, b'1', b'0', b'.', b'0', b'.', b'0', b'.', b'2', b':', b'8', b'0', b'/'],
+            encode_url("http://10.0.0.2:80/").unwrap()
+        );
+        assert_eq!(
+            vec![0x01, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x00, b'a'],
+            encode_url("https://www.example.com/a").unwrap()
+        );
+        assert!(matches!(
+            encode_url("ftp://example.org"),
+            Err(BeaconError::UnsupportedUrl(_))
+        ));
+        assert!(matches!(
+            encode_url("http://192.168.100.200:8080/"),
+            Err(BeaconError::UrlTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_advertising_data() {
+        let data = create_advertising_data("http://10.0.0.2/").unwrap();
+        assert_eq!(&[3, 0x03, 0xaa, 0xfe], &data[..4]);
+        assert_eq!(data.len() - 5, data[4] as usize);
+        assert_eq!(&[0x16, 0xaa, 0xfe, 0x10], &data[5..9]);
+    }
+}
@@ -0,0 +1,247 @@
+//! Handing one file to a whole group, like a classroom
+//!
+//! With `--broadcast N` every recipient's download is tracked and a line like `23/30 devices done`
+//! is printed whenever that changes. The share counts as transferred once N devices have the
+//! whole file. With `--require-name` the landing page asks for the recipient's name before the
+//! download starts, so the console can show who is still missing.
+
+use crate::html;
+use crate::output;
+use crate::paths;
+use bytes::Bytes;
+use futures::stream::Stream;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// Longest name accepted from the landing page, in characters
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipient {
+    pub ip: IpAddr,
+    pub name: Option<String>,
+    /// Bytes sent in the current or last download
+    pub sent: u64,
+    pub done: bool,
+}
+
+impl Recipient {
+    pub fn get_display_name(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", name, self.ip),
+            None => self.ip.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Broadcast {
+    expected: usize,
+    require_name: bool,
+    recipients: Mutex<Vec<Recipient>>,
+}
+
+impl Broadcast {
+    pub fn new(expected: usize, require_name: bool) -> Broadcast {
+        Broadcast {
+            expected,
+            require_name,
+            recipients: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Whether the recipient at `ip` still has to tell their name before downloading
+    pub fn needs_name(&self, ip: IpAddr) -> bool {
+        self.require_name
+            && !self
+                .recipients
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|r| r.ip == ip && r.name.is_some())
+    }
+
+    fn update<F: FnOnce(&mut Recipient)>(&self, ip: IpAddr, f: F) {
+        let mut recipients = self.recipients.lock().unwrap();
+        let index = match recipients.iter().position(|r| r.ip == ip) {
+            Some(index) => index,
+            None => {
+                recipients.push(Recipient {
+                    ip,
+                    name: None,
+                    sent: 0,
+                    done: false,
+                });
+                recipients.len() - 1
+            }
+        };
+        f(&mut recipients[index]);
+    }
+
+    pub fn set_name(&self, ip: IpAddr, name: String) {
+        self.update(ip, |r| r.name = Some(name));
+    }
+
+    /// Registers a download by the recipient at `ip` that is about to start.
+    pub fn start(&self, ip: IpAddr) {
+        self.update(ip, |r| r.sent = 0);
+    }
+
+    fn add_progress(&self, ip: IpAddr, bytes: u64) {
+        self.update(ip, |r| r.sent += bytes);
+    }
+
+    /// Marks the recipient at `ip` as done and prints the new count. Returns whether the expected
+    /// number of devices is done now.
+    pub fn finish(&self, ip: IpAddr) -> bool {
+        let mut newly_done = None;
+        self.update(ip, |r| {
+            if !r.done {
+                newly_done = Some(r.get_display_name());
+            }
+            r.done = true;
+        });
+        let newly_done = match newly_done {
+            Some(name) => {
+                output::print_event(&format!("{} is done, {}", name, self.get_summary()));
+                true
+            }
+            None => false,
+        };
+        newly_done && self.count_done() == self.expected
+    }
+
+    fn count_done(&self) -> usize {
+        self.recipients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.done)
+            .count()
+    }
+
+    /// A line like `23/30 devices done`
+    pub fn get_summary(&self) -> String {
+        format!("{}/{} devices done", self.count_done(), self.expected)
+    }
+
+    pub fn get_recipients(&self) -> Vec<Recipient> {
+        self.recipients.lock().unwrap().clone()
+    }
+}
+
+/// Reads the name sent by the landing page's form from a query string.
+pub fn parse_name(query: Option<&str>) -> Option<String> {
+    let value = query?.split('&').find_map(|p| p.strip_prefix("name="))?;
+    let name = paths::percent_decode(&value.replace('+', " "))?;
+    let name = name
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LENGTH)
+        .collect::<String>();
+    Some(name).filter(|n| !n.is_empty())
+}
+
+/// The landing page asking for the recipient's name. Submitting it starts the download.
+pub fn create_name_page(file_name: &str) -> String {
+    html::create_form_page(
+        file_name,
+        "Enter your name to download the file.",
+        "<form method=\"get\" action=\"\">\n\
+         <input name=\"name\" placeholder=\"Your name\" required autofocus>\n\
+         <button type=\"submit\">Download</button>\n</form>\n",
+    )
+}
+
+/// Wraps a body stream and adds the bytes passing through it to the progress of the recipient,
+/// if the download belongs to a broadcast.
+pub struct TrackedStream<S> {
+    inner: S,
+    recipient: Option<(Arc<Broadcast>, IpAddr)>,
+}
+
+impl<S> TrackedStream<S> {
+    pub fn new(inner: S, recipient: Option<(Arc<Broadcast>, IpAddr)>) -> TrackedStream<S> {
+        TrackedStream { inner, recipient }
+    }
+}
+
+impl<S> Stream for TrackedStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = Pin::new(&mut this.inner).poll_next(cx);
+        if let (Poll::Ready(Some(Ok(chunk))), Some((broadcast, ip))) = (&item, &this.recipient) {
+            broadcast.add_progress(*ip, chunk.len() as u64);
+        }
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_count_done(finished in proptest::collection::vec(0u8..5, 0..20)) {
+            let broadcast = Broadcast::new(5, false);
+            for ip in &finished {
+                broadcast.finish(IpAddr::from([10, 0, 0, *ip]));
+            }
+            let mut distinct = finished.clone();
+            distinct.sort_unstable();
+            distinct.dedup();
+            prop_assert_eq!(format!("{}/5 devices done", distinct.len()), broadcast.get_summary());
+        }
+    }
+
+    #[test]
+    fn test_finish_reports_all_done_once() {
+        let broadcast = Broadcast::new(2, false);
+        let first = IpAddr::from([10, 0, 0, 1]);
+        let second = IpAddr::from([10, 0, 0, 2]);
+        broadcast.start(first);
+        broadcast.add_progress(first, 10);
+        assert!(!broadcast.finish(first));
+        assert!(!broadcast.finish(first));
+        assert!(broadcast.finish(second));
+        assert!(!broadcast.finish(second));
+        assert_eq!(10, broadcast.get_recipients()[0].sent);
+    }
+
+    #[test]
+    fn test_names() {
+        let broadcast = Broadcast::new(2, true);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        assert!(broadcast.needs_name(ip));
+        broadcast.set_name(ip, String::from("Ada"));
+        assert!(!broadcast.needs_name(ip));
+        assert_eq!(
+            "Ada (10.0.0.1)",
+            broadcast.get_recipients()[0].get_display_name()
+        );
+        assert!(!Broadcast::new(2, false).needs_name(ip));
+    }
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(Some(String::from("Ada L")), parse_name(Some("name=Ada+L")));
+        assert_eq!(
+            Some(String::from("Jörg")),
+            parse_name(Some("x=1&name=J%C3%B6rg"))
+        );
+        assert_eq!(None, parse_name(Some("name=+")));
+        assert_eq!(None, parse_name(Some("x=1")));
+        assert_eq!(None, parse_name(None));
+    }
+}
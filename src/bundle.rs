@@ -0,0 +1,387 @@
+//! Several files and texts shared together behind one link, with `rustbelt session`
+//!
+//! Handing over a document, the password to open it and a link copied from the browser used to
+//! take three invocations and three QR codes. A bundle serves all of them below a single random
+//! token: `/<token>/` is an index page listing the items, and every item is at
+//! `/<token>/<n>/<name>`. Texts are shown on a page with a copy button like `--text`, files are
+//! downloaded with Range support. Every item expires and can be revoked on its own from the
+//! console, after which it is left out of the index and answered with 410 Gone, while the others
+//! stay available.
+
+use crate::source::{self, FileSource, MemorySource};
+use crate::tokens::{self, Scope, Token};
+use crate::{clipboard, create_status_response, help, html, paths, snippet};
+use bytes::Bytes;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Prefix of an item given on the command line as text rather than a path
+const TEXT_PREFIX: &str = "text:";
+/// Item standing for the current content of the clipboard
+const CLIPBOARD_ITEM: &str = "clipboard";
+
+enum Content {
+    File(PathBuf),
+    Text(String),
+}
+
+/// A file or text in a bundle
+pub struct Item {
+    pub name: String,
+    content: Content,
+    expires: Mutex<Option<SystemTime>>,
+    revoked: AtomicBool,
+}
+
+/// Whether an item can still be fetched
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ItemState {
+    Available(Option<SystemTime>),
+    Expired,
+    Revoked,
+}
+
+impl fmt::Display for ItemState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ItemState::Available(None) => f.write_str("available"),
+            ItemState::Available(Some(expires)) => {
+                let expires = chrono::DateTime::<chrono::Local>::from(*expires);
+                write!(f, "available until {}", expires.format("%H:%M"))
+            }
+            ItemState::Expired => f.write_str("expired"),
+            ItemState::Revoked => f.write_str("revoked"),
+        }
+    }
+}
+
+impl Item {
+    fn new(name: String, content: Content) -> Item {
+        Item {
+            name,
+            content,
+            expires: Mutex::new(None),
+            revoked: AtomicBool::new(false),
+        }
+    }
+
+    /// Reads an item given on the command line: a file, `text:` followed by the text, or
+    /// `clipboard` for what is on the clipboard right now.
+    pub fn parse(item: &str) -> io::Result<Item> {
+        if let Some(text) = item.strip_prefix(TEXT_PREFIX) {
+            return Ok(Item::new(
+                String::from(snippet::DEFAULT_NAME),
+                Content::Text(text.to_string()),
+            ));
+        }
+        if item == CLIPBOARD_ITEM {
+            let text = clipboard::paste()?;
+            if text.trim().is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the clipboard holds no text",
+                ));
+            }
+            return Ok(Item::new(
+                String::from("clipboard.txt"),
+                Content::Text(text),
+            ));
+        }
+        let path = PathBuf::from(item);
+        if !path.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file", item),
+            ));
+        }
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => String::from("download"),
+        };
+        Ok(Item::new(name, Content::File(path)))
+    }
+
+    pub fn get_path(&self) -> Option<&PathBuf> {
+        match &self.content {
+            Content::File(path) => Some(path),
+            Content::Text(_) => None,
+        }
+    }
+
+    pub fn get_state(&self) -> ItemState {
+        let expires = *self.expires.lock().unwrap();
+        if self.revoked.load(Ordering::SeqCst) {
+            ItemState::Revoked
+        } else if expires.is_some_and(|e| SystemTime::now() >= e) {
+            ItemState::Expired
+        } else {
+            ItemState::Available(expires)
+        }
+    }
+
+    fn expire_after(&self, lifetime: Duration) {
+        *self.expires.lock().unwrap() = SystemTime::now().checked_add(lifetime);
+    }
+}
+
+/// The items shared behind one token
+pub struct Bundle {
+    token: Token,
+    items: Vec<Item>,
+}
+
+impl Bundle {
+    /// Bundles `items`, each of them expiring after `lifetime` if it is given.
+    pub fn new(items: Vec<Item>, lifetime: Option<Duration>) -> Bundle {
+        if let Some(lifetime) = lifetime {
+            items.iter().for_each(|item| item.expire_after(lifetime));
+        }
+        Bundle {
+            token: Token::new(Scope::Download),
+            items,
+        }
+    }
+
+    /// The URL of the index page
+    pub fn get_url(&self, base_url: &str) -> String {
+        self.token.get_url(base_url)
+    }
+
+    pub fn get_items(&self) -> &[Item] {
+        &self.items
+    }
+
+    fn get_item(&self, number: usize) -> Result<&Item, String> {
+        number
+            .checked_sub(1)
+            .and_then(|index| self.items.get(index))
+            .ok_or_else(|| format!("There is no item {}", number))
+    }
+
+    /// Revokes item `number`, counting from 1.
+    pub fn revoke(&self, number: usize) -> Result<&Item, String> {
+        let item = self.get_item(number)?;
+        item.revoked.store(true, Ordering::SeqCst);
+        Ok(item)
+    }
+
+    /// Lets item `number` expire after `lifetime` from now on, unless it is revoked.
+    pub fn expire(&self, number: usize, lifetime: Duration) -> Result<&Item, String> {
+        let item = self.get_item(number)?;
+        if item.get_state() == ItemState::Revoked {
+            return Err(format!("Item {} has been revoked", number));
+        }
+        item.expire_after(lifetime);
+        Ok(item)
+    }
+
+    /// The path of item `number` below the token
+    fn get_item_path(&self, number: usize, item: &Item) -> String {
+        format!(
+            "/{}/{}/{}",
+            self.token.value,
+            number,
+            paths::percent_encode(&item.name)
+        )
+    }
+
+    fn create_index_page(&self) -> String {
+        let links = self
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, item)| match item.get_state() {
+                ItemState::Available(None) => Some((index, item.name.clone())),
+                state @ ItemState::Available(_) => {
+                    Some((index, format!("{}, {}", item.name, state)))
+                }
+                _ => None,
+            })
+            .map(|(index, label)| (self.get_item_path(index + 1, &self.items[index]), label))
+            .collect::<Vec<_>>();
+        if links.is_empty() {
+            return html::create_message_page("Shared items", "Nothing is shared anymore.");
+        }
+        html::create_listing_page("Shared items", &links)
+    }
+}
+
+/// Serves the index page and the items below the token of `bundle`.
+/// What a request of a bundle is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Index,
+    Item,
+}
+
+/// The requests of a bundle, below its token
+pub fn get_routes() -> Vec<help::Route<Route>> {
+    vec![
+        help::Route::new(Method::GET, "/", "Lists the shared items", Route::Index),
+        help::Route::new(
+            Method::GET,
+            "/<n>/<name>",
+            "Downloads a file or shows a text, until the item expires or is revoked",
+            Route::Item,
+        ),
+    ]
+}
+
+pub async fn handle_request(
+    bundle: Arc<Bundle>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let rest = match tokens::split_token(req.uri().path()) {
+        Some((token, rest)) if token == bundle.token.value => rest,
+        _ => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let route = match help::find_route_at(&get_routes(), &req, rest) {
+        Ok(route) => route,
+        Err(response) => return Ok(response),
+    };
+    if route == Route::Index {
+        // Relative links on the index page need the trailing slash.
+        if !req.uri().path().ends_with('/') {
+            return Ok(Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, format!("/{}/", bundle.token.value))
+                .body(Body::empty())
+                .unwrap());
+        }
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(bundle.create_index_page()))
+            .unwrap());
+    }
+    let number = rest[1..].split('/').next().and_then(|n| n.parse().ok());
+    let item = match number.map(|n| bundle.get_item(n)) {
+        Some(Ok(item)) => item,
+        _ => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    match item.get_state() {
+        ItemState::Available(_) => {}
+        ItemState::Expired => {
+            return Ok(create_status_response(
+                StatusCode::GONE,
+                "This item has expired",
+            ))
+        }
+        ItemState::Revoked => {
+            return Ok(create_status_response(
+                StatusCode::GONE,
+                "This item has been revoked",
+            ))
+        }
+    }
+    Ok(match &item.content {
+        Content::File(path) => {
+            let source = FileSource::new(path.clone(), item.name.clone());
+            source::serve(Arc::new(source), &req).await
+        }
+        Content::Text(text) if snippet::wants_page(&req) => snippet::serve_page(&item.name, text),
+        Content::Text(text) => {
+            let content_type = match req.uri().query() {
+                Some(_) => None,
+                None => Some("text/plain; charset=utf-8"),
+            };
+            let content = Bytes::from(text.clone());
+            let source = MemorySource::new(item.name.clone(), content, content_type);
+            source::serve(Arc::new(source), &req).await
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    async fn get(bundle: &Arc<Bundle>, path: &str) -> Response<Body> {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        handle_request(bundle.clone(), req).await.unwrap()
+    }
+
+    async fn read_body(response: Response<Body>) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_item() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.pdf");
+        fs::write(&path, b"%PDF").unwrap();
+        let item = Item::parse(path.to_str().unwrap()).unwrap();
+        assert_eq!("report.pdf", item.name);
+        assert_eq!(Some(&path), item.get_path());
+        let item = Item::parse("text:hunter2").unwrap();
+        assert_eq!(snippet::DEFAULT_NAME, item.name);
+        assert!(item.get_path().is_none());
+        assert!(Item::parse(dir.path().to_str().unwrap()).is_err());
+        assert!(Item::parse(dir.path().join("missing").to_str().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_items_are_revoked_on_their_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.pdf");
+        fs::write(&path, b"%PDF").unwrap();
+        let items = vec![
+            Item::parse(path.to_str().unwrap()).unwrap(),
+            Item::parse("text:hunter2").unwrap(),
+        ];
+        let bundle = Arc::new(Bundle::new(items, None));
+        let token = bundle.token.value.clone();
+        assert_eq!(StatusCode::NOT_FOUND, get(&bundle, "/").await.status());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get(&bundle, "/wrong/").await.status()
+        );
+        let redirect = get(&bundle, &format!("/{}", token)).await;
+        assert_eq!(StatusCode::MOVED_PERMANENTLY, redirect.status());
+
+        let index = read_body(get(&bundle, &format!("/{}/", token)).await).await;
+        assert!(index.contains(&format!("/{}/1/report.pdf", token)));
+        assert!(index.contains(&format!("/{}/2/text.txt", token)));
+        let text = get(&bundle, &format!("/{}/2/text.txt", token)).await;
+        assert_eq!("hunter2", read_body(text).await);
+
+        bundle.revoke(2).unwrap();
+        let index = read_body(get(&bundle, &format!("/{}/", token)).await).await;
+        assert!(!index.contains("text.txt"));
+        let text = get(&bundle, &format!("/{}/2/text.txt", token)).await;
+        assert_eq!(StatusCode::GONE, text.status());
+        let file = get(&bundle, &format!("/{}/1/report.pdf", token)).await;
+        assert_eq!("%PDF", read_body(file).await);
+        assert!(bundle.expire(2, Duration::from_secs(60)).is_err());
+        assert!(bundle.revoke(3).is_err());
+        assert!(bundle.revoke(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_items_expire() {
+        let items = vec![
+            Item::parse("text:a").unwrap(),
+            Item::parse("text:b").unwrap(),
+        ];
+        let bundle = Arc::new(Bundle::new(items, Some(Duration::from_secs(3600))));
+        assert!(matches!(
+            bundle.get_items()[0].get_state(),
+            ItemState::Available(Some(_))
+        ));
+        bundle.expire(1, Duration::from_secs(0)).unwrap();
+        assert_eq!(ItemState::Expired, bundle.get_items()[0].get_state());
+        let token = bundle.token.value.clone();
+        let text = get(&bundle, &format!("/{}/1/text.txt", token)).await;
+        assert_eq!(StatusCode::GONE, text.status());
+        let index = read_body(get(&bundle, &format!("/{}/", token)).await).await;
+        assert!(index.contains(&format!("/{}/2/text.txt", token)));
+        assert!(!index.contains(&format!("/{}/1/text.txt", token)));
+    }
+}
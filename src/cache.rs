@@ -0,0 +1,116 @@
+//! Cache-Control headers of responses
+//!
+//! Share URLs are often meant for a single recipient, so by default nothing may be stored by
+//! proxies in between. A website preview is revalidated on every request so edits show up on
+//! reload. `--cache-control` replaces the policy of all successful responses, error pages are
+//! never stored.
+
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Response};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CachePolicy {
+    /// Never store responses, for secrets and single use links
+    NoStore,
+    /// Store responses but ask the server before reusing them
+    Revalidate,
+    /// Reuse responses for a year without asking, for assets that don't change
+    Static,
+}
+
+impl CachePolicy {
+    pub fn get_header_value(self) -> &'static str {
+        match self {
+            CachePolicy::NoStore => "no-store",
+            CachePolicy::Revalidate => "no-cache",
+            CachePolicy::Static => "public, max-age=31536000, immutable",
+        }
+    }
+}
+
+impl FromStr for CachePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CachePolicy, String> {
+        match s {
+            "no-store" => Ok(CachePolicy::NoStore),
+            "revalidate" => Ok(CachePolicy::Revalidate),
+            "static" => Ok(CachePolicy::Static),
+            _ => Err(format!("Unknown cache control preset: {}", s)),
+        }
+    }
+}
+
+/// Sets the Cache-Control header of `response`. `configured` overrides the header of successful
+/// responses, otherwise `default` is used if the handler didn't set one itself.
+pub fn apply_policy(
+    default: CachePolicy,
+    configured: Option<CachePolicy>,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    let status = response.status();
+    let policy = match configured {
+        Some(policy) if status.is_success() || status.is_redirection() => policy,
+        _ if status.is_client_error() || status.is_server_error() => CachePolicy::NoStore,
+        _ if response.headers().contains_key(header::CACHE_CONTROL) => return response,
+        _ => default,
+    };
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(policy.get_header_value()),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    fn create_response(status: StatusCode, cache_control: Option<&str>) -> Response<Body> {
+        let mut response = Response::builder().status(status);
+        if let Some(value) = cache_control {
+            response = response.header(header::CACHE_CONTROL, value);
+        }
+        response.body(Body::empty()).unwrap()
+    }
+
+    fn get_cache_control(response: &Response<Body>) -> &str {
+        response.headers()[header::CACHE_CONTROL].to_str().unwrap()
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let response = apply_policy(
+            CachePolicy::NoStore,
+            None,
+            create_response(StatusCode::OK, None),
+        );
+        assert_eq!("no-store", get_cache_control(&response));
+
+        let response = apply_policy(
+            CachePolicy::NoStore,
+            None,
+            create_response(StatusCode::OK, Some("no-cache")),
+        );
+        assert_eq!("no-cache", get_cache_control(&response));
+
+        let response = apply_policy(
+            CachePolicy::Revalidate,
+            Some(CachePolicy::Static),
+            create_response(StatusCode::OK, Some("no-cache")),
+        );
+        assert_eq!(
+            "public, max-age=31536000, immutable",
+            get_cache_control(&response)
+        );
+
+        let response = apply_policy(
+            CachePolicy::Revalidate,
+            Some(CachePolicy::Static),
+            create_response(StatusCode::NOT_FOUND, None),
+        );
+        assert_eq!("no-store", get_cache_control(&response));
+    }
+}
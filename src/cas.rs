@@ -0,0 +1,367 @@
+//! Content-addressed URLs, with `--cas`
+//!
+//! Every file below PATH is served at `/sha256/<hash>/<name>`, named after the SHA-256 of its
+//! content. The name segment only sets the name the file is saved under and may be left out.
+//! Since such a URL can only ever return the same bytes, its responses are marked immutable for
+//! browsers and proxies, links from a long-running instance stay valid for as long as the content
+//! exists, and identical files in different places share one URL. `/` lists the files with their
+//! URLs, `/SHA256SUMS` and `/manifest.json` describe them for scripts.
+//!
+//! Files are hashed once at the start. A request for an unknown hash rescans the directory, at
+//! most every `RESCAN_INTERVAL` and rehashing only files whose size or modification time changed,
+//! so files added later are found. Before a file is served, its size and modification time are
+//! compared with those it was hashed with, and a file that changed since is rehashed rather than
+//! served under a hash that no longer matches.
+
+use crate::cache::CachePolicy;
+use crate::manifest::{self, Entry};
+use crate::source::{self, FileSource};
+use crate::{create_status_response, help, html, paths};
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+pub const CAS_PREFIX: &str = "/sha256/";
+/// Shortest time between two rescans caused by requests for unknown hashes
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A file with the path it is listed under and the hash it was found to have
+#[derive(Clone)]
+struct Stored {
+    file: PathBuf,
+    entry: Entry,
+}
+
+/// The files below a directory, by the hashes of their content
+pub struct ContentStore {
+    root: PathBuf,
+    files: Mutex<Vec<Stored>>,
+    scanned: Mutex<Instant>,
+}
+
+/// Size and modification time of `file` as stored in a manifest entry
+fn get_version(file: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(file)?;
+    let modified = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+    Ok((metadata.len(), modified))
+}
+
+/// Lists the files below `root`, reusing the hashes of `known` files that didn't change.
+fn scan(root: &Path, known: &[Stored]) -> io::Result<Vec<Stored>> {
+    let mut files = Vec::new();
+    for file in crate::list_files(root)? {
+        let path = match file.strip_prefix(root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => Path::new(file.file_name().unwrap_or_default()),
+        };
+        let path = path.to_string_lossy().replace('\\', "/");
+        let (size, modified) = get_version(&file)?;
+        let unchanged = known
+            .iter()
+            .find(|k| k.file == file && k.entry.size == size && k.entry.modified == modified);
+        let entry = match unchanged {
+            Some(known) => Entry {
+                path,
+                ..known.entry.clone()
+            },
+            None => manifest::create_entry(&file, path)?,
+        };
+        files.push(Stored { file, entry });
+    }
+    Ok(files)
+}
+
+/// Whether `hash` looks like a hex encoded SHA-256 hash as used in the URLs
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The URL path of `entry`
+pub fn get_url(entry: &Entry) -> String {
+    let name = entry.path.rsplit('/').next().unwrap_or_default();
+    format!(
+        "{}{}/{}",
+        CAS_PREFIX,
+        entry.sha256,
+        paths::percent_encode(name)
+    )
+}
+
+impl ContentStore {
+    /// Hashes every file below `root`.
+    pub fn open(root: PathBuf) -> io::Result<ContentStore> {
+        let files = scan(&root, &[])?;
+        Ok(ContentStore {
+            root,
+            files: Mutex::new(files),
+            scanned: Mutex::new(Instant::now()),
+        })
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn get_entries(&self) -> Vec<Entry> {
+        let files = self.files.lock().unwrap();
+        files.iter().map(|f| f.entry.clone()).collect()
+    }
+
+    /// The file with content `hash` and its name, if it is known and still has that content
+    fn find(&self, hash: &str) -> Option<(PathBuf, Entry)> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .filter(|f| f.entry.sha256 == hash)
+            .find(|f| get_version(&f.file).ok() == Some((f.entry.size, f.entry.modified)))
+            .map(|f| (f.file.clone(), f.entry.clone()))
+    }
+
+    /// Rescans the directory unless it was scanned a moment ago or `force` is given.
+    fn rescan(&self, force: bool) -> io::Result<()> {
+        {
+            let mut scanned = self.scanned.lock().unwrap();
+            if !force && scanned.elapsed() < RESCAN_INTERVAL {
+                return Ok(());
+            }
+            *scanned = Instant::now();
+        }
+        // Requests keep being answered from the known files while the scan runs.
+        let known = self.files.lock().unwrap().clone();
+        let files = scan(&self.root, &known)?;
+        *self.files.lock().unwrap() = files;
+        Ok(())
+    }
+
+    /// Finds the file with content `hash`, rescanning the directory if it isn't known or changed.
+    async fn lookup(self: &Arc<Self>, hash: &str) -> Option<(PathBuf, Entry)> {
+        if let Some(found) = self.find(hash) {
+            return Some(found);
+        }
+        let store = self.clone();
+        let hash = hash.to_string();
+        let changed = {
+            let files = self.files.lock().unwrap();
+            files.iter().any(|f| f.entry.sha256 == hash)
+        };
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = store.rescan(changed) {
+                eprintln!("Could not scan {}: {}", store.root.display(), e);
+            }
+            store.find(&hash)
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    fn create_listing_page(&self) -> String {
+        let links = self
+            .get_entries()
+            .iter()
+            .map(|entry| (get_url(entry), entry.path.clone()))
+            .collect::<Vec<_>>();
+        let title = self.root.file_name().unwrap_or_default().to_string_lossy();
+        html::create_listing_page(&title, &links)
+    }
+}
+
+/// What a request of the store is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Listing,
+    Sums,
+    Manifest,
+    File,
+}
+
+/// The requests the store answers
+pub fn get_routes() -> Vec<help::Route<Route>> {
+    use help::Route as R;
+    vec![
+        R::new(
+            Method::GET,
+            "/",
+            "Lists the files with their URLs",
+            Route::Listing,
+        ),
+        R::new(
+            Method::GET,
+            "/SHA256SUMS",
+            "Checksums in the format of sha256sum",
+            Route::Sums,
+        ),
+        R::new(
+            Method::GET,
+            manifest::MANIFEST_JSON_PATH,
+            "Paths, sizes, SHA-256 and URLs of all files as JSON",
+            Route::Manifest,
+        ),
+        R::new(
+            Method::GET,
+            "/sha256/<hash>/<name>",
+            "Downloads the file with this SHA-256, saved as name if it is given. The response \
+             never changes and may be cached forever",
+            Route::File,
+        )
+        .below("/sha256"),
+    ]
+}
+
+pub async fn handle_request(
+    store: Arc<ContentStore>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    match help::find_route(&get_routes(), &req) {
+        Ok(Route::Listing) => {
+            return Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(store.create_listing_page()))
+                .unwrap())
+        }
+        Ok(Route::Sums) => {
+            let sums = manifest::create_sha256sums(&store.get_entries());
+            return Ok(
+                crate::serve_text("SHA256SUMS", sums, "text/plain; charset=utf-8", &req).await,
+            );
+        }
+        Ok(Route::Manifest) => {
+            let entries = store
+                .get_entries()
+                .into_iter()
+                .map(|entry| {
+                    let url = get_url(&entry);
+                    (entry, url)
+                })
+                .collect::<Vec<_>>();
+            let json = manifest::create_json(&entries);
+            return Ok(crate::serve_text("manifest.json", json, "application/json", &req).await);
+        }
+        Ok(Route::File) => {}
+        Err(response) => return Ok(response),
+    }
+    let rest = match req.uri().path().strip_prefix(CAS_PREFIX) {
+        Some(rest) => rest,
+        None => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let (hash, name) = match rest.find('/') {
+        Some(i) => (&rest[..i], paths::get_path_segment(&rest[i + 1..])),
+        None => (rest, None),
+    };
+    if !is_hash(hash) {
+        return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found"));
+    }
+    let (file, entry) = match store.lookup(hash).await {
+        Some(found) => found,
+        None => {
+            return Ok(create_status_response(
+                StatusCode::NOT_FOUND,
+                "No file with this content is shared",
+            ))
+        }
+    };
+    let name = name.unwrap_or_else(|| {
+        let name = entry.path.rsplit('/').next().unwrap_or_default();
+        name.to_string()
+    });
+    let mut response = source::serve(Arc::new(FileSource::new(file, name)), &req).await;
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(CachePolicy::Static.get_header_value()),
+        );
+        if let Ok(digest) = HeaderValue::from_str(&crate::create_digest(&entry.sha256)) {
+            headers.insert("digest", digest);
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    async fn get(store: &Arc<ContentStore>, path: &str) -> Response<Body> {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        handle_request(store.clone(), req).await.unwrap()
+    }
+
+    async fn read_body(response: Response<Body>) -> Vec<u8> {
+        hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    proptest! {
+        #[test]
+        fn test_only_lowercase_hashes(hash in "[0-9a-fA-F]{60,68}") {
+            prop_assert_eq!(
+                hash.len() == 64 && hash == hash.to_lowercase(),
+                is_hash(&hash)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_identical_files_share_a_url() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("copy")).unwrap();
+        fs::write(dir.path().join("a.txt"), b"same").unwrap();
+        fs::write(dir.path().join("copy").join("b.txt"), b"same").unwrap();
+        let store = Arc::new(ContentStore::open(dir.path().to_path_buf()).unwrap());
+        let entries = store.get_entries();
+        assert_eq!(vec!["a.txt", "copy/b.txt"], {
+            entries.iter().map(|e| e.path.as_str()).collect::<Vec<_>>()
+        });
+        assert_eq!(entries[0].sha256, entries[1].sha256);
+
+        let path = format!("{}{}", CAS_PREFIX, entries[0].sha256);
+        let response = get(&store, &path).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            CachePolicy::Static.get_header_value(),
+            response.headers()[header::CACHE_CONTROL]
+        );
+        assert_eq!(b"same".to_vec(), read_body(response).await);
+        let named = get(&store, &format!("{}/c.txt", path)).await;
+        assert!(named.headers()[header::CONTENT_DISPOSITION]
+            .to_str()
+            .unwrap()
+            .contains("c.txt"));
+        let listing = String::from_utf8(read_body(get(&store, "/").await).await).unwrap();
+        assert!(listing.contains(&get_url(&entries[1])));
+    }
+
+    #[tokio::test]
+    async fn test_changed_files_leave_their_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        fs::write(&file, b"first").unwrap();
+        let store = Arc::new(ContentStore::open(dir.path().to_path_buf()).unwrap());
+        let first = store.get_entries()[0].sha256.clone();
+        fs::write(&file, b"second version").unwrap();
+        let response = get(&store, &format!("{}{}", CAS_PREFIX, first)).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let second = manifest::hash_file(&file).unwrap();
+        let response = get(&store, &format!("{}{}", CAS_PREFIX, second)).await;
+        assert_eq!(b"second version".to_vec(), read_body(response).await);
+        let missing = format!("{}{}", CAS_PREFIX, "0".repeat(64));
+        assert_eq!(StatusCode::NOT_FOUND, get(&store, &missing).await.status());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get(&store, "/sha256/..").await.status()
+        );
+    }
+}
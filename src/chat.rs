@@ -0,0 +1,229 @@
+//! Short messages between the sender and the recipients during a session
+//!
+//! With `--chat`, `/chat` serves a page listing the messages of the session with a box to write
+//! one, kept up to date with server-sent events from `/chat/events`. Messages written on the page
+//! are printed on the terminal, and `say <text>` in the console sends one to every open page.
+
+use crate::{create_status_response, help, html, output};
+use futures::stream::{self, Stream, StreamExt};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+pub const CHAT_PATH: &str = "/chat";
+const EVENTS_PATH: &str = "/chat/events";
+/// Messages kept for pages opened later
+const MAX_MESSAGES: usize = 100;
+/// Longest message in bytes
+const MAX_MESSAGE_SIZE: usize = 2000;
+/// Name of messages typed into the terminal
+pub const SENDER_NAME: &str = "Sender";
+
+const PAGE: &str = r#"<ul id="messages"></ul>
+<form id="form"><input id="text" autocomplete="off" autofocus> <button>Send</button></form>
+<script>
+const list = document.getElementById("messages");
+const events = new EventSource("/chat/events");
+events.onmessage = event => {
+  const message = JSON.parse(event.data);
+  const item = document.createElement("li");
+  item.textContent = message.from + ": " + message.text;
+  list.appendChild(item);
+};
+document.getElementById("form").onsubmit = event => {
+  event.preventDefault();
+  const text = document.getElementById("text");
+  if (text.value.trim()) {
+    fetch("/chat", {method: "POST", body: text.value});
+  }
+  text.value = "";
+};
+</script>
+"#;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub from: String,
+    pub text: String,
+}
+
+/// The messages of the session and the pages listening for new ones
+pub struct ChatRoom {
+    messages: Mutex<VecDeque<Arc<Message>>>,
+    sender: broadcast::Sender<Arc<Message>>,
+}
+
+impl Default for ChatRoom {
+    fn default() -> ChatRoom {
+        let (sender, _) = broadcast::channel(MAX_MESSAGES);
+        ChatRoom {
+            messages: Mutex::new(VecDeque::with_capacity(MAX_MESSAGES)),
+            sender,
+        }
+    }
+}
+
+impl ChatRoom {
+    /// Adds a message and sends it to every open page. Control characters are removed, so
+    /// messages can't move the cursor or change colors when printed.
+    pub fn post(&self, from: &str, text: &str) -> Arc<Message> {
+        let message = Arc::new(Message {
+            from: from.to_string(),
+            text: text.chars().filter(|c| !c.is_control()).collect(),
+        });
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == MAX_MESSAGES {
+            messages.pop_front();
+        }
+        messages.push_back(message.clone());
+        // Sending fails if no page is open, the message is then only kept.
+        let _ = self.sender.send(message.clone());
+        message
+    }
+
+    /// Returns the recent messages and a receiver for everything posted after them.
+    fn subscribe(&self) -> (Vec<Arc<Message>>, broadcast::Receiver<Arc<Message>>) {
+        let messages = self.messages.lock().unwrap();
+        (messages.iter().cloned().collect(), self.sender.subscribe())
+    }
+}
+
+fn create_event(message: &Message) -> String {
+    let data = serde_json::json!({ "from": message.from, "text": message.text });
+    format!("data: {}\n\n", data)
+}
+
+fn create_event_stream(chat: &ChatRoom) -> impl Stream<Item = Result<String, Infallible>> {
+    let (backlog, receiver) = chat.subscribe();
+    let backlog = stream::iter(backlog.into_iter().map(|m| Ok(create_event(&m))));
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(message) => return Some((Ok(create_event(&message)), receiver)),
+                Err(broadcast::RecvError::Lagged(_)) => continue,
+                Err(broadcast::RecvError::Closed) => return None,
+            }
+        }
+    });
+    backlog.chain(live)
+}
+
+/// What a request of the chat is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Page,
+    Events,
+    Post,
+}
+
+/// The requests the chat answers, below the share's own
+pub fn get_routes() -> Vec<help::Route<Route>> {
+    vec![
+        help::Route::new(Method::GET, CHAT_PATH, "Messages", Route::Page),
+        help::Route::new(
+            Method::GET,
+            EVENTS_PATH,
+            "New messages as server-sent events",
+            Route::Events,
+        ),
+        help::Route::new(
+            Method::POST,
+            CHAT_PATH,
+            "Sends the text in the body as a message",
+            Route::Post,
+        ),
+    ]
+}
+
+/// Reads a message sent from the page, which is plain text.
+async fn read_message(mut body: Body) -> Option<String> {
+    let mut text = Vec::new();
+    while let Some(chunk) = body.next().await {
+        text.extend_from_slice(&chunk.ok()?);
+        if text.len() > MAX_MESSAGE_SIZE {
+            return None;
+        }
+    }
+    String::from_utf8(text).ok()
+}
+
+/// Answers requests for the chat page and its events and takes messages, `from` being the name
+/// of the client.
+pub async fn handle_request(
+    chat: Arc<ChatRoom>,
+    from: String,
+    req: Request<Body>,
+) -> Response<Body> {
+    match help::find_route(&get_routes(), &req) {
+        Ok(Route::Page) => Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(html::create_page("Messages", PAGE)))
+            .unwrap(),
+        Ok(Route::Events) => Response::builder()
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::wrap_stream(create_event_stream(&chat)))
+            .unwrap(),
+        Ok(Route::Post) => match read_message(req.into_body()).await {
+            Some(text) if !text.trim().is_empty() => {
+                let message = chat.post(&from, text.trim());
+                output::print_event(&format!("Message from {}: {}", message.from, message.text));
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            _ => create_status_response(StatusCode::BAD_REQUEST, "Invalid message"),
+        },
+        Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_event_is_single_line(from in "\\PC*", text in "\\PC*") {
+            let event = create_event(&Message { from, text });
+            prop_assert!(event.starts_with("data: {"), "{}", event);
+            prop_assert_eq!(2, event.matches('\n').count());
+        }
+    }
+
+    #[test]
+    fn test_control_characters_are_removed() {
+        let chat = ChatRoom::default();
+        let message = chat.post("10.0.0.2", "use \u{1b}[31mthe second\r\nfile");
+        assert_eq!("use [31mthe secondfile", message.text);
+    }
+
+    #[test]
+    fn test_messages_are_bounded() {
+        let chat = ChatRoom::default();
+        for i in 0..MAX_MESSAGES + 3 {
+            chat.post(SENDER_NAME, &i.to_string());
+        }
+        let (messages, _) = chat.subscribe();
+        assert_eq!(MAX_MESSAGES, messages.len());
+        assert_eq!("3", messages[0].text);
+    }
+
+    #[tokio::test]
+    async fn test_pages_receive_messages() {
+        let chat = Arc::new(ChatRoom::default());
+        chat.post(SENDER_NAME, "earlier");
+        let mut events = create_event_stream(&chat).boxed();
+        let req = Request::post(CHAT_PATH).body(Body::from("later")).unwrap();
+        let response = handle_request(chat.clone(), String::from("10.0.0.2"), req).await;
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        let first = events.next().await.unwrap().unwrap();
+        assert!(first.contains("\"text\":\"earlier\""));
+        let second = events.next().await.unwrap().unwrap();
+        assert!(second.contains("\"from\":\"10.0.0.2\""));
+    }
+}
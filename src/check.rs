@@ -0,0 +1,332 @@
+//! Diagnostics for the classic "the phone can't open the URL"
+//!
+//! Before the QR code is printed, running firewalls are asked whether they let connections to the
+//! chosen port in, and a warning with the command to open it is printed if not. With `--check`,
+//! rustbelt only binds the chosen address, connects to it and runs these checks without serving
+//! anything. A local connection doesn't pass the firewall's inbound rules, so asking the firewall
+//! is what finds the usual culprit. `--open-firewall` fixes it, see `firewall`.
+//!
+//! The other common mistake is choosing an interface no phone can reach, like a Docker bridge or a
+//! VM network. Those are recognized by their name or their default address range.
+
+use crate::firewall;
+use crate::interfaces::{self, NetworkInterface};
+use crate::neighbors;
+use ipnetwork::IpNetwork;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Runs a command and returns its exit status and output, or `None` if it isn't installed.
+pub fn run_command(program: &str, args: &[&str]) -> Option<(bool, String)> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    Some((
+        output.status.success(),
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+    ))
+}
+
+fn check_firewalld(interface: &str, port: u16) -> Option<String> {
+    let zone = firewall::get_firewalld_zone(interface)?;
+    let query = format!("--query-port={}/tcp", port);
+    match run_command("firewall-cmd", &[&format!("--zone={}", zone), &query])? {
+        (true, _) => None,
+        _ => Some(format!(
+            "firewalld is running and port {0}/tcp is not open in zone {1}, open it with: \
+             sudo firewall-cmd --zone={1} --add-port={0}/tcp",
+            port, zone
+        )),
+    }
+}
+
+/// Whether a rule in the output of `ufw status` allows `port`, or `None` if ufw is inactive.
+fn parse_ufw_status(output: &str, port: u16) -> Option<bool> {
+    if !output.lines().any(|l| l.trim() == "Status: active") {
+        return None;
+    }
+    let allowed = output.lines().any(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (to, action) = match fields.as_slice() {
+            [to, action, ..] => (*to, *action),
+            _ => return false,
+        };
+        let ports = match to.split_once('/') {
+            Some((ports, "tcp")) => ports,
+            Some(_) => return false,
+            None => to,
+        };
+        let matches = match ports.split_once(':') {
+            Some((start, end)) => match (start.parse::<u16>(), end.parse::<u16>()) {
+                (Ok(start), Ok(end)) => start <= port && port <= end,
+                _ => false,
+            },
+            None => ports.split(',').any(|p| p.parse() == Ok(port)),
+        };
+        matches && action == "ALLOW"
+    });
+    Some(allowed)
+}
+
+fn check_ufw(port: u16) -> Option<String> {
+    let (_, output) = run_command("ufw", &["status"])?;
+    match parse_ufw_status(&output, port)? {
+        true => None,
+        false => Some(format!(
+            "ufw is active and has no rule allowing port {0}/tcp, allow it with: \
+             sudo ufw allow {0}/tcp",
+            port
+        )),
+    }
+}
+
+#[cfg(windows)]
+fn check_windows_firewall(_port: u16) -> Option<String> {
+    let (_, output) = run_command("netsh", &["advfirewall", "show", "currentprofile", "state"])?;
+    let enabled = output
+        .lines()
+        .any(|l| l.starts_with("State") && l.trim_end().ends_with("ON"));
+    if !enabled {
+        return None;
+    }
+    Some(String::from(
+        "Windows Firewall is on and may block incoming connections, allow rustbelt when Windows \
+         asks or add an inbound rule for the port",
+    ))
+}
+
+#[cfg(not(windows))]
+fn check_windows_firewall(_port: u16) -> Option<String> {
+    None
+}
+
+/// Warnings about firewalls that likely block connections to `port` on `interface`.
+pub fn check_firewall(interface: &str, port: u16) -> Vec<String> {
+    let mut warnings = Vec::new();
+    warnings.extend(check_firewalld(interface, port));
+    warnings.extend(check_ufw(port));
+    warnings.extend(check_windows_firewall(port));
+    warnings
+}
+
+/// Names of interfaces and address ranges of networks that only reach this computer or devices
+/// in a virtual network, with what they are
+const UNREACHABLE_NETWORKS: &[(&[&str], Option<&str>, &str)] = &[
+    (
+        &["docker", "br-", "veth"],
+        Some("172.17.0.0/16"),
+        "a Docker bridge, only reachable from this computer and its containers",
+    ),
+    (
+        &["vboxnet"],
+        Some("192.168.56.0/24"),
+        "a VirtualBox host-only network, only reachable from this computer and its virtual \
+         machines",
+    ),
+    (
+        &["virbr"],
+        Some("192.168.122.0/24"),
+        "a libvirt network, only reachable from this computer and its virtual machines",
+    ),
+    (
+        &["vmnet"],
+        None,
+        "a VMware host-only or NAT network, only reachable from this computer and its virtual \
+         machines",
+    ),
+    (
+        &["zt"],
+        None,
+        "a ZeroTier network, only reachable from devices that joined it",
+    ),
+    (
+        &["tailscale"],
+        Some("100.64.0.0/10"),
+        "a Tailscale network, only reachable from devices logged into it",
+    ),
+    (
+        &["lo"],
+        Some("127.0.0.0/8"),
+        "the loopback interface, only reachable from this computer",
+    ),
+    (
+        &[],
+        Some("::1/128"),
+        "the loopback interface, only reachable from this computer",
+    ),
+    (
+        &[],
+        Some("169.254.0.0/16"),
+        "a link-local address, a sign that the network handed out no address",
+    ),
+    (
+        &[],
+        Some("64:ff9b::/96"),
+        "a NAT64 address standing in for an IPv4 address, not one of this computer",
+    ),
+];
+
+/// What kind of network `ip` on `interface` is, if it is one phones usually can't reach.
+fn classify_network(interface: &str, ip: IpAddr) -> Option<&'static str> {
+    UNREACHABLE_NETWORKS
+        .iter()
+        .find(|(prefixes, range, _)| {
+            prefixes.iter().any(|p| interface.starts_with(p))
+                || range.is_some_and(|r| r.parse::<IpNetwork>().unwrap().contains(ip))
+        })
+        .map(|(_, _, description)| *description)
+}
+
+/// A warning if `ip` on `interface` is on a network phones usually can't reach, suggesting the
+/// other `interfaces` that look like a real one.
+pub fn check_network(
+    interface: &str,
+    ip: IpAddr,
+    interfaces: &[NetworkInterface],
+) -> Option<String> {
+    let description = classify_network(interface, ip)?;
+    let mut candidates = interfaces
+        .iter()
+        .filter(|i| {
+            i.ips
+                .iter()
+                .any(|n| n.is_ipv4() && classify_network(&i.name, n.ip()).is_none())
+        })
+        .map(|i| i.name.as_str())
+        .collect::<Vec<_>>();
+    candidates.sort_unstable();
+    let suggestion = if candidates.is_empty() {
+        String::from("connect to the network of the recipient and choose its interface")
+    } else {
+        format!(
+            "choose the interface of the Wi-Fi or LAN with -i, like -i {}",
+            candidates.join(" or -i ")
+        )
+    };
+    Some(format!(
+        "{} on {} is {}. If the recipient can't connect, {}",
+        ip, interface, description, suggestion
+    ))
+}
+
+/// Binds `socket` and connects to it, to find out whether the address can be served on at all.
+fn probe(socket: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(socket)?;
+    let accepting = thread::spawn(move || listener.accept().map(|_| ()));
+    TcpStream::connect_timeout(&socket, PROBE_TIMEOUT)?;
+    accepting
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("The probe failed")))
+}
+
+/// Runs all checks for the chosen address and prints the results.
+pub fn run_check(interface: &NetworkInterface, socket: SocketAddr) {
+    match probe(socket) {
+        Ok(()) => println!("OK: {} can be bound and answers locally", socket),
+        Err(e) => println!("Problem: can't serve on {}: {}", socket, e),
+    }
+    let interfaces = interfaces::interfaces();
+    if let Some(warning) = check_network(&interface.name, socket.ip(), &interfaces) {
+        println!("Problem: {}", warning);
+    }
+    let warnings = check_firewall(&interface.name, socket.port());
+    if warnings.is_empty() {
+        println!("OK: no firewall found that blocks port {}", socket.port());
+    }
+    for warning in warnings {
+        println!("Problem: {}", warning);
+    }
+    let _ = neighbors::write_neighbors(interface, &mut io::stdout());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_ufw_range(start in 1u16..30000, length in 0u16..1000, port in 1u16..40000) {
+            let output = format!(
+                "Status: active\n\nTo                         Action      From\n\
+                 --                         ------      ----\n\
+                 {}:{}/tcp                 ALLOW       Anywhere\n",
+                start,
+                start + length
+            );
+            let allowed = start <= port && port <= start + length;
+            prop_assert_eq!(Some(allowed), parse_ufw_status(&output, port));
+        }
+    }
+
+    #[test]
+    fn test_parse_ufw_status() {
+        let output = "Status: active\n\n\
+                      To                         Action      From\n\
+                      --                         ------      ----\n\
+                      22/tcp                     ALLOW       Anywhere\n\
+                      8080                       ALLOW       Anywhere\n\
+                      9000/udp                   ALLOW       Anywhere\n\
+                      7000/tcp                   DENY        Anywhere\n\
+                      8080 (v6)                  ALLOW       Anywhere (v6)\n";
+        assert_eq!(Some(true), parse_ufw_status(output, 22));
+        assert_eq!(Some(true), parse_ufw_status(output, 8080));
+        assert_eq!(Some(false), parse_ufw_status(output, 9000));
+        assert_eq!(Some(false), parse_ufw_status(output, 7000));
+        assert_eq!(None, parse_ufw_status("Status: inactive\n", 22));
+    }
+
+    #[test]
+    fn test_classify_network() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(classify_network("docker0", ip("172.17.0.1")).is_some());
+        assert!(classify_network("br-1a2b3c", ip("172.18.0.1")).is_some());
+        assert!(classify_network("eth0", ip("192.168.56.1")).is_some());
+        assert!(classify_network("ztc3q5x2", ip("10.147.17.3")).is_some());
+        assert!(classify_network("wlan0", ip("100.100.1.2")).is_some());
+        assert!(classify_network("eth0", ip("169.254.3.4")).is_some());
+        assert!(classify_network("wlan0", ip("192.168.1.20")).is_none());
+        assert!(classify_network("enp3s0", ip("fd00::20")).is_none());
+    }
+
+    #[test]
+    fn test_check_network_suggests_interfaces() {
+        let interface = |name: &str, ip: &str| NetworkInterface {
+            name: name.to_string(),
+            index: 0,
+            mac: None,
+            ips: vec![ip.parse().unwrap()],
+            flags: 0,
+        };
+        let interfaces = vec![
+            interface("wlan0", "192.168.1.20/24"),
+            interface("docker0", "172.17.0.1/16"),
+            interface("lo", "127.0.0.1/8"),
+        ];
+        let warning = check_network("docker0", "172.17.0.1".parse().unwrap(), &interfaces);
+        assert!(warning.unwrap().ends_with("like -i wlan0"));
+        assert_eq!(
+            None,
+            check_network("wlan0", "192.168.1.20".parse().unwrap(), &interfaces)
+        );
+    }
+
+    #[test]
+    fn test_probe() {
+        let socket = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert!(probe(socket).is_ok());
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(probe(taken.local_addr().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+//! The clients that connected during a session
+//!
+//! Clients are told apart by their IP address and numbered in the order they first connected. A
+//! revoked client is answered with 410 Gone from then on. Their User-Agent is turned into a short
+//! device label like `Pixel 8 / Chrome`, so several recipients can be told apart. The round-trip
+//! time and loss of the connection a client used last are shown next to it. With
+//! `--resolve-names`, a client is shown by its host name once it is found, see `hostnames`.
+
+use crate::latency::{PathStats, Probe};
+use crate::output;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub ip: IpAddr,
+    /// Name of the device on the network, with `--resolve-names`
+    pub host_name: Option<String>,
+    /// Device and program of the client, if it sent a User-Agent
+    pub label: Option<String>,
+    pub requests: usize,
+    pub revoked: bool,
+    /// Last reading of the connection the client used last, if it is a TCP connection
+    pub path: Option<PathStats>,
+}
+
+impl Client {
+    /// The host name or address, followed by the device label if there is one
+    pub fn get_display_name(&self) -> String {
+        let name = match &self.host_name {
+            Some(host_name) => host_name.clone(),
+            None => self.ip.to_string(),
+        };
+        match &self.label {
+            Some(label) => format!("{} ({})", name, label),
+            None => name,
+        }
+    }
+}
+
+/// Turns a User-Agent header into a short label of device and program.
+pub fn describe_user_agent(user_agent: &str) -> Option<String> {
+    let user_agent = user_agent.trim();
+    let details = match (user_agent.find('('), user_agent.find(')')) {
+        (Some(start), Some(end)) if start < end => &user_agent[start + 1..end],
+        _ => "",
+    };
+    let details = details.split(';').map(str::trim).collect::<Vec<_>>();
+    let has_detail = |name: &str| details.iter().any(|d| d.starts_with(name));
+    let contains = |name: &str| user_agent.contains(name);
+
+    let device = if has_detail("iPhone") {
+        Some(String::from("iPhone"))
+    } else if has_detail("iPad") {
+        Some(String::from("iPad"))
+    } else if let Some(index) = details.iter().position(|d| d.starts_with("Android")) {
+        // Reduced User-Agents replace the model with "K"
+        let model = details
+            .get(index + 1)
+            .map(|m| m.split(" Build/").next().unwrap_or_default().trim())
+            .filter(|m| !m.is_empty() && *m != "K" && *m != "wv");
+        Some(model.unwrap_or("Android").to_string())
+    } else if has_detail("Windows") {
+        Some(String::from("Windows"))
+    } else if has_detail("Macintosh") {
+        Some(String::from("Mac"))
+    } else if has_detail("CrOS") {
+        Some(String::from("ChromeOS"))
+    } else if has_detail("X11") || has_detail("Linux") {
+        Some(String::from("Linux"))
+    } else {
+        None
+    };
+
+    let program = if contains("Edg/") || contains("EdgA/") || contains("EdgiOS/") {
+        "Edge"
+    } else if contains("OPR/") {
+        "Opera"
+    } else if contains("SamsungBrowser/") {
+        "Samsung Internet"
+    } else if contains("Firefox/") || contains("FxiOS/") {
+        "Firefox"
+    } else if contains("Chrome/") || contains("CriOS/") {
+        "Chrome"
+    } else if contains("Safari/") {
+        "Safari"
+    } else {
+        // Tools like curl/8.5.0 or Wget/1.21 name themselves first
+        user_agent
+            .split(|c: char| c == '/' || c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+    };
+
+    match (device, program) {
+        (None, "") => None,
+        (None, program) => Some(program.to_string()),
+        (Some(device), "") => Some(device),
+        (Some(device), program) => Some(format!("{} / {}", device, program)),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ClientList {
+    clients: Mutex<Vec<Client>>,
+    /// The connection each client used last
+    probes: Mutex<HashMap<IpAddr, Arc<Probe>>>,
+    /// Clients whose host name was looked up
+    lookups: Mutex<HashSet<IpAddr>>,
+}
+
+impl ClientList {
+    /// Counts a request of the client at `ip`, announcing clients that connect for the first
+    /// time. Returns whether the client may still access the share.
+    pub fn record(&self, ip: IpAddr, user_agent: Option<&str>) -> bool {
+        let label = user_agent.and_then(describe_user_agent);
+        let mut clients = self.clients.lock().unwrap();
+        let client = match clients.iter().position(|c| c.ip == ip) {
+            Some(index) => &mut clients[index],
+            None => {
+                clients.push(Client {
+                    ip,
+                    host_name: None,
+                    label: label.clone(),
+                    requests: 0,
+                    revoked: false,
+                    path: None,
+                });
+                let client = clients.last_mut().unwrap();
+                output::print_event(&format!("New client: {}", client.get_display_name()));
+                client
+            }
+        };
+        if label.is_some() {
+            client.label = label;
+        }
+        client.requests += 1;
+        !client.revoked
+    }
+
+    /// Revokes the client given by its number, IP address or host name and returns its address.
+    pub fn revoke(&self, query: &str) -> Option<IpAddr> {
+        let mut clients = self.clients.lock().unwrap();
+        let index = match (query.parse::<usize>(), query.parse::<IpAddr>()) {
+            (Ok(number), _) if number > 0 && number <= clients.len() => number - 1,
+            (_, Ok(ip)) => clients.iter().position(|c| c.ip == ip)?,
+            _ => clients.iter().position(|c| {
+                c.host_name
+                    .as_deref()
+                    .is_some_and(|h| h.eq_ignore_ascii_case(query))
+            })?,
+        };
+        clients[index].revoked = true;
+        Some(clients[index].ip)
+    }
+
+    pub fn is_revoked(&self, ip: IpAddr) -> bool {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c.ip == ip && c.revoked)
+    }
+
+    /// Whether the host name of the client at `ip` still has to be looked up, which is only
+    /// true once.
+    pub fn start_lookup(&self, ip: IpAddr) -> bool {
+        self.lookups.lock().unwrap().insert(ip)
+    }
+
+    /// Shows the client at `ip` by `host_name` from now on.
+    pub fn set_host_name(&self, ip: IpAddr, host_name: String) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.iter_mut().find(|c| c.ip == ip) {
+            output::print_event(&format!("{} is {}", ip, host_name));
+            client.host_name = Some(host_name);
+        }
+    }
+
+    /// Remembers `probe` as the connection the client at `ip` used last.
+    pub fn set_probe(&self, ip: IpAddr, probe: Arc<Probe>) {
+        self.probes.lock().unwrap().insert(ip, probe);
+    }
+
+    pub fn get_clients(&self) -> Vec<Client> {
+        let probes = self.probes.lock().unwrap();
+        let mut clients = self.clients.lock().unwrap().clone();
+        for client in &mut clients {
+            client.path = probes.get(&client.ip).and_then(|p| p.get());
+        }
+        clients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_record_counts_requests(ips in proptest::collection::vec(0u8..4, 0..20)) {
+            let list = ClientList::default();
+            for ip in &ips {
+                prop_assert!(list.record(IpAddr::from([10, 0, 0, *ip]), None));
+            }
+            let clients = list.get_clients();
+            prop_assert_eq!(ips.len(), clients.iter().map(|c| c.requests).sum::<usize>());
+            let mut distinct = ips.clone();
+            distinct.sort_unstable();
+            distinct.dedup();
+            prop_assert_eq!(distinct.len(), clients.len());
+        }
+    }
+
+    #[test]
+    fn test_describe_user_agent() {
+        let cases = [
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) \
+                 Chrome/120.0.0.0 Mobile Safari/537.36",
+                "Pixel 8 / Chrome",
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) \
+                 Chrome/120.0.0.0 Mobile Safari/537.36",
+                "Android / Chrome",
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 \
+                 (KHTML, like Gecko) Version/17.2 Mobile/15E148 Safari/604.1",
+                "iPhone / Safari",
+            ),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+                "Linux / Firefox",
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like \
+                 Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0",
+                "Windows / Edge",
+            ),
+            ("curl/8.5.0", "curl"),
+            ("Wget/1.21.4", "Wget"),
+        ];
+        for (user_agent, label) in cases.iter() {
+            assert_eq!(Some(label.to_string()), describe_user_agent(user_agent));
+        }
+        assert_eq!(None, describe_user_agent(""));
+    }
+
+    #[test]
+    fn test_revoke() {
+        let list = ClientList::default();
+        let first = IpAddr::from([192, 168, 0, 2]);
+        let second = IpAddr::from([192, 168, 0, 3]);
+        list.record(first, None);
+        list.record(second, Some("curl/8.5.0"));
+        assert_eq!(
+            "192.168.0.3 (curl)",
+            list.get_clients()[1].get_display_name()
+        );
+        assert_eq!(Some(second), list.revoke("2"));
+        assert!(list.is_revoked(second));
+        assert!(!list.record(second, None));
+        assert!(list.record(first, None));
+        assert_eq!(Some(first), list.revoke("192.168.0.2"));
+        assert!(!list.record(first, None));
+        assert_eq!(None, list.revoke("3"));
+        assert_eq!(None, list.revoke("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_host_name() {
+        let list = ClientList::default();
+        let ip = IpAddr::from([192, 168, 1, 57]);
+        list.record(ip, Some("curl/8.5.0"));
+        assert!(list.start_lookup(ip));
+        assert!(!list.start_lookup(ip));
+        list.set_host_name(ip, String::from("lena-macbook.local"));
+        assert_eq!(
+            "lena-macbook.local (curl)",
+            list.get_clients()[0].get_display_name()
+        );
+        assert_eq!(None, list.revoke("other.local"));
+        assert_eq!(Some(ip), list.revoke("Lena-MacBook.local"));
+    }
+}
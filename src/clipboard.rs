@@ -0,0 +1,310 @@
+//! Putting text sent from a phone onto the clipboard of the receiving machine, with `--to-clipboard`
+//!
+//! The upload page gets a text field that PUTs its content to `/.clipboard`. Nothing lands on the
+//! clipboard unasked: the text is announced on the terminal with a number and waits there until
+//! `paste <n>` copies it or `discard <n>` drops it, and the sender is told which one it was. The
+//! clipboard is written by the tool of the platform, `wl-copy` under Wayland, `xclip` or `xsel`
+//! under X11, `pbcopy` on macOS and `clip` on Windows, and read by their counterparts for the
+//! `clipboard` item of `rustbelt session`.
+
+use crate::{create_status_response, output};
+use futures::stream::StreamExt;
+use hyper::{Body, Response, StatusCode};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+pub const CLIPBOARD_PATH: &str = "/.clipboard";
+/// The longest text accepted, anything longer is a file rather than something to paste
+pub const MAX_TEXT: usize = 64 * 1024;
+/// Time the sender waits for an answer before the text is discarded
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(300);
+/// Characters of the text shown on the terminal
+const PREVIEW_LENGTH: usize = 60;
+
+/// Text waiting for the owner of the terminal to copy or discard it
+struct Offer {
+    text: String,
+    answer: oneshot::Sender<bool>,
+}
+
+/// The texts sent for the clipboard and not answered yet
+#[derive(Default)]
+pub struct Clipboard {
+    offers: Mutex<BTreeMap<usize, Offer>>,
+    next: AtomicUsize,
+}
+
+impl Clipboard {
+    /// Announces `text` from `sender` and waits until it is answered, false if it is discarded or
+    /// not answered in time.
+    async fn offer(&self, sender: &str, text: String) -> bool {
+        let number = self.next.fetch_add(1, Ordering::SeqCst) + 1;
+        let (answer, answered) = oneshot::channel();
+        output::print_event(&format!(
+            "{} sent text for the clipboard: {}",
+            sender,
+            get_preview(&text)
+        ));
+        eprintln!(
+            "Type paste {0} to copy it to the clipboard or discard {0} to drop it",
+            number
+        );
+        self.offers
+            .lock()
+            .unwrap()
+            .insert(number, Offer { text, answer });
+        let accepted = tokio::time::timeout(ANSWER_TIMEOUT, answered).await;
+        self.offers.lock().unwrap().remove(&number);
+        matches!(accepted, Ok(Ok(true)))
+    }
+
+    /// Copies or discards text `number`, or the oldest one without a number, and returns its
+    /// number.
+    pub fn answer(&self, number: Option<usize>, accept: bool) -> Result<usize, String> {
+        let mut offers = self.offers.lock().unwrap();
+        let number = match number {
+            Some(number) => number,
+            None => *offers
+                .keys()
+                .next()
+                .ok_or_else(|| String::from("No text is waiting for the clipboard"))?,
+        };
+        let offer = offers
+            .remove(&number)
+            .ok_or_else(|| format!("There is no text {} waiting for the clipboard", number))?;
+        drop(offers);
+        let copied = if accept {
+            copy(&offer.text).map_err(|e| format!("Could not copy text {}: {}", number, e))
+        } else {
+            Ok(())
+        };
+        // The sender may have given up in the meantime.
+        let _ = offer.answer.send(accept && copied.is_ok());
+        copied.map(|_| number)
+    }
+}
+
+/// The first line of `text`, shortened for the terminal
+fn get_preview(text: &str) -> String {
+    let line = text.trim().lines().next().unwrap_or_default();
+    let mut preview = line
+        .chars()
+        .take(PREVIEW_LENGTH)
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>();
+    if preview.chars().count() < text.trim().chars().count() {
+        preview.push('…');
+    }
+    preview
+}
+
+/// The commands that write their stdin to the clipboard, in the order they are tried
+fn get_copy_commands() -> Vec<(&'static str, &'static [&'static str])> {
+    if cfg!(target_os = "macos") {
+        vec![("pbcopy", &[])]
+    } else if cfg!(windows) {
+        vec![("clip", &[])]
+    } else if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        vec![("wl-copy", &[]), ("xclip", &["-selection", "clipboard"])]
+    } else {
+        vec![
+            ("xclip", &["-selection", "clipboard"]),
+            ("xsel", &["--clipboard", "--input"]),
+        ]
+    }
+}
+
+/// Puts `text` onto the clipboard with the first copy command that is installed.
+fn copy(text: &str) -> io::Result<()> {
+    for (program, args) in get_copy_commands() {
+        let mut child = match Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        child.stdin.take().unwrap().write_all(text.as_bytes())?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "{} failed with {}",
+                program, status
+            )));
+        }
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no clipboard tool found, install wl-clipboard, xclip or xsel",
+    ))
+}
+
+/// The commands that print the clipboard, in the order they are tried
+fn get_paste_commands() -> Vec<(&'static str, &'static [&'static str])> {
+    if cfg!(target_os = "macos") {
+        vec![("pbpaste", &[])]
+    } else if cfg!(windows) {
+        vec![("powershell", &["-NoProfile", "-Command", "Get-Clipboard"])]
+    } else if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        vec![
+            ("wl-paste", &["--no-newline"]),
+            ("xclip", &["-selection", "clipboard", "-o"]),
+        ]
+    } else {
+        vec![
+            ("xclip", &["-selection", "clipboard", "-o"]),
+            ("xsel", &["--clipboard", "--output"]),
+        ]
+    }
+}
+
+/// The text on the clipboard, read with the first paste command that is installed.
+pub fn paste() -> io::Result<String> {
+    for (program, args) in get_paste_commands() {
+        let mut child = match Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let mut text = String::new();
+        child.stdout.take().unwrap().read_to_string(&mut text)?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "{} failed with {}",
+                program, status
+            )));
+        }
+        return Ok(text);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no clipboard tool found, install wl-clipboard, xclip or xsel",
+    ))
+}
+
+/// Reads the text of a request for the clipboard and answers once it was copied or discarded.
+pub async fn handle_upload(
+    clipboard: &Clipboard,
+    sender: &str,
+    mut body: Body,
+) -> Result<Response<Body>, std::convert::Infallible> {
+    let mut content = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(c) => c,
+            Err(_) => {
+                return Ok(create_status_response(
+                    StatusCode::BAD_REQUEST,
+                    "Failed to read the request",
+                ))
+            }
+        };
+        if content.len() + chunk.len() > MAX_TEXT {
+            return Ok(create_status_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The text is too long for the clipboard, upload it as a file",
+            ));
+        }
+        content.extend_from_slice(&chunk);
+    }
+    let text = match String::from_utf8(content) {
+        Ok(text) if !text.trim().is_empty() => text,
+        Ok(_) => {
+            return Ok(create_status_response(
+                StatusCode::BAD_REQUEST,
+                "Nothing to copy",
+            ))
+        }
+        Err(_) => {
+            return Ok(create_status_response(
+                StatusCode::BAD_REQUEST,
+                "Only UTF-8 text can be put onto the clipboard",
+            ))
+        }
+    };
+    if clipboard.offer(sender, text).await {
+        Ok(create_status_response(
+            StatusCode::OK,
+            "Copied to the clipboard",
+        ))
+    } else {
+        Ok(create_status_response(
+            StatusCode::FORBIDDEN,
+            "The text wasn't copied to the clipboard",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::sync::Arc;
+
+    proptest! {
+        #[test]
+        fn test_preview_is_one_short_line(text in "\\PC*(\n\\PC*)*") {
+            let preview = get_preview(&text);
+            prop_assert!(!preview.contains('\n'));
+            prop_assert!(preview.chars().count() <= PREVIEW_LENGTH + 1);
+        }
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!("hello", get_preview(" hello\n"));
+        assert_eq!("first…", get_preview("first\nsecond"));
+        assert_eq!("a b", get_preview("a\tb"));
+    }
+
+    #[tokio::test]
+    async fn test_discard() {
+        let clipboard = Arc::new(Clipboard::default());
+        assert!(clipboard.answer(None, false).is_err());
+        let offered = {
+            let clipboard = clipboard.clone();
+            tokio::spawn(async move { clipboard.offer("10.0.0.2", String::from("secret")).await })
+        };
+        while clipboard.offers.lock().unwrap().is_empty() {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        assert!(clipboard.answer(Some(2), false).is_err());
+        assert_eq!(Ok(1), clipboard.answer(None, false));
+        assert!(!offered.await.unwrap());
+        assert!(clipboard.answer(Some(1), false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refuses_long_and_binary_text() {
+        let clipboard = Clipboard::default();
+        let response = handle_upload(&clipboard, "10.0.0.2", Body::from(vec![b'a'; MAX_TEXT + 1]))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        let response = handle_upload(&clipboard, "10.0.0.2", Body::from(vec![0xff, 0xfe]))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        let response = handle_upload(&clipboard, "10.0.0.2", Body::from(" \n"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+}
@@ -0,0 +1,428 @@
+//! Commands typed into the terminal while the server is running
+
+use crate::bundle::Bundle;
+use crate::dropbox::DropBox;
+use crate::tokens::LinkState;
+use crate::{Mode, SessionState};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+const HELP: &str = "Commands:
+  status       show what is being served and how often it was requested
+  add <path>   serve another directory (only with --mount)
+  revoke       answer all further requests with 410 Gone
+  revoke <n>   revoke only link n (only with --tokens)
+  clients      list the clients that connected, with round-trip time and loss
+  revoke client <n|ip|host>
+               answer all further requests of a client with 410 Gone
+  say <text>   send a message to the recipients (only with --chat)
+  mint <name>  print the upload link of a sender (only with rustbelt inbox)
+  mint <name>=<destination>
+               store their uploads in destination, like photos/{date}/{original}
+  senders      list the senders and their upload links
+  revoke sender <name>
+               revoke the upload link of a sender
+  items        list the items of the session (only with rustbelt session)
+  revoke item <n>
+               answer all further requests for item n with 410 Gone
+  expire item <n> <minutes>
+               let item n expire in the given minutes
+  paste [n]    copy text n, or the oldest, to the clipboard (only with --to-clipboard)
+  discard [n]  drop text n, or the oldest, without copying it
+  quit         stop the server";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Status,
+    Add(PathBuf),
+    Revoke,
+    RevokeLink(usize),
+    Clients,
+    RevokeClient(String),
+    Say(String),
+    Mint(String, Option<String>),
+    Senders,
+    RevokeSender(String),
+    Items,
+    RevokeItem(usize),
+    ExpireItem(usize, u64),
+    Paste(Option<usize>),
+    Discard(Option<usize>),
+    Quit,
+    Help,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    let (command, argument) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+    match (command, argument) {
+        ("status", "") => Ok(Command::Status),
+        ("add", "") => Err(String::from("Usage: add <path>")),
+        ("add", path) => Ok(Command::Add(PathBuf::from(path))),
+        ("revoke", "") => Ok(Command::Revoke),
+        ("clients", "") => Ok(Command::Clients),
+        ("revoke", argument) if argument.starts_with("client ") => Ok(Command::RevokeClient(
+            argument["client ".len()..].trim().to_string(),
+        )),
+        ("revoke", argument) if argument.starts_with("sender ") => Ok(Command::RevokeSender(
+            argument["sender ".len()..].trim().to_string(),
+        )),
+        ("revoke", argument) if argument.starts_with("item ") => {
+            match argument["item ".len()..].trim().parse::<usize>() {
+                Ok(n) => Ok(Command::RevokeItem(n)),
+                _ => Err(String::from("Usage: revoke item <n>")),
+            }
+        }
+        ("revoke", number) => match number.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Command::RevokeLink(n)),
+            _ => Err(String::from("Usage: revoke [link number]")),
+        },
+        ("mint", "") => Err(String::from("Usage: mint <name>")),
+        ("mint", argument) => match argument.split_once('=') {
+            Some((name, destination)) => Ok(Command::Mint(
+                name.trim().to_string(),
+                Some(destination.trim().to_string()),
+            )),
+            None => Ok(Command::Mint(argument.to_string(), None)),
+        },
+        ("senders", "") => Ok(Command::Senders),
+        ("say", "") => Err(String::from("Usage: say <text>")),
+        ("say", text) => Ok(Command::Say(text.to_string())),
+        ("items", "") => Ok(Command::Items),
+        ("expire", argument) => {
+            let arguments = argument.split_whitespace().collect::<Vec<_>>();
+            match arguments.as_slice() {
+                ["item", number, minutes] => match (number.parse(), minutes.parse::<u64>()) {
+                    (Ok(number), Ok(minutes)) if minutes.checked_mul(60).is_some() => {
+                        Ok(Command::ExpireItem(number, minutes))
+                    }
+                    _ => Err(String::from("Usage: expire item <n> <minutes>")),
+                },
+                _ => Err(String::from("Usage: expire item <n> <minutes>")),
+            }
+        }
+        ("paste", "") => Ok(Command::Paste(None)),
+        ("paste", number) => match number.parse::<usize>() {
+            Ok(n) => Ok(Command::Paste(Some(n))),
+            _ => Err(String::from("Usage: paste [text number]")),
+        },
+        ("discard", "") => Ok(Command::Discard(None)),
+        ("discard", number) => match number.parse::<usize>() {
+            Ok(n) => Ok(Command::Discard(Some(n))),
+            _ => Err(String::from("Usage: discard [text number]")),
+        },
+        ("quit", "") | ("exit", "") => Ok(Command::Quit),
+        ("help", "") | ("?", "") => Ok(Command::Help),
+        _ => Err(format!("Unknown command: {}. Type help for a list.", line)),
+    }
+}
+
+/// Formats a duration given in seconds like `1h 2m 3s`.
+pub fn format_duration(seconds: u64) -> String {
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+fn print_status(mode: &Mode, state: &SessionState) {
+    eprintln!(
+        "Serving in {} mode for {}, {} requests answered{}",
+        mode.get_name(),
+        format_duration(state.started.elapsed().as_secs()),
+        state.requests.load(Ordering::SeqCst),
+        if state.revoked.load(Ordering::SeqCst) {
+            ", access revoked"
+        } else {
+            ""
+        }
+    );
+    for url in state.urls.lock().unwrap().iter() {
+        eprintln!("Listening on {}", url);
+    }
+    match mode {
+        Mode::Send(share) | Mode::Exchange(share, _) => {
+            eprintln!(
+                "{}: {}",
+                share.path.display(),
+                if share.transferred.load(Ordering::SeqCst) {
+                    "transferred"
+                } else {
+                    "not transferred yet"
+                }
+            );
+            for (index, link) in share.links.iter().flat_map(|l| l.get_links()).enumerate() {
+                let link_state = match link.get_state() {
+                    LinkState::Unused => "not used yet",
+                    LinkState::Used => "used",
+                    LinkState::Revoked => "revoked",
+                };
+                eprintln!(
+                    "Link {} (/{}/): {}",
+                    index + 1,
+                    link.token.value,
+                    link_state
+                );
+            }
+            if let Some(broadcast) = &share.broadcast {
+                eprintln!("{}", broadcast.get_summary());
+                let length = std::fs::metadata(&share.path).map_or(0, |m| m.len());
+                for recipient in broadcast.get_recipients() {
+                    let percent = (recipient.sent * 100).checked_div(length);
+                    let progress = match (recipient.done, percent) {
+                        (false, Some(percent)) => format!("{}%", percent),
+                        _ => String::from("done"),
+                    };
+                    eprintln!("{}: {}", recipient.get_display_name(), progress);
+                }
+            }
+        }
+        Mode::Bundle(bundle) => print_items(bundle),
+        Mode::Mounts(table) => {
+            for (name, root) in table.get_mounts() {
+                eprintln!("/{}/ -> {}", name, root.display());
+            }
+        }
+        _ => {}
+    }
+}
+
+fn print_clients(state: &SessionState) {
+    let clients = state.clients.get_clients();
+    if clients.is_empty() {
+        eprintln!("No client has connected yet");
+    }
+    for (index, client) in clients.iter().enumerate() {
+        let path = client
+            .path
+            .map(|path| format!(", {}", path))
+            .unwrap_or_default();
+        eprintln!(
+            "{}. {}: {} requests{}{}",
+            index + 1,
+            client.get_display_name(),
+            client.requests,
+            path,
+            if client.revoked { ", revoked" } else { "" }
+        );
+    }
+}
+
+fn print_senders(dropbox: &DropBox, state: &SessionState) {
+    let senders = dropbox.get_senders();
+    if senders.is_empty() {
+        eprintln!("No sender has a link yet, create one with mint <name>");
+    }
+    let url = &state.urls.lock().unwrap()[0];
+    for sender in senders {
+        let name = sender.label.as_deref().unwrap_or_default();
+        if sender.is_expired() {
+            eprintln!("{}: expired, mint a new link", name);
+        } else {
+            match &sender.destination {
+                Some(destination) => {
+                    eprintln!("{}: {} into {}", name, sender.get_url(url), destination)
+                }
+                None => eprintln!("{}: {}", name, sender.get_url(url)),
+            }
+        }
+    }
+}
+
+fn print_items(bundle: &Bundle) {
+    for (index, item) in bundle.get_items().iter().enumerate() {
+        eprintln!("{}. {}: {}", index + 1, item.name, item.get_state());
+    }
+}
+
+/// Copies or discards text sent for the clipboard.
+fn answer_clipboard(mode: &Mode, number: Option<usize>, paste: bool) {
+    let clipboard = match mode {
+        Mode::Receive(inbox) | Mode::Exchange(_, inbox) => inbox.get_clipboard(),
+        _ => None,
+    };
+    match clipboard.map(|c| c.answer(number, paste)) {
+        Some(Ok(number)) if paste => eprintln!("Text {} copied to the clipboard", number),
+        Some(Ok(number)) => eprintln!("Text {} discarded", number),
+        Some(Err(e)) => eprintln!("{}", e),
+        None => eprintln!("Text is only put onto the clipboard with --to-clipboard"),
+    }
+}
+
+/// Reads commands from stdin until `quit` is entered or stdin is closed.
+pub async fn run_console(mode: Mode, state: Arc<SessionState>, quit: mpsc::UnboundedSender<()>) {
+    eprintln!("Type help for a list of commands");
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_command(&line) {
+            Ok(Command::Status) => print_status(&mode, &state),
+            Ok(Command::Add(path)) => match &mode {
+                Mode::Mounts(table) => match table.add_directory(path) {
+                    Ok(name) => eprintln!("Serving at /{}/", name),
+                    Err(e) => eprintln!("{}", e),
+                },
+                _ => eprintln!("Directories can only be added when serving with --mount"),
+            },
+            Ok(Command::Revoke) => {
+                state.revoked.store(true, Ordering::SeqCst);
+                eprintln!("Access revoked");
+            }
+            Ok(Command::RevokeLink(number)) => {
+                let link = match &mode {
+                    Mode::Send(share) => share
+                        .links
+                        .as_ref()
+                        .and_then(|l| l.get_links().get(number - 1)),
+                    _ => None,
+                };
+                match link {
+                    Some(link) => {
+                        link.revoke();
+                        eprintln!("Link {} revoked", number);
+                    }
+                    None => eprintln!("There is no link {}", number),
+                }
+            }
+            Ok(Command::Clients) => print_clients(&state),
+            Ok(Command::RevokeClient(query)) => match state.clients.revoke(&query) {
+                Some(ip) => eprintln!("Access of {} revoked", ip),
+                None => eprintln!("There is no client {}", query),
+            },
+            Ok(Command::Say(text)) => match &state.chat {
+                Some(chat) => {
+                    chat.post(crate::chat::SENDER_NAME, &text);
+                }
+                None => eprintln!("Messages can only be sent when serving with --chat"),
+            },
+            Ok(Command::Mint(name, destination)) => match &mode {
+                Mode::DropBox(dropbox) => match dropbox.mint(&name, destination.as_deref()) {
+                    Ok(sender) => {
+                        let url = sender.get_url(&state.urls.lock().unwrap()[0]);
+                        eprintln!("Upload link for {}: {}", name.trim(), url);
+                        crate::print_qr_code(&url);
+                    }
+                    Err(e) => eprintln!("Could not create a link for {}: {}", name, e),
+                },
+                _ => eprintln!("Links can only be created when running rustbelt inbox"),
+            },
+            Ok(Command::Senders) => match &mode {
+                Mode::DropBox(dropbox) => print_senders(dropbox, &state),
+                _ => eprintln!("Senders only exist when running rustbelt inbox"),
+            },
+            Ok(Command::RevokeSender(name)) => match &mode {
+                Mode::DropBox(dropbox) => match dropbox.revoke(&name) {
+                    Ok(true) => eprintln!("Link of {} revoked", name),
+                    Ok(false) => eprintln!("There is no sender {}", name),
+                    Err(e) => eprintln!("Could not revoke the link of {}: {}", name, e),
+                },
+                _ => eprintln!("Senders only exist when running rustbelt inbox"),
+            },
+            Ok(Command::Items) => match &mode {
+                Mode::Bundle(bundle) => print_items(bundle),
+                _ => eprintln!("Items only exist when running rustbelt session"),
+            },
+            Ok(Command::RevokeItem(number)) => match &mode {
+                Mode::Bundle(bundle) => match bundle.revoke(number) {
+                    Ok(item) => eprintln!("Item {} ({}) revoked", number, item.name),
+                    Err(e) => eprintln!("{}", e),
+                },
+                _ => eprintln!("Items only exist when running rustbelt session"),
+            },
+            Ok(Command::ExpireItem(number, minutes)) => match &mode {
+                Mode::Bundle(bundle) => {
+                    match bundle.expire(number, Duration::from_secs(minutes * 60)) {
+                        Ok(item) => {
+                            eprintln!("Item {} ({}) {}", number, item.name, item.get_state())
+                        }
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                _ => eprintln!("Items only exist when running rustbelt session"),
+            },
+            Ok(Command::Paste(number)) => answer_clipboard(&mode, number, true),
+            Ok(Command::Discard(number)) => answer_clipboard(&mode, number, false),
+            Ok(Command::Quit) => {
+                let _ = quit.send(());
+                return;
+            }
+            Ok(Command::Help) => eprintln!("{}", HELP),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_parse_add(path in "[^\\s]+( [^\\s]+)*") {
+            prop_assert_eq!(Ok(Command::Add(PathBuf::from(&path))), parse_command(&format!(" add  {} ", path)));
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Ok(Command::Status), parse_command("status\n"));
+        assert_eq!(Ok(Command::Quit), parse_command("exit"));
+        assert!(parse_command("add").is_err());
+        assert!(parse_command("revoke now").is_err());
+        assert_eq!(Ok(Command::RevokeLink(2)), parse_command("revoke 2"));
+        assert!(parse_command("revoke 0").is_err());
+        assert_eq!(
+            Ok(Command::RevokeClient(String::from("10.0.0.7"))),
+            parse_command("revoke client  10.0.0.7")
+        );
+        assert_eq!(
+            Ok(Command::Say(String::from("use the second file"))),
+            parse_command("say use the second file")
+        );
+        assert_eq!(
+            Ok(Command::RevokeSender(String::from("Alice"))),
+            parse_command("revoke sender Alice")
+        );
+        assert_eq!(
+            Ok(Command::Mint(String::from("Bob"), None)),
+            parse_command("mint Bob")
+        );
+        assert_eq!(
+            Ok(Command::Mint(
+                String::from("Bob Smith"),
+                Some(String::from("photos/{date}/{original}"))
+            )),
+            parse_command("mint Bob Smith = photos/{date}/{original}")
+        );
+        assert_eq!(Ok(Command::RevokeItem(2)), parse_command("revoke item 2"));
+        assert_eq!(
+            Ok(Command::ExpireItem(1, 30)),
+            parse_command("expire item 1  30")
+        );
+        assert!(parse_command("expire item 1").is_err());
+        assert!(parse_command(&format!("expire item 1 {}", u64::MAX)).is_err());
+        assert_eq!(Ok(Command::Paste(None)), parse_command("paste"));
+        assert_eq!(Ok(Command::Discard(Some(3))), parse_command("discard 3"));
+        assert!(parse_command("paste all").is_err());
+        assert!(parse_command("say").is_err());
+        assert!(parse_command("stats").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!("5s", format_duration(5));
+        assert_eq!("1m 0s", format_duration(60));
+        assert_eq!("2h 0m 1s", format_duration(7201));
+    }
+}
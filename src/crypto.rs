@@ -0,0 +1,145 @@
+//! Signing and encrypting served files with the external `gpg` and `age` tools
+//!
+//! A detached signature is created once before serving. Encryption runs the tool for every
+//! download and streams its output, so the plain file never leaves the machine.
+
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The tool used to encrypt for a recipient
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionTool {
+    Gpg,
+    Age,
+}
+
+impl EncryptionTool {
+    /// age recipients are either native `age1…` keys or SSH public keys, everything else is
+    /// handed to GPG as a key ID, fingerprint or user ID.
+    pub fn for_recipient(recipient: &str) -> EncryptionTool {
+        if recipient.starts_with("age1") || recipient.starts_with("ssh-") {
+            EncryptionTool::Age
+        } else {
+            EncryptionTool::Gpg
+        }
+    }
+
+    /// File name extension of the encrypted output
+    pub fn get_extension(self) -> &'static str {
+        match self {
+            EncryptionTool::Gpg => "gpg",
+            EncryptionTool::Age => "age",
+        }
+    }
+}
+
+/// A recipient files are encrypted to before they are sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encryption {
+    pub tool: EncryptionTool,
+    pub recipient: String,
+}
+
+impl Encryption {
+    pub fn new(recipient: String) -> Encryption {
+        Encryption {
+            tool: EncryptionTool::for_recipient(&recipient),
+            recipient,
+        }
+    }
+
+    fn create_command(&self) -> Command {
+        let mut command = match self.tool {
+            EncryptionTool::Gpg => {
+                let mut command = Command::new("gpg");
+                command.args(["--batch", "--yes", "--trust-model", "always", "--encrypt"]);
+                command.args(["--output", "-", "--recipient"]);
+                command
+            }
+            EncryptionTool::Age => {
+                let mut command = Command::new("age");
+                command.args(["--encrypt", "--recipient"]);
+                command
+            }
+        };
+        command.arg(&self.recipient);
+        command
+    }
+
+    /// Starts encrypting the file at `path`. The encrypted data is read from the child's stdout.
+    pub fn spawn(&self, path: &Path) -> io::Result<tokio::process::Child> {
+        let mut command = tokio::process::Command::from(self.create_command());
+        command
+            .stdin(Stdio::from(File::open(path)?))
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+    }
+}
+
+#[derive(Debug)]
+pub struct SigningError {
+    key: String,
+    message: String,
+}
+
+impl error::Error for SigningError {}
+
+impl fmt::Display for SigningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not sign with key {}: {}", self.key, self.message)
+    }
+}
+
+impl SigningError {
+    fn new(key: String, message: String) -> SigningError {
+        SigningError { key, message }
+    }
+}
+
+/// Creates an ASCII armored detached GPG signature of the file at `path`.
+pub fn create_signature(path: &Path, key: &str) -> Result<Vec<u8>, SigningError> {
+    let output = Command::new("gpg")
+        .args(["--batch", "--armor", "--detach-sign", "--output", "-"])
+        .arg("--local-user")
+        .arg(key)
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| SigningError::new(key.to_string(), e.to_string()))?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(SigningError::new(
+            key.to_string(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_for_recipient() {
+        assert_eq!(
+            EncryptionTool::Age,
+            EncryptionTool::for_recipient(
+                "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"
+            )
+        );
+        assert_eq!(
+            EncryptionTool::Age,
+            EncryptionTool::for_recipient("ssh-ed25519 AAAA")
+        );
+        assert_eq!(
+            EncryptionTool::Gpg,
+            EncryptionTool::for_recipient("alice@example.com")
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Streaming from a named pipe or character device
+//!
+//! Reading `/dev/video0` or a FIFO yields data of unknown length that can't be read twice, so a
+//! single producer reads it and broadcasts the chunks to every connected client. It starts with
+//! the first client, nothing written before is lost. Clients connecting later join the stream
+//! where it is, and slow clients skip chunks rather than holding up the others. A FIFO is opened
+//! again when its writer closes it, a device ends the stream at its end.
+
+use crate::help;
+use crate::source::{self, ContentSource, ContentStream, SourceFuture, SourceInfo};
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use hyper::{Body, Method, Request, Response};
+use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::sync::broadcast;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Number of chunks a client may fall behind before it skips some
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Whether `path` is a FIFO or character device, which is streamed rather than served as a file
+pub fn is_stream_source(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo() || m.file_type().is_char_device())
+}
+
+/// A chunk read from the source or `None` once it has ended
+type Update = Option<Bytes>;
+
+pub struct DeviceStream {
+    path: PathBuf,
+    file_name: String,
+    /// Set with `--mime`, browsers then show the stream instead of downloading it
+    content_type: Option<String>,
+    started: AtomicBool,
+    sender: broadcast::Sender<Update>,
+}
+
+impl DeviceStream {
+    pub fn new(path: PathBuf, name: Option<&str>, content_type: Option<&str>) -> DeviceStream {
+        let file_name = match (name, path.file_name()) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(name)) => name.to_string_lossy().into_owned(),
+            (None, None) => String::from("stream"),
+        };
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        DeviceStream {
+            path,
+            file_name,
+            content_type: content_type.map(String::from),
+            started: AtomicBool::new(false),
+            sender,
+        }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Subscribes to the stream, starting to read the source for the first client.
+    fn subscribe(self: &Arc<Self>) -> broadcast::Receiver<Update> {
+        let receiver = self.sender.subscribe();
+        if !self.started.swap(true, Ordering::SeqCst) {
+            tokio::spawn(produce(self.clone()));
+        }
+        receiver
+    }
+}
+
+async fn produce(stream: Arc<DeviceStream>) {
+    match read_source(&stream).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => eprintln!("Could not read {}: {}", stream.path.display(), e),
+    }
+    let _ = stream.sender.send(None);
+}
+
+/// Whether nobody is connected anymore, in which case the producer stops until the next client.
+fn stop_if_idle(stream: &DeviceStream) -> bool {
+    if stream.sender.receiver_count() > 0 {
+        return false;
+    }
+    stream.started.store(false, Ordering::SeqCst);
+    // A client subscribing meanwhile either started a new producer or relies on this one.
+    stream.sender.receiver_count() == 0 || stream.started.swap(true, Ordering::SeqCst)
+}
+
+/// Reads the source and publishes its chunks. Returns whether the source ended, rather than the
+/// producer stopping for lack of clients.
+async fn read_source(stream: &DeviceStream) -> io::Result<bool> {
+    let is_fifo = fs::metadata(&stream.path)?.file_type().is_fifo();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        // Opening a FIFO waits for a writer.
+        let mut source = tokio::fs::File::open(&stream.path).await?;
+        loop {
+            let n = source.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            // Sending fails while nobody is connected, the chunk is then dropped like any other
+            // a client misses.
+            let _ = stream
+                .sender
+                .send(Some(Bytes::copy_from_slice(&buffer[..n])));
+        }
+        if !is_fifo {
+            return Ok(true);
+        }
+        if stop_if_idle(stream) {
+            return Ok(false);
+        }
+    }
+}
+
+fn create_chunk_stream(
+    receiver: broadcast::Receiver<Update>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    stream::unfold(Some(receiver), |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(Some(chunk)) => return Some((Ok(chunk), Some(receiver))),
+                Ok(None) | Err(broadcast::RecvError::Closed) => return None,
+                Err(broadcast::RecvError::Lagged(_)) => continue,
+            }
+        }
+    })
+}
+
+/// The requests the stream answers
+pub fn get_routes() -> Vec<help::Route<()>> {
+    vec![help::Route::new(Method::GET, "/", "Joins the stream", ())]
+}
+
+pub async fn handle_request(
+    stream: Arc<DeviceStream>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if let Err(response) = help::find_route(&get_routes(), &req) {
+        return Ok(response);
+    }
+    Ok(source::serve(stream, &req).await)
+}
+
+impl ContentSource for DeviceStream {
+    fn get_info(&self) -> SourceFuture<'_, SourceInfo> {
+        let info = SourceInfo {
+            name: self.file_name.clone(),
+            size: None,
+            version: None,
+            content_type: self.content_type.clone(),
+        };
+        Box::pin(async move { Ok(info) })
+    }
+
+    fn open(self: Arc<Self>, _: Option<(u64, u64)>) -> SourceFuture<'static, ContentStream> {
+        let chunks = create_chunk_stream(self.subscribe()).map(|chunk| match chunk {
+            Ok(chunk) => Ok(chunk),
+            Err(never) => match never {},
+        });
+        let body: ContentStream = Box::pin(chunks);
+        Box::pin(async move { Ok(body) })
+    }
+
+    fn is_replayable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::process::Command;
+    use tokio::io::AsyncWriteExt;
+
+    fn create_fifo(path: &Path) {
+        let status = Command::new("mkfifo").arg(path).status().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_stream_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("fifo");
+        create_fifo(&fifo);
+        assert!(is_stream_source(&fifo));
+        assert!(is_stream_source(Path::new("/dev/null")));
+        assert!(!is_stream_source(dir.path()));
+        fs::write(dir.path().join("file"), b"data").unwrap();
+        assert!(!is_stream_source(&dir.path().join("file")));
+    }
+
+    #[test]
+    fn test_file_names() {
+        let stream = DeviceStream::new(PathBuf::from("/dev/video0"), None, None);
+        assert_eq!("video0", stream.file_name);
+        let stream = DeviceStream::new(PathBuf::from("/dev/video0"), Some("cam.mjpeg"), None);
+        assert_eq!("cam.mjpeg", stream.file_name);
+    }
+
+    #[tokio::test]
+    async fn test_device_stream_ends() {
+        let stream = Arc::new(DeviceStream::new(PathBuf::from("/dev/null"), None, None));
+        let chunks = create_chunk_stream(stream.subscribe())
+            .collect::<Vec<_>>()
+            .await;
+        assert!(chunks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fifo_is_broadcast() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("fifo");
+        create_fifo(&fifo);
+        let stream = Arc::new(DeviceStream::new(fifo.clone(), None, None));
+        let mut first = create_chunk_stream(stream.subscribe()).boxed();
+        let mut second = create_chunk_stream(stream.subscribe()).boxed();
+        let mut writer = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&fifo)
+            .await
+            .unwrap();
+        writer.write_all(b"frame").await.unwrap();
+        for chunks in [&mut first, &mut second].iter_mut() {
+            assert_eq!(
+                Some(Bytes::from("frame")),
+                chunks.next().await.map(Result::unwrap)
+            );
+        }
+        // Without clients the producer stops at the end of the writer's output.
+        drop((first, second));
+        drop(writer);
+        while stream.started.load(Ordering::SeqCst) {
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! A persistent drop box handing every sender an upload link of their own
+//!
+//! `rustbelt inbox DIR` runs until it is stopped. `mint <name>` in the console creates a token for
+//! a sender and prints their upload link and QR code, uploads through it are stored in a folder
+//! named after them. The tokens are kept in `DIR/.rustbelt-inbox.json`, so links handed out stay
+//! valid across restarts until they are revoked or, with `--expire-after`, expire.
+//!
+//! `mint <name>=<destination>` binds the link to a `Destination` instead, like
+//! `photos/{date}/{original}`, so different senders can be given different places in the tree
+//! without seeing any of it.
+
+use crate::tokens::{self, Scope, Token, TokenStore};
+use crate::{create_status_response, help, html, notify, paths, receive};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const TOKENS_FILE: &str = ".rustbelt-inbox.json";
+/// Where uploads go if the link doesn't name a destination
+const DEFAULT_DESTINATION: &str = "{sender}/{original}";
+const SENDER: &str = "{sender}";
+const DATE: &str = "{date}";
+const ORIGINAL: &str = "{original}";
+
+/// Where the uploads through a link are stored below the drop box, like
+/// `photos/{date}/{original}`. `{sender}` stands for the name of the sender, `{date}` for the day
+/// of the upload and `{original}` for the name the file was uploaded with. Without `{original}`
+/// the destination is a folder in which files keep their names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destination {
+    folders: Vec<String>,
+    file: String,
+}
+
+/// Checks that a segment of a destination is a name with known placeholders only.
+fn check_segment(segment: &str) -> Result<(), String> {
+    if segment.is_empty() || segment == "." || segment == ".." || segment.contains('\\') {
+        return Err(format!("Not a valid folder or file name: {:?}", segment));
+    }
+    let literal = [SENDER, DATE, ORIGINAL]
+        .iter()
+        .fold(segment.to_string(), |s, placeholder| {
+            s.replace(placeholder, "")
+        });
+    if literal.contains(['{', '}']) {
+        return Err(format!(
+            "Unknown placeholder in {}, only {}, {} and {} are known",
+            segment, SENDER, DATE, ORIGINAL
+        ));
+    }
+    Ok(())
+}
+
+impl Destination {
+    pub fn parse(pattern: &str) -> Result<Destination, String> {
+        let mut folders = pattern
+            .trim()
+            .trim_matches('/')
+            .split('/')
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        for segment in &folders {
+            check_segment(segment)?;
+        }
+        let file = match folders.last() {
+            Some(last) if last.contains(ORIGINAL) => folders.pop().unwrap(),
+            _ => ORIGINAL.to_string(),
+        };
+        if folders.iter().any(|f| f.contains(ORIGINAL)) {
+            return Err(format!("{} can only be part of the file name", ORIGINAL));
+        }
+        Ok(Destination { folders, file })
+    }
+
+    /// The folder below `root` that uploads of `sender` on `date` go to
+    fn get_folder(&self, root: &Path, sender: &str, date: &str) -> Option<PathBuf> {
+        let mut folder = root.to_path_buf();
+        for segment in &self.folders {
+            folder.push(expand(segment, sender, date, "")?);
+        }
+        Some(folder)
+    }
+
+    /// The name a file uploaded as `original` is stored with
+    fn get_file_name(&self, original: &str, sender: &str, date: &str) -> Option<String> {
+        expand(&self.file, sender, date, original)
+    }
+}
+
+/// Replaces the placeholders in `segment`, unless that leaves no valid name.
+fn expand(segment: &str, sender: &str, date: &str, original: &str) -> Option<String> {
+    let sender = receive::create_folder_name(sender)?;
+    let name = segment
+        .replace(SENDER, &sender)
+        .replace(DATE, date)
+        .replace(ORIGINAL, original);
+    Some(name).filter(|n| !n.is_empty() && n != "." && n != "..")
+}
+
+/// The upload tokens of the senders, labelled with their names, and the inboxes their uploads go
+/// to
+pub struct DropBox {
+    root: PathBuf,
+    /// How long links are valid after they are minted
+    lifetime: Option<Duration>,
+    store: TokenStore,
+    /// The inboxes of the folders uploads went to, by their path
+    inboxes: Mutex<HashMap<PathBuf, Arc<receive::Inbox>>>,
+}
+
+fn create_inbox(folder: PathBuf) -> Arc<receive::Inbox> {
+    Arc::new(receive::Inbox::new(
+        folder,
+        None,
+        None,
+        None,
+        None,
+        false,
+        notify::Notifier::default(),
+    ))
+}
+
+/// Where an upload through the link of a sender goes today
+struct Target {
+    inbox: Arc<receive::Inbox>,
+    destination: Destination,
+    sender: String,
+    date: String,
+}
+
+impl Target {
+    fn get_file_name(&self, original: &str) -> Option<String> {
+        self.destination
+            .get_file_name(original, &self.sender, &self.date)
+    }
+}
+
+impl DropBox {
+    /// Opens the drop box in `root`, with the senders of earlier runs.
+    pub fn open(root: PathBuf, lifetime: Option<Duration>) -> io::Result<DropBox> {
+        let store = TokenStore::open(root.join(TOKENS_FILE))?;
+        Ok(DropBox {
+            root,
+            lifetime,
+            store,
+            inboxes: Mutex::default(),
+        })
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The tokens of the senders, their names being the labels
+    pub fn get_senders(&self) -> Vec<Token> {
+        self.store
+            .get_tokens()
+            .into_iter()
+            .filter(|t| t.label.is_some())
+            .collect()
+    }
+
+    fn find_sender(&self, name: &str) -> Option<Token> {
+        let name = Some(name.trim());
+        self.get_senders()
+            .into_iter()
+            .find(|t| t.label.as_deref() == name)
+    }
+
+    /// Creates a link for the sender called `name`, storing their uploads in `destination` or a
+    /// folder named after them. Returns the link they already have unless it expired or goes
+    /// elsewhere.
+    pub fn mint(&self, name: &str, destination: Option<&str>) -> io::Result<Token> {
+        let name = name.trim();
+        let destination = destination.map(str::trim);
+        if let Some(destination) = destination {
+            Destination::parse(destination)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+        if let Some(sender) = self
+            .find_sender(name)
+            .filter(|t| !t.is_expired() && t.destination.as_deref() == destination)
+        {
+            return Ok(sender);
+        }
+        if receive::create_folder_name(name).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a valid sender name",
+            ));
+        }
+        let mut sender = Token::new(Scope::Upload).with_label(name);
+        if let Some(destination) = destination {
+            sender = sender.with_destination(destination);
+        }
+        if let Some(lifetime) = self.lifetime {
+            sender = sender.with_lifetime(lifetime);
+        }
+        self.store.insert(sender.clone())?;
+        Ok(sender)
+    }
+
+    /// Revokes the link of the sender called `name`. Their files are kept.
+    pub fn revoke(&self, name: &str) -> io::Result<bool> {
+        let sender = match self.find_sender(name) {
+            Some(sender) => sender,
+            None => return Ok(false),
+        };
+        self.store.remove(&sender.value)?;
+        Ok(true)
+    }
+
+    /// The requests the drop box answers, the ones of an inbox below the token of every sender
+    pub fn get_routes(&self) -> Vec<help::Route<Route>> {
+        use help::Route as R;
+        let mut routes = vec![R::new(
+            Method::GET,
+            "/",
+            "Asks for a personal upload link",
+            Route::Welcome,
+        )];
+        let inbox = create_inbox(self.root.clone());
+        let links = receive::get_routes(&inbox).into_iter();
+        routes.extend(links.map(|r| r.under("/<token>").map(|_| Route::Link)));
+        routes.push(R::new(
+            Method::GET,
+            "/<token>",
+            "Redirects to the upload page of the sender",
+            Route::Link,
+        ));
+        routes
+    }
+
+    fn find(&self, token: &str) -> Option<Target> {
+        let sender = self.store.find(token, Scope::Upload)?;
+        let pattern = sender.destination.as_deref().unwrap_or(DEFAULT_DESTINATION);
+        let destination = Destination::parse(pattern).ok()?;
+        let name = sender.label?;
+        let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+        let folder = destination.get_folder(&self.root, &name, &date)?;
+        let inbox = self
+            .inboxes
+            .lock()
+            .unwrap()
+            .entry(folder.clone())
+            .or_insert_with(|| create_inbox(folder))
+            .clone();
+        Some(Target {
+            inbox,
+            destination,
+            sender: name,
+            date,
+        })
+    }
+}
+
+/// Passes requests below a sender's token on to their inbox.
+/// What a request of the drop box is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Welcome,
+    /// Everything below the token of a sender, which their inbox answers
+    Link,
+}
+
+pub async fn handle_request(
+    dropbox: Arc<DropBox>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    match help::find_route(&dropbox.get_routes(), &req) {
+        Ok(Route::Welcome) => {
+            return Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(html::create_message_page(
+                    "Drop box",
+                    "Ask the owner of this drop box for your personal upload link.",
+                )))
+                .unwrap())
+        }
+        Ok(Route::Link) => {}
+        Err(response) => return Ok(response),
+    }
+    let (token, rest) = match tokens::split_token(req.uri().path()) {
+        Some(split) => split,
+        None => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let target = match dropbox.find(token) {
+        Some(target) => target,
+        None => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    // The upload page sends files relative to its own path.
+    if !req.uri().path()[1..].contains('/') {
+        return Ok(Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(header::LOCATION, format!("/{}/", token))
+            .body(Body::empty())
+            .unwrap());
+    }
+    // Uploads are named after the destination of the link, files that can't be are left to the
+    // inbox to refuse.
+    let rest = match paths::get_path_segment(&rest[1..]) {
+        Some(original) if matches!(*req.method(), Method::PUT | Method::POST) => {
+            match target.get_file_name(&original) {
+                Some(name) => format!("/{}", paths::percent_encode(&name)),
+                None => {
+                    return Ok(create_status_response(
+                        StatusCode::BAD_REQUEST,
+                        "Invalid file name",
+                    ))
+                }
+            }
+        }
+        _ => rest.to_string(),
+    };
+    let uri = match req.uri().query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest,
+    };
+    *req.uri_mut() = match uri.parse() {
+        Ok(uri) => uri,
+        Err(_) => {
+            return Ok(create_status_response(
+                StatusCode::BAD_REQUEST,
+                "Invalid path",
+            ))
+        }
+    };
+    receive::handle_request(target.inbox, req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_tokens_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let dropbox = DropBox::open(dir.path().to_path_buf(), None).unwrap();
+        let alice = dropbox.mint("Alice", None).unwrap();
+        assert_eq!(alice, dropbox.mint(" Alice ", None).unwrap());
+        let bob = dropbox.mint("Bob", None).unwrap();
+        assert_ne!(alice.value, bob.value);
+        assert!(dropbox.mint("..", None).is_err());
+        assert!(dropbox.revoke("Bob").unwrap());
+        assert!(!dropbox.revoke("Carol").unwrap());
+
+        let reopened = DropBox::open(dir.path().to_path_buf(), None).unwrap();
+        assert_eq!(vec![alice.clone()], reopened.get_senders());
+        assert!(reopened.find(&alice.value).is_some());
+        assert!(reopened.find(&bob.value).is_none());
+    }
+
+    #[test]
+    fn test_expired_links_are_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let lifetime = Some(Duration::from_secs(0));
+        let dropbox = DropBox::open(dir.path().to_path_buf(), lifetime).unwrap();
+        let alice = dropbox.mint("Alice", None).unwrap();
+        assert!(dropbox.find(&alice.value).is_none());
+        let renewed = dropbox.mint("Alice", None).unwrap();
+        assert_ne!(alice.value, renewed.value);
+        assert_eq!(1, dropbox.get_senders().len());
+    }
+
+    #[tokio::test]
+    async fn test_uploads_go_to_sender_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let dropbox = Arc::new(DropBox::open(dir.path().to_path_buf(), None).unwrap());
+        let sender = dropbox.mint("Alice", None).unwrap();
+        let req = Request::put(format!("/{}/notes.txt", sender.value))
+            .body(Body::from("hello"))
+            .unwrap();
+        let response = handle_request(dropbox.clone(), req).await.unwrap();
+        assert!(response.status().is_success());
+        let stored = fs::read(dir.path().join("Alice").join("notes.txt")).unwrap();
+        assert_eq!(b"hello".to_vec(), stored);
+
+        let req = Request::put("/wrongtoken/notes.txt")
+            .body(Body::from("hello"))
+            .unwrap();
+        let response = handle_request(dropbox.clone(), req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let req = Request::get(format!("/{}", sender.value))
+            .body(Body::empty())
+            .unwrap();
+        let response = handle_request(dropbox, req).await.unwrap();
+        assert_eq!(StatusCode::MOVED_PERMANENTLY, response.status());
+    }
+
+    #[test]
+    fn test_destinations() {
+        let destination = Destination::parse("photos/{date}/{original}").unwrap();
+        let root = Path::new("/srv/inbox");
+        assert_eq!(
+            Some(PathBuf::from("/srv/inbox/photos/2024-05-01")),
+            destination.get_folder(root, "Alice", "2024-05-01")
+        );
+        assert_eq!(
+            Some(String::from("beach.jpg")),
+            destination.get_file_name("beach.jpg", "Alice", "2024-05-01")
+        );
+        let destination = Destination::parse("/scans/{sender}/").unwrap();
+        assert_eq!(
+            Some(PathBuf::from("/srv/inbox/scans/Bob")),
+            destination.get_folder(root, "Bob", "2024-05-01")
+        );
+        let destination = Destination::parse("{date}-{sender}-{original}").unwrap();
+        assert_eq!(
+            Some(PathBuf::from("/srv/inbox")),
+            destination.get_folder(root, "Bob", "x")
+        );
+        assert_eq!(
+            Some(String::from("2024-05-01-Bob-a.pdf")),
+            destination.get_file_name("a.pdf", "Bob", "2024-05-01")
+        );
+        assert!(Destination::parse("").is_err());
+        assert!(Destination::parse("photos/../secrets").is_err());
+        assert!(Destination::parse("photos//{original}").is_err());
+        assert!(Destination::parse("{original}/photos").is_err());
+        assert!(Destination::parse("photos/{year}").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_uploads_go_to_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let dropbox = Arc::new(DropBox::open(dir.path().to_path_buf(), None).unwrap());
+        let alice = dropbox.mint("Alice", None).unwrap();
+        let moved = dropbox
+            .mint("Alice", Some("photos/{sender}-{original}"))
+            .unwrap();
+        assert_ne!(alice.value, moved.value);
+        assert_eq!(
+            moved,
+            dropbox
+                .mint("Alice", Some("photos/{sender}-{original}"))
+                .unwrap()
+        );
+        assert!(dropbox.find(&alice.value).is_none());
+        assert!(dropbox.mint("Bob", Some("photos/{month}")).is_err());
+
+        let req = Request::put(format!("/{}/beach%20day.jpg", moved.value))
+            .body(Body::from("jpeg"))
+            .unwrap();
+        let response = handle_request(dropbox.clone(), req).await.unwrap();
+        assert!(response.status().is_success());
+        let stored = fs::read(dir.path().join("photos").join("Alice-beach day.jpg")).unwrap();
+        assert_eq!(b"jpeg".to_vec(), stored);
+        assert!(!dir.path().join("Alice").exists());
+
+        let reopened = DropBox::open(dir.path().to_path_buf(), None).unwrap();
+        assert_eq!(
+            Some("photos/{sender}-{original}"),
+            reopened.get_senders()[0].destination.as_deref()
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Running a share from another program, like a system tray app
+//!
+//! `ShareBuilder` starts sending a file or receiving into a directory on a thread of its own, and
+//! returns once the server listens, or with the error that kept it from listening. The returned
+//! `ShareHandle` has everything a front end shows and does: the URLs and their QR code as a PNG,
+//! the events the terminal would print, the clients, revoking access and stopping. The server
+//! runs with the defaults of the command line and without the console.
+//!
+//! ```no_run
+//! let share = rustbelt::embed::ShareBuilder::send("report.pdf").start()?;
+//! std::fs::write("share.png", share.get_qr_png()?)?;
+//! for event in share.events() {
+//!     println!("{:?}", event);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use crate::{
+    create_ip_string, create_socket, create_url, find_interfaces, is_link_local, network, notify,
+    output, qr, receive, timeouts, Address, Mode, NetworkInterfaceExistanceError, ServerOptions,
+    SessionState, Share,
+};
+use std::error;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc as tokio_mpsc;
+
+/// Port used unless another one is chosen, like on the command line
+const DEFAULT_PORT: u16 = 8080;
+
+/// Something that happened to a running share
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// What the terminal shows after the time, like a new client or a completed transfer
+    Message(String),
+    /// The server stopped, with the error if it failed or ended by itself, like when the share
+    /// expired
+    Stopped(Option<String>),
+}
+
+/// A client that sent a request to the share
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub ip: IpAddr,
+    /// Host name or address, and the device if the client told it
+    pub name: String,
+    pub requests: usize,
+    pub revoked: bool,
+}
+
+enum Kind {
+    Send(PathBuf),
+    Receive(PathBuf),
+}
+
+/// What to share, and where
+pub struct ShareBuilder {
+    kind: Kind,
+    interface: Option<String>,
+    port: u16,
+}
+
+impl ShareBuilder {
+    /// Shares a file for download.
+    pub fn send<P: Into<PathBuf>>(path: P) -> ShareBuilder {
+        ShareBuilder::new(Kind::Send(path.into()))
+    }
+
+    /// Accepts uploads into a directory.
+    pub fn receive<P: Into<PathBuf>>(dir: P) -> ShareBuilder {
+        ShareBuilder::new(Kind::Receive(dir.into()))
+    }
+
+    fn new(kind: Kind) -> ShareBuilder {
+        ShareBuilder {
+            kind,
+            interface: None,
+            port: DEFAULT_PORT,
+        }
+    }
+
+    /// Listens on the network interface called `name`, instead of the first one that is up.
+    pub fn interface(mut self, name: &str) -> ShareBuilder {
+        self.interface = Some(name.to_string());
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> ShareBuilder {
+        self.port = port;
+        self
+    }
+
+    /// Starts the server and returns once it listens.
+    pub fn start(self) -> Result<ShareHandle, Box<dyn error::Error>> {
+        let network = Arc::new(network::SystemNetwork);
+        let address = find_address(&*network, self.interface.as_deref(), self.port)?;
+        let mode = match self.kind {
+            Kind::Send(path) if path.is_file() => Mode::Send(Arc::new(Share::new(path))),
+            Kind::Send(path) => return Err(format!("Not a file: {}", path.display()).into()),
+            Kind::Receive(dir) if dir.is_dir() => Mode::Receive(Arc::new(receive::Inbox::new(
+                dir,
+                None,
+                None,
+                None,
+                None,
+                false,
+                notify::Notifier::default(),
+            ))),
+            Kind::Receive(dir) => return Err(format!("Not a directory: {}", dir.display()).into()),
+        };
+        let (events, receiver) = mpsc::channel();
+        let (ready, started) = mpsc::channel();
+        let embedding = Arc::new(Embedding {
+            events: Mutex::new(events.clone()),
+            ready: Mutex::new(Some(ready.clone())),
+            running: Mutex::default(),
+        });
+        let options = ServerOptions {
+            timeouts: timeouts::Timeouts {
+                write: Some(Duration::from_secs(300)),
+                keep_alive: Some(Duration::from_secs(60)),
+                header: Some(Duration::from_secs(20)),
+                body: Some(Duration::from_secs(60)),
+                ..timeouts::Timeouts::default()
+            },
+            max_half_open: Some(64),
+            embedding: Some(embedding.clone()),
+            ..ServerOptions::default()
+        };
+        let thread = thread::spawn(move || {
+            let result =
+                crate::run_http_server(network, address, mode, options).map_err(|e| e.to_string());
+            // Only the first message on `ready` counts, so this does nothing once it listened.
+            if let Err(e) = &result {
+                let _ = ready.send(Err(e.clone()));
+            }
+            let _ = events.send(Event::Stopped(result.err()));
+        });
+        match started.recv() {
+            Ok(Ok(())) => Ok(ShareHandle {
+                embedding,
+                events: receiver,
+                thread: Some(thread),
+            }),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err("The server stopped before it listened".into()),
+        }
+    }
+}
+
+/// The address on `interface`, or the first interface that is up, to listen on
+fn find_address(
+    network: &dyn network::Network,
+    interface: Option<&str>,
+    port: u16,
+) -> Result<Address, Box<dyn error::Error>> {
+    let mut interfaces = find_interfaces(network);
+    let interface = match interface {
+        Some(name) => interfaces
+            .remove(name)
+            .ok_or_else(|| NetworkInterfaceExistanceError::new(name.to_string()))?,
+        None => {
+            let mut candidates = interfaces
+                .into_values()
+                .filter(|i| i.is_up() && !i.is_loopback())
+                .collect::<Vec<_>>();
+            candidates.sort_by(|a, b| a.name.cmp(&b.name));
+            candidates
+                .into_iter()
+                .next()
+                .ok_or("No network interface with an address is up")?
+        }
+    };
+    let ip = interface
+        .ips
+        .iter()
+        .filter(|ip| !is_link_local(ip.ip()))
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| interface.ips.iter().find(|ip| !is_link_local(ip.ip())))
+        .ok_or_else(|| format!("{} has no usable address", interface.name))?;
+    Ok(Address {
+        interface: interface.name.clone(),
+        socket: create_socket(*ip, port),
+        url: create_url(create_ip_string(ip), port),
+        alternate: None,
+        dual_stack: false,
+    })
+}
+
+/// The connection between a running server and its `ShareHandle`
+pub(crate) struct Embedding {
+    events: Mutex<mpsc::Sender<Event>>,
+    /// Told once the server listens
+    ready: Mutex<Option<mpsc::Sender<Result<(), String>>>>,
+    /// How to stop the server and what happens in it, once it runs
+    running: Mutex<Option<(tokio_mpsc::UnboundedSender<()>, Arc<SessionState>)>>,
+}
+
+impl Embedding {
+    /// Passes the events printed by the server on to the handle.
+    pub(crate) fn get_sink(self: &Arc<Self>) -> output::EventSink {
+        let embedding = self.clone();
+        Arc::new(move |message: &str| {
+            let events = embedding.events.lock().unwrap();
+            let _ = events.send(Event::Message(message.to_string()));
+        })
+    }
+
+    /// Called by the server once it listens.
+    pub(crate) fn set_running(
+        &self,
+        quit: tokio_mpsc::UnboundedSender<()>,
+        state: Arc<SessionState>,
+    ) {
+        *self.running.lock().unwrap() = Some((quit, state));
+        if let Some(ready) = self.ready.lock().unwrap().take() {
+            let _ = ready.send(Ok(()));
+        }
+    }
+
+    fn get_state(&self) -> Arc<SessionState> {
+        let running = self.running.lock().unwrap();
+        running.as_ref().unwrap().1.clone()
+    }
+
+    fn quit(&self) {
+        if let Some((quit, _)) = &*self.running.lock().unwrap() {
+            let _ = quit.send(());
+        }
+    }
+}
+
+/// A running share. Dropping it stops the server without waiting for it.
+pub struct ShareHandle {
+    embedding: Arc<Embedding>,
+    events: mpsc::Receiver<Event>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ShareHandle {
+    /// Every URL the share can be reached at, more are added when the interface gets new addresses
+    pub fn get_urls(&self) -> Vec<String> {
+        self.embedding.get_state().urls.lock().unwrap().clone()
+    }
+
+    /// The QR code of the first URL as a PNG file
+    pub fn get_qr_png(&self) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        qr::create_png_for(&self.get_urls()[0])
+    }
+
+    /// What happens in the share, ending with `Event::Stopped`
+    pub fn events(&self) -> &mpsc::Receiver<Event> {
+        &self.events
+    }
+
+    pub fn get_clients(&self) -> Vec<Client> {
+        self.embedding
+            .get_state()
+            .clients
+            .get_clients()
+            .into_iter()
+            .map(|c| Client {
+                ip: c.ip,
+                name: c.get_display_name(),
+                requests: c.requests,
+                revoked: c.revoked,
+            })
+            .collect()
+    }
+
+    /// Answers the client at `ip` with 410 Gone from now on, and returns whether there is one.
+    pub fn revoke_client(&self, ip: IpAddr) -> bool {
+        let state = self.embedding.get_state();
+        state.clients.revoke(&ip.to_string()).is_some()
+    }
+
+    /// Answers everyone with 410 Gone from now on, and breaks off running downloads.
+    pub fn revoke(&self) {
+        let state = self.embedding.get_state();
+        state.revoked.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops the server and waits until running transfers finished.
+    pub fn stop(mut self) {
+        self.embedding.quit();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ShareHandle {
+    fn drop(&mut self) {
+        self.embedding.quit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interfaces;
+    use crate::network::mock::{create_interface, MockNetwork};
+
+    #[test]
+    fn test_find_address() {
+        let mut lo = create_interface("lo", 1, &["127.0.0.1/8"]);
+        lo.flags |= interfaces::FLAG_LOOPBACK;
+        let network = MockNetwork::new(vec![
+            lo,
+            create_interface("wlan0", 3, &["fe80::1/64", "fd00::5/64"]),
+            create_interface("eth0", 2, &["fe80::2/64", "192.168.1.5/24"]),
+        ]);
+        let address = find_address(&network, None, 8080).unwrap();
+        assert_eq!("eth0", address.interface);
+        assert_eq!("http://192.168.1.5:8080", address.url);
+        let address = find_address(&network, Some("wlan0"), 9000).unwrap();
+        assert_eq!("http://[fd00::5]:9000", address.url);
+        assert!(find_address(&network, Some("eth1"), 8080).is_err());
+    }
+
+    #[test]
+    fn test_events_reach_the_handle() {
+        let (events, receiver) = mpsc::channel();
+        let embedding = Arc::new(Embedding {
+            events: Mutex::new(events),
+            ready: Mutex::default(),
+            running: Mutex::default(),
+        });
+        let sink = embedding.get_sink();
+        thread::spawn(move || {
+            output::set_event_sink(sink);
+            output::print_event("New client: 10.0.0.2");
+        })
+        .join()
+        .unwrap();
+        output::print_event("Not for this share");
+        assert_eq!(
+            Event::Message(String::from("New client: 10.0.0.2")),
+            receiver.try_recv().unwrap()
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! A rough estimate of how long a download takes, from the nominal speed of the network link
+//!
+//! Nothing is measured. Wired links report their speed in `/sys/class/net/<interface>/speed`,
+//! Wi-Fi links report the current bitrate through `iw`. Real transfers are usually slower, so the
+//! estimate is only meant to tell seconds from minutes.
+
+use crate::console::format_duration;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkKind {
+    Wired,
+    WiFi,
+}
+
+impl LinkKind {
+    fn get_name(self) -> &'static str {
+        match self {
+            LinkKind::Wired => "Ethernet",
+            LinkKind::WiFi => "Wi-Fi",
+        }
+    }
+}
+
+/// The `tx bitrate` in Mbit/s from the output of `iw dev <interface> link`.
+fn parse_iw_bitrate(output: &str) -> Option<u64> {
+    let line = output
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("tx bitrate:"))?;
+    let mbit = line.split_whitespace().next()?.parse::<f64>().ok()?;
+    Some(mbit.round() as u64).filter(|&m| m > 0)
+}
+
+/// The kind and nominal speed in Mbit/s of the link of `interface`, if it can be found out.
+pub fn get_link_speed(interface: &str) -> Option<(LinkKind, u64)> {
+    let device = Path::new("/sys/class/net").join(interface);
+    if device.join("wireless").exists() || device.join("phy80211").exists() {
+        let output = Command::new("iw")
+            .args(["dev", interface, "link"])
+            .stdin(Stdio::null())
+            .output()
+            .ok()?;
+        let mbit = parse_iw_bitrate(&String::from_utf8_lossy(&output.stdout))?;
+        return Some((LinkKind::WiFi, mbit));
+    }
+    // Virtual and disconnected devices fail to read or report -1.
+    let speed = fs::read_to_string(device.join("speed")).ok()?;
+    let mbit = speed.trim().parse::<i64>().ok().filter(|&m| m > 0)?;
+    Some((LinkKind::Wired, mbit as u64))
+}
+
+/// Seconds needed to send `size` bytes at `mbit` Mbit/s, rounded up.
+pub fn estimate_seconds(size: u64, mbit: u64) -> u64 {
+    let bits_per_second = mbit.max(1) as u128 * 1_000_000;
+    (size as u128 * 8).div_ceil(bits_per_second) as u64
+}
+
+/// A line like `≈ 45s over Wi-Fi (866 Mbit/s)`
+pub fn format_estimate(size: u64, kind: LinkKind, mbit: u64) -> String {
+    format!(
+        "≈ {} over {} ({} Mbit/s)",
+        format_duration(estimate_seconds(size, mbit)),
+        kind.get_name(),
+        mbit
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_estimate_is_enough(size in any::<u32>(), mbit in 1u64..100_000) {
+            let seconds = estimate_seconds(size as u64, mbit);
+            prop_assert!(seconds as u128 * mbit as u128 * 1_000_000 >= size as u128 * 8);
+            prop_assert!(seconds == 0 || (seconds as u128 - 1) * mbit as u128 * 1_000_000 < size as u128 * 8);
+        }
+    }
+
+    #[test]
+    fn test_format_estimate() {
+        assert_eq!(
+            "≈ 45s over Wi-Fi (866 Mbit/s)",
+            format_estimate(4_871_250_000, LinkKind::WiFi, 866)
+        );
+        assert_eq!(
+            "≈ 1m 20s over Ethernet (100 Mbit/s)",
+            format_estimate(1_000_000_000, LinkKind::Wired, 100)
+        );
+    }
+
+    #[test]
+    fn test_parse_iw_bitrate() {
+        let output = "Connected to 00:11:22:33:44:55 (on wlan0)\n\
+                      \tSSID: home\n\
+                      \tfreq: 5180\n\
+                      \ttx bitrate: 866.7 MBit/s VHT-MCS 9 80MHz short GI VHT-NSS 2\n";
+        assert_eq!(Some(867), parse_iw_bitrate(output));
+        assert_eq!(None, parse_iw_bitrate("Not connected.\n"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Sending and receiving in a single session
+//!
+//! With `--exchange DIR`, the page at `/` offers the shared file for download and accepts uploads
+//! into DIR, so two people swap files through one QR code. Uploads work like in receive mode,
+//! every other request is answered like in send mode.
+
+use crate::{help, html, paths, receive};
+use hyper::{header, Body, Method, Response};
+
+const EXCHANGE_PAGE: &str = include_str!("exchange.html");
+
+/// Who answers a request in an exchange
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Page,
+    Upload,
+    Download,
+}
+
+/// The requests of an exchange: the page, the `downloads` of the shared file and the uploads into
+/// `inbox`
+pub fn get_routes<T: Clone>(
+    downloads: Vec<help::Route<T>>,
+    inbox: &receive::Inbox,
+) -> Vec<help::Route<Route>> {
+    let mut routes = vec![help::Route::new(
+        Method::GET,
+        "/",
+        "Page offering the file and a form for uploads",
+        Route::Page,
+    )];
+    routes.extend(downloads.into_iter().map(|r| r.map(|_| Route::Download)));
+    let uploads = receive::get_upload_routes(inbox).into_iter();
+    routes.extend(uploads.map(|r| r.map(|_| Route::Upload)));
+    routes
+}
+
+/// The page offering `file_name` and a form for uploads
+fn create_page(file_name: &str) -> String {
+    EXCHANGE_PAGE
+        .replace(
+            "{href}",
+            &html::escape(&format!("/{}", paths::percent_encode(file_name))),
+        )
+        .replace("{name}", &html::escape(file_name))
+}
+
+pub fn create_page_response(file_name: &str) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(create_page(file_name)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify;
+    use hyper::Request;
+
+    #[test]
+    fn test_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = receive::Inbox::new(
+            dir.path().to_path_buf(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            notify::Notifier::default(),
+        );
+        let downloads =
+            vec![help::Route::new(Method::GET, "/report.pdf", "Downloads the file", ()).any_path()];
+        let routes = get_routes(downloads, &inbox);
+        let route = |method: Method, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(())
+                .unwrap();
+            help::find_route(&routes, &request).unwrap()
+        };
+        assert_eq!(Route::Page, route(Method::GET, "/"));
+        assert_eq!(Route::Upload, route(Method::PUT, "/photo.jpg"));
+        assert_eq!(Route::Upload, route(Method::POST, "/"));
+        assert_eq!(Route::Download, route(Method::GET, "/report.pdf"));
+        assert_eq!(Route::Download, route(Method::GET, "/SHA256SUMS"));
+    }
+
+    #[test]
+    fn test_page_escapes_file_name() {
+        let page = create_page("<b> & c.txt");
+        assert!(page.contains(">&lt;b&gt; &amp; c.txt</a>"));
+        assert!(page.contains("href=\"/%3Cb%3E%20%26%20c.txt\""));
+    }
+}
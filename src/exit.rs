@@ -0,0 +1,59 @@
+//! Exit codes telling wrapper scripts how rustbelt ended
+//!
+//! | Code | Meaning                                                                    |
+//! |------|----------------------------------------------------------------------------|
+//! | 0    | Stopped by the user, or the transfer completed                             |
+//! | 1    | Any other error, including invalid arguments                               |
+//! | 2    | The network interface doesn't exist                                        |
+//! | 3    | The address and port could not be bound                                    |
+//! | 4    | The confirmation before serving was declined                               |
+//! | 5    | The server shut down as the share expired or all one-time links were used  |
+//! | 6    | A download, upload or sync with another instance failed                    |
+
+use std::error;
+
+pub const ERROR: i32 = 1;
+pub const INTERFACE_NOT_FOUND: i32 = 2;
+pub const BIND_FAILED: i32 = 3;
+pub const USER_ABORT: i32 = 4;
+pub const SHARE_ENDED: i32 = 5;
+pub const TRANSFER_FAILED: i32 = 6;
+
+/// Returns the exit code for the error rustbelt ends with.
+pub fn get_exit_code(error: &(dyn error::Error + 'static)) -> i32 {
+    if error.is::<crate::NetworkInterfaceExistanceError>() {
+        INTERFACE_NOT_FOUND
+    } else if error.is::<crate::BindError>() {
+        BIND_FAILED
+    } else if error.is::<crate::UserAbortError>() {
+        USER_ABORT
+    } else if error.is::<crate::ShareEndedError>() {
+        SHARE_ENDED
+    } else if error.is::<crate::sync::PeerResponseError>()
+        || error.is::<crate::get::PieceError>()
+        || error.is::<hyper::Error>()
+    {
+        TRANSFER_FAILED
+    } else {
+        ERROR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_exit_codes() {
+        let interface = crate::NetworkInterfaceExistanceError::new(String::from("wlan9"));
+        assert_eq!(INTERFACE_NOT_FOUND, get_exit_code(&interface));
+        assert_eq!(USER_ABORT, get_exit_code(&crate::UserAbortError));
+        let peer = crate::sync::PeerResponseError::new(
+            String::from("http://10.0.0.2:8080/"),
+            hyper::StatusCode::NOT_FOUND,
+        );
+        assert_eq!(TRANSFER_FAILED, get_exit_code(&peer));
+        assert_eq!(ERROR, get_exit_code(&io::Error::other("disk full")));
+    }
+}
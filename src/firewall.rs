@@ -0,0 +1,282 @@
+//! Opening the port in the firewall while serving
+//!
+//! With `--open-firewall`, rustbelt asks before adding a rule that lets connections to the chosen
+//! port in on the chosen interface, and removes it again when it stops. firewalld only gets a
+//! runtime rule, which doesn't survive a reload either. For plain nftables the rule is inserted
+//! into the `input` chain of the `inet filter` table, marked with a comment to find it again.
+
+use crate::check::run_command;
+use crate::output;
+use std::error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Firewall {
+    Firewalld { zone: String },
+    Ufw,
+    Nftables,
+}
+
+#[derive(Debug)]
+pub struct FirewallError {
+    message: String,
+}
+
+impl error::Error for FirewallError {}
+
+impl fmt::Display for FirewallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl FirewallError {
+    fn new(message: String) -> FirewallError {
+        FirewallError { message }
+    }
+}
+
+/// The zone firewalld applies to `interface`, if firewalld is running.
+pub fn get_firewalld_zone(interface: &str) -> Option<String> {
+    let (_, state) = run_command("firewall-cmd", &["--state"])?;
+    if state != "running" {
+        return None;
+    }
+    match run_command(
+        "firewall-cmd",
+        &[&format!("--get-zone-of-interface={}", interface)],
+    ) {
+        Some((true, zone)) if !zone.is_empty() => Some(zone),
+        _ => Some(run_command("firewall-cmd", &["--get-default-zone"])?.1),
+    }
+}
+
+/// The firewall managing inbound connections, if one of the supported ones is active.
+pub fn detect(interface: &str) -> Option<Firewall> {
+    if let Some(zone) = get_firewalld_zone(interface) {
+        return Some(Firewall::Firewalld { zone });
+    }
+    if let Some((_, status)) = run_command("ufw", &["status"]) {
+        if status.lines().any(|l| l.trim() == "Status: active") {
+            return Some(Firewall::Ufw);
+        }
+    }
+    match run_command("nft", &["list", "chain", "inet", "filter", "input"]) {
+        Some((true, _)) => Some(Firewall::Nftables),
+        _ => None,
+    }
+}
+
+fn get_nft_comment(port: u16) -> String {
+    format!("rustbelt {}", port)
+}
+
+/// The handle of the rule with `comment` in the output of `nft -a list chain`.
+fn find_nft_handle(output: &str, comment: &str) -> Option<String> {
+    let quoted = format!("comment \"{}\"", comment);
+    output
+        .lines()
+        .filter(|l| l.contains(&quoted))
+        .find_map(|l| {
+            l.rsplit_once("# handle ")
+                .map(|(_, h)| h.trim().to_string())
+        })
+}
+
+impl Firewall {
+    fn get_name(&self) -> &'static str {
+        match self {
+            Firewall::Firewalld { .. } => "firewalld",
+            Firewall::Ufw => "ufw",
+            Firewall::Nftables => "nftables",
+        }
+    }
+
+    /// The program and arguments adding the rule for `port` on `interface`
+    fn get_open_command(&self, interface: &str, port: u16) -> (&'static str, Vec<String>) {
+        let port_string = port.to_string();
+        match self {
+            Firewall::Firewalld { zone } => (
+                "firewall-cmd",
+                vec![
+                    format!("--zone={}", zone),
+                    format!("--add-port={}/tcp", port),
+                ],
+            ),
+            Firewall::Ufw => (
+                "ufw",
+                ["allow", "in", "on", interface, "to", "any", "port"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .chain(vec![
+                        port_string,
+                        String::from("proto"),
+                        String::from("tcp"),
+                    ])
+                    .collect(),
+            ),
+            Firewall::Nftables => (
+                "nft",
+                vec![
+                    String::from("insert rule inet filter input iifname"),
+                    format!("\"{}\"", interface),
+                    format!("tcp dport {} accept comment", port),
+                    format!("\"{}\"", get_nft_comment(port)),
+                ],
+            ),
+        }
+    }
+
+    fn run(program: &str, args: &[String]) -> Result<(), FirewallError> {
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        match run_command(program, &args) {
+            Some((true, _)) => Ok(()),
+            Some((false, _)) => Err(FirewallError::new(format!(
+                "{} {} failed, does it need root?",
+                program,
+                args.join(" ")
+            ))),
+            None => Err(FirewallError::new(format!("{} is not installed", program))),
+        }
+    }
+
+    fn close(&self, interface: &str, port: u16) -> Result<(), FirewallError> {
+        match self {
+            Firewall::Firewalld { zone } => Firewall::run(
+                "firewall-cmd",
+                &[
+                    format!("--zone={}", zone),
+                    format!("--remove-port={}/tcp", port),
+                ],
+            ),
+            Firewall::Ufw => {
+                let (_, args) = self.get_open_command(interface, port);
+                let args = std::iter::once(String::from("delete"))
+                    .chain(args)
+                    .collect::<Vec<_>>();
+                Firewall::run("ufw", &args)
+            }
+            Firewall::Nftables => {
+                let (_, listing) =
+                    run_command("nft", &["-a", "list", "chain", "inet", "filter", "input"])
+                        .ok_or_else(|| FirewallError::new(String::from("nft is not installed")))?;
+                let handle =
+                    find_nft_handle(&listing, &get_nft_comment(port)).ok_or_else(|| {
+                        FirewallError::new(String::from("The nftables rule is gone already"))
+                    })?;
+                Firewall::run(
+                    "nft",
+                    &[format!("delete rule inet filter input handle {}", handle)],
+                )
+            }
+        }
+    }
+}
+
+/// A rule letting connections in, removed again when dropped
+pub struct OpenPort {
+    firewall: Firewall,
+    interface: String,
+    port: u16,
+}
+
+impl Drop for OpenPort {
+    fn drop(&mut self) {
+        match self.firewall.close(&self.interface, self.port) {
+            Ok(()) => eprintln!(
+                "Removed the {} rule for port {}",
+                self.firewall.get_name(),
+                self.port
+            ),
+            Err(e) => eprintln!("Could not remove the firewall rule: {}", e),
+        }
+    }
+}
+
+/// Asks for confirmation, unless `assume_yes`, and opens `port` on `interface` in the active
+/// firewall until the returned rule is dropped. Returns `None` if no supported firewall is active
+/// or the user declines.
+pub fn open_port(
+    interface: &str,
+    port: u16,
+    assume_yes: bool,
+) -> Result<Option<OpenPort>, Box<dyn error::Error>> {
+    let firewall = match detect(interface) {
+        Some(firewall) => firewall,
+        None => {
+            eprintln!("No active firewalld, ufw or nftables firewall found, nothing to open");
+            return Ok(None);
+        }
+    };
+    let (program, args) = firewall.get_open_command(interface, port);
+    let question = format!(
+        "Open port {}/tcp on {} until rustbelt stops by running: {} {}?",
+        port,
+        interface,
+        program,
+        args.join(" ")
+    );
+    if assume_yes {
+        eprintln!("{}", question.trim_end_matches('?'));
+    } else if !output::confirm(&question)? {
+        return Ok(None);
+    }
+    Firewall::run(program, &args)?;
+    eprintln!("Opened port {} in {}", port, firewall.get_name());
+    Ok(Some(OpenPort {
+        firewall,
+        interface: interface.to_string(),
+        port,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_find_nft_handle(port in any::<u16>(), handle in any::<u32>()) {
+            let listing = format!(
+                "table inet filter {{\n\tchain input {{\n\
+                 \t\ttcp dport 22 accept # handle 4\n\
+                 \t\tiifname \"wlan0\" tcp dport {} accept comment \"{}\" # handle {}\n\
+                 \t}}\n}}\n",
+                port,
+                get_nft_comment(port),
+                handle
+            );
+            prop_assert_eq!(Some(handle.to_string()), find_nft_handle(&listing, &get_nft_comment(port)));
+            prop_assert_eq!(None, find_nft_handle(&listing, &get_nft_comment(port.wrapping_add(1))));
+        }
+    }
+
+    #[test]
+    fn test_open_commands() {
+        let zone = Firewall::Firewalld {
+            zone: String::from("home"),
+        };
+        assert_eq!(
+            (
+                "firewall-cmd",
+                vec![
+                    String::from("--zone=home"),
+                    String::from("--add-port=8080/tcp")
+                ]
+            ),
+            zone.get_open_command("wlan0", 8080)
+        );
+        let (_, args) = Firewall::Ufw.get_open_command("wlan0", 8080);
+        assert_eq!(
+            "allow in on wlan0 to any port 8080 proto tcp",
+            args.join(" ")
+        );
+        let (_, args) = Firewall::Nftables.get_open_command("wlan0", 8080);
+        assert_eq!(
+            "insert rule inet filter input iifname \"wlan0\" tcp dport 8080 accept comment \
+             \"rustbelt 8080\"",
+            args.join(" ")
+        );
+    }
+}
@@ -0,0 +1,562 @@
+//! The built-in download client for `rustbelt get`
+//!
+//! If the sender offers a piece manifest, pieces are fetched in parallel, verified against their
+//! hashes and retried individually. Otherwise the file is downloaded in one go, and continued
+//! with a range request where it broke off.
+//!
+//! Connection errors and answers like 503 are retried up to `MAX_ATTEMPTS` times, waiting twice
+//! as long after every failure. After a failure the next address of the sender is tried, as named
+//! in the `Link` headers of its answers, so a download started over IPv6 can finish over IPv4.
+//! The finished file is always checked against the SHA-256 the sender announces in its Digest
+//! header or in `/SHA256SUMS`, and the verified hash is sent back to the sender, for one started
+//! with `--move` to know the file arrived intact.
+
+use crate::manifest;
+use crate::mirrors::Mirrors;
+use crate::pieces::{self, PieceManifest};
+use crate::sync::PeerResponseError;
+use crate::transfer;
+use futures::stream::{self, StreamExt};
+use futures::Future;
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request, StatusCode};
+use std::error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+const MAX_ATTEMPTS: usize = 8;
+/// Longest wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A piece that doesn't match its hash
+#[derive(Debug)]
+pub struct PieceError {
+    index: usize,
+}
+
+impl error::Error for PieceError {}
+
+impl fmt::Display for PieceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Piece {} does not match its hash", self.index)
+    }
+}
+
+impl PieceError {
+    fn new(index: usize) -> PieceError {
+        PieceError { index }
+    }
+}
+
+/// A downloaded file whose SHA-256 differs from the one announced by the sender
+#[derive(Debug)]
+pub struct ChecksumError {
+    path: PathBuf,
+    expected: String,
+    actual: String,
+}
+
+impl error::Error for ChecksumError {}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The SHA-256 of {} is {}, but the sender announced {}",
+            self.path.display(),
+            self.actual,
+            self.expected
+        )
+    }
+}
+
+/// Returns a file name from a server supplied name that can't point outside the current
+/// directory.
+pub fn get_safe_file_name(name: &str) -> PathBuf {
+    match Path::new(name).file_name() {
+        Some(n) => PathBuf::from(n),
+        None => PathBuf::from("download"),
+    }
+}
+
+fn get_file_name_from_disposition(disposition: &str) -> Option<String> {
+    let start = disposition.find("filename=\"")? + "filename=\"".len();
+    let mut name = String::new();
+    let mut escaped = false;
+    for c in disposition[start..].chars() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(name),
+            _ => {
+                name.push(c);
+                escaped = false;
+            }
+        }
+    }
+    None
+}
+
+/// Turns the value of a Digest header into a hex encoded SHA-256 hash.
+fn parse_digest(digest: &str) -> Option<String> {
+    let value = digest.split(',').map(str::trim).find_map(|d| {
+        let (algorithm, value) = d.split_at(d.find('=')?);
+        Some(value[1..].to_string()).filter(|_| algorithm.eq_ignore_ascii_case("sha-256"))
+    })?;
+    let bytes = base64::decode(value).ok().filter(|b| b.len() == 32)?;
+    Some(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// How long to wait after the failed attempt `attempt`, counted from 1
+fn get_backoff(attempt: usize) -> Duration {
+    let shift = (attempt.max(1) - 1).min(16) as u32;
+    (Duration::from_secs(1) * 2u32.pow(shift)).min(MAX_BACKOFF)
+}
+
+/// Whether trying again may help after `error`. The sender refusing a request for good, like
+/// with 404 or 410, isn't retried.
+fn is_transient(error: &(dyn error::Error + 'static)) -> bool {
+    match error.downcast_ref::<PeerResponseError>() {
+        Some(e) => {
+            let status = e.get_status();
+            status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        None => true,
+    }
+}
+
+/// Returns `error` if it ends the download, or moves on to the next address and waits before
+/// attempt `attempt + 1`.
+async fn back_off(
+    mirrors: &Mirrors,
+    index: usize,
+    attempt: usize,
+    what: &str,
+    error: Box<dyn error::Error>,
+) -> Result<(), Box<dyn error::Error>> {
+    if attempt >= MAX_ATTEMPTS || !is_transient(error.as_ref()) {
+        return Err(error);
+    }
+    let delay = get_backoff(attempt);
+    mirrors.fail(index);
+    eprintln!(
+        "{} failed: {} (attempt {}), trying {} in {} s",
+        what,
+        error,
+        attempt,
+        mirrors.get().1,
+        delay.as_secs()
+    );
+    tokio::time::delay_for(delay).await;
+    Ok(())
+}
+
+/// Runs `request` against the base URL of an address of the sender until it succeeds.
+async fn retry<T, F, R>(
+    mirrors: &Mirrors,
+    what: &str,
+    request: F,
+) -> Result<T, Box<dyn error::Error>>
+where
+    F: Fn(String) -> R,
+    R: Future<Output = Result<T, Box<dyn error::Error>>>,
+{
+    let mut attempt = 1;
+    loop {
+        let (index, base) = mirrors.get();
+        match request(base).await {
+            Ok(value) => return Ok(value),
+            Err(e) => back_off(mirrors, index, attempt, what, e).await?,
+        }
+        attempt += 1;
+    }
+}
+
+/// Downloads the share at `url` to `output`, or to the file name announced by the sender.
+#[tokio::main]
+pub async fn run_get(
+    url: &str,
+    output: Option<PathBuf>,
+    parallel: usize,
+) -> Result<(), Box<dyn error::Error>> {
+    let client = Client::new();
+    let mirrors = Mirrors::new(url);
+
+    let manifest = retry(&mirrors, "Fetching the piece manifest", |base| {
+        fetch_manifest(&client, &mirrors, base)
+    })
+    .await?;
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None => {
+            let (output, digest) = download_whole(&client, &mirrors, output).await?;
+            return verify(&client, &mirrors, &output, digest).await;
+        }
+    };
+    let output = output.unwrap_or_else(|| get_safe_file_name(&manifest.file_name));
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&output)?;
+    file.set_len(manifest.size)?;
+    drop(file);
+
+    eprintln!(
+        "Downloading {} pieces of {} to {}",
+        manifest.hashes.len(),
+        manifest.file_name,
+        output.display()
+    );
+    let results = stream::iter(0..manifest.hashes.len())
+        .map(|index| download_piece(&client, &mirrors, &manifest, index, &output))
+        .buffer_unordered(parallel.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    for result in results {
+        result?;
+    }
+    verify(&client, &mirrors, &output, None).await
+}
+
+/// Fetches the piece manifest, `None` if the sender doesn't offer one.
+async fn fetch_manifest(
+    client: &Client<HttpConnector>,
+    mirrors: &Mirrors,
+    base: String,
+) -> Result<Option<PieceManifest>, Box<dyn error::Error>> {
+    let url = format!("{}/pieces", base);
+    let response = client.get(url.parse()?).await?;
+    mirrors.add_links(response.headers());
+    let status = response.status();
+    if status.is_server_error() {
+        return Err(PeerResponseError::new(url, status).into());
+    }
+    // Senders without pieces answer every path with the file itself.
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|t| t == "application/json");
+    if !status.is_success() || !is_json {
+        return Ok(None);
+    }
+    let manifest = serde_json::from_slice(
+        &transfer::read_limited(response.into_body(), transfer::MAX_JSON_SIZE).await?,
+    )?;
+    Ok(Some(manifest))
+}
+
+async fn download_piece(
+    client: &Client<HttpConnector>,
+    mirrors: &Mirrors,
+    manifest: &PieceManifest,
+    index: usize,
+    output: &Path,
+) -> Result<(), Box<dyn error::Error>> {
+    let (offset, length) = manifest.get_piece_range(index);
+    let data = retry(mirrors, &format!("Piece {}", index), |base| async move {
+        let data = fetch_piece(client, &format!("{}/pieces/{}", base, index), length).await?;
+        if data.len() as u64 != length || pieces::hash_piece(&data) != manifest.hashes[index] {
+            return Err(PieceError::new(index).into());
+        }
+        Ok(data)
+    })
+    .await?;
+    let output = output.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = OpenOptions::new().write(true).open(output)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&data)
+    })
+    .await??;
+    Ok(())
+}
+
+/// Fetches a piece. While the sender is busy with other transfers, this waits as long as asked by
+/// its Retry-After header, without counting as a failed attempt.
+async fn fetch_piece(
+    client: &Client<HttpConnector>,
+    url: &str,
+    length: u64,
+) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    let mut response = client.get(url.parse()?).await?;
+    while response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        let seconds = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|r| r.to_str().ok())
+            .and_then(|r| r.parse().ok())
+            .unwrap_or(1);
+        tokio::time::delay_for(Duration::from_secs(seconds)).await;
+        response = client.get(url.parse()?).await?;
+    }
+    if !response.status().is_success() {
+        return Err(PeerResponseError::new(url.to_string(), response.status()).into());
+    }
+    // A piece is held in memory until it is verified, so a peer can't make it grow without end.
+    Ok(transfer::read_limited(response.into_body(), length).await?)
+}
+
+/// A download of the whole file, continued where it broke off
+#[derive(Default)]
+struct WholeDownload {
+    output: Option<PathBuf>,
+    /// Bytes written to `output`
+    written: u64,
+    /// Version of the file that was started, so a changed file is downloaded anew
+    etag: Option<String>,
+    /// Value of the Digest header of the file
+    digest: Option<String>,
+}
+
+impl WholeDownload {
+    /// Downloads the rest of the file from the address at `base`.
+    async fn resume(
+        &mut self,
+        client: &Client<HttpConnector>,
+        mirrors: &Mirrors,
+        base: &str,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let mut request = Request::get(base);
+        if self.written > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", self.written));
+            if let Some(etag) = &self.etag {
+                request = request.header(header::IF_RANGE, etag.as_str());
+            }
+        }
+        let mut response = client.request(request.body(Body::empty())?).await?;
+        mirrors.add_links(response.headers());
+        let continued = match response.status() {
+            StatusCode::PARTIAL_CONTENT if self.written > 0 => true,
+            status if status.is_success() => false,
+            status => return Err(PeerResponseError::new(base.to_string(), status).into()),
+        };
+        let headers = response.headers();
+        if !continued {
+            if self.written > 0 {
+                eprintln!("The file changed on the sender, starting over");
+            }
+            self.written = 0;
+            self.etag = headers
+                .get(header::ETAG)
+                .and_then(|e| e.to_str().ok())
+                .map(String::from);
+            self.digest = headers
+                .get("digest")
+                .and_then(|d| d.to_str().ok())
+                .map(String::from);
+        } else {
+            eprintln!(
+                "Continuing at {} from {}",
+                transfer::format_size(self.written),
+                base
+            );
+        }
+        let output = match &self.output {
+            Some(output) => output.clone(),
+            None => get_safe_file_name(
+                &headers
+                    .get(header::CONTENT_DISPOSITION)
+                    .and_then(|d| d.to_str().ok())
+                    .and_then(get_file_name_from_disposition)
+                    .unwrap_or_default(),
+            ),
+        };
+        self.output = Some(output.clone());
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(!continued)
+            .open(&output)
+            .await?;
+        file.set_len(self.written).await?;
+        file.seek(SeekFrom::Start(self.written)).await?;
+        // What was written is kept for the next attempt, even if the connection breaks.
+        let result = async {
+            while let Some(chunk) = response.body_mut().next().await {
+                let chunk = chunk?;
+                file.write_all(&chunk).await?;
+                self.written += chunk.len() as u64;
+            }
+            Ok::<_, Box<dyn error::Error>>(())
+        }
+        .await;
+        file.flush().await?;
+        result
+    }
+}
+
+/// Downloads the file in one go and returns where it was stored with its Digest header.
+async fn download_whole(
+    client: &Client<HttpConnector>,
+    mirrors: &Mirrors,
+    output: Option<PathBuf>,
+) -> Result<(PathBuf, Option<String>), Box<dyn error::Error>> {
+    let mut download = WholeDownload {
+        output,
+        ..WholeDownload::default()
+    };
+    let mut attempt = 1;
+    loop {
+        let (index, base) = mirrors.get();
+        match download.resume(client, mirrors, &base).await {
+            Ok(()) => break,
+            Err(e) => back_off(mirrors, index, attempt, "Download", e).await?,
+        }
+        attempt += 1;
+    }
+    let output = download.output.unwrap();
+    eprintln!("Downloaded {}", output.display());
+    Ok((output, download.digest))
+}
+
+/// Asks the sender for the SHA-256 of the file, `None` if it doesn't tell.
+async fn fetch_checksum(
+    client: &Client<HttpConnector>,
+    base: String,
+) -> Result<Option<String>, Box<dyn error::Error>> {
+    // The sender only answers HEAD requests once it hashed the file.
+    let request = Request::builder()
+        .method(Method::HEAD)
+        .uri(base.as_str())
+        .body(Body::empty())?;
+    let response = client.request(request).await?;
+    if response.status().is_server_error() {
+        return Err(PeerResponseError::new(base, response.status()).into());
+    }
+    if let Some(digest) = response
+        .headers()
+        .get("digest")
+        .and_then(|d| d.to_str().ok())
+        .and_then(parse_digest)
+    {
+        return Ok(Some(digest));
+    }
+    let url = format!("{}/SHA256SUMS", base);
+    let response = client.get(url.parse()?).await?;
+    if response.status().is_server_error() {
+        return Err(PeerResponseError::new(url, response.status()).into());
+    }
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+    let sums = transfer::read_limited(response.into_body(), transfer::MAX_JSON_SIZE).await?;
+    let sums = String::from_utf8_lossy(&sums);
+    Ok(sums
+        .lines()
+        .next()
+        .map(|line| line.trim_start_matches('\\'))
+        .and_then(|line| line.split_whitespace().next())
+        .map(str::to_lowercase))
+}
+
+/// Checks the downloaded file against the SHA-256 announced by the sender.
+async fn verify(
+    client: &Client<HttpConnector>,
+    mirrors: &Mirrors,
+    output: &Path,
+    digest: Option<String>,
+) -> Result<(), Box<dyn error::Error>> {
+    let expected = match digest.as_deref().and_then(parse_digest) {
+        Some(expected) => Some(expected),
+        None => {
+            retry(mirrors, "Fetching the checksum", |base| {
+                fetch_checksum(client, base)
+            })
+            .await?
+        }
+    };
+    let expected = match expected {
+        Some(expected) => expected,
+        None => {
+            eprintln!(
+                "The sender announced no checksum, {} could not be verified",
+                output.display()
+            );
+            return Ok(());
+        }
+    };
+    let path = output.to_path_buf();
+    let actual = tokio::task::spawn_blocking(move || manifest::hash_file(&path)).await??;
+    if actual != expected {
+        return Err(Box::new(ChecksumError {
+            path: output.to_path_buf(),
+            expected,
+            actual,
+        }));
+    }
+    eprintln!("Downloaded and verified {}", output.display());
+    confirm(client, mirrors, &actual).await;
+    Ok(())
+}
+
+/// Confirms the download with its SHA-256. Only senders started with `--move` wait for this, the
+/// others refuse it, which is fine.
+async fn confirm(client: &Client<HttpConnector>, mirrors: &Mirrors, sha256: &str) {
+    let (_, base) = mirrors.get();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("{}{}", base, crate::CONFIRM_PATH))
+        .body(Body::from(sha256.to_string()));
+    if let Ok(request) = request {
+        let _ = client.request(request).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_content_disposition, create_digest};
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_disposition_roundtrip(a in "\\PC*") {
+            prop_assert_eq!(Some(a.clone()), get_file_name_from_disposition(&create_content_disposition(&a)));
+        }
+
+        #[test]
+        fn test_digest_roundtrip(sha256 in "[0-9a-f]{64}") {
+            prop_assert_eq!(Some(sha256.clone()), parse_digest(&create_digest(&sha256)));
+        }
+
+        #[test]
+        fn test_backoff_grows(attempt in 1usize..100) {
+            prop_assert!(get_backoff(attempt) <= get_backoff(attempt + 1));
+            prop_assert!(get_backoff(attempt) <= MAX_BACKOFF);
+        }
+    }
+
+    #[test]
+    fn test_safe_file_name() {
+        assert_eq!(
+            PathBuf::from("passwd"),
+            get_safe_file_name("../../etc/passwd")
+        );
+        assert_eq!(PathBuf::from("download"), get_safe_file_name(".."));
+        assert_eq!(PathBuf::from("download"), get_safe_file_name(""));
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let answer = |status| -> Box<dyn error::Error> {
+            Box::new(PeerResponseError::new(String::from("http://a/"), status))
+        };
+        assert!(is_transient(answer(StatusCode::BAD_GATEWAY).as_ref()));
+        assert!(is_transient(answer(StatusCode::TOO_MANY_REQUESTS).as_ref()));
+        assert!(!is_transient(answer(StatusCode::GONE).as_ref()));
+        assert!(is_transient(&PieceError::new(3)));
+        assert_eq!(
+            None,
+            parse_digest("md5=HUXZLQLMuI/KZ5KDcJPcOA==, sha-256=abc")
+        );
+        assert_eq!(Duration::from_secs(4), get_backoff(3));
+    }
+}
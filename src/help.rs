@@ -0,0 +1,387 @@
+//! Describing the endpoints of a share at `/help`
+//!
+//! Someone scripting against a share shouldn't have to read the source to find out where the
+//! checksums are or how an upload is continued. Every mode finds the handler of a request in a
+//! table of `Route`s, built from the options it runs with, and `/help` lists the same table, so it
+//! names exactly the endpoints the running instance answers. Browsers get a page,
+//! `Accept: application/json` or `?format=json` gets the same list as JSON. Paths of one-time
+//! links show the placeholder `<token>` and never a token itself.
+
+use crate::{create_status_response, html};
+use hyper::{header, Body, HeaderMap, Method, Request, Response, StatusCode};
+use serde::Serialize;
+
+pub const HELP_PATH: &str = "/help";
+
+/// A request the share answers
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Endpoint {
+    pub method: String,
+    /// URL path, with placeholders like `<name>` in angle brackets
+    pub path: String,
+    pub description: String,
+}
+
+impl Endpoint {
+    pub fn new(method: Method, path: &str, description: &str) -> Endpoint {
+        Endpoint {
+            method: method.to_string(),
+            path: path.to_string(),
+            description: description.to_string(),
+        }
+    }
+}
+
+/// A request a mode answers, looked up by its handler and listed at `/help`. HEAD requests reach
+/// the handlers as GET requests, so routes for HEAD also take GET requests.
+#[derive(Debug, Clone)]
+pub struct Route<T> {
+    endpoint: Endpoint,
+    paths: Paths,
+    /// Checks the headers of the request, for routes only some requests for the path take
+    guard: Option<fn(&HeaderMap) -> bool>,
+    target: T,
+}
+
+/// Which paths a route takes besides the listed one
+#[derive(Debug, Clone)]
+enum Paths {
+    Listed,
+    /// Every path, as the last resort
+    Any,
+    /// The prefix and every path below it
+    Below(String),
+}
+
+impl<T: Clone> Route<T> {
+    /// A route for `path`. A `<name>` placeholder stands for a single path segment, as the last
+    /// segment also for the rest of the path.
+    pub fn new(method: Method, path: &str, description: &str, target: T) -> Route<T> {
+        Route {
+            endpoint: Endpoint::new(method, path, description),
+            paths: Paths::Listed,
+            guard: None,
+            target,
+        }
+    }
+
+    /// Takes every path that no route before it takes, listed as `path`. Like for routes with a
+    /// guard, other methods are answered with 404 rather than 405 for paths only it takes.
+    pub fn any_path(mut self) -> Route<T> {
+        self.paths = Paths::Any;
+        self
+    }
+
+    /// Takes `prefix` and every path below it, listed as `path`.
+    pub fn below(mut self, prefix: &str) -> Route<T> {
+        self.paths = Paths::Below(prefix.to_string());
+        self
+    }
+
+    /// Only takes requests whose headers pass `guard`.
+    pub fn when(mut self, guard: fn(&HeaderMap) -> bool) -> Route<T> {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// The same route with the target `f` makes of this one
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Route<U> {
+        Route {
+            endpoint: self.endpoint,
+            paths: self.paths,
+            guard: self.guard,
+            target: f(self.target),
+        }
+    }
+
+    /// The same route below `prefix`, like `/<token>`
+    pub fn under(mut self, prefix: &str) -> Route<T> {
+        self.endpoint.path = format!("{}{}", prefix, self.endpoint.path);
+        if let Paths::Below(below) = &mut self.paths {
+            below.insert_str(0, prefix);
+        }
+        self
+    }
+
+    fn matches_method(&self, method: &Method) -> bool {
+        self.endpoint.method == method.as_str()
+            || (self.endpoint.method == Method::HEAD.as_str() && method == Method::GET)
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        match &self.paths {
+            Paths::Listed => matches_template(&self.endpoint.path, path),
+            Paths::Any => true,
+            Paths::Below(prefix) => path
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+        }
+    }
+}
+
+/// Whether the URL `path` fits `template`, see `Route::new`.
+fn matches_template(template: &str, path: &str) -> bool {
+    if template == "*" {
+        return true;
+    }
+    let mut segments = path.split('/');
+    let mut placeholders = template.split('/').peekable();
+    while let Some(placeholder) = placeholders.next() {
+        let segment = match segments.next() {
+            Some(segment) => segment,
+            None => return false,
+        };
+        if !(placeholder.starts_with('<') && placeholder.ends_with('>')) {
+            if placeholder != segment {
+                return false;
+            }
+        } else if segment.is_empty() {
+            return false;
+        } else if placeholders.peek().is_none() {
+            return true;
+        }
+    }
+    segments.next().is_none()
+}
+
+/// The target of the first of `routes` that answers `req`, otherwise the answer to it: 405 with
+/// the methods that may be used if a route takes the path with another method, 404 if none does.
+#[allow(clippy::result_large_err)]
+pub fn find_route<T: Clone, B>(routes: &[Route<T>], req: &Request<B>) -> Result<T, Response<Body>> {
+    find_route_at(routes, req, req.uri().path())
+}
+
+/// Like `find_route`, for `path` instead of the path of `req`, like the rest after a token.
+#[allow(clippy::result_large_err)]
+pub fn find_route_at<T: Clone, B>(
+    routes: &[Route<T>],
+    req: &Request<B>,
+    path: &str,
+) -> Result<T, Response<Body>> {
+    let found = routes.iter().find(|r| {
+        r.matches_method(req.method())
+            && r.matches_path(path)
+            && r.guard.is_none_or(|guard| guard(req.headers()))
+    });
+    if let Some(route) = found {
+        return Ok(route.target.clone());
+    }
+    // `*` only stands for the server as a whole, for OPTIONS.
+    let mut allowed = Vec::new();
+    for route in routes {
+        let takes_path = route.guard.is_none()
+            && !matches!(route.paths, Paths::Any)
+            && route.endpoint.path != "*"
+            && route.matches_path(path);
+        if takes_path && !allowed.contains(&route.endpoint.method) {
+            allowed.push(route.endpoint.method.clone());
+        }
+    }
+    if allowed.is_empty() {
+        return Err(create_status_response(StatusCode::NOT_FOUND, "Not found"));
+    }
+    Err(Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(header::ALLOW, allowed.join(", "))
+        .body(Body::from("Method not allowed"))
+        .unwrap())
+}
+
+/// The endpoints `routes` answer, for the list
+pub fn get_endpoints<T>(routes: &[Route<T>]) -> Vec<Endpoint> {
+    routes.iter().map(|r| r.endpoint.clone()).collect()
+}
+
+#[derive(Serialize)]
+struct Help<'a> {
+    mode: &'a str,
+    endpoints: &'a [Endpoint],
+}
+
+/// Whether the client asked for JSON rather than a page
+fn wants_json<T>(req: &Request<T>) -> bool {
+    let query = req.uri().query().unwrap_or("");
+    if query.split('&').any(|p| p == "format=json") {
+        return true;
+    }
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|a| a.to_str().ok())
+        .unwrap_or("");
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+fn create_json(mode: &str, endpoints: &[Endpoint]) -> String {
+    serde_json::to_string_pretty(&Help { mode, endpoints }).unwrap()
+}
+
+fn create_page(mode: &str, endpoints: &[Endpoint]) -> String {
+    let mut rows = String::new();
+    for endpoint in endpoints {
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td><code>{}</code></td><td>{}</td></tr>\n",
+            html::escape(&endpoint.method),
+            html::escape(&endpoint.path),
+            html::escape(&endpoint.description)
+        ));
+    }
+    html::create_page(
+        "Endpoints",
+        &format!(
+            "<p>This rustbelt instance is in {} mode. The list is also available as JSON at \
+             <code>{}?format=json</code>.</p>\n\
+             <table>\n<tr><th>Method</th><th>Path</th><th>Description</th></tr>\n{}</table>\n",
+            html::escape(mode),
+            HELP_PATH,
+            rows
+        ),
+    )
+}
+
+/// Answers a request for `/help` describing the `endpoints` of a share in `mode`.
+pub fn create_response<T>(mode: &str, endpoints: &[Endpoint], req: &Request<T>) -> Response<Body> {
+    let (content_type, body) = if wants_json(req) {
+        ("application/json", create_json(mode, endpoints))
+    } else {
+        ("text/html; charset=utf-8", create_page(mode, endpoints))
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn request(uri: &str, accept: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(accept) = accept {
+            builder = builder.header(header::ACCEPT, accept);
+        }
+        builder.body(()).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn test_page_escapes_endpoints(path in "\\PC*", description in "\\PC*") {
+            let endpoints = [Endpoint::new(Method::GET, &path, &description)];
+            let page = create_page("send", &endpoints);
+            prop_assert!(page.contains(&html::escape(&path)));
+            prop_assert!(page.contains(&html::escape(&description)));
+            let json: serde_json::Value = serde_json::from_str(&create_json("send", &endpoints)).unwrap();
+            prop_assert_eq!(json["endpoints"][0]["path"].as_str(), Some(path.as_str()));
+        }
+    }
+
+    #[test]
+    fn test_wants_json() {
+        assert!(!wants_json(&request("/help", None)));
+        assert!(wants_json(&request("/help?format=json", None)));
+        assert!(wants_json(&request("/help", Some("application/json"))));
+        assert!(!wants_json(&request(
+            "/help",
+            Some("text/html,application/xhtml+xml,application/json;q=0.9")
+        )));
+        assert!(!wants_json(&request("/help?format=jsonp", None)));
+    }
+
+    #[test]
+    fn test_matches_template() {
+        assert!(matches_template("/", "/"));
+        assert!(!matches_template("/", "/a"));
+        assert!(matches_template("/SHA256SUMS", "/SHA256SUMS"));
+        assert!(matches_template("/<name>", "/a.txt"));
+        assert!(matches_template("/files/<path>", "/files/a/b"));
+        assert!(!matches_template("/files/<path>", "/files/"));
+        assert!(matches_template("/<token>/", "/abc/"));
+        assert!(!matches_template("/<token>/", "/abc/d"));
+        assert!(matches_template("/<token>/<n>/<name>", "/abc/1/a.txt"));
+        assert!(!matches_template("/<token>/<n>/<name>", "/abc/1"));
+        assert!(matches_template("*", "/anything"));
+    }
+
+    #[test]
+    fn test_find_route() {
+        let routes = vec![
+            Route::new(Method::GET, "/", "Page", 1),
+            Route::new(Method::PUT, "/<name>", "Upload", 2),
+            Route::new(Method::GET, "/file", "File", 3).any_path(),
+        ];
+        let get = |method: Method, path: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(())
+                .unwrap();
+            find_route(&routes, &req).map_err(|response| response.status())
+        };
+        assert_eq!(Ok(1), get(Method::GET, "/"));
+        assert_eq!(Ok(2), get(Method::PUT, "/a"));
+        assert_eq!(Ok(3), get(Method::GET, "/a/b"));
+        assert_eq!(
+            Err(StatusCode::METHOD_NOT_ALLOWED),
+            get(Method::DELETE, "/a")
+        );
+        let req = Request::get("/a/b").body(()).unwrap();
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            find_route(&routes[..1], &req).unwrap_err().status()
+        );
+        let endpoints = get_endpoints(&routes);
+        assert_eq!(3, endpoints.len());
+        assert_eq!("/file", endpoints[2].path);
+    }
+
+    #[test]
+    fn test_find_route_below_and_with_headers() {
+        let routes = vec![
+            Route::new(Method::HEAD, "/<name>", "Offset", 1)
+                .when(|headers| headers.contains_key("x-upload-size")),
+            Route::new(Method::GET, "/files/<path>", "File", 2).below("/files"),
+            Route::new(Method::PUT, "/files/<path>", "Upload", 3).below("/files"),
+        ];
+        let request = |method: Method, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .body(())
+                .unwrap()
+        };
+        assert_eq!(
+            2,
+            find_route(&routes, &request(Method::GET, "/files")).unwrap()
+        );
+        assert_eq!(
+            2,
+            find_route(&routes, &request(Method::GET, "/files/")).unwrap()
+        );
+        let response = find_route(&routes, &request(Method::DELETE, "/files/a")).unwrap_err();
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+        assert_eq!("GET, PUT", response.headers()[header::ALLOW]);
+        let response = find_route(&routes, &request(Method::GET, "/filesystem")).unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let mut offset = request(Method::GET, "/a.txt");
+        offset.headers_mut().insert("x-upload-size", 5.into());
+        assert_eq!(1, find_route(&routes, &offset).unwrap());
+        let response = find_route(&routes, &request(Method::GET, "/a.txt")).unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let under = routes[1].clone().under("/<token>");
+        assert_eq!("/<token>/files/<path>", get_endpoints(&[under])[0].path);
+    }
+
+    #[test]
+    fn test_json() {
+        let endpoints = [Endpoint::new(Method::PUT, "/<name>", "Uploads a file")];
+        let json: serde_json::Value =
+            serde_json::from_str(&create_json("receive", &endpoints)).unwrap();
+        assert_eq!("receive", json["mode"]);
+        assert_eq!("PUT", json["endpoints"][0]["method"]);
+        assert_eq!("/<name>", json["endpoints"][0]["path"]);
+        assert_eq!("Uploads a file", json["endpoints"][0]["description"]);
+    }
+}
@@ -0,0 +1,241 @@
+//! Host names of clients, with `--resolve-names`
+//!
+//! An address like 192.168.1.57 says little about who is downloading, `lena-macbook.local` does.
+//! The name of every new client is looked up in the background, first by asking the devices on
+//! the network over multicast DNS, which is what Avahi and Apple's Bonjour answer, and then with
+//! a reverse lookup of the system resolver, for networks whose router names its DHCP clients.
+//! Until a name is found, and for clients without one, the address is shown.
+
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Time to wait for a device to answer the multicast query
+pub const MDNS_TIMEOUT: Duration = Duration::from_secs(1);
+const MDNS_PORT: u16 = 5353;
+const MDNS_IPV4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_IPV6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const TYPE_PTR: u16 = 12;
+/// Class IN with the bit asking for a unicast answer
+const CLASS_IN_UNICAST: u16 = 0x8001;
+
+/// The name a reverse lookup of `ip` asks for, like `57.1.168.192.in-addr.arpa`.
+fn get_reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+/// A DNS query for the PTR record of `name`.
+fn create_query(id: u16, name: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(12 + name.len() + 6);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Flags, one question, no other records
+    packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN_UNICAST.to_be_bytes());
+    packet
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *packet.get(offset)?,
+        *packet.get(offset + 1)?,
+    ]))
+}
+
+/// Reads the possibly compressed name at `offset`, and returns it with the offset after it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Every pointer has to lead backwards, which rules out loops.
+    let mut limit = offset;
+    loop {
+        let length = *packet.get(offset)? as usize;
+        if length & 0xc0 == 0xc0 {
+            let target = (read_u16(packet, offset)? & 0x3fff) as usize;
+            if target >= limit {
+                return None;
+            }
+            end.get_or_insert(offset + 2);
+            limit = target;
+            offset = target;
+        } else if length == 0 {
+            let name = labels.join(".");
+            return Some((name, end.unwrap_or(offset + 1)));
+        } else {
+            let label = packet.get(offset + 1..offset + 1 + length)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + length;
+        }
+    }
+}
+
+/// The host name in the answer to the query `id` for the PTR record of `name`.
+fn parse_answer(packet: &[u8], id: u16, name: &str) -> Option<String> {
+    // Multicast answers carry no ID, legacy unicast ones repeat the query's.
+    let answer_id = read_u16(packet, 0)?;
+    let is_response = read_u16(packet, 2)? & 0x8000 != 0;
+    if !is_response || (answer_id != 0 && answer_id != id) {
+        return None;
+    }
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+    for _ in 0..answers {
+        let (owner, next) = read_name(packet, offset)?;
+        let kind = read_u16(packet, next)?;
+        let length = read_u16(packet, next + 8)? as usize;
+        let data = next + 10;
+        if kind == TYPE_PTR && owner.eq_ignore_ascii_case(name) {
+            let (host, _) = read_name(packet, data)?;
+            return Some(host).filter(|h| !h.is_empty());
+        }
+        offset = data + length;
+    }
+    None
+}
+
+/// Asks the devices on the network for the name of `ip` over multicast DNS.
+async fn query_mdns(ip: IpAddr) -> Option<String> {
+    let (bind, group): (SocketAddr, SocketAddr) = match ip {
+        IpAddr::V4(_) => (
+            (Ipv4Addr::UNSPECIFIED, 0).into(),
+            (MDNS_IPV4, MDNS_PORT).into(),
+        ),
+        IpAddr::V6(_) => (
+            (Ipv6Addr::UNSPECIFIED, 0).into(),
+            (MDNS_IPV6, MDNS_PORT).into(),
+        ),
+    };
+    let name = get_reverse_name(ip);
+    let id = rand::random();
+    let mut socket = UdpSocket::bind(bind).await.ok()?;
+    socket.send_to(&create_query(id, &name), group).await.ok()?;
+    let answer = async {
+        let mut buffer = [0; 1500];
+        loop {
+            let (length, _) = socket.recv_from(&mut buffer).await.ok()?;
+            if let Some(host) = parse_answer(&buffer[..length], id, &name) {
+                return Some(host);
+            }
+        }
+    };
+    tokio::time::timeout(MDNS_TIMEOUT, answer).await.ok()?
+}
+
+/// Looks `ip` up with the system resolver, which blocks.
+fn query_dns(ip: IpAddr) -> Option<String> {
+    let address = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    // Safe, getnameinfo writes at most the given length to host and reads the address only.
+    let result = unsafe {
+        libc::getnameinfo(
+            address.as_ptr(),
+            address.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    let host = unsafe { CStr::from_ptr(host.as_ptr()) };
+    Some(host.to_string_lossy().into_owned())
+}
+
+/// The host name of `ip`, if a device on the network or the resolver knows it.
+pub async fn lookup(ip: IpAddr) -> Option<String> {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    };
+    if let Some(host) = query_mdns(ip).await {
+        return Some(host);
+    }
+    tokio::task::spawn_blocking(move || query_dns(ip))
+        .await
+        .ok()
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// The answer of a device named `host` to `query`, pointing back to the question's name.
+    fn create_answer(query: &[u8], host: &str) -> Vec<u8> {
+        let mut packet = query.to_vec();
+        packet[2] = 0x84;
+        packet[7] = 1;
+        // The owner points to the question at offset 12.
+        packet.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1, 0, 0, 0, 120]);
+        let mut data = Vec::new();
+        for label in host.split('.') {
+            data.push(label.len() as u8);
+            data.extend_from_slice(label.as_bytes());
+        }
+        data.push(0);
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&data);
+        packet
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_never_panics(packet in proptest::collection::vec(any::<u8>(), 0..200)) {
+            let _ = parse_answer(&packet, 1, "57.1.168.192.in-addr.arpa");
+        }
+
+        #[test]
+        fn test_answer_roundtrip(octets in any::<[u8; 4]>(), host in "[a-z][a-z0-9-]{0,20}\\.local") {
+            let name = get_reverse_name(IpAddr::from(octets));
+            let query = create_query(7, &name);
+            prop_assert_eq!(Some(host.clone()), parse_answer(&create_answer(&query, &host), 7, &name));
+            prop_assert_eq!(None, parse_answer(&create_answer(&query, &host), 8, &name));
+        }
+    }
+
+    #[test]
+    fn test_reverse_name() {
+        assert_eq!(
+            "57.1.168.192.in-addr.arpa",
+            get_reverse_name("192.168.1.57".parse().unwrap())
+        );
+        let name = get_reverse_name("fe80::1".parse().unwrap());
+        assert!(name.starts_with("1.0.0.0.0.0.0.0."));
+        assert!(name.ends_with(".0.8.e.f.ip6.arpa"));
+        assert_eq!(32 * 2 + "ip6.arpa".len(), name.len());
+    }
+
+    #[test]
+    fn test_read_name_rejects_loops() {
+        let packet = [0u8, 0, 0xc0, 2];
+        assert_eq!(None, read_name(&packet, 2));
+        let packet = [3, b'a', b'b', b'c', 0, 0xc0, 0];
+        assert_eq!(Some((String::from("abc"), 7)), read_name(&packet, 5));
+    }
+}
@@ -0,0 +1,88 @@
+//! Small helpers for the HTML pages served to the recipient
+
+/// Escapes text for use in HTML element content and quoted attribute values.
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders a page listing links, each given as `(href, label)`. Both are escaped.
+pub fn create_listing_page(title: &str, links: &[(String, String)]) -> String {
+    let mut items = String::new();
+    for (href, label) in links {
+        items.push_str(&format!(
+            "<li><a href=\"{}\">{}</a></li>\n",
+            escape(href),
+            escape(label)
+        ));
+    }
+    create_page(title, &format!("<ul>\n{}</ul>\n", items))
+}
+
+/// Renders a page showing a single message, which is escaped.
+pub fn create_message_page(title: &str, message: &str) -> String {
+    create_page(title, &format!("<p>{}</p>\n", escape(message)))
+}
+
+/// Renders a page showing a message, which is escaped, followed by the markup of a form.
+pub fn create_form_page(title: &str, message: &str, form: &str) -> String {
+    create_page(title, &format!("<p>{}</p>\n{}", escape(message), form))
+}
+
+/// Wraps already escaped `content` into a page with a heading.
+pub fn create_page(title: &str, content: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n{1}</body>\n</html>\n",
+        escape(title),
+        content
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_escape_removes_markup(a in "\\PC*") {
+            let escaped = escape(&a);
+            prop_assert!(!escaped.contains('<'));
+            prop_assert!(!escaped.contains('>'));
+            prop_assert!(!escaped.contains('"'));
+        }
+    }
+
+    #[test]
+    fn test_listing_page() {
+        let page =
+            create_listing_page("a<b", &[(String::from("/x?y&z"), String::from("<script>"))]);
+        assert!(page.contains("<title>a&lt;b</title>"));
+        assert!(page.contains("<li><a href=\"/x?y&amp;z\">&lt;script&gt;</a></li>"));
+    }
+
+    #[test]
+    fn test_message_page() {
+        let page = create_message_page("Gone", "a & b");
+        assert!(page.contains("<h1>Gone</h1>"));
+        assert!(page.contains("<p>a &amp; b</p>"));
+    }
+
+    #[test]
+    fn test_form_page() {
+        let page = create_form_page("Name", "<you>", "<form></form>\n");
+        assert!(page.contains("<p>&lt;you&gt;</p>\n<form></form>\n</body>"));
+    }
+}
@@ -0,0 +1,467 @@
+//! Enumerating the network interfaces and their addresses
+//!
+//! By default pnet lists them. It doesn't build for musl, so with the `pure-rust-net` feature, or
+//! without the default `pnet` feature, rustbelt reads them itself: the links from `/sys/class/net`
+//! and the addresses from the kernel over rtnetlink. That needs nothing but the system calls, so
+//!
+//! ```text
+//! cargo build --release --no-default-features --features pure-rust-net \
+//!     --target x86_64-unknown-linux-musl
+//! ```
+//!
+//! gives a static binary that runs on any Linux machine. This backend is Linux only.
+
+use ipnetwork::IpNetwork;
+use std::fmt;
+
+/// The flags of an interface, with the values Linux uses for them
+pub const FLAG_UP: u32 = 0x1;
+pub const FLAG_BROADCAST: u32 = 0x2;
+pub const FLAG_LOOPBACK: u32 = 0x8;
+pub const FLAG_POINT_TO_POINT: u32 = 0x10;
+pub const FLAG_MULTICAST: u32 = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let octets = self.0.iter().map(|o| format!("{:02x}", o));
+        write!(f, "{}", octets.collect::<Vec<_>>().join(":"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub index: u32,
+    pub mac: Option<MacAddr>,
+    pub ips: Vec<IpNetwork>,
+    /// `FLAG_*` values
+    pub flags: u32,
+}
+
+impl NetworkInterface {
+    pub fn is_up(&self) -> bool {
+        self.flags & FLAG_UP != 0
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.flags & FLAG_BROADCAST != 0
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.flags & FLAG_LOOPBACK != 0
+    }
+
+    pub fn is_point_to_point(&self) -> bool {
+        self.flags & FLAG_POINT_TO_POINT != 0
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.flags & FLAG_MULTICAST != 0
+    }
+}
+
+#[cfg(all(feature = "pnet", not(feature = "pure-rust-net")))]
+impl From<pnet::datalink::NetworkInterface> for NetworkInterface {
+    fn from(interface: pnet::datalink::NetworkInterface) -> NetworkInterface {
+        // pnet's flags are those of the operating system, which differ between them.
+        let flags = [
+            (interface.is_up(), FLAG_UP),
+            (interface.is_broadcast(), FLAG_BROADCAST),
+            (interface.is_loopback(), FLAG_LOOPBACK),
+            (interface.is_point_to_point(), FLAG_POINT_TO_POINT),
+            (interface.is_multicast(), FLAG_MULTICAST),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        NetworkInterface {
+            mac: interface
+                .mac
+                .map(|m| MacAddr([m.0, m.1, m.2, m.3, m.4, m.5])),
+            name: interface.name,
+            index: interface.index,
+            ips: interface.ips,
+            flags,
+        }
+    }
+}
+
+/// Lists the network interfaces of this machine.
+#[cfg(all(feature = "pnet", not(feature = "pure-rust-net")))]
+pub fn interfaces() -> Vec<NetworkInterface> {
+    pnet::datalink::interfaces()
+        .into_iter()
+        .map(NetworkInterface::from)
+        .collect()
+}
+
+/// Lists the network interfaces of this machine. Interfaces whose addresses can't be read are
+/// listed without any.
+#[cfg(any(feature = "pure-rust-net", not(feature = "pnet")))]
+pub fn interfaces() -> Vec<NetworkInterface> {
+    let mut interfaces = sys::read_links().unwrap_or_default();
+    if let Ok(addresses) = netlink::read_addresses() {
+        for (index, ip) in addresses {
+            if let Some(interface) = interfaces.iter_mut().find(|i| i.index == index) {
+                interface.ips.push(ip);
+            }
+        }
+    }
+    interfaces
+}
+
+#[cfg(all(
+    any(feature = "pure-rust-net", not(feature = "pnet")),
+    not(target_os = "linux")
+))]
+compile_error!("The pure-rust-net backend only supports Linux, build with the pnet feature");
+
+/// Reading the links from sysfs
+#[cfg(all(
+    target_os = "linux",
+    any(test, feature = "pure-rust-net", not(feature = "pnet"))
+))]
+mod sys {
+    use super::{MacAddr, NetworkInterface};
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+
+    const NET_CLASS: &str = "/sys/class/net";
+
+    /// Parses a MAC address like `3c:22:fb:01:02:03`.
+    pub fn parse_mac(mac: &str) -> Option<MacAddr> {
+        let octets = mac
+            .trim()
+            .split(':')
+            .map(|o| u8::from_str_radix(o, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        let mut mac = [0; 6];
+        if octets.len() != mac.len() {
+            return None;
+        }
+        mac.copy_from_slice(&octets);
+        Some(MacAddr(mac))
+    }
+
+    /// Parses the flags in hexadecimal like `0x1003`.
+    pub fn parse_flags(flags: &str) -> Option<u32> {
+        u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok()
+    }
+
+    fn read_link(dir: &Path) -> io::Result<NetworkInterface> {
+        let read = |file: &str| fs::read_to_string(dir.join(file));
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid interface attribute");
+        Ok(NetworkInterface {
+            name: dir
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            index: read("ifindex")?.trim().parse().map_err(|_| invalid())?,
+            // Interfaces without a hardware address, like tunnels, have an empty one.
+            mac: read("address").ok().and_then(|m| parse_mac(&m)),
+            ips: Vec::new(),
+            flags: parse_flags(&read("flags")?).ok_or_else(invalid)?,
+        })
+    }
+
+    /// Lists the links, ordered by their index.
+    pub fn read_links() -> io::Result<Vec<NetworkInterface>> {
+        let mut links = Vec::new();
+        for entry in fs::read_dir(NET_CLASS)? {
+            // Links can disappear while they are listed.
+            if let Ok(link) = read_link(&entry?.path()) {
+                links.push(link);
+            }
+        }
+        links.sort_by_key(|l| l.index);
+        Ok(links)
+    }
+}
+
+/// Asking the kernel for the addresses of all interfaces over an rtnetlink socket
+#[cfg(all(
+    target_os = "linux",
+    any(test, feature = "pure-rust-net", not(feature = "pnet"))
+))]
+mod netlink {
+    use ipnetwork::IpNetwork;
+    use std::convert::TryInto;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    const HEADER_LENGTH: usize = 16;
+    const ADDRESS_MESSAGE_LENGTH: usize = 8;
+    const NLMSG_ERROR: u16 = 2;
+    const NLMSG_DONE: u16 = 3;
+    const RTM_NEWADDR: u16 = 20;
+    const RTM_GETADDR: u16 = 22;
+    const NLM_F_REQUEST: u16 = 0x1;
+    const NLM_F_DUMP: u16 = 0x300;
+    const IFA_ADDRESS: u16 = 1;
+    const IFA_LOCAL: u16 = 2;
+    const AF_INET: u8 = 2;
+    const AF_INET6: u8 = 10;
+    const RECEIVE_BUFFER: usize = 32 * 1024;
+
+    fn align(length: usize) -> usize {
+        (length + 3) & !3
+    }
+
+    fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+        Some(u16::from_ne_bytes(data.get(at..at + 2)?.try_into().ok()?))
+    }
+
+    fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+        Some(u32::from_ne_bytes(data.get(at..at + 4)?.try_into().ok()?))
+    }
+
+    /// Builds the request to dump the addresses of all families.
+    pub fn create_request(sequence: u32) -> Vec<u8> {
+        let length = (HEADER_LENGTH + ADDRESS_MESSAGE_LENGTH) as u32;
+        let mut request = Vec::with_capacity(length as usize);
+        request.extend_from_slice(&length.to_ne_bytes());
+        request.extend_from_slice(&RTM_GETADDR.to_ne_bytes());
+        request.extend_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+        request.extend_from_slice(&sequence.to_ne_bytes());
+        request.extend_from_slice(&0u32.to_ne_bytes());
+        request.extend_from_slice(&[0; ADDRESS_MESSAGE_LENGTH]);
+        request
+    }
+
+    fn parse_ip(family: u8, data: &[u8]) -> Option<IpAddr> {
+        match family {
+            AF_INET => {
+                let octets: [u8; 4] = data.try_into().ok()?;
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            AF_INET6 => {
+                let octets: [u8; 16] = data.try_into().ok()?;
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Reads the interface index and address of an `RTM_NEWADDR` message.
+    fn parse_address(message: &[u8]) -> Option<(u32, IpNetwork)> {
+        let family = *message.first()?;
+        let prefix = *message.get(1)?;
+        let index = read_u32(message, 4)?;
+        let (mut address, mut local) = (None, None);
+        let mut at = ADDRESS_MESSAGE_LENGTH;
+        while at + 4 <= message.len() {
+            let length = read_u16(message, at)? as usize;
+            if length < 4 || at + length > message.len() {
+                break;
+            }
+            let data = &message[at + 4..at + length];
+            match read_u16(message, at + 2)? {
+                IFA_ADDRESS => address = parse_ip(family, data),
+                IFA_LOCAL => local = parse_ip(family, data),
+                _ => {}
+            }
+            at += align(length);
+        }
+        // On point to point links IFA_ADDRESS is the peer, the own address is IFA_LOCAL.
+        let ip = local.or(address)?;
+        Some((index, IpNetwork::new(ip, prefix).ok()?))
+    }
+
+    /// Adds the addresses in a buffer of messages to `addresses` and returns whether the dump is
+    /// complete.
+    pub fn parse_messages(data: &[u8], addresses: &mut Vec<(u32, IpNetwork)>) -> io::Result<bool> {
+        let truncated = || io::Error::new(io::ErrorKind::InvalidData, "Truncated netlink message");
+        let mut at = 0;
+        while at + HEADER_LENGTH <= data.len() {
+            let length = read_u32(data, at).ok_or_else(truncated)? as usize;
+            if length < HEADER_LENGTH || at + length > data.len() {
+                return Err(truncated());
+            }
+            let payload = &data[at + HEADER_LENGTH..at + length];
+            match read_u16(data, at + 4).ok_or_else(truncated)? {
+                NLMSG_DONE => return Ok(true),
+                NLMSG_ERROR => {
+                    let code = read_u32(payload, 0).ok_or_else(truncated)? as i32;
+                    if code != 0 {
+                        return Err(io::Error::from_raw_os_error(-code));
+                    }
+                }
+                RTM_NEWADDR => addresses.extend(parse_address(payload)),
+                _ => {}
+            }
+            at += align(length);
+        }
+        Ok(false)
+    }
+
+    pub fn read_addresses() -> io::Result<Vec<(u32, IpNetwork)>> {
+        use std::mem;
+        use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+        // Safe, the arguments are plain constants.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe, the descriptor was just opened and nothing else owns it.
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        let request = create_request(1);
+        // Safe, sockaddr_nl is plain data for which zeroes are valid.
+        let mut kernel: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        kernel.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        // Safe, the request and the address outlive the call and their lengths are given.
+        let sent = unsafe {
+            libc::sendto(
+                socket.as_raw_fd(),
+                request.as_ptr() as *const libc::c_void,
+                request.len(),
+                0,
+                &kernel as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut addresses = Vec::new();
+        let mut buffer = vec![0u8; RECEIVE_BUFFER];
+        loop {
+            // Safe, the kernel writes at most the length of the buffer.
+            let received = unsafe {
+                libc::recv(
+                    socket.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                )
+            };
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if received == 0 || parse_messages(&buffer[..received as usize], &mut addresses)? {
+                return Ok(addresses);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::net::IpAddr;
+
+    /// An `RTM_NEWADDR` message with the given attributes
+    fn create_address_message(
+        family: u8,
+        prefix: u8,
+        index: u32,
+        attributes: &[(u16, &[u8])],
+    ) -> Vec<u8> {
+        let mut payload = vec![family, prefix, 0, 0];
+        payload.extend_from_slice(&index.to_ne_bytes());
+        for (kind, data) in attributes {
+            payload.extend_from_slice(&(4 + data.len() as u16).to_ne_bytes());
+            payload.extend_from_slice(&kind.to_ne_bytes());
+            payload.extend_from_slice(data);
+            while payload.len() % 4 != 0 {
+                payload.push(0);
+            }
+        }
+        create_message(20, &payload)
+    }
+
+    fn create_message(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&(16 + payload.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&[0; 10]);
+        message.extend_from_slice(payload);
+        message
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_mac(octets in any::<[u8; 6]>()) {
+            let mac = MacAddr(octets);
+            prop_assert_eq!(Some(mac), sys::parse_mac(&format!("{}\n", mac)));
+        }
+
+        #[test]
+        fn test_parse_any_messages(data in proptest::collection::vec(any::<u8>(), 0..200)) {
+            let _ = netlink::parse_messages(&data, &mut Vec::new());
+        }
+    }
+
+    #[test]
+    fn test_parse_sysfs_values() {
+        assert_eq!(None, sys::parse_mac(""));
+        assert_eq!(None, sys::parse_mac("00:11:22:33:44"));
+        assert_eq!(Some(0x1003), sys::parse_flags("0x1003\n"));
+        let interface = NetworkInterface {
+            name: String::from("eth0"),
+            index: 2,
+            mac: None,
+            ips: Vec::new(),
+            flags: sys::parse_flags("0x1003").unwrap(),
+        };
+        assert!(interface.is_up() && interface.is_broadcast() && interface.is_multicast());
+        assert!(!interface.is_loopback());
+    }
+
+    #[test]
+    fn test_parse_address_dump() {
+        let mut dump = create_address_message(
+            2,
+            24,
+            3,
+            &[(1, &[192, 168, 1, 255]), (2, &[192, 168, 1, 20])],
+        );
+        let v6 = "fd00::2".parse::<std::net::Ipv6Addr>().unwrap().octets();
+        dump.extend(create_address_message(10, 64, 3, &[(1, &v6)]));
+        dump.extend(create_message(3, &[0; 4]));
+        let mut addresses = Vec::new();
+        assert!(netlink::parse_messages(&dump, &mut addresses).unwrap());
+        assert_eq!(
+            vec![
+                (3, "192.168.1.20/24".parse().unwrap()),
+                (3, "fd00::2/64".parse().unwrap())
+            ],
+            addresses
+        );
+        assert_eq!(IpAddr::from([192, 168, 1, 20]), addresses[0].1.ip());
+        let error = create_message(2, &(-1i32).to_ne_bytes());
+        assert!(netlink::parse_messages(&error, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_read_loopback() {
+        let links = sys::read_links().unwrap();
+        let loopback = links.iter().find(|l| l.name == "lo").unwrap();
+        assert!(loopback.is_loopback());
+        let addresses = netlink::read_addresses().unwrap();
+        assert!(
+            addresses
+                .iter()
+                .any(|(index, ip)| *index == loopback.index
+                    && ip.ip() == IpAddr::from([127, 0, 0, 1]))
+        );
+    }
+
+    #[test]
+    fn test_request() {
+        let request = netlink::create_request(7);
+        assert_eq!(24, request.len());
+        assert_eq!(&24u32.to_ne_bytes(), &request[..4]);
+    }
+}
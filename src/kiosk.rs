@@ -0,0 +1,341 @@
+//! A download page for unattended distribution points
+//!
+//! With `--kiosk` the QR code leads to `/.kiosk/` followed by the path of the download. The page
+//! counts down from `--countdown` seconds and starts the download, which carries a random id
+//! chosen by the page. The server notes when the response for that id has been sent completely,
+//! and the page polls `/.kiosk-status` for it to tell the visitor that they can close it.
+
+use crate::html;
+use bytes::Bytes;
+use futures::stream::Stream;
+use hyper::{header, Body, Method, Request, Response};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+pub const KIOSK_PATH: &str = "/.kiosk";
+const STATUS_PATH: &str = "/.kiosk-status";
+/// Downloads whose state is kept for pages asking about them
+const MAX_DOWNLOADS: usize = 256;
+/// Longest id accepted from a page
+const MAX_ID_LENGTH: usize = 64;
+
+const SCRIPT: &str = r#"<p id="status">Your download starts in <span id="count"></span> seconds.</p>
+<p><a id="start" href="">Start now</a></p>
+<script>
+const id = Array.from(crypto.getRandomValues(new Uint32Array(4)), n => n.toString(36)).join("");
+const target = path + "?kiosk=" + id;
+const show = text => document.getElementById("status").textContent = text;
+let started = false;
+const start = () => {
+  if (started) {
+    return;
+  }
+  started = true;
+  clearInterval(timer);
+  document.getElementById("start").remove();
+  location.href = target;
+  show("Downloading…");
+  const poll = setInterval(async () => {
+    const response = await fetch("/.kiosk-status?id=" + id, {cache: "no-store"});
+    const state = (await response.json()).state;
+    if (state === "done") {
+      clearInterval(poll);
+      show("Done, you can close this page.");
+    } else if (state === "failed") {
+      clearInterval(poll);
+      show("The download failed. Reload the page to try again.");
+    }
+  }, 1000);
+};
+document.getElementById("count").textContent = count;
+document.getElementById("start").href = target;
+document.getElementById("start").onclick = event => {
+  event.preventDefault();
+  start();
+};
+const timer = setInterval(() => {
+  count -= 1;
+  document.getElementById("count").textContent = count;
+  if (count <= 0) {
+    start();
+  }
+}, 1000);
+</script>
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DownloadState {
+    Running,
+    Done,
+    Failed,
+}
+
+impl DownloadState {
+    fn get_name(self) -> &'static str {
+        match self {
+            DownloadState::Running => "running",
+            DownloadState::Done => "done",
+            DownloadState::Failed => "failed",
+        }
+    }
+}
+
+pub struct Kiosk {
+    /// Seconds before the download starts
+    countdown: u32,
+    downloads: Mutex<VecDeque<(String, DownloadState)>>,
+}
+
+/// Reads a download id from the parameter `name` of a query.
+fn parse_id(query: Option<&str>, name: &str) -> Option<String> {
+    query?
+        .split('&')
+        .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+        .filter(|id| !id.is_empty() && id.len() <= MAX_ID_LENGTH)
+        .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric()))
+        .map(String::from)
+}
+
+/// The id a kiosk page gave its download, from the `kiosk` parameter
+pub fn get_download_id(query: Option<&str>) -> Option<String> {
+    parse_id(query, "kiosk")
+}
+
+impl Kiosk {
+    pub fn new(countdown: u32) -> Kiosk {
+        Kiosk {
+            countdown,
+            downloads: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The URL of the kiosk page for the download at `url` below `base_url`
+    pub fn get_kiosk_url(&self, base_url: &str, url: &str) -> String {
+        let path = url
+            .strip_prefix(base_url)
+            .filter(|p| p.starts_with('/'))
+            .unwrap_or("/");
+        format!("{}{}{}", base_url, KIOSK_PATH, path)
+    }
+
+    fn set_state(&self, id: &str, state: DownloadState) {
+        let mut downloads = self.downloads.lock().unwrap();
+        match downloads.iter_mut().find(|(i, _)| i == id) {
+            Some((_, current)) => *current = state,
+            None => {
+                if downloads.len() == MAX_DOWNLOADS {
+                    downloads.pop_front();
+                }
+                downloads.push_back((id.to_string(), state));
+            }
+        }
+    }
+
+    fn get_state(&self, id: &str) -> Option<DownloadState> {
+        let downloads = self.downloads.lock().unwrap();
+        downloads.iter().find(|(i, _)| i == id).map(|(_, s)| *s)
+    }
+
+    /// Answers requests for the kiosk page and the state of downloads, and leaves all others to
+    /// the mode.
+    pub fn handle_request(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if req.method() != Method::GET {
+            return None;
+        }
+        let path = req.uri().path();
+        if path == STATUS_PATH {
+            let state = parse_id(req.uri().query(), "id")
+                .and_then(|id| self.get_state(&id))
+                .map_or("unknown", DownloadState::get_name);
+            return Some(
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::CACHE_CONTROL, "no-store")
+                    .body(Body::from(
+                        serde_json::json!({ "state": state }).to_string(),
+                    ))
+                    .unwrap(),
+            );
+        }
+        let target = match path.strip_prefix(KIOSK_PATH) {
+            Some(target) if target.starts_with('/') => target,
+            Some("") => "/",
+            _ => return None,
+        };
+        Some(
+            Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(Body::from(self.create_page(target)))
+                .unwrap(),
+        )
+    }
+
+    fn create_page(&self, target: &str) -> String {
+        // JSON is valid JavaScript, as long as it can't close the script element.
+        let path = serde_json::to_string(target).unwrap().replace("</", "<\\/");
+        let script = SCRIPT.replacen(
+            "<script>\n",
+            &format!(
+                "<script>\nconst path = {};\nlet count = {};\n",
+                path, self.countdown
+            ),
+            1,
+        );
+        html::create_page("Your download", &script)
+    }
+}
+
+/// A response body noting in the kiosk when it has been sent completely
+struct TrackedBody {
+    body: Body,
+    kiosk: Arc<Kiosk>,
+    id: String,
+    /// Bytes left of a body with a Content-Length, hyper stops reading it after the last one
+    remaining: Option<u64>,
+    finished: bool,
+}
+
+impl TrackedBody {
+    fn finish(&mut self, state: DownloadState) {
+        if !self.finished {
+            self.finished = true;
+            self.kiosk.set_state(&self.id, state);
+        }
+    }
+}
+
+impl Stream for TrackedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.body).poll_next(cx);
+        match &result {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(remaining) = &mut this.remaining {
+                    *remaining = remaining.saturating_sub(chunk.len() as u64);
+                    if *remaining == 0 {
+                        this.finish(DownloadState::Done);
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.finish(DownloadState::Failed),
+            Poll::Ready(None) => this.finish(DownloadState::Done),
+            Poll::Pending => {}
+        }
+        result
+    }
+}
+
+impl Drop for TrackedBody {
+    // The client went away before the end.
+    fn drop(&mut self) {
+        self.finish(DownloadState::Failed);
+    }
+}
+
+/// Tracks the download `id` started by a kiosk page until `response` has been sent.
+pub fn track(kiosk: &Arc<Kiosk>, id: String, response: Response<Body>) -> Response<Body> {
+    if !response.status().is_success() {
+        kiosk.set_state(&id, DownloadState::Failed);
+        return response;
+    }
+    kiosk.set_state(&id, DownloadState::Running);
+    let remaining = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok()?.parse().ok());
+    let (parts, body) = response.into_parts();
+    let body = Body::wrap_stream(TrackedBody {
+        body,
+        kiosk: kiosk.clone(),
+        id,
+        remaining,
+        finished: false,
+    });
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_kiosk_url_keeps_path(path in "(/[a-zA-Z0-9_-]{1,8}){0,3}/?") {
+            let kiosk = Kiosk::new(5);
+            let url = kiosk.get_kiosk_url("http://10.0.0.1:8080", &format!("http://10.0.0.1:8080{}", path));
+            let expected = if path.is_empty() { "/" } else { path.as_str() };
+            prop_assert_eq!(format!("http://10.0.0.1:8080{}{}", KIOSK_PATH, expected), url);
+        }
+    }
+
+    #[test]
+    fn test_download_id() {
+        assert_eq!(
+            Some(String::from("a1b2")),
+            get_download_id(Some("x=1&kiosk=a1b2"))
+        );
+        assert_eq!(None, get_download_id(Some("kiosk=")));
+        assert_eq!(None, get_download_id(Some("kiosk=%3Cscript%3E")));
+        assert_eq!(None, get_download_id(None));
+    }
+
+    #[test]
+    fn test_page_leads_to_download() {
+        let kiosk = Kiosk::new(3);
+        let req = Request::get("/.kiosk/tok/").body(Body::empty()).unwrap();
+        assert!(kiosk.handle_request(&req).is_some());
+        let page = kiosk.create_page("/tok/");
+        assert!(page.contains("const path = \"/tok/\";\nlet count = 3;"));
+        let req = Request::get("/.kiosky").body(Body::empty()).unwrap();
+        assert!(kiosk.handle_request(&req).is_none());
+    }
+
+    async fn get_state(kiosk: &Kiosk, id: &str) -> String {
+        let req = Request::get(format!("{}?id={}", STATUS_PATH, id))
+            .body(Body::empty())
+            .unwrap();
+        let response = kiosk.handle_request(&req).unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let state: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        state["state"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_finished_downloads_are_done() {
+        let kiosk = Arc::new(Kiosk::new(5));
+        assert_eq!("unknown", get_state(&kiosk, "abc").await);
+        let response = track(
+            &kiosk,
+            String::from("abc"),
+            Response::new(Body::from("data")),
+        );
+        assert_eq!("running", get_state(&kiosk, "abc").await);
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!("done", get_state(&kiosk, "abc").await);
+
+        let response = track(
+            &kiosk,
+            String::from("def"),
+            Response::new(Body::from("data")),
+        );
+        drop(response);
+        assert_eq!("failed", get_state(&kiosk, "def").await);
+
+        // hyper stops at the Content-Length without reading the end of the body.
+        let response = Response::builder()
+            .header(header::CONTENT_LENGTH, 4)
+            .body(Body::from("data"))
+            .unwrap();
+        let mut body = track(&kiosk, String::from("ghi"), response).into_body();
+        body.next().await.unwrap().unwrap();
+        drop(body);
+        assert_eq!("done", get_state(&kiosk, "ghi").await);
+    }
+}
@@ -0,0 +1,218 @@
+//! A landing page that works over IPv4 and IPv6 alike
+//!
+//! With `--both-families` the server also listens on an address of the other IP family, and the
+//! QR code leads to `/.landing`. The page tries every address at once and continues to the first
+//! one that answers, so one QR code works for phones that only reach one of the two families.
+//!
+//! With `--deep-link` the page first hands a `rustbelt://host:port/path` URI to a companion app,
+//! which can then run a resumable, checksummed transfer of its own. Without the app the page stays
+//! visible and continues in the browser after a moment, so plain camera apps keep working.
+
+use crate::html;
+use crate::paths;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+
+pub const LANDING_PATH: &str = "/.landing";
+/// Answered by every address, for the landing page to find out which ones are reachable
+pub const PING_PATH: &str = "/.ping";
+pub const APP_SCHEME: &str = "rustbelt://";
+/// Milliseconds the page waits for the app to take over before continuing in the browser
+const APP_TIMEOUT: u32 = 1500;
+
+#[derive(Debug)]
+pub struct Landing {
+    /// Base URLs of all addresses, the chosen one first
+    urls: Vec<String>,
+    /// Try to open the companion app first, with `--deep-link`
+    deep_link: bool,
+}
+
+/// Turns the HTTP URL of a share into the URI the companion app is registered for.
+pub fn get_app_url(url: &str) -> String {
+    format!("{}{}", APP_SCHEME, url.trim_start_matches("http://"))
+}
+
+impl Landing {
+    pub fn new(urls: Vec<String>) -> Landing {
+        Landing {
+            urls,
+            deep_link: false,
+        }
+    }
+
+    pub fn with_deep_link(mut self) -> Landing {
+        self.deep_link = true;
+        self
+    }
+
+    pub fn has_deep_link(&self) -> bool {
+        self.deep_link
+    }
+
+    /// The URL of the landing page leading to `url`, which has to be below one of the addresses.
+    pub fn get_landing_url(&self, url: &str) -> String {
+        let path = self
+            .urls
+            .iter()
+            .find_map(|base| url.strip_prefix(base.as_str()))
+            .filter(|p| !p.is_empty())
+            .unwrap_or("/");
+        format!(
+            "{}{}?to={}",
+            self.urls[0],
+            LANDING_PATH,
+            paths::percent_encode(path)
+        )
+    }
+
+    /// Answers requests for the landing page and pings, and leaves all others to the mode.
+    pub fn handle_request(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if req.method() != Method::GET {
+            return None;
+        }
+        match req.uri().path() {
+            PING_PATH => Some(
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                    .header(header::CACHE_CONTROL, "no-store")
+                    .body(Body::empty())
+                    .unwrap(),
+            ),
+            LANDING_PATH => {
+                let path = get_target_path(req.uri().query());
+                Some(
+                    Response::builder()
+                        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                        .header(header::CACHE_CONTROL, "no-store")
+                        .body(Body::from(self.create_page(&path)))
+                        .unwrap(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    fn create_page(&self, path: &str) -> String {
+        let mut links = String::new();
+        for url in &self.urls {
+            links.push_str(&format!(
+                "<li><a href=\"{0}\">{0}</a></li>\n",
+                html::escape(&format!("{}{}", url, path))
+            ));
+        }
+        // JSON is valid JavaScript, as long as it can't close the script element.
+        let bases = serde_json::to_string(&self.urls)
+            .unwrap()
+            .replace("</", "<\\/");
+        let path_json = serde_json::to_string(path).unwrap().replace("</", "<\\/");
+        // The page stays visible if no app took over.
+        let start = if self.deep_link {
+            let app_url = get_app_url(&format!("{}{}", self.urls[0], path));
+            links.push_str(&format!(
+                "<li><a href=\"{0}\">{0}</a></li>\n",
+                html::escape(&app_url)
+            ));
+            format!(
+                "location.href = {};\n\
+                 setTimeout(() => document.hidden || connect(), {});\n",
+                serde_json::to_string(&app_url)
+                    .unwrap()
+                    .replace("</", "<\\/"),
+                APP_TIMEOUT
+            )
+        } else {
+            String::from("connect();\n")
+        };
+        html::create_page(
+            "Connecting",
+            &format!(
+                "<p id=\"status\">Trying all addresses of the server…</p>\n<ul>\n{}</ul>\n\
+                 <script>\n\
+                 const bases = {};\n\
+                 const path = {};\n\
+                 const probe = base => fetch(base + \"{}\", {{mode: \"no-cors\", cache: \
+                 \"no-store\"}}).then(() => base);\n\
+                 const connect = () => Promise.any(bases.map(probe))\n\
+                 \x20 .then(base => location.replace(base + path))\n\
+                 \x20 .catch(() => document.getElementById(\"status\").textContent = \
+                 \"None of the addresses answered, try the links below.\");\n\
+                 {}</script>\n",
+                links, bases, path_json, PING_PATH, start
+            ),
+        )
+    }
+}
+
+/// The path the landing page leads to, from its `to` parameter. Only absolute paths on the same
+/// server are accepted.
+fn get_target_path(query: Option<&str>) -> String {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|p| p.strip_prefix("to="))
+        .and_then(paths::percent_decode)
+        .filter(|p| p.starts_with('/') && !p.starts_with("//"))
+        .unwrap_or_else(|| String::from("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn create_landing() -> Landing {
+        Landing::new(vec![
+            String::from("http://192.168.1.2:8080"),
+            String::from("http://[2001:db8::2]:8080"),
+        ])
+    }
+
+    proptest! {
+        #[test]
+        fn test_landing_url_roundtrip(path in "(/[a-zA-Z0-9 _&?=-]{1,8}){1,3}/?") {
+            let landing = create_landing();
+            let url = landing.get_landing_url(&format!("http://[2001:db8::2]:8080{}", path));
+            let query = url.split_once('?').unwrap().1;
+            prop_assert_eq!(path, get_target_path(Some(query)));
+        }
+    }
+
+    #[test]
+    fn test_landing_url() {
+        let landing = create_landing();
+        assert_eq!(
+            "http://192.168.1.2:8080/.landing?to=%2F",
+            landing.get_landing_url("http://192.168.1.2:8080")
+        );
+        assert_eq!(
+            "http://192.168.1.2:8080/.landing?to=%2Fabc%2F",
+            landing.get_landing_url("http://192.168.1.2:8080/abc/")
+        );
+    }
+
+    #[test]
+    fn test_target_path_stays_on_server() {
+        assert_eq!("/", get_target_path(Some("to=%2F%2Fevil.example")));
+        assert_eq!("/", get_target_path(Some("to=http%3A%2F%2Fevil.example")));
+        assert_eq!("/", get_target_path(None));
+        assert_eq!("/a/", get_target_path(Some("x=1&to=%2Fa%2F")));
+    }
+
+    #[test]
+    fn test_page_embeds_addresses() {
+        let page = create_landing().create_page("/</script>/");
+        assert!(page.contains("\"http://[2001:db8::2]:8080\""));
+        assert!(page.contains("\"/<\\/script>/\""));
+        assert!(page.contains("<a href=\"http://192.168.1.2:8080/&lt;/script&gt;/\">"));
+        assert!(!page.contains(APP_SCHEME));
+    }
+
+    #[test]
+    fn test_page_tries_app_first() {
+        let landing = Landing::new(vec![String::from("http://192.168.1.2:8080")]).with_deep_link();
+        let page = landing.create_page("/x7Kq/");
+        assert!(page.contains("location.href = \"rustbelt://192.168.1.2:8080/x7Kq/\";"));
+        assert!(page.contains("<a href=\"rustbelt://192.168.1.2:8080/x7Kq/\">"));
+    }
+}
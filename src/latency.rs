@@ -0,0 +1,209 @@
+//! Round-trip time and packet loss of the connections of clients
+//!
+//! When a transfer is slow, the operator wants to know whether the network or the disk is to
+//! blame. The kernel keeps track of the round-trip time of every TCP connection and of the
+//! segments it had to send again, and hands both out with the `TCP_INFO` socket option. A
+//! connection reads them at most every `SAMPLE_INTERVAL` while it is used, and the `clients`
+//! command shows the last reading of each client. A high round-trip time or a large share of
+//! resent segments points at the network, a transfer slow with neither at the disk.
+//!
+//! Loss is only seen on the way to the client, the segments lost on their way in are resent by
+//! the client and only noticed there.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time between two readings of a connection
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The start of `struct tcp_info` of linux/tcp.h, up to the segment counters of Linux 4.2. Older
+/// kernels fill in less of it, which `read_path_stats` tells by the length they return.
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct TcpInfo {
+    state: u8,
+    ca_state: u8,
+    retransmits: u8,
+    probes: u8,
+    backoff: u8,
+    options: u8,
+    wscale: u8,
+    app_limited: u8,
+    rto: u32,
+    ato: u32,
+    snd_mss: u32,
+    rcv_mss: u32,
+    unacked: u32,
+    sacked: u32,
+    lost: u32,
+    retrans: u32,
+    fackets: u32,
+    last_data_sent: u32,
+    last_ack_sent: u32,
+    last_data_recv: u32,
+    last_ack_recv: u32,
+    pmtu: u32,
+    rcv_ssthresh: u32,
+    rtt: u32,
+    rttvar: u32,
+    snd_ssthresh: u32,
+    snd_cwnd: u32,
+    advmss: u32,
+    reordering: u32,
+    rcv_rtt: u32,
+    rcv_space: u32,
+    total_retrans: u32,
+    pacing_rate: u64,
+    max_pacing_rate: u64,
+    bytes_acked: u64,
+    bytes_received: u64,
+    segs_out: u32,
+    segs_in: u32,
+}
+
+/// What the kernel knows about the path to a client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathStats {
+    /// Smoothed round-trip time
+    pub rtt: Duration,
+    /// Mean deviation of the round-trip time, the jitter
+    pub rtt_variation: Duration,
+    /// Segments sent again since the connection was opened
+    pub resent: u32,
+    /// Segments sent since the connection was opened, if the kernel counts them
+    pub sent: Option<u32>,
+}
+
+impl PathStats {
+    /// Share of the sent segments that had to be sent again, from 0 to 1
+    pub fn get_loss(&self) -> Option<f64> {
+        self.sent
+            .filter(|sent| *sent > 0)
+            .map(|sent| f64::from(self.resent.min(sent)) / f64::from(sent))
+    }
+}
+
+impl fmt::Display for PathStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} ms ± {:.1} ms",
+            self.rtt.as_secs_f64() * 1000.0,
+            self.rtt_variation.as_secs_f64() * 1000.0
+        )?;
+        match self.get_loss() {
+            Some(loss) => write!(f, ", {:.1}% resent", loss * 100.0),
+            None => write!(f, ", {} segments resent", self.resent),
+        }
+    }
+}
+
+/// Reads the round-trip time and loss of the TCP socket `fd`.
+pub fn read_path_stats(fd: RawFd) -> io::Result<PathStats> {
+    let mut info = TcpInfo::default();
+    let mut length = mem::size_of::<TcpInfo>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut TcpInfo as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let counts_segments = length as usize >= mem::size_of::<TcpInfo>();
+    Ok(PathStats {
+        rtt: Duration::from_micros(info.rtt.into()),
+        rtt_variation: Duration::from_micros(info.rttvar.into()),
+        resent: info.total_retrans,
+        sent: if counts_segments {
+            Some(info.segs_out)
+        } else {
+            None
+        },
+    })
+}
+
+/// The last reading of a connection, shared with the list of clients
+#[derive(Debug, Default)]
+pub struct Probe {
+    latest: Mutex<Option<(Instant, PathStats)>>,
+}
+
+impl Probe {
+    /// Reads the socket `fd` unless it was read less than `SAMPLE_INTERVAL` ago.
+    pub fn sample(&self, fd: RawFd) {
+        let mut latest = self.latest.lock().unwrap();
+        if latest.is_some_and(|(time, _)| time.elapsed() < SAMPLE_INTERVAL) {
+            return;
+        }
+        if let Ok(stats) = read_path_stats(fd) {
+            *latest = Some((Instant::now(), stats));
+        }
+    }
+
+    pub fn get(&self) -> Option<PathStats> {
+        self.latest.lock().unwrap().map(|(_, stats)| stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+
+    proptest! {
+        #[test]
+        fn test_loss_is_a_share(resent in 0u32..1000, sent in 0u32..1000) {
+            let stats = PathStats {
+                rtt: Duration::from_millis(5),
+                rtt_variation: Duration::from_millis(1),
+                resent,
+                sent: Some(sent),
+            };
+            if let Some(loss) = stats.get_loss() {
+                prop_assert!((0.0..=1.0).contains(&loss));
+            }
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let stats = PathStats {
+            rtt: Duration::from_micros(12_340),
+            rtt_variation: Duration::from_micros(2_500),
+            resent: 3,
+            sent: Some(200),
+        };
+        assert_eq!("12.3 ms ± 2.5 ms, 1.5% resent", stats.to_string());
+        let stats = PathStats {
+            sent: None,
+            ..stats
+        };
+        assert_eq!("12.3 ms ± 2.5 ms, 3 segments resent", stats.to_string());
+    }
+
+    #[test]
+    fn test_sample_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let probe = Probe::default();
+        assert_eq!(None, probe.get());
+        probe.sample(server.as_raw_fd());
+        let stats = probe.get().unwrap();
+        assert_eq!(0, stats.resent);
+        assert!(stats.rtt < Duration::from_secs(1));
+        assert!(read_path_stats(-1).is_err());
+    }
+}
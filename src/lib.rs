@@ -1,14 +1,100 @@
-use colored::Colorize;
+use bytes::Bytes;
+use futures::future;
+use futures::stream::StreamExt;
+use hyper::server::accept;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
-use pnet::datalink;
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
 use qrcode::QrCode;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::error;
 use std::fmt;
+use std::fs;
 use std::io;
+use std::io::{IsTerminal, Write};
 use std::net;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+mod access;
+mod archive;
+mod beacon;
+mod broadcast;
+mod bundle;
+mod cache;
+mod cas;
+mod chat;
+mod check;
+mod clients;
+mod clipboard;
+mod console;
+mod crypto;
+mod device;
+mod dropbox;
+pub mod embed;
+mod eta;
+mod exchange;
+mod exit;
+mod firewall;
+mod get;
+mod help;
+mod hostnames;
+mod html;
+mod interfaces;
+mod kiosk;
+mod landing;
+mod latency;
+mod limits;
+mod live;
+mod manifest;
+mod methods;
+mod metrics;
+mod mirrors;
+mod mounts;
+mod neighbors;
+mod network;
+mod notify;
+mod output;
+mod paths;
+mod pieces;
+mod punch;
+mod qr;
+mod receive;
+mod record;
+mod relay;
+mod remote;
+mod resume;
+mod robots;
+mod scan;
+mod schedule;
+mod screen;
+mod selection;
+mod sheet;
+mod short;
+mod site;
+mod sizes;
+mod snippet;
+mod source;
+mod space;
+mod speedtest;
+mod state;
+mod storage;
+mod suspend;
+mod sync;
+mod timeouts;
+mod tokens;
+mod transfer;
+mod trash;
+mod wifi;
+mod wsd;
+
+/// Subdirectory of the receive destination used when `--on-receive` is given without `--quarantine`
+const DEFAULT_QUARANTINE: &str = "quarantine";
+/// How the summary labels the directory uploads go to
+const RECEIVING_LABEL: &str = "Receiving into";
 
 #[derive(Debug)]
 struct ChoiceError<T> {
@@ -60,215 +146,3085 @@ impl NetworkInterfaceExistanceError {
     }
 }
 
-enum IpString {
-    V4(String),
-    V6(String),
+#[derive(Debug)]
+struct BindError {
+    socket: net::SocketAddr,
+    error: io::Error,
+}
+
+impl error::Error for BindError {}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not listen on {}: {}", self.socket, self.error)
+    }
+}
+
+impl BindError {
+    fn new(socket: net::SocketAddr, error: io::Error) -> BindError {
+        BindError { socket, error }
+    }
+}
+
+/// Why the server shut down by itself before anyone stopped it
+#[derive(Debug)]
+enum ShareEndedError {
+    Expired,
+    LinksUsed,
+}
+
+impl error::Error for ShareEndedError {}
+
+impl fmt::Display for ShareEndedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShareEndedError::Expired => write!(f, "The share expired"),
+            ShareEndedError::LinksUsed => write!(f, "All one-time links have been used"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct UserAbortError;
+
+impl error::Error for UserAbortError {}
+
+impl fmt::Display for UserAbortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Nothing was shared")
+    }
+}
+
+#[derive(Debug)]
+struct MoveDirectoryError {
+    path: PathBuf,
+}
+
+impl error::Error for MoveDirectoryError {}
+
+impl fmt::Display for MoveDirectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Only single files can be moved, but this is a directory: {}",
+            self.path.display()
+        )
+    }
+}
+
+impl MoveDirectoryError {
+    fn new(path: PathBuf) -> MoveDirectoryError {
+        MoveDirectoryError { path }
+    }
+}
+
+#[derive(Debug)]
+struct DirectoryShareError {
+    path: PathBuf,
+}
+
+impl error::Error for DirectoryShareError {}
+
+impl fmt::Display for DirectoryShareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Only single files can be shared, but this is a directory: {}",
+            self.path.display()
+        )
+    }
+}
+
+impl DirectoryShareError {
+    fn new(path: PathBuf) -> DirectoryShareError {
+        DirectoryShareError { path }
+    }
+}
+
+#[derive(Debug)]
+struct UnsupportedArchiveError {
+    path: PathBuf,
+}
+
+impl error::Error for UnsupportedArchiveError {}
+
+impl fmt::Display for UnsupportedArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Not a .zip, .tar.gz or .tgz archive: {}",
+            self.path.display()
+        )
+    }
+}
+
+impl UnsupportedArchiveError {
+    fn new(path: PathBuf) -> UnsupportedArchiveError {
+        UnsupportedArchiveError { path }
+    }
+}
+
+#[derive(Debug)]
+struct MissingIndexError {
+    path: PathBuf,
+}
+
+impl error::Error for MissingIndexError {}
+
+impl fmt::Display for MissingIndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Not a directory containing an index.html: {}",
+            self.path.display()
+        )
+    }
+}
+
+impl MissingIndexError {
+    fn new(path: PathBuf) -> MissingIndexError {
+        MissingIndexError { path }
+    }
+}
+
+#[derive(Debug)]
+struct TooLargeError {
+    value: String,
+    unit: &'static str,
+}
+
+impl error::Error for TooLargeError {}
+
+impl fmt::Display for TooLargeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} is too large", self.value, self.unit)
+    }
+}
+
+impl TooLargeError {
+    fn new(value: &str, unit: &'static str) -> TooLargeError {
+        TooLargeError {
+            value: value.to_string(),
+            unit,
+        }
+    }
+}
+
+enum IpString {
+    V4(String),
+    V6(String),
+}
+
+/// Returns the process exit code for an error `run_rustbelt` returned, see the `exit` module.
+pub fn get_exit_code(error: &(dyn error::Error + 'static)) -> i32 {
+    exit::get_exit_code(error)
+}
+
+pub fn get_network_interfaces() -> HashMap<String, interfaces::NetworkInterface> {
+    find_interfaces(&network::SystemNetwork)
+}
+
+/// The interfaces of `network` that have an address, by their name
+fn find_interfaces(
+    network: &dyn network::Network,
+) -> HashMap<String, interfaces::NetworkInterface> {
+    let mut interface_map = HashMap::<String, interfaces::NetworkInterface>::new();
+    for interface in network.interfaces() {
+        if !interface.ips.is_empty() {
+            interface_map.insert(String::from(&interface.name), interface);
+        }
+    }
+    interface_map
+}
+
+fn create_qr_code(data: String) -> String {
+    render_qr_code(&QrCode::new(data).unwrap())
+}
+
+/// Renders `code` for the terminal, two characters per module to keep it square.
+fn render_qr_code(code: &QrCode) -> String {
+    draw_qr_code(code, output::is_ascii_qr())
+}
+
+fn draw_qr_code(code: &QrCode, ascii: bool) -> String {
+    code.render()
+        .light_color(" ")
+        .dark_color(if ascii { "#" } else { "█" })
+        .module_dimensions(2, 1)
+        .build()
+}
+
+fn print_qr_code(url: &str) {
+    eprintln!(
+        "{}",
+        output::paint_qr_code(&create_qr_code(url.to_string()))
+    );
+}
+
+fn select_item(
+    choice: String,
+    choices: &[String],
+) -> Result<(usize, String), Box<dyn error::Error>> {
+    let choice_num = match choice.trim().parse::<usize>() {
+        Ok(n) => n,
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    if choice_num >= choices.len() {
+        Err(Box::new(ChoiceError::new(0, choices.len() - 1)))
+    } else {
+        Ok((choice_num, choices[choice_num].clone()))
+    }
+}
+
+/// Lets the user pick one of `choices` by number. If there is a `default`, an empty answer picks
+/// it.
+fn choose_number(
+    message: String,
+    choices: Vec<String>,
+    default: Option<usize>,
+) -> Result<(usize, String), Box<dyn std::error::Error>> {
+    eprintln!("{}", message);
+    for (index, choice) in choices.iter().enumerate() {
+        if Some(index) == default {
+            eprintln!("{} - {} (last used, press Enter)", index, choice);
+        } else {
+            eprintln!("{} - {}", index, choice);
+        }
+    }
+    let mut choice_num_str = String::new();
+    io::stdin().read_line(&mut choice_num_str).unwrap();
+
+    match default {
+        Some(index) if choice_num_str.trim().is_empty() => Ok((index, choices[index].clone())),
+        _ => select_item(choice_num_str, &choices),
+    }
+}
+
+fn choose_ip(
+    message: String,
+    choices: Vec<IpString>,
+    remembered: Option<&str>,
+) -> Result<(usize, IpString), Box<dyn error::Error>> {
+    let choice_strings = choices
+        .iter()
+        .map(|ip| match ip {
+            IpString::V4(s) => s.clone(),
+            IpString::V6(s) => s.clone(),
+        })
+        .collect::<Vec<_>>();
+    let default = selection::find_default(&choice_strings, remembered);
+    let (interface_num, ip_string) = choose_number(message, choice_strings, default)?;
+    Ok((
+        interface_num,
+        match choices[interface_num] {
+            IpString::V4(_) => IpString::V4(ip_string),
+            IpString::V6(_) => IpString::V6(ip_string),
+        },
+    ))
+}
+
+/// What the HTTP server is doing: offering a file, accepting uploads, syncing a directory,
+/// streaming live output, previewing a website or relaying transfers
+#[derive(Clone)]
+enum Mode {
+    Send(Arc<Share>),
+    Receive(Arc<receive::Inbox>),
+    Sync(Arc<sync::SyncRoot>),
+    Archive(Arc<archive::ArchiveShare>),
+    Live(Arc<live::LiveOutput>),
+    Site(Arc<site::Site>),
+    Mounts(Arc<mounts::MountTable>),
+    Relay(Arc<relay::Relay>),
+    /// Sending a file while receiving uploads, with `--exchange`
+    Exchange(Arc<Share>, Arc<receive::Inbox>),
+    /// Streaming a file from an HTTP origin or S3 through
+    Remote(Arc<remote::RemoteShare>),
+    /// Streaming from a FIFO or character device
+    Device(Arc<device::DeviceStream>),
+    /// Receiving uploads through the link of every sender, with `rustbelt inbox`
+    DropBox(Arc<dropbox::DropBox>),
+    /// Sharing text given on the command line or stdin, with `--text`
+    Text(Arc<snippet::Snippet>),
+    /// Sharing files and texts behind one link, with `rustbelt session`
+    Bundle(Arc<bundle::Bundle>),
+    /// Serving files at URLs named after their content, with `--cas`
+    Cas(Arc<cas::ContentStore>),
+    /// Showing the screen to browsers, with `--screen`
+    Screen(Arc<screen::ScreenStream>),
+}
+
+/// Uploads to a synced directory or a mount, which have no upload page
+const WRITE_METHODS: [Method; 4] = [Method::GET, Method::HEAD, Method::PUT, Method::OPTIONS];
+/// A relay has no HEAD, answering it like GET would pair the request with a waiting sender.
+const RELAY_METHODS: [Method; 4] = [Method::GET, Method::PUT, Method::POST, Method::OPTIONS];
+/// A device has no HEAD, its data can only be read once.
+const DEVICE_METHODS: [Method; 2] = [Method::GET, Method::OPTIONS];
+/// A file moved away after the transfer also takes the confirmation of the recipient.
+const CONFIRM_METHODS: [Method; 4] = [Method::GET, Method::HEAD, Method::POST, Method::OPTIONS];
+/// Where the recipient confirms a download with the SHA-256 of the file, see `Share::confirm`
+pub const CONFIRM_PATH: &str = "/confirm";
+
+impl Mode {
+    fn get_name(&self) -> &'static str {
+        match self {
+            Mode::Send(_) => "send",
+            Mode::Receive(_) => "receive",
+            Mode::Sync(_) => "sync",
+            Mode::Archive(_) => "archive",
+            Mode::Live(_) => "live output",
+            Mode::Site(_) => "website",
+            Mode::Mounts(_) => "mount",
+            Mode::Relay(_) => "relay",
+            Mode::Exchange(_, _) => "exchange",
+            Mode::Remote(_) => "remote",
+            Mode::Device(_) => "stream",
+            Mode::DropBox(_) => "inbox",
+            Mode::Text(_) => "text",
+            Mode::Bundle(_) => "session",
+            Mode::Cas(_) => "content-addressed",
+            Mode::Screen(_) => "screen",
+        }
+    }
+
+    /// The local files and directories the mode makes available, with what happens to them
+    fn get_paths(&self) -> Vec<(&'static str, PathBuf)> {
+        match self {
+            Mode::Send(share) => vec![("Sharing", share.path.clone())],
+            Mode::Receive(inbox) if inbox.describe_storage().is_some() => Vec::new(),
+            Mode::Receive(inbox) => vec![(RECEIVING_LABEL, inbox.get_root().to_path_buf())],
+            Mode::Sync(sync_root) => vec![("Syncing", sync_root.get_root().to_path_buf())],
+            Mode::Archive(archive) => vec![("Sharing", archive.get_path().to_path_buf())],
+            Mode::Cas(store) => vec![("Sharing", store.get_root().to_path_buf())],
+            Mode::Site(site) => vec![("Serving", site.get_root().to_path_buf())],
+            Mode::Mounts(table) => table
+                .get_mounts()
+                .into_iter()
+                .map(|(_, root)| ("Sharing", root))
+                .collect(),
+            Mode::Exchange(share, inbox) => vec![
+                ("Sharing", share.path.clone()),
+                (RECEIVING_LABEL, inbox.get_root().to_path_buf()),
+            ],
+            Mode::Device(stream) => vec![("Streaming", stream.get_path().to_path_buf())],
+            Mode::DropBox(dropbox) => vec![(RECEIVING_LABEL, dropbox.get_root().to_path_buf())],
+            Mode::Bundle(bundle) => bundle
+                .get_items()
+                .iter()
+                .filter_map(|item| Some(("Sharing", item.get_path()?.clone())))
+                .collect(),
+            Mode::Live(_) | Mode::Relay(_) | Mode::Remote(_) | Mode::Text(_) | Mode::Screen(_) => {
+                Vec::new()
+            }
+        }
+    }
+
+    /// Cache-Control policy of responses that don't set their own
+    fn get_cache_policy(&self) -> cache::CachePolicy {
+        match self {
+            Mode::Site(_) => cache::CachePolicy::Revalidate,
+            _ => cache::CachePolicy::NoStore,
+        }
+    }
+
+    /// The methods clients may use, announced in answers to OPTIONS requests
+    fn get_allowed_methods(&self) -> &'static [Method] {
+        match self {
+            Mode::Receive(_) | Mode::DropBox(_) => methods::AccessMode::Receive.get_methods(),
+            Mode::Exchange(_, _) => methods::AccessMode::Exchange.get_methods(),
+            Mode::Sync(_) | Mode::Mounts(_) => &WRITE_METHODS,
+            Mode::Relay(_) => &RELAY_METHODS,
+            Mode::Device(_) | Mode::Screen(_) => &DEVICE_METHODS,
+            Mode::Send(share) if share.confirm => &CONFIRM_METHODS,
+            _ => methods::AccessMode::Send.get_methods(),
+        }
+    }
+
+    /// The requests the mode answers, listed at `/help` from the routes its handler looks them up
+    /// in. A website, a mount or a file named `help` own the path themselves.
+    fn get_endpoints(&self) -> Option<Vec<help::Endpoint>> {
+        let endpoints = match self {
+            Mode::Send(share) | Mode::Exchange(share, _)
+                if share.links.is_none() && share.file_name == "help" =>
+            {
+                return None
+            }
+            Mode::Send(share) => help::get_endpoints(&get_listed_share_routes(share)),
+            Mode::Receive(inbox) => help::get_endpoints(&receive::get_routes(inbox)),
+            Mode::Exchange(share, inbox) => {
+                help::get_endpoints(&exchange::get_routes(get_listed_share_routes(share), inbox))
+            }
+            Mode::Sync(_) => help::get_endpoints(&sync::get_routes()),
+            Mode::Archive(_) => help::get_endpoints(&archive::get_routes()),
+            Mode::Live(_) => help::get_endpoints(&live::get_routes()),
+            Mode::Site(_) => return None,
+            Mode::Mounts(table) if table.has_help_mount() => return None,
+            Mode::Mounts(table) => help::get_endpoints(&table.get_routes()),
+            Mode::Relay(_) => help::get_endpoints(&relay::get_routes()),
+            Mode::Remote(_) => help::get_endpoints(&remote::get_routes()),
+            Mode::Device(_) => help::get_endpoints(&device::get_routes()),
+            Mode::DropBox(dropbox) => help::get_endpoints(&dropbox.get_routes()),
+            Mode::Text(_) => help::get_endpoints(&snippet::get_routes()),
+            Mode::Cas(_) => help::get_endpoints(&cas::get_routes()),
+            Mode::Bundle(_) => {
+                let routes = bundle::get_routes().into_iter();
+                help::get_endpoints(&routes.map(|r| r.under("/<token>")).collect::<Vec<_>>())
+            }
+            Mode::Screen(_) => help::get_endpoints(&screen::get_routes()),
+        };
+        Some(endpoints)
+    }
+}
+
+/// What a request for a shared file is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ShareRoute {
+    File,
+    Sums,
+    Manifest,
+    Pieces,
+    Signature,
+    Confirm,
+}
+
+/// The requests for a shared file, below the token if it is shared with one-time links
+fn get_share_routes(share: &Share) -> Vec<help::Route<ShareRoute>> {
+    use help::Route as R;
+    let file = match &share.links {
+        Some(_) => String::from("/<name>"),
+        None => format!("/{}", paths::percent_encode(&share.file_name)),
+    };
+    let mut routes = vec![
+        R::new(
+            Method::GET,
+            "/SHA256SUMS",
+            "Checksum of the file in the format of sha256sum",
+            ShareRoute::Sums,
+        ),
+        R::new(
+            Method::GET,
+            manifest::MANIFEST_JSON_PATH,
+            "Name, size, SHA-256 and URL of the file as JSON",
+            ShareRoute::Manifest,
+        ),
+    ];
+    if share.pieces.is_some() {
+        routes.push(R::new(
+            Method::GET,
+            "/pieces",
+            "The pieces the file is split into, with their hashes",
+            ShareRoute::Pieces,
+        ));
+        routes.push(
+            R::new(
+                Method::GET,
+                "/pieces/<index>",
+                "Downloads a single piece",
+                ShareRoute::Pieces,
+            )
+            .below("/pieces"),
+        );
+    }
+    if share.signature.is_some() {
+        routes.push(R::new(
+            Method::GET,
+            "/signature.asc",
+            "Detached signature of the file",
+            ShareRoute::Signature,
+        ));
+    }
+    if share.confirm {
+        routes.push(R::new(
+            Method::POST,
+            CONFIRM_PATH,
+            "Confirms the download with the SHA-256 of the file in the body, after which it is \
+             moved to the trash",
+            ShareRoute::Confirm,
+        ));
+    }
+    // Every other path is the file, whatever name it is saved under.
+    routes.push(
+        R::new(
+            Method::GET,
+            &file,
+            "Downloads the file, Range requests continue an interrupted download",
+            ShareRoute::File,
+        )
+        .any_path(),
+    );
+    routes.push(
+        R::new(
+            Method::HEAD,
+            &file,
+            "Size, ETag and SHA-256 Digest of the file without downloading it",
+            ShareRoute::File,
+        )
+        .any_path(),
+    );
+    routes
+}
+
+/// The routes of a shared file as listed, below the placeholder of a one-time link if it has them
+fn get_listed_share_routes(share: &Share) -> Vec<help::Route<ShareRoute>> {
+    let routes = get_share_routes(share).into_iter();
+    match &share.links {
+        Some(_) => routes.map(|r| r.under("/<token>")).collect(),
+        None => routes.collect(),
+    }
+}
+
+/// Who answers a request every mode takes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ServerRoute {
+    Help,
+    Speedtest,
+    Chat,
+    Wsd,
+    Robots,
+    Options,
+}
+
+/// The routes every mode takes, depending on the options
+fn get_server_routes(options: &ServerOptions) -> Vec<help::Route<ServerRoute>> {
+    use help::Route as R;
+    let mut routes = vec![R::new(
+        Method::GET,
+        help::HELP_PATH,
+        "This list, as JSON with ?format=json or Accept: application/json",
+        ServerRoute::Help,
+    )];
+    if options.speedtest {
+        let speedtest = speedtest::get_routes().into_iter();
+        routes.extend(speedtest.map(|r| r.map(|_| ServerRoute::Speedtest)));
+    }
+    if options.chat {
+        let chat = chat::get_routes().into_iter();
+        routes.extend(chat.map(|r| r.map(|_| ServerRoute::Chat)));
+    }
+    if options.wsd.is_some() {
+        routes.push(R::new(
+            Method::POST,
+            wsd::WSD_PATH,
+            "Describes the share to Windows Explorer over WS-Discovery",
+            ServerRoute::Wsd,
+        ));
+    }
+    if options.noindex {
+        routes.push(R::new(
+            Method::GET,
+            robots::ROBOTS_PATH,
+            "Keeps search engines out",
+            ServerRoute::Robots,
+        ));
+    }
+    routes.push(R::new(
+        Method::OPTIONS,
+        "*",
+        "The allowed methods in the Allow header",
+        ServerRoute::Options,
+    ));
+    routes
+}
+
+/// The file served by the HTTP server and the state of its transfer
+struct Share {
+    path: PathBuf,
+    file_name: String,
+    transferred: AtomicBool,
+    pieces: Option<pieces::PieceManifest>,
+    /// Detached signature of the file, served at `/signature.asc`
+    signature: Option<Vec<u8>>,
+    encryption: Option<crypto::Encryption>,
+    /// ETag and Digest header value of the file's content, once hashed
+    digest: Mutex<Option<(String, String)>>,
+    /// One-time links the file is only served under, if any
+    links: Option<tokens::LinkSet>,
+    /// Progress of every recipient when handing the file to a group
+    broadcast: Option<Arc<broadcast::Broadcast>>,
+    /// Whether the transfer only counts once the recipient confirms the SHA-256 of the file at
+    /// `CONFIRM_PATH`, as the file is moved away afterwards. Handing over the last byte doesn't
+    /// mean it arrived.
+    confirm: bool,
+    /// Whether a download reached the end, which a confirmation has to follow
+    downloaded: AtomicBool,
+}
+
+impl Share {
+    fn new(path: PathBuf) -> Share {
+        let file_name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => String::from("download"),
+        };
+        Share {
+            path,
+            file_name,
+            transferred: AtomicBool::new(false),
+            pieces: None,
+            signature: None,
+            encryption: None,
+            digest: Mutex::new(None),
+            links: None,
+            broadcast: None,
+            confirm: false,
+            downloaded: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the cached Digest header value if it belongs to the file version `etag`.
+    fn get_cached_digest(&self, etag: &str) -> Option<String> {
+        match &*self.digest.lock().unwrap() {
+            Some((e, digest)) if e == etag => Some(digest.clone()),
+            _ => None,
+        }
+    }
+
+    /// The path the share is reached at by a request for `path`, below the token of its link
+    fn get_root_path(&self, path: &str) -> String {
+        match self.links.as_ref().and_then(|links| links.find(path)) {
+            Some((index, _)) => format!(
+                "/{}/",
+                self.links.as_ref().unwrap().get_links()[index].token.value
+            ),
+            None => String::from("/"),
+        }
+    }
+
+    /// Hashes the file unless its current version has already been hashed.
+    async fn update_digest(&self) -> io::Result<()> {
+        let etag = create_etag(&tokio::fs::metadata(&self.path).await?);
+        if self.get_cached_digest(&etag).is_some() {
+            return Ok(());
+        }
+        let path = self.path.clone();
+        let hash = tokio::task::spawn_blocking(move || manifest::hash_file(&path))
+            .await
+            .map_err(io::Error::other)??;
+        *self.digest.lock().unwrap() = Some((etag, create_digest(&hash)));
+        Ok(())
+    }
+}
+
+/// Derives an ETag from size and modification time, so it changes whenever the file does.
+fn create_etag(metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!(
+        "\"{:x}-{:x}.{:x}\"",
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    )
+}
+
+/// Formats a hex encoded SHA-256 hash as value of an RFC 3230 Digest header.
+fn create_digest(sha256: &str) -> String {
+    let bytes = (0..sha256.len() / 2)
+        .filter_map(|i| u8::from_str_radix(&sha256[2 * i..2 * i + 2], 16).ok())
+        .collect::<Vec<_>>();
+    format!("sha-256={}", base64::encode(&bytes))
+}
+
+fn create_status_response(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+fn quote_file_name(file_name: &str) -> String {
+    file_name.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn create_content_disposition(file_name: &str) -> String {
+    format!("attachment; filename=\"{}\"", quote_file_name(file_name))
+}
+
+/// Content-Disposition of content browsers should show rather than download
+fn create_inline_disposition(file_name: &str) -> String {
+    format!("inline; filename=\"{}\"", quote_file_name(file_name))
+}
+
+/// Answers with generated text, in ranges like any other content.
+async fn serve_text(
+    name: &str,
+    text: String,
+    content_type: &str,
+    req: &Request<Body>,
+) -> Response<Body> {
+    let source = source::MemorySource::new(name.to_string(), Bytes::from(text), Some(content_type));
+    source::serve(Arc::new(source), req).await
+}
+
+/// Answers with a `SHA256SUMS` listing of the entries returned by `create_entries`, which is run
+/// in a blocking task as it hashes files.
+async fn serve_sha256sums<F>(create_entries: F, req: &Request<Body>) -> Response<Body>
+where
+    F: FnOnce() -> io::Result<Vec<manifest::Entry>> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(create_entries).await {
+        Ok(Ok(entries)) => {
+            let sums = manifest::create_sha256sums(&entries);
+            serve_text("SHA256SUMS", sums, "text/plain; charset=utf-8", req).await
+        }
+        _ => create_status_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not hash files"),
+    }
+}
+
+/// Answers with `/manifest.json` listing the entries returned by `create_entries` together with
+/// their URL paths. It is run in a blocking task as it hashes files.
+async fn serve_manifest_json<F>(create_entries: F, req: &Request<Body>) -> Response<Body>
+where
+    F: FnOnce() -> io::Result<Vec<(manifest::Entry, String)>> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(create_entries).await {
+        Ok(Ok(entries)) => {
+            let json = manifest::create_json(&entries);
+            serve_text("manifest.json", json, "application/json", req).await
+        }
+        _ => create_status_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not hash files"),
+    }
+}
+
+/// Completes the transfer if the SHA-256 in the body of `req` is the one of the shared file.
+async fn confirm_download(
+    share: &Share,
+    completed: &mpsc::UnboundedSender<()>,
+    req: Request<Body>,
+) -> Response<Body> {
+    if !share.downloaded.load(Ordering::SeqCst) {
+        return create_status_response(
+            StatusCode::CONFLICT,
+            "The file has not been downloaded completely yet",
+        );
+    }
+    let sha256 = match transfer::read_limited(req.into_body(), 1024).await {
+        Ok(body) => String::from_utf8_lossy(&body).trim().to_lowercase(),
+        Err(_) => return create_status_response(StatusCode::BAD_REQUEST, "Invalid checksum"),
+    };
+    if let Err(e) = share.update_digest().await {
+        eprintln!("Could not hash {}: {}", share.path.display(), e);
+        return create_status_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not hash file");
+    }
+    let digest = share
+        .digest
+        .lock()
+        .unwrap()
+        .clone()
+        .map(|(_, digest)| digest);
+    if digest != Some(create_digest(&sha256)) {
+        output::print_event(&format!(
+            "The recipient reported another checksum for {}, it is kept",
+            share.file_name
+        ));
+        return create_status_response(StatusCode::CONFLICT, "The checksum does not match");
+    }
+    output::print_event(&format!("The recipient confirmed {}", share.file_name));
+    share.transferred.store(true, Ordering::SeqCst);
+    let _ = completed.send(());
+    create_status_response(StatusCode::OK, "Confirmed")
+}
+
+async fn serve_file(
+    share: Arc<Share>,
+    completed: mpsc::UnboundedSender<()>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let mut link = None;
+    if let Some(links) = &share.links {
+        let (index, rest) = match links.find(req.uri().path()) {
+            Some(found) => found,
+            None => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+        };
+        match links.get_links()[index].get_state() {
+            tokens::LinkState::Unused => {}
+            tokens::LinkState::Used => {
+                return Ok(create_status_response(
+                    StatusCode::GONE,
+                    "This link has already been used",
+                ))
+            }
+            tokens::LinkState::Revoked => {
+                return Ok(create_status_response(
+                    StatusCode::GONE,
+                    "This link has been revoked",
+                ))
+            }
+        }
+        let uri = match req.uri().query() {
+            Some(query) => format!("{}?{}", rest, query),
+            None => rest.to_string(),
+        };
+        *req.uri_mut() = match uri.parse() {
+            Ok(uri) => uri,
+            Err(_) => {
+                return Ok(create_status_response(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid path",
+                ))
+            }
+        };
+        link = Some(index);
+    }
+
+    match help::find_route(&get_share_routes(&share), &req) {
+        Ok(ShareRoute::File) => {}
+        Ok(ShareRoute::Pieces) => {
+            let manifest = share.pieces.as_ref().unwrap();
+            let rest = req.uri().path().strip_prefix("/pieces").unwrap();
+            return Ok(pieces::serve_pieces(share.path.clone(), manifest, rest).await);
+        }
+        Ok(ShareRoute::Sums) => {
+            let (path, name) = (share.path.clone(), share.file_name.clone());
+            return Ok(serve_sha256sums(
+                move || Ok(vec![manifest::create_entry(&path, name)?]),
+                &req,
+            )
+            .await);
+        }
+        Ok(ShareRoute::Manifest) => {
+            let (path, name) = (share.path.clone(), share.file_name.clone());
+            let prefix = match (link, &share.links) {
+                (Some(index), Some(links)) => {
+                    format!("/{}/", links.get_links()[index].token.value)
+                }
+                _ => String::from("/"),
+            };
+            let url = format!("{}{}", prefix, paths::percent_encode(&name));
+            return Ok(serve_manifest_json(
+                move || Ok(vec![(manifest::create_entry(&path, name)?, url)]),
+                &req,
+            )
+            .await);
+        }
+        Ok(ShareRoute::Signature) => {
+            let signature = share.signature.clone().unwrap();
+            return Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "application/pgp-signature")
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    create_content_disposition(&format!("{}.asc", share.file_name)),
+                )
+                .body(Body::from(signature))
+                .unwrap());
+        }
+        Ok(ShareRoute::Confirm) => return Ok(confirm_download(&share, &completed, req).await),
+        Err(response) => return Ok(response),
+    }
+    let ip = req.extensions().get::<net::SocketAddr>().map(|a| a.ip());
+    let recipient = match (&share.broadcast, ip) {
+        (Some(broadcast), Some(ip)) => {
+            if let Some(name) = broadcast::parse_name(req.uri().query()) {
+                broadcast.set_name(ip, name);
+            }
+            if broadcast.needs_name(ip) {
+                return Ok(Response::builder()
+                    .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                    .body(Body::from(broadcast::create_name_page(&share.file_name)))
+                    .unwrap());
+            }
+            Some((broadcast.clone(), ip))
+        }
+        _ => None,
+    };
+    if let Some(encryption) = &share.encryption {
+        return Ok(serve_encrypted_file(&share, encryption));
+    }
+    // Showing the text doesn't download it, so it can't take the place of a counted download.
+    if share.links.is_none() && recipient.is_none() && snippet::wants_page(&req) {
+        let path = share.path.clone();
+        if let Ok(Some(text)) =
+            tokio::task::spawn_blocking(move || snippet::read_inline_text(&path)).await
+        {
+            return Ok(snippet::serve_page(&share.file_name, &text));
+        }
+    }
+
+    let source = Arc::new(source::FileSource::new(
+        share.path.clone(),
+        share.file_name.clone(),
+    ));
+    let prepared = match source::prepare(source, &req).await {
+        Ok(prepared) => prepared,
+        Err(response) => return Ok(response),
+    };
+    if let Some((broadcast, ip)) = &recipient {
+        broadcast.start(*ip);
+    }
+    let share_handle = share.clone();
+    let reaches_end = prepared.reaches_end;
+    let count = prepared.count.unwrap_or_default();
+    let stream = broadcast::TrackedStream::new(prepared.body, recipient.clone());
+    let body = transfer::CountingStream::new(stream, count, move || {
+        // Only the part up to the end completes a download, earlier parts are just resumed.
+        if !reaches_end {
+            return;
+        }
+        if let (Some(index), Some(links)) = (link, &share_handle.links) {
+            links.get_links()[index].mark_used();
+            output::print_event(&format!("Link {} has been used", index + 1));
+            if !links.is_exhausted() {
+                return;
+            }
+        }
+        if let Some((broadcast, ip)) = recipient {
+            if !broadcast.finish(ip) {
+                return;
+            }
+        }
+        if share_handle.confirm {
+            share_handle.downloaded.store(true, Ordering::SeqCst);
+            output::print_event(&format!(
+                "{} has been downloaded, waiting for the recipient to confirm it",
+                share_handle.file_name
+            ));
+            return;
+        }
+        share_handle.transferred.store(true, Ordering::SeqCst);
+        let _ = completed.send(());
+    });
+
+    let mut response = prepared.response;
+    if !prepared.partial {
+        if let Some(digest) = prepared
+            .version
+            .and_then(|etag| share.get_cached_digest(&etag))
+        {
+            response = response.header("Digest", digest);
+        }
+    }
+    let response = response.body(Body::wrap_stream(body)).unwrap();
+    Ok(transfer::make_revocable(response, move || {
+        link.is_some_and(|index| {
+            let links = share.links.as_ref().unwrap();
+            links.get_links()[index].get_state() == tokens::LinkState::Revoked
+        })
+    }))
+}
+
+/// Streams the output of the encryption tool. Its length is unknown in advance, so encrypted
+/// transfers are never counted as completed.
+fn serve_encrypted_file(share: &Share, encryption: &crypto::Encryption) -> Response<Body> {
+    let mut child = match encryption.spawn(&share.path) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Could not start encryption: {}", e);
+            return create_status_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not encrypt file",
+            );
+        }
+    };
+    let stdout = child.stdout.take().unwrap();
+    tokio::spawn(async move {
+        match child.await {
+            Ok(status) if !status.success() => eprintln!("Encryption failed with {}", status),
+            Err(e) => eprintln!("Encryption failed: {}", e),
+            _ => {}
+        }
+    });
+
+    let file_name = format!("{}.{}", share.file_name, encryption.tool.get_extension());
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(
+            header::CONTENT_DISPOSITION,
+            create_content_disposition(&file_name),
+        )
+        .body(Body::wrap_stream(transfer::FileStream::new(stdout)))
+        .unwrap()
+}
+
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to install CTRL+C signal handler");
+    eprintln!("Shutting down server");
+}
+
+/// What happened in the running session, changed by requests and console commands
+struct SessionState {
+    started: Instant,
+    requests: AtomicUsize,
+    revoked: AtomicBool,
+    /// Every URL the server can be reached at, more are added when the interface gets new
+    /// addresses
+    urls: Mutex<Vec<String>>,
+    clients: clients::ClientList,
+    /// Messages between sender and recipients, with `--chat`
+    chat: Option<Arc<chat::ChatRoom>>,
+    /// Downloads and uploads that haven't finished yet
+    transfers: Arc<transfer::Transfers>,
+}
+
+impl SessionState {
+    fn new(url: String, chat: bool) -> SessionState {
+        SessionState {
+            started: Instant::now(),
+            requests: AtomicUsize::new(0),
+            revoked: AtomicBool::new(false),
+            urls: Mutex::new(vec![url]),
+            clients: clients::ClientList::default(),
+            chat: if chat { Some(Arc::default()) } else { None },
+            transfers: Arc::default(),
+        }
+    }
+}
+
+/// Settings of the HTTP server that apply to all modes
+#[derive(Clone, Default)]
+struct ServerOptions {
+    stop_after_transfer: bool,
+    window: schedule::Window,
+    transfer_limit: Option<Arc<transfer::TransferLimit>>,
+    /// Bytes per second shared by all transfers
+    rate_limit: Option<Arc<transfer::RateLimit>>,
+    /// Bytes per second of every single transfer
+    client_rate: Option<u64>,
+    /// Bytes per second shared by all uploads
+    upload_rate_limit: Option<Arc<transfer::RateLimit>>,
+    /// Bytes per second of every single upload
+    client_upload_rate: Option<u64>,
+    cache_control: Option<cache::CachePolicy>,
+    /// Ask search engines not to index anything
+    noindex: bool,
+    /// Announce readiness with a JSON line for wrapping programs
+    porcelain: bool,
+    /// Print nothing but the URLs to stdout, and no status unless verbose
+    url_only: bool,
+    verbosity: u64,
+    /// Advertise the URL as a Bluetooth beacon
+    beacon: bool,
+    /// Landing page trying both IP families, with `--both-families`
+    landing: Option<Arc<landing::Landing>>,
+    /// Download page with a countdown, with `--kiosk`
+    kiosk: Option<Arc<kiosk::Kiosk>>,
+    /// Short URLs redirecting to the share, with `--short`
+    short: Option<Arc<short::ShortLinks>>,
+    /// Network whose QR code is printed before the share's, with `--wifi`
+    wifi: Option<wifi::WifiNetwork>,
+    /// Serve the speed test page, with `--speedtest`
+    speedtest: bool,
+    /// Serve the message page, with `--chat`
+    chat: bool,
+    timeouts: timeouts::Timeouts,
+    /// Connections waiting for the headers of a request above which new ones are refused
+    max_half_open: Option<usize>,
+    limits: limits::RequestLimits,
+    /// Run with a single worker thread and small buffers, with `--low-memory`
+    low_memory: bool,
+    /// Where links, counters and interrupted uploads are saved, with `--state-file`
+    state_file: Option<Arc<state::StateFile>>,
+    /// Where requests and responses are written to, with `--record`
+    recorder: Option<Arc<record::Recorder>>,
+    /// Look up the host names of clients, with `--resolve-names`
+    resolve_names: bool,
+    /// Announce the share to Windows Explorer, with `--wsd`
+    wsd: Option<Arc<wsd::Announcer>>,
+    /// Where the counters are written for node_exporter, with `--metrics-textfile`
+    metrics: Option<Arc<metrics::Textfile>>,
+    /// The program running the server, which gets its events instead of the console
+    embedding: Option<Arc<embed::Embedding>>,
+}
+
+/// Threads for file system work besides the single worker with `--low-memory`
+const LOW_MEMORY_BLOCKING_THREADS: usize = 4;
+/// Read buffer of every connection with `--low-memory`, hyper doesn't go below 8 KiB
+const LOW_MEMORY_READ_BUFFER: usize = 16 * 1024;
+/// Downloads served at the same time with `--low-memory`, unless `--max-active-transfers` is given
+const LOW_MEMORY_MAX_TRANSFERS: usize = 2;
+
+async fn handle_request(
+    mode: Mode,
+    options: ServerOptions,
+    state: Arc<SessionState>,
+    completed: mpsc::UnboundedSender<()>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    state.requests.fetch_add(1, Ordering::SeqCst);
+    if let Some(response) = options.limits.check(&req) {
+        return Ok(response);
+    }
+    if state.revoked.load(Ordering::SeqCst) {
+        return Ok(create_status_response(
+            StatusCode::GONE,
+            "This share has been revoked",
+        ));
+    }
+    let client_ip = req.extensions().get::<net::SocketAddr>().map(|a| a.ip());
+    if let Some(ip) = client_ip {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|u| u.to_str().ok());
+        if let Some(probe) = req.extensions().get::<Arc<latency::Probe>>() {
+            state.clients.set_probe(ip, probe.clone());
+        }
+        if !state.clients.record(ip, user_agent) {
+            return Ok(create_status_response(
+                StatusCode::GONE,
+                "Your access to this share has been revoked",
+            ));
+        }
+        if options.resolve_names && state.clients.start_lookup(ip) {
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Some(host_name) = hostnames::lookup(ip).await {
+                    state.clients.set_host_name(ip, host_name);
+                }
+            });
+        }
+    }
+    let availability = options.window.check(chrono::Local::now());
+    if let Some(response) = schedule::create_unavailable_response(availability) {
+        return Ok(response);
+    }
+    if let Some(response) = options
+        .landing
+        .as_ref()
+        .and_then(|l| l.handle_request(&req))
+    {
+        return Ok(response);
+    }
+    if let Some(response) = options.short.as_ref().and_then(|s| s.handle_request(&req)) {
+        return Ok(response);
+    }
+    if let Some(response) = options.kiosk.as_ref().and_then(|k| k.handle_request(&req)) {
+        return Ok(response);
+    }
+    let kiosk_download = options
+        .kiosk
+        .as_ref()
+        .and_then(|k| Some((k.clone(), kiosk::get_download_id(req.uri().query())?)));
+    let server_routes = get_server_routes(&options);
+    match help::find_route(&server_routes, &req) {
+        Ok(ServerRoute::Help) => {
+            if let Some(mut endpoints) = mode.get_endpoints() {
+                endpoints.extend(help::get_endpoints(&server_routes));
+                return Ok(help::create_response(mode.get_name(), &endpoints, &req));
+            }
+        }
+        Ok(ServerRoute::Speedtest) => return Ok(speedtest::handle_request(req).await),
+        Ok(ServerRoute::Chat) => {
+            let from = state
+                .clients
+                .get_clients()
+                .into_iter()
+                .find(|c| Some(c.ip) == client_ip)
+                .map_or_else(|| String::from("Unknown"), |c| c.get_display_name());
+            let chat = state.chat.clone().unwrap();
+            return Ok(chat::handle_request(chat, from, req).await);
+        }
+        Ok(ServerRoute::Wsd) => {
+            let wsd = options.wsd.as_ref().unwrap();
+            return Ok(wsd::handle_request(wsd, req).await);
+        }
+        Ok(ServerRoute::Robots) => return Ok(robots::create_robots_response()),
+        Ok(ServerRoute::Options) => {
+            return Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header(
+                    header::ALLOW,
+                    methods::format_allow(mode.get_allowed_methods()),
+                )
+                .body(Body::empty())
+                .unwrap());
+        }
+        // Everything else is up to the mode.
+        Err(_) => {}
+    }
+    if let Some(response) = methods::check(mode.get_allowed_methods(), &req) {
+        return Ok(response);
+    }
+    let mut upload_limits = Vec::new();
+    upload_limits.extend(options.upload_rate_limit.clone());
+    upload_limits.extend(
+        options
+            .client_upload_rate
+            .map(|r| Arc::new(transfer::RateLimit::new(r))),
+    );
+    let transfer_name = get_transfer_name(&mode, req.uri().path());
+    let req = state
+        .transfers
+        .track_request(client_ip, &transfer_name, req);
+    let mut req = transfer::throttle_request(req, upload_limits);
+    // HEAD is answered like GET without the body. Only HEAD requests wait for the file to be
+    // hashed, GET requests include the Digest header once it is known.
+    let is_head = req.method() == Method::HEAD;
+    if is_head {
+        *req.method_mut() = Method::GET;
+        if let Mode::Send(share) | Mode::Exchange(share, _) = &mode {
+            if let Err(e) = share.update_digest().await {
+                eprintln!("Could not hash {}: {}", share.path.display(), e);
+            }
+        }
+    }
+    let cache_policy = mode.get_cache_policy();
+    // Downloads name the other addresses of the share, for clients to continue there.
+    let mirror_path = match &mode {
+        Mode::Send(share) => Some(share.get_root_path(req.uri().path())),
+        _ => None,
+    };
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(String::from);
+    let response = match mode {
+        Mode::Send(share) => serve_file(share, completed, req).await?,
+        Mode::Receive(inbox) => receive::handle_request(inbox, req).await?,
+        Mode::Sync(sync_root) => sync::handle_request(sync_root, req).await?,
+        Mode::Archive(archive) => archive::handle_request(archive, req).await?,
+        Mode::Live(output) => live::handle_request(output, req).await?,
+        Mode::Site(site) => site::handle_request(site, req).await?,
+        Mode::Mounts(table) => mounts::handle_request(table, req).await?,
+        Mode::Relay(relay) => relay::handle_request(relay, req).await?,
+        Mode::Remote(share) => remote::handle_request(share, req).await?,
+        Mode::Device(stream) => device::handle_request(stream, req).await?,
+        Mode::DropBox(dropbox) => dropbox::handle_request(dropbox, req).await?,
+        Mode::Text(snippet) => snippet::handle_request(snippet, req).await?,
+        Mode::Bundle(bundle) => bundle::handle_request(bundle, req).await?,
+        Mode::Cas(store) => cas::handle_request(store, req).await?,
+        Mode::Screen(screen) => screen::handle_request(screen, req).await?,
+        Mode::Exchange(share, inbox) => {
+            let routes = exchange::get_routes(get_share_routes(&share), &inbox);
+            match help::find_route(&routes, &req) {
+                Ok(exchange::Route::Page) => exchange::create_page_response(&share.file_name),
+                Ok(exchange::Route::Upload) => receive::handle_request(inbox, req).await?,
+                Ok(exchange::Route::Download) => serve_file(share, completed, req).await?,
+                Err(response) => response,
+            }
+        }
+    };
+    let mut response = cache::apply_policy(cache_policy, options.cache_control, response);
+    if let Some(path) = mirror_path.filter(|_| response.status().is_success()) {
+        let urls = state.urls.lock().unwrap().clone();
+        mirrors::add_links(response.headers_mut(), &urls, host.as_deref(), &path);
+    }
+    if options.noindex {
+        response = robots::add_robots_tag(response);
+    }
+    if is_head {
+        let (parts, _) = response.into_parts();
+        return Ok(Response::from_parts(parts, Body::empty()));
+    }
+    let response = state
+        .transfers
+        .track_response(client_ip, &transfer_name, response);
+    let response = transfer::make_revocable(response, move || {
+        state.revoked.load(Ordering::SeqCst)
+            || client_ip.is_some_and(|ip| state.clients.is_revoked(ip))
+    });
+    let mut rate_limits = Vec::new();
+    rate_limits.extend(options.rate_limit.clone());
+    rate_limits.extend(
+        options
+            .client_rate
+            .map(|r| Arc::new(transfer::RateLimit::new(r))),
+    );
+    let response = transfer::throttle_response(response, rate_limits);
+    let response = match &options.transfer_limit {
+        Some(limit) => transfer::limit_response(limit, response),
+        None => response,
+    };
+    match kiosk_download {
+        Some((kiosk, id)) => Ok(kiosk::track(&kiosk, id, response)),
+        None => Ok(response),
+    }
+}
+
+/// What a transfer of `path` is called while shutting down waits for it
+fn get_transfer_name(mode: &Mode, path: &str) -> String {
+    match mode {
+        Mode::Send(share) | Mode::Exchange(share, _) => share.file_name.clone(),
+        _ => path
+            .rsplit('/')
+            .find(|s| !s.is_empty())
+            .and_then(paths::percent_decode)
+            .unwrap_or_else(|| String::from("files")),
+    }
+}
+
+fn run_http_server(
+    network: Arc<dyn network::Network>,
+    address: Address,
+    mode: Mode,
+    options: ServerOptions,
+) -> Result<(), Box<dyn error::Error>> {
+    let mut builder = tokio::runtime::Builder::new();
+    builder.threaded_scheduler().enable_all();
+    if options.low_memory {
+        builder
+            .core_threads(1)
+            .max_threads(1 + LOW_MEMORY_BLOCKING_THREADS);
+    }
+    // Events are printed on whichever thread runs the task, and passed on from all of them.
+    if let Some(embedding) = &options.embedding {
+        let sink = embedding.get_sink();
+        output::set_event_sink(sink.clone());
+        builder.on_thread_start(move || output::set_event_sink(sink.clone()));
+    }
+    let mut runtime = builder.build()?;
+    let result = runtime.block_on(run_http_server_async(network, address, mode, options));
+    // The console's read from stdin can't be cancelled and would keep the runtime alive until the
+    // next line is entered, so don't wait for it.
+    runtime.shutdown_timeout(Duration::from_millis(100));
+    result
+}
+
+async fn run_http_server_async(
+    network: Arc<dyn network::Network>,
+    address: Address,
+    mode: Mode,
+    options: ServerOptions,
+) -> Result<(), Box<dyn error::Error>> {
+    let (completed_tx, mut completed_rx) = mpsc::unbounded_channel::<()>();
+    let (quit_tx, mut quit_rx) = mpsc::unbounded_channel::<()>();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (new_socket_tx, mut new_socket_rx) = mpsc::unbounded_channel();
+    if let Mode::Live(output) = &mode {
+        tokio::spawn(live::produce(output.clone()));
+    }
+    if let Mode::Receive(inbox) | Mode::Exchange(_, inbox) = &mode {
+        if let Some(monitor) = inbox.get_space_monitor() {
+            tokio::spawn(space::watch(monitor));
+        }
+    }
+    let stop_after_transfer = options.stop_after_transfer;
+    let state = Arc::new(SessionState::new(address.url.clone(), options.chat));
+    if let Some(state_file) = &options.state_file {
+        if let Some(restored) = state_file.get_restored() {
+            state.requests.store(restored.requests, Ordering::SeqCst);
+        }
+        tokio::spawn(keep_state(state_file.clone(), mode.clone(), state.clone()));
+    }
+    if let Some(textfile) = &options.metrics {
+        tokio::spawn(keep_metrics(textfile.clone(), state.clone()));
+    }
+    let embedding = options.embedding.clone();
+    if embedding.is_none() {
+        tokio::spawn(console::run_console(
+            mode.clone(),
+            state.clone(),
+            quit_tx.clone(),
+        ));
+    }
+    tokio::spawn(watch_interface(
+        network.clone(),
+        address.clone(),
+        new_socket_tx,
+    ));
+    let announcement = options.wsd.as_ref().map(|announcer| {
+        tokio::spawn(wsd::run(
+            announcer.clone(),
+            address.socket.ip(),
+            address.interface.clone(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    let mut shutdown = Box::pin(wait_for_shutdown(shutdown_rx.clone()));
+    // Connections of all sockets waiting for the headers of a request
+    let half_open = Arc::new(AtomicUsize::new(0));
+    let bind = |socket: net::SocketAddr| -> io::Result<_> {
+        let mode = mode.clone();
+        let options = options.clone();
+        let timeouts = options.timeouts;
+        let low_memory = options.low_memory;
+        let max_half_open = options.max_half_open;
+        let half_open = half_open.clone();
+        let state = state.clone();
+        let completed_tx = completed_tx.clone();
+        let make_svc =
+            make_service_fn(move |conn: &timeouts::TimeoutStream<network::Connection>| {
+                let remote_address = conn.get_ref().remote_address();
+                let probe = conn.get_ref().get_probe();
+                let connection = conn.get_state();
+                let mode = mode.clone();
+                let options = options.clone();
+                let state = state.clone();
+                let completed_tx = completed_tx.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                        req.extensions_mut().insert(remote_address);
+                        req.extensions_mut().insert(probe.clone());
+                        let request = connection.start_request();
+                        let req = timeouts::watch_body(req, timeouts.body, request.clone());
+                        let (req, recording) = match &options.recorder {
+                            Some(recorder) => {
+                                let (req, recording) = recorder.start(req);
+                                (req, Some(recording))
+                            }
+                            None => (req, None),
+                        };
+                        let req = match &options.metrics {
+                            Some(textfile) => textfile.count_request(req),
+                            None => req,
+                        };
+                        let metrics = options.metrics.clone();
+                        let response = handle_request(
+                            mode.clone(),
+                            options.clone(),
+                            state.clone(),
+                            completed_tx.clone(),
+                            req,
+                        );
+                        async move {
+                            let response = response.await;
+                            drop(request);
+                            let response = match metrics {
+                                Some(textfile) => response.map(|r| textfile.count_response(r)),
+                                None => response,
+                            };
+                            match recording {
+                                Some(recording) => response.map(|r| recording.finish(r)),
+                                None => response,
+                            }
+                        }
+                    }))
+                }
+            });
+        // The unspecified address stands for the dual-stack socket on the whole interface.
+        let listener = if socket.ip().is_unspecified() {
+            network.bind_dual_stack(&address.interface, socket.port(), timeouts.keep_alive)?
+        } else {
+            network.bind(socket, timeouts.keep_alive)?
+        };
+        let connections = accept::from_stream(listener.filter_map(move |conn| {
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => return future::ready(Some(Err(e))),
+            };
+            let peer = conn.remote_address();
+            if max_half_open.is_some_and(|max| half_open.load(Ordering::SeqCst) >= max) {
+                output::print_event(&format!(
+                    "Refused a connection of {}, too many clients are still sending their request",
+                    peer.ip()
+                ));
+                return future::ready(None);
+            }
+            let conn =
+                timeouts::TimeoutStream::new(conn, timeouts).with_peer(peer, half_open.clone());
+            future::ready(Some(Ok(conn)))
+        }));
+        let mut builder = Server::builder(connections);
+        if low_memory {
+            builder = builder.http1_max_buf_size(LOW_MEMORY_READ_BUFFER);
+        }
+        let server = builder
+            .serve(make_svc)
+            .with_graceful_shutdown(wait_for_shutdown(shutdown_rx.clone()));
+        Ok(tokio::spawn(server))
+    };
+    let socket = match address.dual_stack {
+        true => net::SocketAddr::from((net::Ipv6Addr::UNSPECIFIED, address.socket.port())),
+        false => address.socket,
+    };
+    let mut servers = vec![bind(socket).map_err(|e| BindError::new(socket, e))?];
+    // The addresses with a bound socket, which keeps listening while the address is gone
+    let mut bound = vec![(address.socket.ip(), address.url.clone())];
+    if options.porcelain {
+        print_ready_event(&address, &mode);
+    }
+    if options.url_only {
+        print_plain_urls(&address.url, &mode);
+    }
+    let mut summary = vec![("Listening on", address.url.clone())];
+    if let Some((socket, url)) = address.alternate.as_ref().filter(|_| address.dual_stack) {
+        bound.push((socket.ip(), url.clone()));
+        summary.push(("Also listening on", url.clone()));
+        state.urls.lock().unwrap().push(url.clone());
+    } else if let Some((socket, url)) = &address.alternate {
+        match bind(*socket) {
+            Ok(server) => {
+                servers.push(server);
+                bound.push((socket.ip(), url.clone()));
+                summary.push(("Also listening on", url.clone()));
+                state.urls.lock().unwrap().push(url.clone());
+            }
+            Err(e) => eprintln!("Could not listen on {}: {}", url, e),
+        }
+    }
+    summary.push(("Mode", mode.get_name().to_string()));
+    if options.speedtest {
+        let url = format!("{}{}", address.url, speedtest::SPEEDTEST_PATH);
+        summary.push(("Speed test", url));
+    }
+    if options.chat {
+        summary.push(("Messages", format!("{}{}", address.url, chat::CHAT_PATH)));
+    }
+    if let Mode::Remote(share) = &mode {
+        summary.push(("Proxying", share.describe()));
+    }
+    if let Mode::Text(snippet) = &mode {
+        summary.push(("Sharing", snippet.describe()));
+    }
+    if let Mode::Screen(screen) = &mode {
+        summary.push(("Showing", screen.describe()));
+    }
+    if let Some(storage) = match &mode {
+        Mode::Receive(inbox) => inbox.describe_storage(),
+        _ => None,
+    } {
+        summary.push(("Storing in", storage));
+    }
+    if let Mode::Send(share) | Mode::Exchange(share, _) = &mode {
+        if let Ok(metadata) = fs::metadata(&share.path) {
+            let size = transfer::format_size(metadata.len());
+            summary.push(("File", format!("{} ({})", share.file_name, size)));
+            if let Some(estimate) = get_estimate(&address.interface, metadata.len()) {
+                summary.push(("Download", estimate));
+            }
+        }
+    }
+    // Scripts capturing the URL only see status if they ask for it.
+    if !options.url_only || options.verbosity > 0 {
+        output::print_summary(&summary);
+        if let Some(wifi) = &options.wifi {
+            eprintln!("Join the Wi-Fi {} first:", wifi.ssid);
+            print_qr_code(&wifi.get_qr_text());
+        }
+        print_share_urls(
+            &address.url,
+            &mode,
+            options.landing.as_deref(),
+            options.kiosk.as_deref(),
+        );
+        if let Some(short) = &options.short {
+            short.print(&address.url);
+        }
+    }
+    let beacon = options.beacon && start_beacon(&address.url, &mode);
+    if let Some(embedding) = &embedding {
+        embedding.set_running(quit_tx, state.clone());
+    }
+
+    // One-time links only complete a transfer once all of them have been used.
+    let has_links = matches!(&mode, Mode::Send(share) if share.links.is_some());
+    let expires_in = options
+        .window
+        .get_until()
+        .map(|until| (until - chrono::Local::now()).to_std().unwrap_or_default());
+    let ended = tokio::spawn(async move {
+        let ended = tokio::select! {
+            // The program embedding the server decides what Ctrl+C does.
+            _ = shutdown_signal(), if embedding.is_none() => None,
+            Some(_) = quit_rx.recv() => {
+                eprintln!("Shutting down server");
+                None
+            }
+            _ = completed_rx.recv(), if stop_after_transfer || has_links => {
+                if stop_after_transfer {
+                    output::print_event("Transfer complete, shutting down server");
+                    None
+                } else {
+                    output::print_event("All one-time links have been used, shutting down server");
+                    Some(ShareEndedError::LinksUsed)
+                }
+            }
+            _ = tokio::time::delay_for(expires_in.unwrap_or_default()), if expires_in.is_some() => {
+                output::print_event("The share expired, shutting down server");
+                Some(ShareEndedError::Expired)
+            }
+        };
+        let _ = shutdown_tx.broadcast(true);
+        ended
+    });
+
+    // Existing sockets stay bound when the interface gets a new address, so running transfers
+    // continue while new clients can use the new address.
+    loop {
+        tokio::select! {
+            Some(change) = new_socket_rx.recv() => match change {
+                InterfaceChange::Added(socket, url)
+                    if bound.iter().any(|(ip, _)| *ip == socket.ip()) =>
+                {
+                    output::print_event(&format!("The network interface got {} back", url));
+                    state.urls.lock().unwrap().push(url);
+                }
+                // The dual-stack socket already accepts connections to every address.
+                InterfaceChange::Added(socket, url) if address.dual_stack => {
+                    bound.push((socket.ip(), url.clone()));
+                    eprintln!(
+                        "The network interface got a new address, also reachable at {}",
+                        url
+                    );
+                    print_qr_code(&url);
+                    state.urls.lock().unwrap().push(url);
+                }
+                InterfaceChange::Added(socket, url) => match bind(socket) {
+                    Ok(server) => {
+                        servers.push(server);
+                        bound.push((socket.ip(), url.clone()));
+                        eprintln!(
+                            "The network interface got a new address, also listening on {}",
+                            url
+                        );
+                        print_qr_code(&url);
+                        state.urls.lock().unwrap().push(url);
+                    }
+                    Err(e) => eprintln!("Could not listen on {}: {}", url, e),
+                },
+                InterfaceChange::Resumed(asleep, lost) => {
+                    output::print_event(&format!(
+                        "Woke up after {} asleep",
+                        console::format_duration(asleep.as_secs())
+                    ));
+                    let first = {
+                        let mut urls = state.urls.lock().unwrap();
+                        for (_, url) in bound.iter().filter(|(ip, _)| lost.contains(ip)) {
+                            output::print_event(&format!(
+                                "{} can't be reached anymore, the interface lost the address",
+                                url
+                            ));
+                            urls.retain(|u| u != url);
+                        }
+                        urls.first().cloned()
+                    };
+                    match first {
+                        // Registrations name the first address, which may have changed.
+                        Some(url) if beacon => {
+                            let _ = beacon::stop();
+                            start_beacon(&url, &mode);
+                        }
+                        Some(_) => {}
+                        None => eprintln!("Waiting for the network interface to get an address"),
+                    }
+                }
+            },
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drain(servers, &state, options.timeouts.drain).await?;
+    if let Some(state_file) = &options.state_file {
+        if let Err(e) = state_file.save(create_snapshot(state_file, &mode, &state)) {
+            eprintln!("Could not save the state to the state file: {}", e);
+        }
+    }
+    if let Some(textfile) = &options.metrics {
+        textfile.remove();
+    }
+    if beacon {
+        if let Err(e) = beacon::stop() {
+            eprintln!("Could not stop the Bluetooth beacon: {}", e);
+        }
+    }
+    // Windows forgets the share once it said Bye.
+    if let Some(announcement) = announcement {
+        announcement.await?;
+    }
+
+    match ended.await? {
+        Some(ended) => Err(Box::new(ended)),
+        None => Ok(()),
+    }
+}
+
+/// How often shutting down shows the progress of the transfers it waits for
+const DRAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Waits for the servers, which stopped accepting connections, to finish the running transfers.
+/// Gives up on them after `timeout` or when Ctrl+C is pressed again.
+async fn drain(
+    servers: Vec<tokio::task::JoinHandle<Result<(), hyper::Error>>>,
+    state: &SessionState,
+    timeout: Option<Duration>,
+) -> Result<(), Box<dyn error::Error>> {
+    let mut servers = future::try_join_all(servers);
+    let mut progress = tokio::time::interval(DRAIN_PROGRESS_INTERVAL);
+    let mut deadline = tokio::time::delay_for(timeout.unwrap_or_default());
+    let mut waiting = false;
+    loop {
+        tokio::select! {
+            results = &mut servers => {
+                for result in results? {
+                    if let Err(e) = result {
+                        eprintln!("server error: {}", e);
+                    }
+                }
+                if waiting {
+                    output::print_event("All transfers finished");
+                }
+                return Ok(());
+            }
+            _ = progress.tick() => {
+                let running = state.transfers.get_running();
+                if running.is_empty() {
+                    continue;
+                }
+                if !waiting {
+                    eprintln!(
+                        "Waiting for {} to finish, press Ctrl+C again to stop right away",
+                        if running.len() == 1 {
+                            String::from("1 transfer")
+                        } else {
+                            format!("{} transfers", running.len())
+                        }
+                    );
+                    waiting = true;
+                }
+                for transfer in running {
+                    output::print_event(&transfer.describe());
+                }
+            }
+            _ = &mut deadline, if timeout.is_some() => {
+                if !state.transfers.get_running().is_empty() {
+                    eprintln!("Breaking off the transfers that didn't finish in time");
+                }
+                return Ok(());
+            }
+            _ = tokio::signal::ctrl_c() => {
+                eprintln!("Breaking off the running transfers");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// What the state file keeps of the running server
+fn create_snapshot(
+    state_file: &state::StateFile,
+    mode: &Mode,
+    session: &SessionState,
+) -> state::Snapshot {
+    let mut snapshot = state_file.create_snapshot();
+    snapshot.requests = session.requests.load(Ordering::SeqCst);
+    if let Mode::Send(share) | Mode::Exchange(share, _) = mode {
+        if let Some(links) = &share.links {
+            snapshot.links = links.save();
+        }
+    }
+    if let Mode::Receive(inbox) | Mode::Exchange(_, inbox) = mode {
+        snapshot.uploads = inbox.get_interrupted_uploads();
+    }
+    snapshot
+}
+
+/// Saves the state whenever it changed while the server runs, so a crash only loses what
+/// happened in the last `state::SAVE_INTERVAL`.
+async fn keep_state(state_file: Arc<state::StateFile>, mode: Mode, session: Arc<SessionState>) {
+    let mut interval = tokio::time::interval(state::SAVE_INTERVAL);
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        match state_file.save(create_snapshot(&state_file, &mode, &session)) {
+            Ok(()) => failing = false,
+            // Only the first of a series of failures is reported.
+            Err(e) if !failing => {
+                eprintln!("Could not save the state to the state file: {}", e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+fn get_metrics_sample(session: &SessionState) -> metrics::Sample {
+    metrics::Sample {
+        requests: session.requests.load(Ordering::SeqCst) as u64,
+        clients: session.clients.get_clients().len(),
+    }
+}
+
+/// Writes the counters for node_exporter every `metrics::WRITE_INTERVAL` while the server runs.
+async fn keep_metrics(textfile: Arc<metrics::Textfile>, session: Arc<SessionState>) {
+    let mut interval = tokio::time::interval(metrics::WRITE_INTERVAL);
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        match textfile.write(get_metrics_sample(&session)) {
+            Ok(()) => failing = false,
+            // Only the first of a series of failures is reported.
+            Err(e) if !failing => {
+                eprintln!("Could not write the metrics: {}", e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Prints roughly how long downloading the file takes over the chosen interface.
+fn get_estimate(interface: &str, size: u64) -> Option<String> {
+    let (kind, mbit) = eta::get_link_speed(interface)?;
+    Some(eta::format_estimate(size, kind, mbit))
+}
+
+/// Advertises the share URL, or the first one-time link, and returns whether that worked.
+fn start_beacon(url: &str, mode: &Mode) -> bool {
+    let url = match get_link_urls(url, mode) {
+        Some(link_urls) => link_urls[0].clone(),
+        None => url.to_string(),
+    };
+    match beacon::start(&url) {
+        Ok(()) => {
+            eprintln!("Advertising {} as a Bluetooth beacon", url);
+            true
+        }
+        Err(e) => {
+            eprintln!("Could not advertise the URL over Bluetooth: {}", e);
+            false
+        }
+    }
+}
+
+/// The URLs of the one-time links of a share, if it has any
+fn get_link_urls(url: &str, mode: &Mode) -> Option<Vec<String>> {
+    match mode {
+        Mode::Send(share) => share.links.as_ref().map(|links| {
+            links
+                .get_links()
+                .iter()
+                .map(|l| l.token.get_url(url))
+                .collect()
+        }),
+        Mode::DropBox(dropbox) => {
+            let senders = dropbox.get_senders();
+            let senders = senders.iter().filter(|s| !s.is_expired());
+            Some(senders.map(|s| s.get_url(url)).collect()).filter(|u: &Vec<_>| !u.is_empty())
+        }
+        Mode::Bundle(bundle) => Some(vec![bundle.get_url(url)]),
+        _ => None,
+    }
+}
+
+/// The URL the QR code of `url` leads to. With a landing page, that is the landing page. With
+/// `--kiosk` it is the kiosk page of the download, through the landing page if there is one.
+fn get_qr_url(
+    base_url: &str,
+    url: &str,
+    landing: Option<&landing::Landing>,
+    kiosk: Option<&kiosk::Kiosk>,
+) -> String {
+    let url = match kiosk {
+        Some(kiosk) => kiosk.get_kiosk_url(base_url, url),
+        None => url.to_string(),
+    };
+    match landing {
+        Some(landing) => landing.get_landing_url(&url),
+        None => url,
+    }
+}
+
+/// Prints the QR code of the server's URL, or the URL and QR code of every one-time link.
+fn print_share_urls(
+    url: &str,
+    mode: &Mode,
+    landing: Option<&landing::Landing>,
+    kiosk: Option<&kiosk::Kiosk>,
+) {
+    let base_url = url;
+    let get_qr_url = |url: &str| get_qr_url(base_url, url, landing, kiosk);
+    let has_deep_link = landing.is_some_and(landing::Landing::has_deep_link);
+    match get_link_urls(url, mode) {
+        Some(link_urls) => {
+            for (index, link_url) in link_urls.iter().enumerate() {
+                eprintln!("Link {}: {}", index + 1, link_url);
+                if has_deep_link {
+                    eprintln!("App link {}: {}", index + 1, landing::get_app_url(link_url));
+                }
+                print_qr_code(&get_qr_url(link_url));
+            }
+        }
+        None => {
+            if landing.is_some() || kiosk.is_some() {
+                eprintln!("QR code: {}", get_qr_url(url));
+            }
+            if has_deep_link {
+                eprintln!("App link: {}", landing::get_app_url(url));
+            }
+            print_qr_code(&get_qr_url(url))
+        }
+    }
+}
+
+/// Prints the URLs to share to stdout, one per line and nothing else, for `--url-only`.
+fn print_plain_urls(url: &str, mode: &Mode) {
+    match get_link_urls(url, mode) {
+        Some(link_urls) => link_urls.iter().for_each(|u| println!("{}", u)),
+        None => println!("{}", url),
+    }
+    let _ = io::stdout().flush();
+}
+
+/// Prints a single JSON line telling wrapping programs that the server is ready and where.
+fn print_ready_event(address: &Address, mode: &Mode) {
+    let mut event = serde_json::json!({
+        "event": "ready",
+        "url": address.url,
+        "ip": address.socket.ip().to_string(),
+        "port": address.socket.port(),
+        "mode": mode.get_name(),
+    });
+    if let Some(link_urls) = get_link_urls(&address.url, mode) {
+        event["links"] = serde_json::json!(link_urls);
+    }
+    println!("{}", event);
+    let _ = io::stdout().flush();
+}
+
+async fn wait_for_shutdown(mut shutdown: watch::Receiver<bool>) {
+    while let Some(stop) = shutdown.recv().await {
+        if stop {
+            return;
+        }
+    }
+}
+
+/// How often the network interface is checked for new addresses
+const INTERFACE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What changed about the network interface while the server runs
+enum InterfaceChange {
+    /// The interface got an address, with the socket to listen on and its URL
+    Added(net::SocketAddr, String),
+    /// The system woke up after sleeping for the given time, and the interface lost these
+    /// addresses meanwhile
+    Resumed(Duration, Vec<net::IpAddr>),
+}
+
+/// Reports addresses the interface gets after the server has been started, of the same IP version
+/// as the chosen one, together with their URL. After the system was suspended, the addresses it
+/// lost are reported as well, and are reported again if they come back.
+async fn watch_interface(
+    network: Arc<dyn network::Network>,
+    address: Address,
+    changes: mpsc::UnboundedSender<InterfaceChange>,
+) {
+    let mut known = vec![address.socket.ip()];
+    known.extend(address.alternate.iter().map(|(socket, _)| socket.ip()));
+    let mut clock = suspend::SleepClock::default();
+    loop {
+        tokio::time::delay_for(INTERFACE_POLL_INTERVAL).await;
+        let asleep = clock.check();
+        let ips = match find_interfaces(&*network).remove(&address.interface) {
+            Some(interface) => interface.ips,
+            None if asleep.is_some() => Vec::new(),
+            None => continue,
+        };
+        let sockets = ips
+            .iter()
+            .map(|ip| (create_socket(*ip, address.socket.port()), ip))
+            .collect::<Vec<_>>();
+        let mut lost = Vec::new();
+        if asleep.is_some() {
+            known.retain(|known| {
+                let kept = sockets.iter().any(|(socket, _)| socket.ip() == *known);
+                if !kept {
+                    lost.push(*known);
+                }
+                kept
+            });
+        }
+        for (socket, ip) in sockets {
+            if socket.is_ipv4() != address.socket.is_ipv4() || known.contains(&socket.ip()) {
+                continue;
+            }
+            known.push(socket.ip());
+            let url = create_url(create_ip_string(ip), socket.port());
+            if changes.send(InterfaceChange::Added(socket, url)).is_err() {
+                return;
+            }
+        }
+        if let Some(asleep) = asleep {
+            if changes
+                .send(InterfaceChange::Resumed(asleep, lost))
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+/// Where the server listens: the chosen interface, the socket on one of its addresses and the
+/// URL announced for it
+#[derive(Clone)]
+struct Address {
+    interface: String,
+    socket: net::SocketAddr,
+    url: String,
+    /// Socket and URL on an address of the other IP family, with `--both-families`
+    alternate: Option<(net::SocketAddr, String)>,
+    /// Serve both IP families on every address of the interface with one socket, with
+    /// `--dual-stack`
+    dual_stack: bool,
+}
+
+/// Whether `ip` is only valid on its link, which URLs can't express without a zone index.
+fn is_link_local(ip: net::IpAddr) -> bool {
+    match ip {
+        net::IpAddr::V4(v4) => v4.is_link_local(),
+        net::IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// The first address in `ips` of the other IP family than `chosen` that can be put into a URL.
+fn find_other_family(
+    ips: &[ipnetwork::IpNetwork],
+    chosen: net::IpAddr,
+) -> Option<ipnetwork::IpNetwork> {
+    ips.iter()
+        .find(|n| n.is_ipv4() != chosen.is_ipv4() && !is_link_local(n.ip()))
+        .copied()
+}
+
+fn create_ip_string(ip: &ipnetwork::IpNetwork) -> IpString {
+    match canonicalize_ip(ip.ip()) {
+        net::IpAddr::V4(v4) => IpString::V4(v4.to_string()),
+        net::IpAddr::V6(v6) => IpString::V6(v6.to_string()),
+    }
+}
+
+/// Turns IPv4 addresses mapped into IPv6 (`::ffff:a.b.c.d`) back into plain IPv4 addresses, which
+/// is how clients have to connect to them.
+fn canonicalize_ip(ip: net::IpAddr) -> net::IpAddr {
+    match ip {
+        net::IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => net::IpAddr::V4(v4),
+            None => net::IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+/// The interface given with `-i`, or the one the user chooses from a list.
+fn choose_interface(
+    network: &dyn network::Network,
+    matches: &clap::ArgMatches,
+    remembered: Option<&selection::Selection>,
+) -> Result<interfaces::NetworkInterface, Box<dyn error::Error>> {
+    let mut interface_map = find_interfaces(network);
+    if matches.occurrences_of("network interface") == 1 {
+        let name = matches.value_of("network interface").unwrap();
+        return match interface_map.remove(name) {
+            Some(i) => Ok(i),
+            None => Err(Box::new(NetworkInterfaceExistanceError::new(
+                name.to_string(),
+            ))),
+        };
+    }
+    let mut interface_names = interface_map.keys().cloned().collect::<Vec<String>>();
+    interface_names.sort();
+    let default =
+        selection::find_default(&interface_names, remembered.map(|r| r.interface.as_str()));
+    let name_width = interface_names.iter().map(String::len).max().unwrap_or(0);
+    let choices = interface_names
+        .iter()
+        .map(|n| output::format_interface_choice(n, &interface_map[n].ips, name_width))
+        .collect();
+    let (interface_num, _) = choose_number(
+        String::from("Found network interfaces, choose one:"),
+        choices,
+        default,
+    )?;
+    Ok(interface_map
+        .remove(&interface_names[interface_num])
+        .unwrap())
+}
+
+fn get_network_socket(
+    network: &dyn network::Network,
+    matches: &clap::ArgMatches,
+) -> Result<Address, Box<dyn error::Error>> {
+    let remembered = if matches.is_present("forget") {
+        selection::forget()?;
+        None
+    } else {
+        selection::load()
+    };
+    let network_interface = choose_interface(network, matches, remembered.as_ref())?;
+
+    if matches.occurrences_of("verbose") >= 1 {
+        eprintln!("{}", output::format_interface(&network_interface));
+        let _ = neighbors::write_neighbors(&network_interface, &mut io::stderr());
+    }
+
+    let remembered_ip = remembered
+        .as_ref()
+        .filter(|r| r.interface == network_interface.name)
+        .map(|r| r.ip.as_str());
+    let (ipaddr_count, ipaddr_string) = choose_ip(
+        String::from("Choose an IP address:"),
+        network_interface.ips.iter().map(create_ip_string).collect(),
+        remembered_ip,
+    )?;
+    let chosen = selection::Selection {
+        interface: network_interface.name.clone(),
+        ip: match &ipaddr_string {
+            IpString::V4(s) | IpString::V6(s) => s.clone(),
+        },
+    };
+    if let Err(e) = selection::save(&chosen) {
+        eprintln!("Could not remember the chosen address: {}", e);
+    }
+    let port = matches.value_of("port").unwrap().parse::<u16>()?;
+    let socket = create_socket(network_interface.ips[ipaddr_count], port);
+    let url = create_url(ipaddr_string, port);
+    let dual_stack = matches.is_present("dual stack");
+    let alternate = if matches.is_present("both families") || dual_stack {
+        let other = find_other_family(&network_interface.ips, socket.ip());
+        if other.is_none() {
+            eprintln!(
+                "{} has no address of the other IP family, serving on {} only",
+                network_interface.name, url
+            );
+        }
+        other.map(|ip| {
+            (
+                create_socket(ip, port),
+                create_url(create_ip_string(&ip), port),
+            )
+        })
+    } else {
+        None
+    };
+    Ok(Address {
+        interface: network_interface.name.clone(),
+        socket,
+        url,
+        alternate,
+        dual_stack,
+    })
+}
+
+fn create_socket(ip: ipnetwork::IpNetwork, port: u16) -> net::SocketAddr {
+    match ip {
+        ipnetwork::IpNetwork::V4(v4) => {
+            std::net::SocketAddr::V4(std::net::SocketAddrV4::new(v4.ip(), port))
+        }
+        ipnetwork::IpNetwork::V6(v6) => {
+            std::net::SocketAddr::V6(std::net::SocketAddrV6::new(v6.ip(), port, 0, 0))
+        }
+    }
+}
+
+fn create_url(ip: IpString, port: u16) -> String {
+    match ip {
+        IpString::V4(v4) => format!("http://{}:{}", v4, port),
+        IpString::V6(v6) => format!("http://[{}]:{}", v6, port),
+    }
+}
+
+pub fn run_rustbelt(matches: &clap::ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    if matches.is_present("ascii qr") {
+        output::use_ascii_qr();
+    }
+    if matches.is_present("high contrast") {
+        output::use_high_contrast();
+    }
+    match matches.subcommand() {
+        ("sync", Some(sync_matches)) => return run_sync(sync_matches),
+        ("tail", Some(tail_matches)) => {
+            let path = PathBuf::from(tail_matches.value_of("FILE").unwrap());
+            let output = live::LiveOutput::new(live::LiveSource::Tail(path));
+            return serve(tail_matches, Mode::Live(Arc::new(output)), false, None);
+        }
+        ("trash", Some(trash_matches)) => return self::trash::run_trash(trash_matches),
+        ("qr", Some(qr_matches)) => return qr::run_qr(qr_matches),
+        ("inbox", Some(inbox_matches)) => {
+            let lifetime = match inbox_matches.value_of("expire after") {
+                Some(days) => Some(Duration::from_secs(parse_scaled(
+                    days,
+                    "days",
+                    24 * 60 * 60,
+                )?)),
+                None => None,
+            };
+            let dir = PathBuf::from(inbox_matches.value_of("DIR").unwrap());
+            let dropbox = dropbox::DropBox::open(dir, lifetime)?;
+            for sender in inbox_matches.values_of("sender").into_iter().flatten() {
+                match sender.split_once('=') {
+                    Some((name, destination)) => dropbox.mint(name, Some(destination))?,
+                    None => dropbox.mint(sender, None)?,
+                };
+            }
+            return serve(inbox_matches, Mode::DropBox(Arc::new(dropbox)), false, None);
+        }
+        ("session", Some(session_matches)) => {
+            let lifetime = match session_matches.value_of("expire after") {
+                Some(minutes) => Some(Duration::from_secs(parse_scaled(minutes, "minutes", 60)?)),
+                None => None,
+            };
+            let mut items = Vec::new();
+            for item in session_matches.values_of("ITEM").into_iter().flatten() {
+                let item = bundle::Item::parse(item)
+                    .map_err(|e| format!("Could not add {}: {}", item, e))?;
+                items.push(item);
+            }
+            let bundle = bundle::Bundle::new(items, lifetime);
+            return serve(session_matches, Mode::Bundle(Arc::new(bundle)), false, None);
+        }
+        ("neighbors", Some(neighbors_matches)) => {
+            let remembered = selection::load();
+            let interface = choose_interface(
+                &network::SystemNetwork,
+                neighbors_matches,
+                remembered.as_ref(),
+            )?;
+            neighbors::write_neighbors(&interface, &mut io::stdout())?;
+            return Ok(());
+        }
+        ("relay", Some(relay_matches)) => {
+            return match relay_matches.subcommand() {
+                ("send", Some(send_matches)) => relay::run_relay_send(
+                    send_matches.value_of("RELAY").unwrap(),
+                    Path::new(send_matches.value_of("FILE").unwrap()),
+                ),
+                ("get", Some(get_matches)) => relay::run_relay_get(
+                    get_matches.value_of("RELAY").unwrap(),
+                    get_matches.value_of("CODE").unwrap(),
+                    get_matches.value_of("output").map(PathBuf::from),
+                ),
+                _ => serve(relay_matches, Mode::Relay(Arc::default()), false, None),
+            }
+        }
+        ("send", Some(send_matches)) => {
+            return resume::run_send(
+                send_matches.value_of("URL").unwrap(),
+                Path::new(send_matches.value_of("FILE").unwrap()),
+                send_matches.value_of("name"),
+            )
+        }
+        ("replay", Some(replay_matches)) => {
+            return record::run_replay(
+                Path::new(replay_matches.value_of("FILE").unwrap()),
+                replay_matches.value_of("URL").unwrap(),
+                replay_matches.is_present("fast"),
+            )
+        }
+        ("get", Some(get_matches)) => {
+            return get::run_get(
+                get_matches.value_of("URL").unwrap(),
+                get_matches.value_of("output").map(PathBuf::from),
+                get_matches.value_of("parallel").unwrap().parse()?,
+            )
+        }
+        _ => {}
+    }
+
+    let path = PathBuf::from(matches.value_of("PATH").unwrap_or("."));
+    let remove_source = matches.is_present("move");
+    if remove_source && path.is_dir() {
+        return Err(Box::new(MoveDirectoryError::new(path)));
+    }
+    let state_file = matches
+        .value_of("state file")
+        .map(|file| state::StateFile::open(PathBuf::from(file), &path))
+        .transpose()?
+        .map(Arc::new);
+    let restored = state_file.as_ref().and_then(|s| s.get_restored());
+    let access_mode = get_access_mode(matches)?;
+    let mode = if let Some(text) = matches.value_of("text") {
+        let text = qr::read_text(text, &mut io::stdin())?;
+        let name = matches.value_of("name").unwrap_or(snippet::DEFAULT_NAME);
+        Mode::Text(Arc::new(snippet::Snippet::new(name, text)))
+    } else if let Some(mounts) = matches.values_of("mount") {
+        let mounts = mounts
+            .map(str::parse)
+            .collect::<Result<Vec<mounts::Mount>, _>>()?;
+        Mode::Mounts(Arc::new(mounts::MountTable::new(
+            mounts,
+            get_protect_rules(matches)?,
+        )?))
+    } else if matches.is_present("screen") {
+        let region = matches
+            .value_of("screen region")
+            .map(str::parse)
+            .transpose()?;
+        Mode::Screen(Arc::new(screen::ScreenStream::new(region)))
+    } else if let Some(command) = matches.value_of("exec") {
+        let source = live::LiveSource::Command(command.to_string());
+        Mode::Live(Arc::new(live::LiveOutput::new(source)))
+    } else if access_mode == methods::AccessMode::Receive {
+        let quarantine = match matches.value_of("quarantine") {
+            Some(q) => Some(path.join(q)),
+            None if matches.is_present("on receive") || matches.is_present("scan") => {
+                Some(path.join(DEFAULT_QUARANTINE))
+            }
+            None => None,
+        };
+        let append_log = matches
+            .value_of("append")
+            .map(|a| receive::AppendLog::new(path.join(a)));
+        let mut inbox = receive::Inbox::new(
+            path.clone(),
+            quarantine,
+            matches.value_of("on receive").map(String::from),
+            append_log,
+            matches.value_of("dedupe").map(str::parse).transpose()?,
+            matches.is_present("ask name"),
+            notify::Notifier {
+                command: matches.value_of("notify cmd").map(String::from),
+                email: matches
+                    .value_of("notify email")
+                    .map(|address| notify::Email {
+                        server: matches
+                            .value_of("smtp server")
+                            .unwrap_or(notify::DEFAULT_SMTP_SERVER)
+                            .to_string(),
+                        address: address.to_string(),
+                    }),
+            },
+        );
+        if let Some(pin) = matches.value_of("list received") {
+            inbox = inbox.with_listing(pin)?;
+            eprintln!(
+                "Listing received files at {} for PIN holders",
+                receive::LISTING_PATH
+            );
+        }
+        if let Some(scanner) = matches.value_of("scan") {
+            let scanner: scan::Scanner = scanner.parse()?;
+            eprintln!("Scanning received files with {}", scanner);
+            inbox = inbox.with_scanner(scanner);
+        }
+        if matches.is_present("porcelain") {
+            inbox = inbox.with_porcelain();
+        }
+        if matches.is_present("to clipboard") {
+            inbox = inbox.with_clipboard();
+        }
+        if let Some(target) = matches.value_of("store to") {
+            inbox = inbox.with_storage(storage::open(target)?);
+        }
+        if let Some(restored) = restored {
+            restore_uploads(&inbox, &restored.uploads)?;
+        }
+        inbox = inbox.with_space_check(get_min_free(matches)?);
+        if let Some(quota) = matches.value_of("quota") {
+            let limit = transfer::parse_size(quota)?;
+            let used = receive::get_directory_size(&path)?;
+            eprintln!(
+                "{} of the quota of {} used",
+                transfer::format_size(used),
+                transfer::format_size(limit)
+            );
+            inbox = inbox.with_quota(receive::Quota::new(limit, used));
+        }
+        Mode::Receive(Arc::new(inbox))
+    } else if matches.is_present("index") || matches.is_present("spa") {
+        let access = get_protect_rules(matches)?.load(&path)?;
+        let site = site::Site::new(path.clone(), matches.is_present("spa"), access);
+        if !site.has_index() {
+            return Err(Box::new(MissingIndexError::new(path)));
+        }
+        Mode::Site(Arc::new(site))
+    } else if matches.is_present("cas") {
+        eprintln!("Hashing the files in {}", path.display());
+        Mode::Cas(Arc::new(cas::ContentStore::open(path)?))
+    } else if matches.is_present("explode") {
+        match archive::ArchiveKind::from_path(&path) {
+            Some(kind) => Mode::Archive(Arc::new(archive::ArchiveShare::new(path, kind)?)),
+            None => return Err(Box::new(UnsupportedArchiveError::new(path))),
+        }
+    } else if remote::is_remote(matches.value_of("PATH").unwrap_or_default()) {
+        let share = remote::RemoteShare::open(matches.value_of("PATH").unwrap())?;
+        Mode::Remote(Arc::new(share))
+    } else if device::is_stream_source(&path) {
+        Mode::Device(Arc::new(device::DeviceStream::new(
+            path,
+            matches.value_of("name"),
+            matches.value_of("mime"),
+        )))
+    } else if path.is_dir() {
+        return Err(Box::new(DirectoryShareError::new(path)));
+    } else {
+        let mut share = Share::new(path);
+        share.confirm = remove_source;
+        if matches.is_present("pieces") {
+            let piece_size = match matches.value_of("piece size") {
+                Some(s) => parse_scaled(s, "MiB", 1024 * 1024)?,
+                None => pieces::DEFAULT_PIECE_SIZE,
+            };
+            eprintln!("Hashing pieces of {}", share.path.display());
+            share.pieces = Some(pieces::create_piece_manifest(
+                &share.path,
+                share.file_name.clone(),
+                piece_size,
+            )?);
+        }
+        if let Some(key) = matches.value_of("sign") {
+            share.signature = Some(crypto::create_signature(&share.path, key)?);
+            eprintln!("Serving a signature of the file at /signature.asc");
+        }
+        share.encryption = matches
+            .value_of("encrypt to")
+            .map(|r| crypto::Encryption::new(r.to_string()));
+        if let Some(count) = matches.value_of("tokens") {
+            let links = match restored.filter(|r| !r.links.is_empty()) {
+                Some(restored) => {
+                    let links = tokens::LinkSet::restore(restored.links.clone());
+                    if links.is_exhausted() {
+                        return Err(Box::new(ShareEndedError::LinksUsed));
+                    }
+                    eprintln!(
+                        "Continuing with the {} one-time links of the last run",
+                        links.get_links().len()
+                    );
+                    links
+                }
+                None => tokens::LinkSet::new(count.parse()?),
+            };
+            share.links = Some(links);
+        }
+        if let Some(count) = matches.value_of("broadcast") {
+            let require_name = matches.is_present("require name");
+            share.broadcast = Some(Arc::new(broadcast::Broadcast::new(
+                count.parse()?,
+                require_name,
+            )));
+        }
+        if access_mode == methods::AccessMode::Exchange {
+            // Without a directory, uploads land next to the shared file.
+            let dir = match matches.value_of("exchange") {
+                Some(dir) => PathBuf::from(dir),
+                None => match share.path.parent() {
+                    Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                    _ => PathBuf::from("."),
+                },
+            };
+            let mut inbox = receive::Inbox::new(
+                dir,
+                None,
+                None,
+                None,
+                None,
+                false,
+                notify::Notifier::default(),
+            )
+            .with_space_check(get_min_free(matches)?);
+            if matches.is_present("to clipboard") {
+                inbox = inbox.with_clipboard();
+            }
+            if let Some(restored) = restored {
+                restore_uploads(&inbox, &restored.uploads)?;
+            }
+            Mode::Exchange(Arc::new(share), Arc::new(inbox))
+        } else {
+            Mode::Send(Arc::new(share))
+        }
+    };
+
+    let share = match &mode {
+        Mode::Send(share) => Some(share.clone()),
+        _ => None,
+    };
+    serve(matches, mode, remove_source, state_file)?;
+
+    if let Some(share) = share {
+        if remove_source && share.transferred.load(Ordering::SeqCst) {
+            remove_source_file(&share.path)?;
+        } else if remove_source && share.downloaded.load(Ordering::SeqCst) {
+            eprintln!(
+                "Kept {}, the recipient didn't confirm the download",
+                share.path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Lets `inbox` continue the interrupted uploads of the last run.
+fn restore_uploads(
+    inbox: &receive::Inbox,
+    uploads: &[state::PartialUpload],
+) -> Result<(), Box<dyn error::Error>> {
+    let count = inbox.restore_uploads(uploads)?;
+    if count > 0 {
+        eprintln!(
+            "{} interrupted uploads of the last run can be continued",
+            count
+        );
+    }
+    Ok(())
+}
+
+/// Collects the rules given with `--protect`.
+fn get_protect_rules(
+    matches: &clap::ArgMatches,
+) -> Result<access::AccessRules, Box<dyn error::Error>> {
+    let rules = match matches.values_of("protect") {
+        Some(values) => values.map(str::parse).collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+    Ok(access::AccessRules::new(rules))
+}
+
+/// Options only receiving uses
+const RECEIVE_OPTIONS: [&str; 11] = [
+    "store to",
+    "quarantine",
+    "append",
+    "dedupe",
+    "list received",
+    "quota",
+    "ask name",
+    "notify cmd",
+    "notify email",
+    "on receive",
+    "scan",
+];
+/// Options that change what is sent, and can't be used while receiving
+const SEND_OPTIONS: [&str; 11] = [
+    "move",
+    "explode",
+    "index",
+    "spa",
+    "pieces",
+    "sign",
+    "encrypt to",
+    "tokens",
+    "broadcast",
+    "name",
+    "mime",
+];
+/// Options an exchange doesn't support on the sending side
+const EXCHANGE_EXCLUDED_OPTIONS: [&str; 6] =
+    ["move", "explode", "index", "spa", "tokens", "broadcast"];
+
+/// The mode from `--mode`, or from `--receive` and `--exchange`, after checking that the other
+/// options fit it.
+fn get_access_mode(
+    matches: &clap::ArgMatches,
+) -> Result<methods::AccessMode, Box<dyn error::Error>> {
+    let mode = match matches.value_of("mode") {
+        Some(mode) => mode.parse()?,
+        None if matches.is_present("receive") => methods::AccessMode::Receive,
+        None if matches.is_present("exchange") => methods::AccessMode::Exchange,
+        None => methods::AccessMode::Send,
+    };
+    if matches.is_present("exchange") && mode != methods::AccessMode::Exchange {
+        return Err(format!("--exchange can't be used with --mode {}", mode).into());
+    }
+    if matches.is_present("mode")
+        && mode != methods::AccessMode::Receive
+        && !matches.is_present("PATH")
+    {
+        return Err(format!("--mode {} needs a PATH to share", mode).into());
+    }
+    let excluded = match mode {
+        methods::AccessMode::Send => &RECEIVE_OPTIONS[..],
+        methods::AccessMode::Receive => &SEND_OPTIONS[..],
+        methods::AccessMode::Exchange => &EXCHANGE_EXCLUDED_OPTIONS[..],
+    };
+    let receive_only = match mode {
+        methods::AccessMode::Exchange => &RECEIVE_OPTIONS[..],
+        _ => &[],
+    };
+    let option = excluded
+        .iter()
+        .chain(receive_only)
+        .find(|o| matches.is_present(o));
+    match option {
+        Some(option) => Err(format!(
+            "--{} can't be used with --mode {}",
+            option.replace(' ', "-"),
+            mode
+        )
+        .into()),
+        None => Ok(mode),
+    }
+}
+
+/// Parses a number of `unit`s given on the command line and multiplies it by `factor`.
+fn parse_scaled(
+    value: &str,
+    unit: &'static str,
+    factor: u64,
+) -> Result<u64, Box<dyn error::Error>> {
+    value
+        .parse::<u64>()?
+        .checked_mul(factor)
+        .ok_or_else(|| TooLargeError::new(value, unit).into())
 }
 
-pub fn get_network_interfaces() -> HashMap<String, datalink::NetworkInterface> {
-    let mut interface_map = HashMap::<String, datalink::NetworkInterface>::new();
-    for interface in datalink::interfaces() {
-        if !interface.ips.is_empty() {
-            interface_map.insert(String::from(&interface.name), interface);
-        }
-    }
-    interface_map
+/// The free space below which `--min-free` warns, when receiving.
+fn get_min_free(matches: &clap::ArgMatches) -> Result<u64, Box<dyn error::Error>> {
+    Ok(transfer::parse_size(matches.value_of("min free").unwrap())?)
 }
 
-fn create_qr_code(data: String) -> String {
-    QrCode::new(data)
-        .unwrap()
-        .render()
-        .light_color(" ")
-        .dark_color("█")
-        .module_dimensions(2, 1)
-        .build()
+fn run_sync(matches: &clap::ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let root = PathBuf::from(matches.value_of("DIR").unwrap());
+    let policy = matches.value_of("conflict").unwrap().parse()?;
+    match matches.value_of("peer") {
+        Some(peer) => sync::run_sync_client(&root, peer, policy),
+        None => serve(
+            matches,
+            Mode::Sync(Arc::new(sync::SyncRoot::new(root))),
+            false,
+            None,
+        ),
+    }
 }
 
-fn select_item(
-    choice: String,
-    choices: &[String],
-) -> Result<(usize, String), Box<dyn error::Error>> {
-    let choice_num = match choice.trim().parse::<usize>() {
-        Ok(n) => n,
-        Err(e) => return Err(Box::new(e)),
+fn serve(
+    matches: &clap::ArgMatches,
+    mode: Mode,
+    stop_after_transfer: bool,
+    state_file: Option<Arc<state::StateFile>>,
+) -> Result<(), Box<dyn error::Error>> {
+    // Counting runs while the interface is chosen.
+    let paths = mode.get_paths();
+    let calculation =
+        sizes::SizeCalculation::start(paths.iter().map(|(_, path)| path.clone()).collect());
+    let warn_size = transfer::parse_size(
+        matches
+            .value_of("warn size")
+            .unwrap_or(sizes::DEFAULT_WARN_SIZE),
+    )?;
+    let window = schedule::Window::new(
+        matches
+            .value_of("from")
+            .map(schedule::parse_time)
+            .transpose()?,
+        matches
+            .value_of("until")
+            .map(schedule::parse_time)
+            .transpose()?,
+    )?;
+    let low_memory = matches.is_present("low memory");
+    if low_memory {
+        transfer::use_small_chunks();
+    }
+    let mut options = ServerOptions {
+        stop_after_transfer,
+        window,
+        transfer_limit: match matches.value_of("max active transfers") {
+            Some(max) => Some(Arc::new(transfer::TransferLimit::new(max.parse()?))),
+            None if low_memory => Some(Arc::new(transfer::TransferLimit::new(
+                LOW_MEMORY_MAX_TRANSFERS,
+            ))),
+            None => None,
+        },
+        rate_limit: match matches.value_of("limit rate") {
+            Some(rate) => Some(Arc::new(transfer::RateLimit::new(transfer::parse_rate(
+                rate,
+            )?))),
+            None => None,
+        },
+        client_rate: matches
+            .value_of("limit rate per client")
+            .map(transfer::parse_rate)
+            .transpose()?,
+        upload_rate_limit: match matches.value_of("limit upload rate") {
+            Some(rate) => Some(Arc::new(transfer::RateLimit::new(transfer::parse_rate(
+                rate,
+            )?))),
+            None => None,
+        },
+        client_upload_rate: matches
+            .value_of("limit upload rate per client")
+            .map(transfer::parse_rate)
+            .transpose()?,
+        cache_control: matches
+            .value_of("cache control")
+            .map(str::parse)
+            .transpose()?,
+        noindex: false,
+        porcelain: matches.is_present("porcelain"),
+        url_only: matches.is_present("url only"),
+        verbosity: matches.occurrences_of("verbose"),
+        beacon: matches.is_present("beacon"),
+        landing: None,
+        kiosk: None,
+        short: None,
+        wifi: matches
+            .value_of("wifi")
+            .map(wifi::get_network)
+            .transpose()?,
+        speedtest: matches.is_present("speedtest"),
+        chat: matches.is_present("chat"),
+        timeouts: timeouts::Timeouts {
+            read: matches
+                .value_of("read timeout")
+                .map(timeouts::parse_seconds)
+                .transpose()?
+                .flatten(),
+            write: timeouts::parse_seconds(matches.value_of("write timeout").unwrap())?,
+            keep_alive: timeouts::parse_seconds(matches.value_of("keep alive").unwrap())?,
+            header: timeouts::parse_seconds(matches.value_of("header timeout").unwrap())?,
+            body: timeouts::parse_seconds(matches.value_of("body timeout").unwrap())?,
+            drain: timeouts::parse_seconds(matches.value_of("drain timeout").unwrap())?,
+        },
+        max_half_open: match matches.value_of("max half open").unwrap().parse()? {
+            0 => None,
+            max => Some(max),
+        },
+        limits: limits::RequestLimits {
+            headers: matches.value_of("max headers").unwrap().parse()?,
+            header_bytes: transfer::parse_size(matches.value_of("max header size").unwrap())?
+                as usize,
+            uri_length: matches.value_of("max uri length").unwrap().parse()?,
+        },
+        low_memory,
+        state_file,
+        recorder: match matches.value_of("record") {
+            Some(path) => Some(Arc::new(
+                record::Recorder::create(Path::new(path), matches.is_present("record bodies"))
+                    .map_err(|e| format!("Could not open {}: {}", path, e))?,
+            )),
+            None => None,
+        },
+        resolve_names: matches.is_present("resolve names"),
+        wsd: None,
+        metrics: None,
+        embedding: None,
     };
-
-    if choice_num >= choices.len() {
-        Err(Box::new(ChoiceError::new(0, choices.len() - 1)))
+    let network = Arc::new(network::SystemNetwork);
+    let address = get_network_socket(&*network, matches)?;
+    if matches.is_present("check") {
+        if let Some(interface) = find_interfaces(&*network).get(&address.interface) {
+            check::run_check(interface, address.socket);
+        }
+        return Ok(());
+    }
+    let confirm = !matches.is_present("yes") && io::stdin().is_terminal();
+    let totals = if matches.is_present("dry run") || confirm {
+        Some(calculation.wait())
     } else {
-        Ok((choice_num, choices[choice_num].clone()))
+        report_sizes(
+            calculation,
+            paths.clone(),
+            warn_size,
+            options.url_only && options.verbosity == 0,
+        );
+        None
+    };
+    if matches.is_present("dry run") {
+        let totals = totals.unwrap_or_default();
+        print_size_warnings(&paths, &totals, warn_size);
+        return print_dry_run(matches, &address, &mode, &totals);
     }
-}
-
-fn choose_number(
-    message: String,
-    choices: Vec<String>,
-) -> Result<(usize, String), Box<dyn std::error::Error>> {
-    println!("{}", message);
-    for (index, choice) in choices.iter().enumerate() {
-        println!("{} - {}", index, choice);
+    let interfaces = find_interfaces(&*network).into_values().collect::<Vec<_>>();
+    if let Some(warning) =
+        check::check_network(&address.interface, address.socket.ip(), &interfaces)
+    {
+        output::print_warning(&warning);
     }
-    let mut choice_num_str = String::new();
-    io::stdin().read_line(&mut choice_num_str).unwrap();
-
-    select_item(choice_num_str, &choices)
+    // Scripts without a terminal to answer on have to pass --yes anyway to open the firewall.
+    if let Some(totals) = totals {
+        print_size_warnings(&paths, &totals, warn_size);
+        confirm_share(matches, &address, &mode, &totals)?;
+    }
+    let port = address.socket.port();
+    let open_port = if matches.is_present("open firewall") {
+        firewall::open_port(&address.interface, port, matches.is_present("yes"))?
+    } else {
+        None
+    };
+    if open_port.is_none() {
+        for warning in check::check_firewall(&address.interface, port) {
+            eprintln!("Warning: {}", warning);
+        }
+    }
+    let mut urls = vec![address.url.clone()];
+    if let Some((_, alternate_url)) = &address.alternate {
+        urls.push(alternate_url.clone());
+    }
+    if matches.is_present("deep link") {
+        options.landing = Some(Arc::new(landing::Landing::new(urls).with_deep_link()));
+    } else if urls.len() > 1 {
+        options.landing = Some(Arc::new(landing::Landing::new(urls)));
+    }
+    if matches.is_present("kiosk") {
+        if matches!(mode, Mode::Send(_) | Mode::Archive(_) | Mode::Remote(_)) {
+            let countdown = matches.value_of("countdown").unwrap().parse()?;
+            options.kiosk = Some(Arc::new(kiosk::Kiosk::new(countdown)));
+        } else {
+            eprintln!("--kiosk only applies when sending a file, ignoring it");
+        }
+    }
+    if matches.is_present("short") {
+        let paths = match get_link_urls(&address.url, &mode) {
+            Some(link_urls) => link_urls
+                .iter()
+                .map(|u| u[address.url.len()..].to_string())
+                .collect(),
+            None => vec![String::from("/")],
+        };
+        options.short = Some(Arc::new(short::ShortLinks::new(paths)));
+    }
+    options.noindex = !matches.is_present("allow indexing")
+        && (matches.is_present("domain") || robots::is_public(address.socket.ip()));
+    if matches.is_present("wsd") {
+        let url = match &mode {
+            Mode::Bundle(bundle) => bundle.get_url(&address.url),
+            _ => address.url.clone(),
+        };
+        let url = get_qr_url(
+            &address.url,
+            &url,
+            options.landing.as_deref(),
+            options.kiosk.as_deref(),
+        );
+        let name = format!("{} (rustbelt)", get_share_title(&mode));
+        options.wsd = Some(Arc::new(wsd::Announcer::new(&name, &address.url, &url)));
+    }
+    if let Some(path) = matches.value_of("metrics textfile") {
+        let textfile =
+            metrics::Textfile::new(Path::new(path), mode.get_name(), &get_share_title(&mode));
+        if let Some(warning) = textfile.check_path() {
+            eprintln!("Warning: {}", warning);
+        }
+        options.metrics = Some(Arc::new(textfile));
+    }
+    if let Some(file) = matches.value_of("print sheet") {
+        create_share_sheet(matches, &address.url, &mode, &options)?.write(Path::new(file))?;
+        eprintln!("Wrote a printable share sheet to {}", file);
+    }
+    run_http_server(network, address, mode, options)?;
+    drop(open_port);
+    Ok(())
 }
 
-fn choose_ip(
-    message: String,
-    choices: Vec<IpString>,
-) -> Result<(usize, IpString), Box<dyn error::Error>> {
-    let (interface_num, ip_string) = choose_number(
-        message,
-        choices
-            .iter()
-            .map(|ip| match ip {
-                IpString::V4(s) => s.clone(),
-                IpString::V6(s) => s.clone(),
-            })
-            .collect(),
-    )?;
-    Ok((
-        interface_num,
-        match choices[interface_num] {
-            IpString::V4(_) => IpString::V4(ip_string),
-            IpString::V6(_) => IpString::V6(ip_string),
+/// What the share is called on the share sheet and in the network neighborhood
+fn get_share_title(mode: &Mode) -> String {
+    match mode {
+        Mode::Send(share) | Mode::Exchange(share, _) => share.file_name.clone(),
+        _ => match mode.get_paths().first() {
+            Some((_, path)) => path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into(),
+            None => String::from("rustbelt"),
         },
-    ))
+    }
 }
 
-async fn hello(_: Request<Body>) -> Result<Response<Body>, Infallible> {
-    Ok(Response::new(Body::from("Hello World!")))
+/// The sheet for `--print-sheet`, with the links of the share, the PINs it asks for and the time it
+/// ends.
+fn create_share_sheet(
+    matches: &clap::ArgMatches,
+    url: &str,
+    mode: &Mode,
+    options: &ServerOptions,
+) -> Result<sheet::Sheet, Box<dyn error::Error>> {
+    let title = get_share_title(mode);
+    let links = get_link_urls(url, mode)
+        .unwrap_or_else(|| vec![url.to_string()])
+        .into_iter()
+        .map(|link_url| sheet::SheetLink {
+            qr_text: get_qr_url(
+                url,
+                &link_url,
+                options.landing.as_deref(),
+                options.kiosk.as_deref(),
+            ),
+            url: link_url,
+        })
+        .collect();
+    let mut notes = Vec::new();
+    if let Some(values) = matches.values_of("protect") {
+        for rule in values.map(str::parse::<access::AccessRule>) {
+            let rule = rule?;
+            notes.push(format!(
+                "PIN for {}: {}",
+                rule.get_pattern(),
+                rule.get_pin()
+            ));
+        }
+    }
+    if let Some(pin) = matches.value_of("list received") {
+        notes.push(format!("PIN for the list of received files: {}", pin));
+    }
+    if let Some(until) = options.window.get_until() {
+        notes.push(format!(
+            "Available until {}",
+            until.format("%Y-%m-%d %H:%M")
+        ));
+    }
+    Ok(sheet::Sheet {
+        title,
+        links,
+        notes,
+    })
 }
 
-async fn shutdown_signal() {
-    tokio::signal::ctrl_c()
-        .await
-        .expect("failed to install CTRL+C signal handler");
-    println!("Shutting down server");
+/// Describes a path with its size, and its number of files if it is one of the counted directories.
+fn describe_path(path: &Path, totals: &HashMap<PathBuf, sizes::Totals>) -> String {
+    match totals.get(path) {
+        Some(totals) => format!(
+            "{} ({}, {})",
+            path.display(),
+            sizes::format_file_count(totals.files),
+            transfer::format_size(totals.size)
+        ),
+        None => match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                format!(
+                    "{} ({})",
+                    path.display(),
+                    transfer::format_size(metadata.len())
+                )
+            }
+            _ => path.display().to_string(),
+        },
+    }
 }
 
-#[tokio::main]
-async fn run_http_server(
-    socket: std::net::SocketAddr,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(hello)) });
-
-    let server = Server::bind(&socket).serve(make_svc);
-
-    let graceful = server.with_graceful_shutdown(shutdown_signal());
-
-    if let Err(e) = graceful.await {
-        eprintln!("server error: {}", e);
+/// Warns about shared directories larger than `limit`. Directories receiving uploads may grow as
+/// large as they like.
+fn print_size_warnings(
+    paths: &[(&str, PathBuf)],
+    totals: &HashMap<PathBuf, sizes::Totals>,
+    limit: u64,
+) {
+    for (label, path) in paths {
+        if *label == RECEIVING_LABEL {
+            continue;
+        }
+        if let Some(warning) = totals
+            .get(path)
+            .and_then(|t| sizes::check_size(path, *t, limit))
+        {
+            output::print_warning(&warning);
+        }
     }
-
-    Ok(())
 }
 
-fn get_network_socket(
-    matches: &clap::ArgMatches,
-) -> Result<(String, net::SocketAddr), Box<dyn error::Error>> {
-    let interface_map = get_network_interfaces();
-    let network_interface = if matches.occurrences_of("network interface") == 1 {
-        match interface_map.get(matches.value_of("network interface").unwrap()) {
-            Some(i) => i,
-            None => {
-                return Err(Box::new(NetworkInterfaceExistanceError::new(
-                    matches.value_of("network interface").unwrap().to_string(),
-                )))
+/// Reports the sizes of the shared directories once they are counted, while the server runs.
+fn report_sizes(
+    calculation: sizes::SizeCalculation,
+    paths: Vec<(&'static str, PathBuf)>,
+    limit: u64,
+    quiet: bool,
+) {
+    std::thread::spawn(move || {
+        let totals = calculation.wait();
+        if !quiet {
+            for (label, path) in &paths {
+                if totals.contains_key(path) {
+                    output::print_event(&format!("{} {}", label, describe_path(path, &totals)));
+                }
             }
         }
+        print_size_warnings(&paths, &totals, limit);
+    });
+}
+
+/// Who can get at the share, from the options restricting it
+fn describe_access(matches: &clap::ArgMatches) -> String {
+    let mut restrictions = Vec::new();
+    if let Some(count) = matches.value_of("tokens") {
+        restrictions.push(format!("{} one-time links", count));
+    }
+    if matches.is_present("protect") {
+        restrictions.push(String::from("password protected paths"));
+    }
+    if matches.is_present("require name") || matches.is_present("ask name") {
+        restrictions.push(String::from("everyone has to enter their name"));
+    }
+    if let Some(recipient) = matches.value_of("encrypt to") {
+        restrictions.push(format!("encrypted to {}", recipient));
+    }
+    if restrictions.is_empty() {
+        String::from("anyone who can reach the URL")
     } else {
-        println!("Found network interfaces, choose one:");
-        let mut interface_names = interface_map.keys().cloned().collect::<Vec<String>>();
-        interface_names.sort();
-        let (interface_num, _) = choose_number(
-            String::from("Found network interfaces, choose one:"),
-            interface_names.clone(),
-        )?;
-
-        &interface_map[&interface_names[interface_num]]
-    };
+        restrictions.join(", ")
+    }
+}
 
-    if matches.occurrences_of("verbose") >= 1 {
-        println!("{:#?}", network_interface);
+/// Lists the files below `path` in order, or `path` itself if it is a file.
+fn list_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !fs::metadata(path)?.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    let mut files = Vec::new();
+    for entry in entries {
+        files.append(&mut list_files(&entry)?);
     }
+    Ok(files)
+}
 
-    let (ipaddr_count, ipaddr_string) = choose_ip(
-        String::from("Choose an IP address:"),
-        network_interface
-            .ips
-            .iter()
-            .map(|ip| match ip {
-                ipnetwork::IpNetwork::V4(ipv4) => IpString::V4(format!(
-                    "{}.{}.{}.{}",
-                    ipv4.ip().octets()[0],
-                    ipv4.ip().octets()[1],
-                    ipv4.ip().octets()[2],
-                    ipv4.ip().octets()[3]
-                )),
-                ipnetwork::IpNetwork::V6(ipv6) => IpString::V6(format!(
-                    "{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
-                    ipv6.ip().segments()[0],
-                    ipv6.ip().segments()[1],
-                    ipv6.ip().segments()[2],
-                    ipv6.ip().segments()[3],
-                    ipv6.ip().segments()[4],
-                    ipv6.ip().segments()[5],
-                    ipv6.ip().segments()[6],
-                    ipv6.ip().segments()[7]
-                )),
-            })
-            .collect(),
-    )?;
-    let socket = create_socket(
-        network_interface.ips[ipaddr_count],
-        matches.value_of("port").unwrap().parse::<u16>()?,
-    );
-    let url = create_url(
-        ipaddr_string,
-        matches.value_of("port").unwrap().parse::<u16>()?,
-    );
-    Ok((url, socket))
+/// The rows describing what is about to be served, where and to whom
+fn get_share_summary<'a>(
+    matches: &clap::ArgMatches,
+    address: &Address,
+    mode: &Mode,
+    totals: &HashMap<PathBuf, sizes::Totals>,
+) -> Vec<(&'a str, String)> {
+    let mut summary = mode
+        .get_paths()
+        .iter()
+        .map(|(label, path)| (*label, describe_path(path, totals)))
+        .collect::<Vec<_>>();
+    if let Mode::Remote(share) = mode {
+        summary.push(("Proxying", share.describe()));
+    }
+    if let Mode::Text(snippet) = mode {
+        summary.push(("Sharing", snippet.describe()));
+    }
+    summary.push(("Mode", mode.get_name().to_string()));
+    summary.push(("Interface", address.interface.clone()));
+    summary.push(("URL", address.url.clone()));
+    summary.push(("Access", describe_access(matches)));
+    summary
 }
 
-fn create_socket(ip: ipnetwork::IpNetwork, port: u16) -> net::SocketAddr {
-    match ip {
-        ipnetwork::IpNetwork::V4(v4) => {
-            std::net::SocketAddr::V4(std::net::SocketAddrV4::new(v4.ip(), port))
-        }
-        ipnetwork::IpNetwork::V6(v6) => {
-            std::net::SocketAddr::V6(std::net::SocketAddrV6::new(v6.ip(), port, 0, 0))
+/// Prints what would be served without binding, for `--dry-run`. The summary and QR code go to
+/// stderr, the shared files to stdout, one per line, or the URLs with `--url-only`.
+fn print_dry_run(
+    matches: &clap::ArgMatches,
+    address: &Address,
+    mode: &Mode,
+    totals: &HashMap<PathBuf, sizes::Totals>,
+) -> Result<(), Box<dyn error::Error>> {
+    if matches.is_present("url only") {
+        print_plain_urls(&address.url, mode);
+        return Ok(());
+    }
+    output::print_summary(&get_share_summary(matches, address, mode, totals));
+    print_share_urls(&address.url, mode, None, None);
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for (_, path) in mode.get_paths() {
+        // The directory uploads go to may not exist yet.
+        if path.exists() {
+            for file in list_files(&path)? {
+                writeln!(stdout, "{}", file.display())?;
+            }
         }
     }
+    Ok(())
 }
 
-fn create_url(ip: IpString, port: u16) -> String {
-    match ip {
-        IpString::V4(v4) => format!("http://{}:{}", v4, port),
-        IpString::V6(v6) => format!("http://[{}]:{}", v6, port),
+/// Shows what is about to be served and asks whether to go ahead.
+fn confirm_share(
+    matches: &clap::ArgMatches,
+    address: &Address,
+    mode: &Mode,
+    totals: &HashMap<PathBuf, sizes::Totals>,
+) -> Result<(), Box<dyn error::Error>> {
+    output::print_summary(&get_share_summary(matches, address, mode, totals));
+    if output::confirm("Start serving?")? {
+        Ok(())
+    } else {
+        Err(Box::new(UserAbortError))
     }
 }
 
-pub fn run_rustbelt(matches: &clap::ArgMatches) -> Result<(), Box<dyn error::Error>> {
-    let (url, socket) = get_network_socket(matches)?;
-
-    println!("Listening on {}", url);
-
-    for split in create_qr_code(url).split('\n') {
-        println!("{}", split.black().on_white());
-    }
-    match run_http_server(socket) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
-    }
+fn remove_source_file(path: &Path) -> io::Result<()> {
+    self::trash::move_to_trash(path)?;
+    eprintln!(
+        "Moved {} to the trash, rustbelt trash restore brings it back",
+        path.display()
+    );
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use proptest::prelude::*;
+    use tokio::io::AsyncReadExt;
 
     prop_compose! {
         fn create_choice_test_vec(length: usize)(index in 0..length, test_vec in any_with::<Vec<String>>(proptest::collection::size_range(length).lift())) -> (usize, Vec<String>) {
@@ -294,16 +3250,30 @@ mod tests {
 
         #[test]
         fn test_url_creation_v4(a: u8, b: u8, c: u8, d: u8, p: u16) {
-            let ip_string = format!("{}.{}.{}.{}", a, b, c, d);
-            let url = create_url(IpString::V4(ip_string.clone()), p);
-            prop_assert_eq!(format!("http://{}:{}", ip_string, p), url);
+            let ip_addr = net::Ipv4Addr::new(a, b, c, d);
+            let ip = ipnetwork::IpNetwork::V4(ipnetwork::Ipv4Network::new(ip_addr, 32)?);
+            let url = create_url(create_ip_string(&ip), p);
+            prop_assert_eq!(format!("http://{}:{}", ip_addr, p), url.clone());
+            let socket: net::SocketAddr = url.trim_start_matches("http://").parse()?;
+            prop_assert_eq!(net::SocketAddr::new(net::IpAddr::V4(ip_addr), p), socket);
         }
 
         #[test]
         fn test_url_creation_v6(a: u16, b: u16, c: u16, d: u16, e: u16, f: u16, g: u16, h: u16, p: u16) {
-            let ip_string = format!("{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}", a, b, c, d, e, f, g, h);
-            let url = create_url(IpString::V6(ip_string.clone()), p);
-            prop_assert_eq!(format!("http://[{}]:{}", ip_string, p), url);
+            let ip_addr = net::Ipv6Addr::new(a, b, c, d, e, f, g, h);
+            let ip = ipnetwork::IpNetwork::V6(ipnetwork::Ipv6Network::new(ip_addr, 128)?);
+            let url = create_url(create_ip_string(&ip), p);
+            let socket: net::SocketAddr = url.trim_start_matches("http://").parse()?;
+            prop_assert_eq!(net::SocketAddr::new(canonicalize_ip(net::IpAddr::V6(ip_addr)), p), socket);
+        }
+
+        #[test]
+        fn test_url_creation_v6_compressed(a: u16, b: u16, p: u16) {
+            let ip_addr = net::Ipv6Addr::new(0xfe80, 0, 0, 0, a, 0, 0, b);
+            let ip = ipnetwork::IpNetwork::V6(ipnetwork::Ipv6Network::new(ip_addr, 64)?);
+            let url = create_url(create_ip_string(&ip), p);
+            prop_assert_eq!(format!("http://[{}]:{}", ip_addr, p), url.clone());
+            prop_assert!(url.starts_with("http://[fe80::"));
         }
 
         #[test]
@@ -333,6 +3303,14 @@ mod tests {
             }
         }
 
+        #[test]
+        fn test_parse_scaled_never_overflows(value: u64, factor in 1u64..=1024 * 1024) {
+            match value.checked_mul(factor) {
+                Some(scaled) => prop_assert_eq!(scaled, parse_scaled(&value.to_string(), "MiB", factor).unwrap()),
+                None => prop_assert!(parse_scaled(&value.to_string(), "MiB", factor).is_err()),
+            }
+        }
+
         #[test]
         fn test_choice_error_creation_u32(a: u32, b: u32) {
             let error = ChoiceError::new(a, b);
@@ -381,6 +3359,11 @@ mod tests {
             prop_assert!(debug_output.contains(&debug_b));
         }
 
+        #[test]
+        fn test_content_disposition(a in "[^\"\\\\]*") {
+            prop_assert_eq!(format!("attachment; filename=\"{}\"", a), create_content_disposition(&a));
+        }
+
         #[test]
         fn test_networkinterfaceexistanceerror_creation(a in "\\PC*") {
             let error = NetworkInterfaceExistanceError::new(a.clone());
@@ -403,9 +3386,612 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_canonicalize_ip() {
+        let mapped: net::IpAddr = "::ffff:192.168.1.2".parse().unwrap();
+        assert_eq!(net::IpAddr::from([192, 168, 1, 2]), canonicalize_ip(mapped));
+        let loopback: net::IpAddr = "::1".parse().unwrap();
+        assert_eq!(loopback, canonicalize_ip(loopback));
+    }
+
+    #[test]
+    fn test_find_other_family() {
+        let ips = ["fe80::1/64", "192.168.1.2/24", "2001:db8::2/64"]
+            .iter()
+            .map(|ip| ip.parse::<ipnetwork::IpNetwork>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(Some(ips[2]), find_other_family(&ips, ips[1].ip()));
+        assert_eq!(Some(ips[1]), find_other_family(&ips, ips[2].ip()));
+        assert_eq!(None, find_other_family(&ips[..2], ips[1].ip()));
+    }
+
+    #[test]
+    fn test_create_digest() {
+        assert_eq!(
+            "sha-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+            create_digest("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+    }
+
+    #[test]
+    fn test_describe_path() {
+        let mut totals = HashMap::new();
+        let path = PathBuf::from("/tmp/share");
+        totals.insert(path.clone(), sizes::Totals { files: 1, size: 3 });
+        assert_eq!(
+            "/tmp/share (1 file, 3 bytes)",
+            describe_path(&path, &totals)
+        );
+        totals.insert(path.clone(), sizes::Totals { files: 2, size: 3 });
+        assert_eq!(
+            "/tmp/share (2 files, 3 bytes)",
+            describe_path(&path, &totals)
+        );
+    }
+
+    #[test]
+    fn test_content_disposition_escaping() {
+        assert_eq!(
+            "attachment; filename=\"a\\\"b\\\\c\"",
+            create_content_disposition("a\"b\\c")
+        );
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let paths = |mode: &Mode| -> Vec<(String, String)> {
+            let endpoints = mode.get_endpoints().unwrap();
+            endpoints.into_iter().map(|e| (e.method, e.path)).collect()
+        };
+        let mut share = Share::new(PathBuf::from("notes.txt"));
+        let send = paths(&Mode::Send(Arc::new(Share::new(PathBuf::from(
+            "notes.txt",
+        )))));
+        assert!(send.contains(&("GET".into(), "/notes.txt".into())));
+        assert!(send.contains(&("GET".into(), "/SHA256SUMS".into())));
+        assert!(!send.iter().any(|(_, path)| path.ends_with("signature.asc")));
+        share.signature = Some(Vec::new());
+        share.links = Some(tokens::LinkSet::new(1));
+        let linked = paths(&Mode::Send(Arc::new(share)));
+        assert!(linked.contains(&("GET".into(), "/<token>/manifest.json".into())));
+        assert!(linked.contains(&("GET".into(), "/<token>/signature.asc".into())));
+        assert!(!linked.iter().any(|(_, path)| path.contains("notes")));
+
+        let help = Share::new(PathBuf::from("help"));
+        assert!(Mode::Send(Arc::new(help)).get_endpoints().is_none());
+    }
+
     #[test]
     fn test_create_qr_code() {
         let test_code = "                                                          \n                                                          \n                                                          \n                                                          \n        ██████████████      ██      ██████████████        \n        ██          ██  ██  ██  ██  ██          ██        \n        ██  ██████  ██        ██    ██  ██████  ██        \n        ██  ██████  ██    ████      ██  ██████  ██        \n        ██  ██████  ██  ████  ████  ██  ██████  ██        \n        ██          ██    ██  ██    ██          ██        \n        ██████████████  ██  ██  ██  ██████████████        \n                          ████                            \n        ██  ██  ██  ██      ██  ██      ██    ██          \n            ████████  ██    ████  ██  ██      ████        \n        ██  ██      ████████████  ██████  ████████        \n              ██████    ████████████  ████    ██          \n        ██  ██  ██  ██    ██████  ██████  ██  ████        \n                        ██          ██    ██    ██        \n        ██████████████    ██    ██      ████  ████        \n        ██          ██      ██      ██        ██          \n        ██  ██████  ██  ██████  ██  ██  ████  ████        \n        ██  ██████  ██      ████  ██  ██      ██          \n        ██  ██████  ██  ████████  ██████    ██  ██        \n        ██          ██      ████████  ██████  ██          \n        ██████████████  ████████  ██████    ██████        \n                                                          \n                                                          \n                                                          \n                                                          ";
         assert_eq!(test_code, create_qr_code(String::from("test")));
+        let ascii = draw_qr_code(&QrCode::new("test").unwrap(), true);
+        assert_eq!(test_code.replace('█', "#"), ascii);
+    }
+
+    #[test]
+    fn test_list_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("b")).unwrap();
+        fs::write(dir.path().join("b").join("c"), b"").unwrap();
+        fs::write(dir.path().join("a"), b"").unwrap();
+        fs::create_dir(dir.path().join("empty")).unwrap();
+        assert_eq!(
+            vec![dir.path().join("a"), dir.path().join("b").join("c")],
+            list_files(dir.path()).unwrap()
+        );
+        let file = dir.path().join("a");
+        assert_eq!(vec![file.clone()], list_files(&file).unwrap());
+    }
+    use network::mock::{create_interface, MockNetwork};
+    use network::Network;
+    use std::future::Future;
+    use tokio::io::AsyncWriteExt;
+
+    const SERVER_SOCKET: &str = "10.0.0.1:8080";
+    const CLIENT_SOCKET: &str = "10.0.0.2:40000";
+
+    fn create_options(stop_after_transfer: bool) -> ServerOptions {
+        ServerOptions {
+            stop_after_transfer,
+            window: schedule::Window::new(None, None).unwrap(),
+            transfer_limit: None,
+            rate_limit: None,
+            client_rate: None,
+            upload_rate_limit: None,
+            client_upload_rate: None,
+            cache_control: None,
+            noindex: false,
+            porcelain: false,
+            url_only: true,
+            verbosity: 0,
+            beacon: false,
+            landing: None,
+            kiosk: None,
+            short: None,
+            wifi: None,
+            speedtest: false,
+            chat: false,
+            timeouts: timeouts::Timeouts::default(),
+            max_half_open: None,
+            limits: limits::RequestLimits::default(),
+            low_memory: false,
+            state_file: None,
+            recorder: None,
+            resolve_names: false,
+            wsd: None,
+            metrics: None,
+            embedding: None,
+        }
+    }
+
+    fn create_network() -> (Arc<MockNetwork>, Address) {
+        let interface = create_interface("eth0", 2, &["10.0.0.1/24"]);
+        let address = Address {
+            interface: interface.name.clone(),
+            socket: SERVER_SOCKET.parse().unwrap(),
+            url: format!("http://{}", SERVER_SOCKET),
+            alternate: None,
+            dual_stack: false,
+        };
+        (Arc::new(MockNetwork::new(vec![interface])), address)
+    }
+
+    /// Runs `future` like `run_http_server` runs the server, not waiting for the console.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let output = runtime.block_on(future);
+        runtime.shutdown_timeout(Duration::from_millis(100));
+        output
+    }
+
+    /// Sends `req` to the server once it listens, from another device on the network.
+    async fn send_request(network: &MockNetwork, req: Request<Body>) -> Response<Body> {
+        let socket = SERVER_SOCKET.parse().unwrap();
+        while !network.is_bound(socket) {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        let stream = network
+            .connect(socket, CLIENT_SOCKET.parse().unwrap())
+            .unwrap();
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        sender.send_request(req).await.unwrap()
+    }
+
+    /// Serves `mode` on a mock network while `client` sends requests to it.
+    fn run_client<F, Fut>(mode: Mode, client: F) -> Fut::Output
+    where
+        F: FnOnce(Arc<MockNetwork>) -> Fut,
+        Fut: Future,
+    {
+        run_client_with(mode, create_options(false), client)
+    }
+
+    fn run_client_with<F, Fut>(mode: Mode, options: ServerOptions, client: F) -> Fut::Output
+    where
+        F: FnOnce(Arc<MockNetwork>) -> Fut,
+        Fut: Future,
+    {
+        let (network, address) = create_network();
+        let server = run_http_server_async(network.clone(), address, mode, options);
+        block_on(async move {
+            tokio::select! {
+                result = server => panic!("The server stopped: {:?}", result.err()),
+                output = client(network) => output,
+            }
+        })
+    }
+
+    async fn read_body(response: Response<Body>) -> Vec<u8> {
+        hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_choose_interface() {
+        let (network, _) = create_network();
+        let app = clap::App::new("rustbelt").arg(
+            clap::Arg::with_name("network interface")
+                .short("i")
+                .takes_value(true),
+        );
+        let matches = app.clone().get_matches_from(vec!["rustbelt", "-i", "eth0"]);
+        let interface = choose_interface(&*network, &matches, None).unwrap();
+        assert_eq!(
+            vec!["10.0.0.1/24".parse::<ipnetwork::IpNetwork>().unwrap()],
+            interface.ips
+        );
+        let matches = app.get_matches_from(vec!["rustbelt", "-i", "wlan0"]);
+        let error = choose_interface(&*network, &matches, None).unwrap_err();
+        assert!(error.is::<NetworkInterfaceExistanceError>());
+    }
+
+    #[test]
+    fn test_server_sends_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, "hello").unwrap();
+        let mode = Mode::Send(Arc::new(Share::new(path)));
+        run_client(mode, |network| async move {
+            let response =
+                send_request(&network, Request::get("/").body(Body::empty()).unwrap()).await;
+            assert_eq!(StatusCode::OK, response.status());
+            assert_eq!(
+                "attachment; filename=\"notes.txt\"",
+                response.headers()[header::CONTENT_DISPOSITION]
+            );
+            assert_eq!(b"hello".to_vec(), read_body(response).await);
+
+            let req = Request::head("/").body(Body::empty()).unwrap();
+            let response = send_request(&network, req).await;
+            assert_eq!("5", response.headers()[header::CONTENT_LENGTH]);
+            assert!(read_body(response).await.is_empty());
+
+            let req = Request::get("/")
+                .header(header::RANGE, "bytes=1-")
+                .body(Body::empty())
+                .unwrap();
+            let response = send_request(&network, req).await;
+            assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+            assert_eq!(b"ello".to_vec(), read_body(response).await);
+        });
+    }
+
+    #[test]
+    fn test_move_waits_for_confirmation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, "hello").unwrap();
+        let mut share = Share::new(path);
+        share.confirm = true;
+        let share = Arc::new(share);
+        let confirm = |sha256: &str| {
+            Request::post(CONFIRM_PATH)
+                .body(Body::from(sha256.to_string()))
+                .unwrap()
+        };
+        let sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        run_client(Mode::Send(share.clone()), |network| async move {
+            let response = send_request(&network, confirm(sha256)).await;
+            assert_eq!(StatusCode::CONFLICT, response.status());
+
+            let response =
+                send_request(&network, Request::get("/").body(Body::empty()).unwrap()).await;
+            assert_eq!(b"hello".to_vec(), read_body(response).await);
+            assert!(!share.transferred.load(Ordering::SeqCst));
+
+            let response = send_request(&network, confirm(&"0".repeat(64))).await;
+            assert_eq!(StatusCode::CONFLICT, response.status());
+            assert!(!share.transferred.load(Ordering::SeqCst));
+            let response = send_request(&network, confirm(sha256)).await;
+            assert_eq!(StatusCode::OK, response.status());
+            assert!(share.transferred.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn test_help_lists_the_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, "hello").unwrap();
+        let mut share = Share::new(path.clone());
+        share.pieces = Some(
+            pieces::create_piece_manifest(
+                &path,
+                share.file_name.clone(),
+                pieces::DEFAULT_PIECE_SIZE,
+            )
+            .unwrap(),
+        );
+        share.signature = Some(b"signature".to_vec());
+        let mut options = create_options(false);
+        options.speedtest = true;
+        options.noindex = true;
+        run_client_with(Mode::Send(Arc::new(share)), options, |network| async move {
+            let req = Request::get("/help?format=json")
+                .body(Body::empty())
+                .unwrap();
+            let response = send_request(&network, req).await;
+            let json: serde_json::Value =
+                serde_json::from_slice(&read_body(response).await).unwrap();
+            let endpoints = json["endpoints"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| {
+                    let method = e["method"].as_str().unwrap().to_string();
+                    (method, e["path"].as_str().unwrap().to_string())
+                })
+                .collect::<Vec<_>>();
+            // The routes `handle_request` and `serve_file` answer for this share
+            let routes = [
+                ("GET", "/notes.txt"),
+                ("HEAD", "/notes.txt"),
+                ("GET", "/SHA256SUMS"),
+                ("GET", manifest::MANIFEST_JSON_PATH),
+                ("GET", "/pieces"),
+                ("GET", "/pieces/<index>"),
+                ("GET", "/signature.asc"),
+                ("GET", help::HELP_PATH),
+                ("GET", speedtest::SPEEDTEST_PATH),
+                ("GET", robots::ROBOTS_PATH),
+                ("OPTIONS", "*"),
+            ];
+            for (method, path) in routes.iter() {
+                let route = (method.to_string(), path.to_string());
+                assert!(endpoints.contains(&route), "{} {} is missing", method, path);
+            }
+            for (method, path) in endpoints {
+                let uri = match path.as_str() {
+                    "*" => String::from("/"),
+                    _ => path.replace("<index>", "0"),
+                };
+                let req = Request::builder()
+                    .method(method.as_str())
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap();
+                let response = send_request(&network, req).await;
+                assert!(
+                    response.status().is_success(),
+                    "{} {} answered {}",
+                    method,
+                    path,
+                    response.status()
+                );
+                read_body(response).await;
+            }
+        });
+    }
+
+    #[test]
+    fn test_server_receives_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = receive::Inbox::new(
+            dir.path().to_path_buf(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            notify::Notifier::default(),
+        );
+        run_client(Mode::Receive(Arc::new(inbox)), |network| async move {
+            let req = Request::put("/notes.txt")
+                .body(Body::from("hello"))
+                .unwrap();
+            let response = send_request(&network, req).await;
+            assert!(response.status().is_success());
+        });
+        assert_eq!(
+            b"hello".to_vec(),
+            fs::read(dir.path().join("notes.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_server_stops_after_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, "hello").unwrap();
+        let mode = Mode::Send(Arc::new(Share::new(path)));
+        let (network, address) = create_network();
+        let server = run_http_server_async(network.clone(), address, mode, create_options(true));
+        let client = async {
+            let response =
+                send_request(&network, Request::get("/").body(Body::empty()).unwrap()).await;
+            read_body(response).await
+        };
+        let (result, body) = block_on(futures::future::join(server, client));
+        assert_eq!(b"hello".to_vec(), body);
+        assert!(result.is_ok());
+        assert!(!network.is_bound(SERVER_SOCKET.parse().unwrap()));
+    }
+
+    #[test]
+    fn test_server_listens_on_new_addresses() {
+        let mode = Mode::Send(Arc::new(Share::new(PathBuf::from("notes.txt"))));
+        run_client(mode, |network| async move {
+            let new_socket = "10.0.0.3:8080".parse().unwrap();
+            network.set_interfaces(vec![create_interface(
+                "eth0",
+                2,
+                &["10.0.0.1/24", "10.0.0.3/24", "fd00::1/64"],
+            )]);
+            while !network.is_bound(new_socket) {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+            // Only addresses of the chosen IP family are added.
+            assert!(!network.is_bound("[fd00::1]:8080".parse().unwrap()));
+        });
+    }
+
+    #[test]
+    fn test_dual_stack_serves_both_families_with_one_socket() {
+        let interface = create_interface("eth0", 2, &["10.0.0.1/24", "fd00::1/64"]);
+        let network = Arc::new(MockNetwork::new(vec![interface]));
+        let address = Address {
+            interface: String::from("eth0"),
+            socket: SERVER_SOCKET.parse().unwrap(),
+            url: format!("http://{}", SERVER_SOCKET),
+            alternate: Some((
+                "[fd00::1]:8080".parse().unwrap(),
+                String::from("http://[fd00::1]:8080"),
+            )),
+            dual_stack: true,
+        };
+        let mode = Mode::Send(Arc::new(Share::new(PathBuf::from("notes.txt"))));
+        let server = run_http_server_async(network.clone(), address, mode, create_options(false));
+        let client = async {
+            let socket = "[::]:8080".parse().unwrap();
+            while !network.is_bound(socket) {
+                tokio::time::delay_for(Duration::from_millis(1)).await;
+            }
+            assert!(!network.is_bound(SERVER_SOCKET.parse().unwrap()));
+            assert!(!network.is_bound("[fd00::1]:8080".parse().unwrap()));
+            for (server, client) in &[
+                (SERVER_SOCKET, CLIENT_SOCKET),
+                ("[fd00::1]:8080", "[fd00::2]:40000"),
+            ] {
+                let stream = network
+                    .connect(server.parse().unwrap(), client.parse().unwrap())
+                    .unwrap();
+                let (mut sender, connection) =
+                    hyper::client::conn::handshake(stream).await.unwrap();
+                tokio::spawn(connection);
+                let req = Request::get("/").body(Body::empty()).unwrap();
+                let response = sender.send_request(req).await.unwrap();
+                assert_eq!(StatusCode::NOT_FOUND, response.status());
+            }
+        };
+        block_on(async move {
+            tokio::select! {
+                result = server => panic!("The server stopped: {:?}", result.err()),
+                _ = client => {}
+            }
+        });
+    }
+
+    /// Connects without speaking HTTP, once the server listens.
+    async fn connect_raw(network: &MockNetwork, port: u16) -> tokio::io::DuplexStream {
+        let socket = SERVER_SOCKET.parse().unwrap();
+        while !network.is_bound(socket) {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        let from =
+            net::SocketAddr::new(CLIENT_SOCKET.parse::<net::SocketAddr>().unwrap().ip(), port);
+        network.connect(socket, from).unwrap()
+    }
+
+    #[test]
+    fn test_server_drops_slow_requests() {
+        let mut options = create_options(false);
+        options.timeouts.header = Some(Duration::from_millis(100));
+        let mode = Mode::Send(Arc::new(Share::new(PathBuf::from("notes.txt"))));
+        run_client_with(mode, options, |network| async move {
+            let mut client = connect_raw(&network, 40001).await;
+            client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+            for _ in 0..5u8 {
+                tokio::time::delay_for(Duration::from_millis(40)).await;
+                if client.write_all(b"X-Slow: 1\r\n").await.is_err() {
+                    break;
+                }
+            }
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+
+            // Requests sent in time are answered.
+            let req = Request::get("/").body(Body::empty()).unwrap();
+            let response = send_request(&network, req).await;
+            assert_eq!(StatusCode::NOT_FOUND, response.status());
+        });
+    }
+
+    #[test]
+    fn test_server_limits_half_open_connections() {
+        let mut options = create_options(false);
+        options.max_half_open = Some(1);
+        let mode = Mode::Send(Arc::new(Share::new(PathBuf::from("notes.txt"))));
+        run_client_with(mode, options, |network| async move {
+            let _silent = connect_raw(&network, 40001).await;
+            let mut refused = connect_raw(&network, 40002).await;
+            let mut rest = Vec::new();
+            refused.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        });
+    }
+
+    /// Sends `request` as it is and returns everything the server answers until it closes the
+    /// connection.
+    async fn send_raw(network: &MockNetwork, request: &[u8]) -> String {
+        let mut client = connect_raw(network, 40001).await;
+        client.write_all(request).await.unwrap();
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        String::from_utf8_lossy(&answer).into_owned()
+    }
+
+    #[test]
+    fn test_server_refuses_smuggled_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = receive::Inbox::new(
+            dir.path().to_path_buf(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            notify::Notifier::default(),
+        );
+        run_client(Mode::Receive(Arc::new(inbox)), |network| async move {
+            // A proxy going by the Content-Length would take the second request for the body.
+            let answer = send_raw(
+                &network,
+                b"PUT /a.txt HTTP/1.1\r\nHost: 10.0.0.1\r\nContent-Length: 5\r\n\
+                  Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n\
+                  PUT /b.txt HTTP/1.1\r\nHost: 10.0.0.1\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await;
+            assert!(answer.starts_with("HTTP/1.1 400"));
+            assert_eq!(1, answer.matches("HTTP/1.1").count());
+
+            let answer = send_raw(
+                &network,
+                b"PUT /a.txt HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            )
+            .await;
+            assert!(answer.starts_with("HTTP/1.1 400"));
+
+            let answer = send_raw(
+                &network,
+                b"PUT /a.txt HTTP/1.1\r\nTransfer-Encoding: identity\r\n\r\nab",
+            )
+            .await;
+            assert!(answer.starts_with("HTTP/1.1 400"));
+
+            let answer = send_raw(
+                &network,
+                b"GET / HTTP/1.1\r\nHost: 10.0.0.1\r\nHost: evil\r\n\r\n",
+            )
+            .await;
+            assert!(answer.starts_with("HTTP/1.1 400"));
+
+            let request = format!(
+                "GET /{} HTTP/1.1\r\nConnection: close\r\n\r\n",
+                "a".repeat(5000)
+            );
+            let answer = send_raw(&network, request.as_bytes()).await;
+            assert!(answer.starts_with("HTTP/1.1 414"));
+
+            let mut request = String::from("GET / HTTP/1.1\r\nConnection: close\r\n");
+            for i in 0..80 {
+                request.push_str(&format!("X-Header-{}: 1\r\n", i));
+            }
+            request.push_str("\r\n");
+            let answer = send_raw(&network, request.as_bytes()).await;
+            assert!(answer.starts_with("HTTP/1.1 431"));
+        });
+        assert!(!dir.path().join("a.txt").exists());
+        assert!(!dir.path().join("b.txt").exists());
+    }
+
+    #[test]
+    fn test_server_bind_error() {
+        let (network, address) = create_network();
+        let _taken = network.bind(address.socket, None).unwrap();
+        let mode = Mode::Send(Arc::new(Share::new(PathBuf::from("notes.txt"))));
+        let result = block_on(run_http_server_async(
+            network.clone(),
+            address,
+            mode,
+            create_options(false),
+        ));
+        let error = result.unwrap_err();
+        assert!(error.is::<BindError>());
+        assert_eq!(3, get_exit_code(&*error));
     }
 }
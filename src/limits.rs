@@ -0,0 +1,169 @@
+//! Limits on what a request may look like
+//!
+//! rustbelt is meant to be started on networks nobody vetted, like the Wi-Fi of a coffee shop, so
+//! every request is checked before it reaches a mode. Requests with more headers than
+//! `--max-headers`, more header bytes than `--max-header-size` or a longer URI than
+//! `--max-uri-length` are refused with 431 or 414.
+//!
+//! Requests whose length is ambiguous are refused with 400 and the connection is closed, so no
+//! part of their body can be taken for the next request: a Content-Length together with
+//! Transfer-Encoding, any transfer coding but plain `chunked`, and more than one Host. hyper
+//! already refuses differing Content-Lengths, and ignores a Content-Length that follows
+//! Transfer-Encoding, framing such a request by its chunks.
+
+use crate::create_status_response;
+use hyper::{header, Body, Request, Response, StatusCode};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestLimits {
+    /// Number of header fields
+    pub headers: usize,
+    /// Bytes of all header names and values together
+    pub header_bytes: usize,
+    /// Bytes of the request target, with the query
+    pub uri_length: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        RequestLimits {
+            headers: 64,
+            header_bytes: 16 * 1024,
+            uri_length: 4096,
+        }
+    }
+}
+
+/// Answers a request whose body can't be told from what follows it, and closes the connection.
+fn create_ambiguous_response(message: &str) -> Response<Body> {
+    let mut response = create_status_response(StatusCode::BAD_REQUEST, message);
+    response.headers_mut().insert(
+        header::CONNECTION,
+        header::HeaderValue::from_static("close"),
+    );
+    response
+}
+
+impl RequestLimits {
+    /// The answer to `req` if it breaks a limit or is ambiguous, `None` if it may be handled.
+    pub fn check<T>(&self, req: &Request<T>) -> Option<Response<Body>> {
+        let uri_length = req.uri().path_and_query().map_or(0, |p| p.as_str().len());
+        if uri_length > self.uri_length {
+            return Some(create_status_response(
+                StatusCode::URI_TOO_LONG,
+                "URI too long",
+            ));
+        }
+        let headers = req.headers();
+        let header_bytes: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        if headers.len() > self.headers || header_bytes > self.header_bytes {
+            return Some(create_status_response(
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "Request headers too large",
+            ));
+        }
+
+        let codings = headers.get_all(header::TRANSFER_ENCODING);
+        if codings.iter().next().is_some() {
+            if headers.contains_key(header::CONTENT_LENGTH) {
+                return Some(create_ambiguous_response(
+                    "Content-Length and Transfer-Encoding conflict",
+                ));
+            }
+            let codings = codings.iter().collect::<Vec<_>>();
+            if codings.len() != 1 || !codings[0].as_bytes().eq_ignore_ascii_case(b"chunked") {
+                return Some(create_ambiguous_response("Unsupported Transfer-Encoding"));
+            }
+        }
+        if headers.get_all(header::HOST).iter().count() > 1 {
+            return Some(create_ambiguous_response("More than one Host"));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_short_uris_pass(path in "(/[a-z0-9]{0,20}){1,10}") {
+            let req = Request::get(path.as_str()).body(()).unwrap();
+            prop_assert!(RequestLimits::default().check(&req).is_none());
+        }
+    }
+
+    fn get_status(req: Request<()>) -> Option<StatusCode> {
+        let limits = RequestLimits {
+            headers: 4,
+            header_bytes: 100,
+            uri_length: 20,
+        };
+        limits.check(&req).map(|r| r.status())
+    }
+
+    #[test]
+    fn test_limits() {
+        let req = Request::get("/file.txt?q=1").body(()).unwrap();
+        assert_eq!(None, get_status(req));
+        let req = Request::get(format!("/{}", "a".repeat(20)))
+            .body(())
+            .unwrap();
+        assert_eq!(Some(StatusCode::URI_TOO_LONG), get_status(req));
+
+        let mut req = Request::get("/").body(()).unwrap();
+        for i in 0..5 {
+            req.headers_mut().append(
+                format!("x-{}", i).parse::<header::HeaderName>().unwrap(),
+                "1".parse().unwrap(),
+            );
+        }
+        assert_eq!(
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            get_status(req)
+        );
+        let req = Request::get("/")
+            .header("x-long", "a".repeat(100))
+            .body(())
+            .unwrap();
+        assert_eq!(
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            get_status(req)
+        );
+    }
+
+    #[test]
+    fn test_ambiguous_requests() {
+        let req = Request::put("/a")
+            .header(header::TRANSFER_ENCODING, "Chunked")
+            .body(())
+            .unwrap();
+        assert_eq!(None, get_status(req));
+
+        for (name, value) in &[
+            (header::CONTENT_LENGTH, "5"),
+            (header::TRANSFER_ENCODING, "chunked"),
+            (header::HOST, "evil"),
+        ] {
+            let req = Request::put("/a")
+                .header(header::TRANSFER_ENCODING, "chunked")
+                .header(header::HOST, "10.0.0.1")
+                .header(name, *value)
+                .body(())
+                .unwrap();
+            let response = RequestLimits::default().check(&req).unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, response.status());
+            assert_eq!("close", response.headers()[header::CONNECTION]);
+        }
+        let req = Request::put("/a")
+            .header(header::TRANSFER_ENCODING, "gzip, chunked")
+            .body(())
+            .unwrap();
+        assert_eq!(Some(StatusCode::BAD_REQUEST), get_status(req));
+    }
+}
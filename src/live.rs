@@ -0,0 +1,276 @@
+//! Streaming the live output of a command or a growing file to every connected client
+//!
+//! A single producer reads the output line by line and broadcasts it. Clients joining later first
+//! receive the most recent lines. `/` answers with chunked plain text, `/events` with server-sent
+//! events.
+
+use crate::help;
+use futures::stream::{self, Stream};
+use hyper::{header, Body, Method, Request, Response};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::broadcast;
+
+/// Number of lines kept for clients connecting after the output started
+const BACKLOG_LINES: usize = 100;
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Where the streamed output comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum LiveSource {
+    /// A shell command whose stdout and stderr are streamed
+    Command(String),
+    /// A file that is followed as it grows, like `tail -f`
+    Tail(PathBuf),
+}
+
+/// A message from the producer: a line of output or `None` once the output has ended
+type Update = Option<Arc<String>>;
+
+struct Backlog {
+    lines: VecDeque<Arc<String>>,
+    finished: bool,
+}
+
+/// The output shared with all clients
+pub struct LiveOutput {
+    source: LiveSource,
+    backlog: Mutex<Backlog>,
+    sender: broadcast::Sender<Update>,
+}
+
+impl LiveOutput {
+    pub fn new(source: LiveSource) -> LiveOutput {
+        let (sender, _) = broadcast::channel(BACKLOG_LINES);
+        LiveOutput {
+            source,
+            backlog: Mutex::new(Backlog {
+                lines: VecDeque::with_capacity(BACKLOG_LINES),
+                finished: false,
+            }),
+            sender,
+        }
+    }
+
+    fn publish(&self, update: Update) {
+        let mut backlog = self.backlog.lock().unwrap();
+        match &update {
+            Some(line) => {
+                if backlog.lines.len() == BACKLOG_LINES {
+                    backlog.lines.pop_front();
+                }
+                backlog.lines.push_back(line.clone());
+            }
+            None => backlog.finished = true,
+        }
+        // Sending fails if nobody is connected, which is fine as the line is in the backlog.
+        let _ = self.sender.send(update);
+    }
+
+    /// Returns the recent lines and a receiver for everything published after them.
+    fn subscribe(&self) -> (Vec<Update>, Option<broadcast::Receiver<Update>>) {
+        let backlog = self.backlog.lock().unwrap();
+        let mut updates = backlog.lines.iter().cloned().map(Some).collect::<Vec<_>>();
+        if backlog.finished {
+            updates.push(None);
+            (updates, None)
+        } else {
+            (updates, Some(self.sender.subscribe()))
+        }
+    }
+}
+
+/// Reads the output of the source and publishes it until it ends.
+pub async fn produce(output: Arc<LiveOutput>) {
+    let result = match &output.source {
+        LiveSource::Command(command) => produce_command(&output, command).await,
+        LiveSource::Tail(path) => produce_tail(&output, path).await,
+    };
+    if let Err(e) = result {
+        output.publish(Some(Arc::new(format!("[rustbelt: {}]", e))));
+    }
+    output.publish(None);
+}
+
+async fn produce_lines<R: AsyncRead + Unpin>(output: &LiveOutput, reader: R) -> io::Result<()> {
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        output.publish(Some(Arc::new(line)));
+    }
+    Ok(())
+}
+
+async fn produce_command(output: &LiveOutput, command: &str) -> io::Result<()> {
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("exec 2>&1\n{}", command))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    produce_lines(output, stdout).await?;
+    let status = child.await?;
+    output.publish(Some(Arc::new(format!(
+        "[rustbelt: command exited with {}]",
+        status
+    ))));
+    Ok(())
+}
+
+async fn produce_tail(output: &LiveOutput, path: &Path) -> io::Result<()> {
+    let mut reader = BufReader::new(tokio::fs::File::open(path).await?);
+    let mut position = 0;
+    let mut line = String::new();
+    loop {
+        let n = reader.read_line(&mut line).await?;
+        position += n as u64;
+        if line.ends_with('\n') {
+            line.pop();
+            output.publish(Some(Arc::new(line.split_off(0))));
+            continue;
+        }
+        if n > 0 {
+            // A partial line at the end of the file, the rest follows later
+            continue;
+        }
+        tokio::time::delay_for(TAIL_POLL_INTERVAL).await;
+        if tokio::fs::metadata(path).await?.len() < position {
+            output.publish(Some(Arc::new(String::from("[rustbelt: file truncated]"))));
+            reader = BufReader::new(tokio::fs::File::open(path).await?);
+            position = 0;
+            line.clear();
+        }
+    }
+}
+
+/// Formats a line as a server-sent event. Lines never contain line breaks.
+fn create_event(line: &str) -> String {
+    format!("data: {}\n\n", line)
+}
+
+fn create_update_stream(
+    output: &LiveOutput,
+    sse: bool,
+) -> impl Stream<Item = Result<String, Infallible>> {
+    let (backlog, receiver) = output.subscribe();
+    let format = move |update: Update| match update {
+        Some(line) if sse => create_event(&line),
+        Some(line) => format!("{}\n", line),
+        None if sse => String::from("event: end\ndata:\n\n"),
+        None => String::new(),
+    };
+    let backlog = stream::iter(backlog.into_iter().map(format).map(Ok));
+    let live = stream::unfold(receiver, move |receiver| async move {
+        let mut receiver = receiver?;
+        match receiver.recv().await {
+            Ok(Some(line)) => Some((Ok(format(Some(line))), Some(receiver))),
+            Ok(None) | Err(broadcast::RecvError::Closed) => Some((Ok(format(None)), None)),
+            Err(broadcast::RecvError::Lagged(skipped)) => {
+                let notice = format!("[rustbelt: skipped {} lines]", skipped);
+                Some((Ok(format(Some(Arc::new(notice)))), Some(receiver)))
+            }
+        }
+    });
+    futures::StreamExt::chain(backlog, live)
+}
+
+/// What a request of the live output is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Output,
+    Events,
+}
+
+/// The requests the live output answers
+pub fn get_routes() -> Vec<help::Route<Route>> {
+    vec![
+        help::Route::new(
+            Method::GET,
+            "/",
+            "The output as chunked plain text",
+            Route::Output,
+        ),
+        help::Route::new(
+            Method::GET,
+            "/events",
+            "The output as server-sent events",
+            Route::Events,
+        ),
+    ]
+}
+
+pub async fn handle_request(
+    output: Arc<LiveOutput>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let wants_events = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|a| a.to_str().ok())
+        .is_some_and(|a| a.contains("text/event-stream"));
+    let sse = match help::find_route(&get_routes(), &req) {
+        Ok(Route::Output) => wants_events,
+        Ok(Route::Events) => true,
+        Err(response) => return Ok(response),
+    };
+    let content_type = if sse {
+        "text/event-stream"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header("X-Content-Type-Options", "nosniff")
+        .body(Body::wrap_stream(create_update_stream(&output, sse)))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_event_format(a in "[^\r\n]*") {
+            let event = create_event(&a);
+            prop_assert!(event.starts_with("data: "));
+            prop_assert!(event.ends_with("\n\n"));
+            prop_assert_eq!(2, event.matches('\n').count());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_output_reaches_late_clients() {
+        let output = Arc::new(LiveOutput::new(LiveSource::Command(String::from(
+            "echo a; echo b >&2",
+        ))));
+        produce(output.clone()).await;
+        let text = create_update_stream(&output, false)
+            .map(|s| s.unwrap())
+            .collect::<String>()
+            .await;
+        assert!(text.starts_with("a\nb\n[rustbelt: command exited with "));
+    }
+
+    #[test]
+    fn test_backlog_is_bounded() {
+        let output = LiveOutput::new(LiveSource::Command(String::new()));
+        for i in 0..BACKLOG_LINES + 5 {
+            output.publish(Some(Arc::new(i.to_string())));
+        }
+        let (backlog, receiver) = output.subscribe();
+        assert!(receiver.is_some());
+        assert_eq!(BACKLOG_LINES, backlog.len());
+        assert_eq!(Some(Arc::new(String::from("5"))), backlog[0]);
+    }
+}
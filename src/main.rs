@@ -3,56 +3,804 @@
 extern crate clap;
 extern crate colored;
 extern crate ipnetwork;
-extern crate pnet;
 
-use clap::{crate_authors, crate_version, App, Arg};
-use rustbelt;
+use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgGroup, SubCommand};
 use std::path::Path;
+use std::process;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() {
+    let validate_seconds = |s: String| match s.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("Must be a whole number of seconds")),
+    };
+    let validate_count = |s: String| match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(String::from("Must be a positive integer")),
+    };
+    let validate_piece_size = |s: String| match s.parse::<u64>() {
+        Ok(n) if (1..=1024).contains(&n) => Ok(()),
+        _ => Err(String::from("Must be between 1 and 1024 MiB")),
+    };
     let matches = App::new("rustbelt")
         .author(crate_authors!())
         .version(crate_version!())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .after_help(
+            "EXIT CODES:\n    \
+             0    Stopped by the user, or the transfer completed\n    \
+             1    Any other error, including invalid arguments\n    \
+             2    The network interface doesn't exist\n    \
+             3    The address and port could not be bound\n    \
+             4    The confirmation before serving was declined\n    \
+             5    The server shut down as the share expired or all one-time links were used\n    \
+             6    A download, upload or sync with another instance failed",
+        )
         .arg(
             Arg::with_name("PATH")
-                .required_unless("receive")
+                .required_unless_one(&["receive", "mode", "exec", "mount", "text", "screen"])
                 .validator(|s: String| {
-                    if Path::new(&s).exists() {
+                    let remote = ["s3://", "http://", "https://"]
+                        .iter()
+                        .any(|scheme| s.starts_with(scheme));
+                    if remote || Path::new(&s).exists() {
                         Ok(())
                     } else {
                         Err(String::from("File or path does not exist"))
                     }
                 })
-                .help("Path to a file or directory to be transferred."),
+                .help(
+                    "Path to a file to be transferred. In receive mode the directory received \
+                     files are stored in. An s3://bucket/key or http(s):// URL is streamed \
+                     through from there.",
+                ),
         )
         .arg(
             Arg::with_name("receive")
-                .help("Receive data from a source instead of sending it")
+                .help("Receive data from a source instead of sending it, like --mode receive")
                 .short("r")
                 .long("receive"),
         )
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .value_name("MODE")
+                .possible_values(&["send", "receive", "exchange"])
+                .conflicts_with_all(&["mount", "exec", "text"])
+                .help(
+                    "Which way files go, send (default) answers downloads only, receive \
+                     uploads into PATH and exchange both, uploads going to --exchange DIR or \
+                     the directory PATH is in. Requests with other HTTP methods are refused",
+                ),
+        )
+        .group(ArgGroup::with_name("receiving").args(&["receive", "mode"]))
+        .arg(
+            Arg::with_name("store to")
+                .long("store-to")
+                .value_name("STORAGE")
+                .requires("receiving")
+                .conflicts_with_all(&[
+                    "quarantine",
+                    "append",
+                    "dedupe",
+                    "list received",
+                    "quota",
+                    "on receive",
+                    "scan",
+                ])
+                .validator(|s: String| {
+                    if s.starts_with("s3://") || s.starts_with("exec:") {
+                        Ok(())
+                    } else {
+                        Err(String::from("Expected s3://BUCKET/PREFIX or exec:COMMAND"))
+                    }
+                })
+                .help(
+                    "Hand received files to STORAGE instead of writing them to PATH: \
+                     s3://BUCKET/PREFIX uploads them to S3 with the credentials from the AWS_* \
+                     variables, exec:COMMAND pipes each into a shell command, which finds the \
+                     file name in $RUSTBELT_FILE",
+                ),
+        )
+        .arg(
+            Arg::with_name("quarantine")
+                .long("quarantine")
+                .value_name("DIR")
+                .requires("receiving")
+                .help("Store received files in this subdirectory until they are accepted"),
+        )
+        .arg(
+            Arg::with_name("append")
+                .long("append")
+                .value_name("FILE")
+                .requires("receiving")
+                .conflicts_with_all(&["quarantine", "on receive"])
+                .help(
+                    "Append every line sent with PUT or POST to FILE as newline delimited JSON, \
+                     together with the time and the sender's address, instead of storing files",
+                ),
+        )
+        .arg(
+            Arg::with_name("to clipboard")
+                .long("to-clipboard")
+                .requires("receiving")
+                .conflicts_with("append")
+                .help(
+                    "Let senders put short text onto the clipboard of this machine through a \
+                     text field on the upload page. Every text waits for paste or discard on the \
+                     terminal",
+                ),
+        )
+        .arg(
+            Arg::with_name("dedupe")
+                .long("dedupe")
+                .value_name("MODE")
+                .possible_values(&["skip", "link"])
+                .requires("receiving")
+                .conflicts_with("append")
+                .help(
+                    "Don't store uploads whose content already exists in the directory. With \
+                     skip they are dropped, with link they are hard-linked to the existing file. \
+                     The sender is told that the file is already there",
+                ),
+        )
+        .arg(
+            Arg::with_name("list received")
+                .long("list-received")
+                .value_name("PIN")
+                .requires("receiving")
+                .conflicts_with("append")
+                .help(
+                    "Serve a list of the received files with their sizes and times at \
+                     /.received, to those who give PIN as password or pin parameter",
+                ),
+        )
+        .arg(
+            Arg::with_name("quota")
+                .long("quota")
+                .value_name("SIZE")
+                .requires("receiving")
+                .conflicts_with("append")
+                .help(
+                    "Stop accepting uploads once the directory holds SIZE bytes, like 500M or \
+                     20G. Files already in it count as well",
+                ),
+        )
+        .arg(
+            Arg::with_name("min free")
+                .long("min-free")
+                .value_name("SIZE")
+                .default_value("1G")
+                .help(
+                    "Warn when less than SIZE is free on the disk received files are written \
+                     to, with --receive or --exchange. Uploads larger than the free space are \
+                     refused before they start",
+                ),
+        )
+        .arg(
+            Arg::with_name("ask name")
+                .long("ask-name")
+                .requires("receiving")
+                .conflicts_with("append")
+                .help(
+                    "Ask uploaders for their name on the upload page and store their files in a \
+                     folder of that name, to collect assignments or photos from a group",
+                ),
+        )
+        .arg(
+            Arg::with_name("notify cmd")
+                .long("notify-cmd")
+                .value_name("CMD")
+                .requires("receiving")
+                .conflicts_with("append")
+                .help(
+                    "Command run in the background for every accepted file, like \
+                     'script.sh {file} {ip}'. {file} and {ip} are replaced by the file's path \
+                     and the uploader's address, without them both are appended",
+                ),
+        )
+        .arg(
+            Arg::with_name("notify email")
+                .long("notify-email")
+                .value_name("ADDRESS")
+                .requires("receiving")
+                .conflicts_with("append")
+                .help(
+                    "Send an email to ADDRESS for every accepted file, through the SMTP relay \
+                     given with --smtp-server. Only plain SMTP without login is supported",
+                ),
+        )
+        .arg(
+            Arg::with_name("smtp server")
+                .long("smtp-server")
+                .value_name("HOST[:PORT]")
+                .requires("notify email")
+                .help("SMTP relay for --notify-email [default: localhost:25]"),
+        )
+        .arg(
+            Arg::with_name("on receive")
+                .long("on-receive")
+                .value_name("CMD")
+                .requires("receiving")
+                .help(
+                    "Command run for every received file while it is in quarantine. {file} is \
+                     replaced by the file's path, otherwise it is appended. The file is only \
+                     moved to its destination if the command succeeds, otherwise it is moved to \
+                     the trash",
+                ),
+        )
+        .arg(
+            Arg::with_name("scan")
+                .long("scan")
+                .value_name("SCANNER")
+                .requires("receiving")
+                .conflicts_with("append")
+                .help(
+                    "Scan received files for malware while they are in quarantine, with clamd \
+                     at clamd:/path/to/socket or clamd:host[:port], or an ICAP server at \
+                     icap://host[:port]/service. Infected files are deleted, files that \
+                     couldn't be scanned stay in quarantine",
+                ),
+        )
+        .arg(
+            Arg::with_name("move")
+                .long("move")
+                .conflicts_with("receive")
+                .help(
+                    "Move the source file to the trash once the recipient confirmed its SHA-256, \
+                     which rustbelt get does after a complete download. Browsers can't confirm, \
+                     the file is kept then",
+                ),
+        )
+        .arg(
+            Arg::with_name("explode")
+                .long("explode")
+                .conflicts_with_all(&["receive", "move"])
+                .help(
+                    "Serve the content of a .zip, .tar.gz or .tgz archive as a directory, so \
+                     single files can be downloaded from it",
+                ),
+        )
+        .arg(
+            Arg::with_name("cas")
+                .long("cas")
+                .conflicts_with_all(&[
+                    "receive",
+                    "mode",
+                    "move",
+                    "explode",
+                    "pieces",
+                    "sign",
+                    "encrypt to",
+                    "exchange",
+                    "tokens",
+                    "broadcast",
+                    "index",
+                    "spa",
+                    "mount",
+                    "exec",
+                    "text",
+                ])
+                .help(
+                    "Serve every file in PATH at /sha256/<hash>, named after the SHA-256 of its \
+                     content, with headers letting browsers and proxies cache it forever. Links \
+                     stay valid as long as the content exists, identical files share one",
+                ),
+        )
+        .arg(
+            Arg::with_name("index")
+                .long("index")
+                .conflicts_with_all(&["receive", "move", "explode"])
+                .help(
+                    "Serve PATH as a static website, answering directories with their \
+                     index.html",
+                ),
+        )
+        .arg(
+            Arg::with_name("spa")
+                .long("spa")
+                .conflicts_with_all(&["receive", "move", "explode"])
+                .help(
+                    "Like --index, but answer unknown paths with the root index.html, for single \
+                     page applications using the history API",
+                ),
+        )
+        .arg(
+            Arg::with_name("pieces")
+                .long("pieces")
+                .conflicts_with_all(&["receive", "move", "explode", "index", "spa"])
+                .help(
+                    "Additionally offer the file as pieces with individual hashes, which \
+                     `rustbelt get` downloads in parallel and verifies",
+                ),
+        )
+        .arg(
+            Arg::with_name("piece size")
+                .long("piece-size")
+                .value_name("MIB")
+                .requires("pieces")
+                .validator(validate_piece_size)
+                .help("Size of a piece in MiB, at most 1024 [default: 4]"),
+        )
+        .arg(
+            Arg::with_name("sign")
+                .long("sign")
+                .value_name("KEY")
+                .conflicts_with_all(&["receive", "explode", "index", "spa"])
+                .help("Serve a detached GPG signature of the file made with KEY at /signature.asc"),
+        )
+        .arg(
+            Arg::with_name("encrypt to")
+                .long("encrypt-to")
+                .value_name("RECIPIENT")
+                .conflicts_with_all(&["receive", "explode", "move", "pieces", "index", "spa"])
+                .help(
+                    "Encrypt the file for RECIPIENT while sending it. age1… and ssh- recipients \
+                     are encrypted with age, all others with GPG",
+                ),
+        )
+        .arg(
+            Arg::with_name("exchange")
+                .long("exchange")
+                .value_name("DIR")
+                .conflicts_with_all(&[
+                    "receive",
+                    "explode",
+                    "index",
+                    "spa",
+                    "mount",
+                    "exec",
+                    "move",
+                    "tokens",
+                    "broadcast",
+                ])
+                .help(
+                    "Also accept uploads into DIR on the page that offers the file, so two people \
+                     can swap files through one QR code",
+                ),
+        )
+        .arg(
+            Arg::with_name("tokens")
+                .long("tokens")
+                .value_name("N")
+                .conflicts_with_all(&["receive", "explode", "index", "spa", "mount", "exec"])
+                .validator(validate_count)
+                .help(
+                    "Serve the file under N distinct random links with their own QR codes, each \
+                     good for one download, to give every recipient their own link",
+                ),
+        )
+        .arg(
+            Arg::with_name("state file")
+                .long("state-file")
+                .value_name("FILE")
+                .conflicts_with_all(&["explode", "index", "spa", "mount", "exec"])
+                .help(
+                    "Save the one-time links, the request count and how far interrupted uploads \
+                     got to FILE, and continue from there when started again with the same PATH \
+                     after a restart or crash",
+                ),
+        )
+        .arg(
+            Arg::with_name("resolve names")
+                .long("resolve-names")
+                .global(true)
+                .help(
+                    "Show clients by their host name, asking the network with multicast DNS and \
+                     then the resolver, instead of their IP address",
+                ),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .help(
+                    "Append a JSON line with the headers and timing of every request and its \
+                     response to FILE, to reproduce failed transfers with rustbelt replay. \
+                     Credentials are left out",
+                ),
+        )
+        .arg(
+            Arg::with_name("record bodies")
+                .long("record-bodies")
+                .requires("record")
+                .help("Record the first MiB of every request body as well"),
+        )
+        .arg(
+            Arg::with_name("broadcast")
+                .long("broadcast")
+                .value_name("N")
+                .conflicts_with_all(&[
+                    "receive", "explode", "index", "spa", "mount", "exec", "tokens",
+                ])
+                .validator(validate_count)
+                .help(
+                    "Hand the file to a group of N devices, tracking the progress of every \
+                     recipient and printing how many devices are done. The file counts as \
+                     transferred once all N have it",
+                ),
+        )
+        .arg(
+            Arg::with_name("require name")
+                .long("require-name")
+                .requires("broadcast")
+                .help("Ask recipients for their name on the landing page before the download"),
+        )
+        .arg(
+            Arg::with_name("mount")
+                .long("mount")
+                .value_name("NAME=DIR[,OPTION…]")
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&[
+                    "PATH",
+                    "receive",
+                    "move",
+                    "explode",
+                    "pieces",
+                    "sign",
+                    "encrypt to",
+                    "index",
+                    "spa",
+                    "exec",
+                ])
+                .help(
+                    "Serve DIR below /NAME/. Can be given multiple times. Options are ro \
+                     (default), rw to allow uploads with PUT, mode=send|receive|exchange to say \
+                     so explicitly, receive allowing nothing but uploads, and \
+                     auth=USER:PASSWORD",
+                ),
+        )
+        .arg(
+            Arg::with_name("protect")
+                .long("protect")
+                .value_name("PATTERN:PIN")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Require PIN for paths matching PATTERN with --index, --spa or --mount, \
+                     where paths start with the mount name. * and ? match within a path segment, \
+                     ** matches any number of segments. Further rules are read from a \
+                     .rustbelt-access file in the served directory",
+                ),
+        )
+        .arg(
+            Arg::with_name("exec")
+                .long("exec")
+                .value_name("CMD")
+                .conflicts_with_all(&[
+                    "PATH",
+                    "receive",
+                    "move",
+                    "explode",
+                    "pieces",
+                    "sign",
+                    "encrypt to",
+                    "index",
+                    "spa",
+                ])
+                .help(
+                    "Run a shell command and stream its output to everyone connecting, as plain \
+                     text or as server-sent events at /events",
+                ),
+        )
+        .arg(
+            Arg::with_name("text")
+                .long("text")
+                .value_name("TEXT")
+                .conflicts_with_all(&[
+                    "PATH",
+                    "receive",
+                    "move",
+                    "explode",
+                    "pieces",
+                    "sign",
+                    "encrypt to",
+                    "index",
+                    "spa",
+                    "mount",
+                    "exec",
+                    "tokens",
+                    "broadcast",
+                    "state file",
+                ])
+                .help(
+                    "Share TEXT instead of a file, or the text read from stdin if it is -. \
+                     Browsers show it with a button to copy it",
+                ),
+        )
+        .arg(
+            Arg::with_name("screen")
+                .long("screen")
+                .conflicts_with_all(&[
+                    "PATH",
+                    "receive",
+                    "move",
+                    "explode",
+                    "pieces",
+                    "sign",
+                    "encrypt to",
+                    "index",
+                    "spa",
+                    "mount",
+                    "exec",
+                    "text",
+                    "cas",
+                    "tokens",
+                    "broadcast",
+                    "state file",
+                ])
+                .help(
+                    "Show the screen to browsers as a Motion JPEG stream, captured with ffmpeg \
+                     under X11 or wf-recorder on wlroots Wayland compositors. Capturing runs \
+                     only while someone watches",
+                ),
+        )
+        .arg(
+            Arg::with_name("screen region")
+                .long("region")
+                .value_name("WxH+X+Y")
+                .requires("screen")
+                .help("Show only this region of the screen, like 1280x720+0+0"),
+        )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .value_name("NAME")
+                .conflicts_with_all(&["receive", "mount", "exec"])
+                .help(
+                    "Name the stream is downloaded under when PATH is a FIFO or character \
+                     device, which is streamed to everyone connecting, or the text of --text",
+                ),
+        )
+        .arg(
+            Arg::with_name("mime")
+                .long("mime")
+                .value_name("TYPE")
+                .conflicts_with_all(&["receive", "mount", "exec"])
+                .help(
+                    "Content type of the stream from a FIFO or character device, like \
+                     multipart/x-mixed-replace for MJPEG. Browsers then show it instead of \
+                     downloading it",
+                ),
+        )
+        .arg(
+            Arg::with_name("ascii qr")
+                .long("ascii-qr")
+                .global(true)
+                .help(
+                    "Draw QR codes with # and spaces and without colors, for monochrome \
+                     terminals and screen readers",
+                ),
+        )
+        .arg(
+            Arg::with_name("high contrast")
+                .long("high-contrast")
+                .global(true)
+                .help(
+                    "Draw QR codes on bright white instead of the often gray white of the \
+                     terminal theme, and leave out faint and red text",
+                ),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
                 .long("verbose")
                 .multiple(true)
-                .help("Produce more verbose output. Multiple usage for more verbose output"),
+                .global(true)
+                .help(
+                    "Produce more verbose output on stderr. -v adds the network interface and \
+                     the devices on it, -vv the parsed arguments",
+                ),
         )
         .arg(
             Arg::with_name("network interface")
                 .short("i")
                 .long("interface")
                 .value_name("NETWORK_INTERFACE")
-                .validator(|d: String| {
-                    for (name, _) in rustbelt::get_network_interfaces() {
-                        if d == name {
-                            return Ok(());
-                        }
-                    }
-                    Err(String::from("Device not found!"))
-                })
+                .global(true)
                 .help("The network device over which the web server will run"),
         )
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .value_name("TIME")
+                .global(true)
+                .help(
+                    "Only answer requests from this local time on, formatted as \
+                     \"YYYY-MM-DD HH:MM\"",
+                ),
+        )
+        .arg(
+            Arg::with_name("until")
+                .long("until")
+                .value_name("TIME")
+                .global(true)
+                .help(
+                    "Only answer requests before this local time, formatted as \
+                     \"YYYY-MM-DD HH:MM\"",
+                ),
+        )
+        .arg(
+            Arg::with_name("max active transfers")
+                .long("max-active-transfers")
+                .value_name("N")
+                .global(true)
+                .validator(validate_count)
+                .help(
+                    "Number of downloads served at the same time. Further clients are asked to \
+                     wait and retry automatically",
+                ),
+        )
+        .arg(
+            Arg::with_name("limit rate")
+                .long("limit-rate")
+                .value_name("RATE")
+                .global(true)
+                .help(
+                    "Maximum speed of all downloads together in bytes per second, with K, M or G \
+                     for multiples of 1024, like 2M",
+                ),
+        )
+        .arg(
+            Arg::with_name("limit rate per client")
+                .long("limit-rate-per-client")
+                .value_name("RATE")
+                .global(true)
+                .help(
+                    "Maximum speed of every single download, so one recipient can't starve the \
+                     others. Applies in addition to --limit-rate",
+                ),
+        )
+        .arg(
+            Arg::with_name("limit upload rate")
+                .long("limit-upload-rate")
+                .value_name("RATE")
+                .global(true)
+                .help(
+                    "Maximum speed of all uploads together in bytes per second, like \
+                     --limit-rate for downloads, so incoming files leave room for other traffic \
+                     on the connection",
+                ),
+        )
+        .arg(
+            Arg::with_name("limit upload rate per client")
+                .long("limit-upload-rate-per-client")
+                .value_name("RATE")
+                .global(true)
+                .help(
+                    "Maximum speed of every single upload. Applies in addition to \
+                     --limit-upload-rate",
+                ),
+        )
+        .arg(
+            Arg::with_name("read timeout")
+                .long("read-timeout")
+                .value_name("SECONDS")
+                .global(true)
+                .validator(validate_seconds)
+                .help(
+                    "Close connections on which neither side sent anything for this long while \
+                     waiting for the client, including idle keep-alive connections. Off by \
+                     default, as relay recipients wait for the sender",
+                ),
+        )
+        .arg(
+            Arg::with_name("write timeout")
+                .long("write-timeout")
+                .value_name("SECONDS")
+                .default_value("300")
+                .global(true)
+                .validator(validate_seconds)
+                .help(
+                    "Close connections to clients that stopped receiving for this long, like \
+                     phones that went to sleep. They continue the download on the same link \
+                     after waking up. 0 waits forever",
+                ),
+        )
+        .arg(
+            Arg::with_name("keep alive")
+                .long("keep-alive")
+                .value_name("SECONDS")
+                .default_value("60")
+                .global(true)
+                .validator(validate_seconds)
+                .help(
+                    "Send TCP keep-alive probes after this many idle seconds, to notice clients \
+                     that left the network. 0 turns them off",
+                ),
+        )
+        .arg(
+            Arg::with_name("header timeout")
+                .long("header-timeout")
+                .value_name("SECONDS")
+                .default_value("20")
+                .global(true)
+                .validator(validate_seconds)
+                .help(
+                    "Close connections of clients that take longer than this to send the \
+                     headers of a request. 0 waits forever",
+                ),
+        )
+        .arg(
+            Arg::with_name("body timeout")
+                .long("body-timeout")
+                .value_name("SECONDS")
+                .default_value("60")
+                .global(true)
+                .validator(validate_seconds)
+                .help(
+                    "Cancel uploads that didn't receive any data for this long. 0 waits forever",
+                ),
+        )
+        .arg(
+            Arg::with_name("drain timeout")
+                .long("drain-timeout")
+                .value_name("SECONDS")
+                .default_value("0")
+                .global(true)
+                .validator(validate_seconds)
+                .help(
+                    "When shutting down, break off transfers that didn't finish within this \
+                     long. Until then the progress of the remaining transfers is shown, and \
+                     pressing Ctrl+C again breaks them off right away. 0 waits forever",
+                ),
+        )
+        .arg(
+            Arg::with_name("max half open")
+                .long("max-half-open")
+                .value_name("COUNT")
+                .default_value("64")
+                .global(true)
+                .validator(|s: String| match s.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(String::from("Must be a number")),
+                })
+                .help(
+                    "Refuse new connections while this many clients are still sending their \
+                     request, so slow clients can't take up all connections. 0 allows any number",
+                ),
+        )
+        .arg(
+            Arg::with_name("max headers")
+                .long("max-headers")
+                .value_name("COUNT")
+                .default_value("64")
+                .global(true)
+                .validator(validate_count)
+                .help("Refuse requests with more header fields than this"),
+        )
+        .arg(
+            Arg::with_name("max header size")
+                .long("max-header-size")
+                .value_name("SIZE")
+                .default_value("16K")
+                .global(true)
+                .help(
+                    "Refuse requests whose header fields take up more than SIZE bytes together, \
+                     like 16K",
+                ),
+        )
+        .arg(
+            Arg::with_name("max uri length")
+                .long("max-uri-length")
+                .value_name("BYTES")
+                .default_value("4096")
+                .global(true)
+                .validator(validate_count)
+                .help("Refuse requests for longer paths than this, with the query"),
+        )
+        .arg(
+            Arg::with_name("cache control")
+                .long("cache-control")
+                .value_name("PRESET")
+                .global(true)
+                .possible_values(&["no-store", "revalidate", "static"])
+                .help(
+                    "Cache-Control of all successful responses: no-store for secrets, revalidate \
+                     to check for changes on every use, static to cache for a year. By default \
+                     nothing is stored, except website previews which are revalidated",
+                ),
+        )
         .arg(
             Arg::with_name("domain")
                 .short("d")
@@ -60,23 +808,531 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .value_name("DOMAIN")
                 .help("The domain, the web server should be served on"),
         )
+        .arg(
+            Arg::with_name("forget")
+                .long("forget")
+                .global(true)
+                .help("Forget the network interface and IP address chosen last time"),
+        )
+        .arg(
+            Arg::with_name("porcelain")
+                .long("porcelain")
+                .global(true)
+                .help(
+                    "Once the server is ready, print a line of JSON with the event \"ready\", \
+                     the url, ip, port and mode before any further output, for scripts and \
+                     graphical wrappers. With --receive, every received file is reported with \
+                     a line with the event \"received\", its path, size, sha256, sender and \
+                     duration in seconds",
+                ),
+        )
+        .arg(
+            Arg::with_name("url only")
+                .long("url-only")
+                .global(true)
+                .conflicts_with("porcelain")
+                .help(
+                    "Print only the URL, or the one-time links, to stdout, as in \
+                     URL=$(rustbelt --url-only FILE). Status goes to stderr, the QR code and \
+                     the startup messages are only shown with -v",
+                ),
+        )
+        .arg(
+            Arg::with_name("both families")
+                .long("both-families")
+                .global(true)
+                .help(
+                    "Also listen on an IPv6 address of the interface when an IPv4 address was \
+                     chosen, or the other way around. The QR code leads to a page that continues \
+                     on whichever address the scanning device can reach",
+                ),
+        )
+        .arg(
+            Arg::with_name("dual stack")
+                .long("dual-stack")
+                .global(true)
+                .help(
+                    "Serve IPv4 and IPv6 with a single socket on every address of the interface, \
+                     still showing the URLs of the chosen address and one of the other IP family. \
+                     Needs Linux 5.7 or newer, or root",
+                ),
+        )
+        .arg(
+            Arg::with_name("deep link")
+                .long("deep-link")
+                .global(true)
+                .help(
+                    "Let the QR code lead to a page that first offers a rustbelt:// link to a \
+                     companion app for a resumable, checksummed transfer, and continues in the \
+                     browser without the app",
+                ),
+        )
+        .arg(
+            Arg::with_name("wsd")
+                .long("wsd")
+                .global(true)
+                .conflicts_with("tokens")
+                .help(
+                    "Announce the share over WS-Discovery, so it appears under Network in \
+                     Windows Explorer and opens in the browser with a double click",
+                ),
+        )
+        .arg(
+            Arg::with_name("metrics textfile")
+                .long("metrics-textfile")
+                .value_name("FILE")
+                .global(true)
+                .help(
+                    "Write request and byte counters to FILE every 15 seconds for the textfile \
+                     collector of node_exporter, FILE has to end in .prom and is removed when \
+                     the server stops",
+                ),
+        )
+        .arg(
+            Arg::with_name("print sheet")
+                .long("print-sheet")
+                .value_name("FILE")
+                .global(true)
+                .help(
+                    "Write a printable PDF to FILE with the QR code, URL, PINs and end of the \
+                     share, one page per link, for posting on a wall",
+                ),
+        )
+        .arg(Arg::with_name("kiosk").long("kiosk").global(true).help(
+            "Let the QR code lead to a page that starts the download after a countdown and then \
+             tells the recipient they can close it, for unattended distribution points",
+        ))
+        .arg(
+            Arg::with_name("countdown")
+                .long("countdown")
+                .value_name("SECONDS")
+                .default_value("5")
+                .global(true)
+                .validator(|s: String| match s.parse::<u32>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(String::from("Must be a number")),
+                })
+                .help("Seconds the kiosk page counts down before the download starts"),
+        )
+        .arg(
+            Arg::with_name("wifi")
+                .long("wifi")
+                .value_name("SSID:PASSWORD")
+                .global(true)
+                .validator(|s: String| {
+                    if s == "current" || s.split_once(':').is_some_and(|(ssid, _)| !ssid.is_empty())
+                    {
+                        Ok(())
+                    } else {
+                        Err(String::from("Expected SSID:PASSWORD, SSID: or current"))
+                    }
+                })
+                .help(
+                    "Print a QR code for joining the Wi-Fi before the one for the share, so a \
+                     recipient can get on the network first. Leave the password empty for open \
+                     networks, or give current to take the active connection from NetworkManager",
+                ),
+        )
+        .arg(Arg::with_name("short").long("short").global(true).help(
+            "Also serve a short URL like http://192.168.1.2:8080/s/7fk2 for typing it in, \
+             printed in large letters. It redirects to the share or to a one-time link, \
+             so the token never has to be typed",
+        ))
+        .arg(
+            Arg::with_name("speedtest")
+                .long("speedtest")
+                .global(true)
+                .help(
+                    "Also serve a speed test at /speedtest, so the recipient can check whether \
+                     the link is fast enough before a large transfer",
+                ),
+        )
+        .arg(
+            Arg::with_name("chat")
+                .long("chat")
+                .global(true)
+                .help(
+                    "Also serve a message page at /chat for notes between sender and \
+                     recipients. Messages are printed here, type say <text> to answer",
+                ),
+        )
+        .arg(
+            Arg::with_name("open firewall")
+                .long("open-firewall")
+                .global(true)
+                .help(
+                    "After asking, open the port on the chosen interface in firewalld, ufw or \
+                     nftables until rustbelt stops. Usually needs root",
+                ),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .global(true)
+                .help(
+                    "Don't ask before serving or opening the firewall. Without a terminal, \
+                     rustbelt doesn't ask before serving either",
+                ),
+        )
+        .arg(Arg::with_name("check").long("check").global(true).help(
+            "Only check whether the chosen address and port can be reached: bind and \
+             connect to it, look for firewalls blocking the port and list the devices \
+             on the network. Firewall warnings are also printed before serving",
+        ))
+        .arg(
+            Arg::with_name("warn size")
+                .long("warn-size")
+                .value_name("SIZE")
+                .global(true)
+                .help(
+                    "Warn when a shared directory holds more than SIZE, like 500M or 20G \
+                     [default: 10G]",
+                ),
+        )
+        .arg(Arg::with_name("low memory").long("low-memory").global(true).help(
+            "Get by with little memory, like as a drop box on a Raspberry Pi Zero: a single \
+             worker thread, small buffers and at most 2 downloads at a time unless \
+             --max-active-transfers says otherwise",
+        ))
+        .arg(
+            Arg::with_name("dry run")
+                .long("dry-run")
+                .global(true)
+                .conflicts_with("check")
+                .help(
+                    "Only show what would be shared and how: print the summary, URL and QR code, \
+                     and list the shared files on stdout, then exit without listening",
+                ),
+        )
+        .arg(Arg::with_name("beacon").long("beacon").global(true).help(
+            "Advertise the URL as an Eddystone-URL Bluetooth beacon, so phones nearby \
+             can open it without scanning anything. Needs BlueZ's btmgmt and usually \
+             root, the URL must fit into 17 bytes",
+        ))
+        .arg(
+            Arg::with_name("allow indexing")
+                .long("allow-indexing")
+                .global(true)
+                .help(
+                    "Don't serve a deny-all robots.txt and X-Robots-Tag headers, which are added \
+                     when serving under a domain or on a public address",
+                ),
+        )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
                 .value_name("PORT")
                 .default_value("3000")
+                .global(true)
                 .validator(|p: String| match &p.parse::<u16>() {
                     Ok(_) => Ok(()),
                     Err(_) => Err(String::from("Must be a integer between 0 and 65536")),
                 })
                 .help("Port the web server listens on"),
         )
+        .subcommand(
+            SubCommand::with_name("sync")
+                .about(
+                    "Synchronise a directory with another rustbelt instance. Without --with, \
+                     serve DIR to a peer, which may read and write all files in it",
+                )
+                .arg(
+                    Arg::with_name("DIR")
+                        .required(true)
+                        .validator(|s: String| {
+                            if Path::new(&s).is_dir() {
+                                Ok(())
+                            } else {
+                                Err(String::from("Not a directory"))
+                            }
+                        })
+                        .help("Directory to synchronise"),
+                )
+                .arg(
+                    Arg::with_name("peer")
+                        .long("with")
+                        .value_name("PEER_URL")
+                        .help("URL of the rustbelt instance serving the other side"),
+                )
+                .arg(
+                    Arg::with_name("conflict")
+                        .long("conflict")
+                        .value_name("POLICY")
+                        .possible_values(&["newer", "local", "remote", "skip"])
+                        .default_value("newer")
+                        .help("Which version to keep if a file differs on both sides"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inbox")
+                .about(
+                    "Run a drop box that gives every sender an upload link of their own. Type \
+                     mint <name> to create one, uploads are stored in a folder named after the \
+                     sender. Links stay valid across restarts until revoked",
+                )
+                .arg(
+                    Arg::with_name("DIR")
+                        .required(true)
+                        .help("Directory the folders of the senders are created in"),
+                )
+                .arg(
+                    Arg::with_name("sender")
+                        .long("sender")
+                        .value_name("NAME[=DESTINATION]")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "Create a link for NAME right away. With DESTINATION, like \
+                             photos/{date}/{original}, their uploads are stored there instead. \
+                             {sender}, {date} and {original} stand for the name of the sender, \
+                             the day of the upload and the name of the file. Can be given \
+                             multiple times",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("expire after")
+                        .long("expire-after")
+                        .value_name("DAYS")
+                        .validator(validate_count)
+                        .help("Let links expire DAYS days after they are minted"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("session")
+                .about(
+                    "Share several files and texts behind one link and QR code, on an index page \
+                     listing them. Type items to see them, revoke item <n> or expire item <n> \
+                     <minutes> to take single ones back",
+                )
+                .arg(
+                    Arg::with_name("ITEM")
+                        .required(true)
+                        .multiple(true)
+                        .help(
+                            "A file, text:TEXT for a text, or clipboard for the text on the \
+                             clipboard now",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("expire after")
+                        .long("expire-after")
+                        .value_name("MINUTES")
+                        .validator(validate_count)
+                        .help("Let every item expire MINUTES minutes after the start"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tail")
+                .about(
+                    "Follow a growing file like tail -f and stream new lines to everyone \
+                     connecting, as plain text or as server-sent events at /events",
+                )
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .validator(|s: String| {
+                            if Path::new(&s).is_file() {
+                                Ok(())
+                            } else {
+                                Err(String::from("Not a file"))
+                            }
+                        })
+                        .help("File to follow"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about(
+                    "Download a file shared by another rustbelt instance. Failed downloads are \
+                     retried, over the other addresses of the sender as well, and the file is \
+                     checked against the SHA-256 announced by the sender",
+                )
+                .arg(
+                    Arg::with_name("URL")
+                        .required(true)
+                        .help("URL printed by the sending rustbelt instance"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Where to store the download instead of the sender's file name"),
+                )
+                .arg(
+                    Arg::with_name("parallel")
+                        .long("parallel")
+                        .value_name("N")
+                        .default_value("4")
+                        .validator(validate_count)
+                        .help("Number of pieces downloaded at the same time"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about(
+                    "Send the requests recorded with --record to another server at the same \
+                     pace, and compare its answers with the recorded ones",
+                )
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("Recording written by --record"),
+                )
+                .arg(
+                    Arg::with_name("URL")
+                        .required(true)
+                        .help("Server to send the requests to, like http://127.0.0.1:8080"),
+                )
+                .arg(
+                    Arg::with_name("fast")
+                        .long("fast")
+                        .help("Send every request right away instead of at its recorded time"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("send")
+                .about(
+                    "Upload a file to another rustbelt instance running with --receive. \
+                     Interrupted uploads are retried and continue where they stopped",
+                )
+                .arg(
+                    Arg::with_name("URL")
+                        .required(true)
+                        .help("URL printed by the receiving rustbelt instance"),
+                )
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .validator(|s: String| {
+                            if Path::new(&s).is_file() {
+                                Ok(())
+                            } else {
+                                Err(String::from("Not a file"))
+                            }
+                        })
+                        .help("File to upload"),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .value_name("NAME")
+                        .help("Your name, for receivers running with --ask-name"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("relay")
+                .about(
+                    "Relay transfers between machines that can't reach each other. Run this on a \
+                     machine both can reach, then use relay send and relay get. Both sides try to \
+                     connect directly first and only go through the relay if that fails. \
+                     Transfers are end-to-end encrypted, the relay never sees the content",
+                )
+                .subcommand(
+                    SubCommand::with_name("send")
+                        .about("Send a file through a relay and print the share code")
+                        .arg(
+                            Arg::with_name("RELAY")
+                                .required(true)
+                                .help("URL printed by the relay"),
+                        )
+                        .arg(
+                            Arg::with_name("FILE")
+                                .required(true)
+                                .validator(|s: String| {
+                                    if Path::new(&s).is_file() {
+                                        Ok(())
+                                    } else {
+                                        Err(String::from("Not a file"))
+                                    }
+                                })
+                                .help("File to send"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("get")
+                        .about("Receive a file sent through a relay")
+                        .arg(
+                            Arg::with_name("RELAY")
+                                .required(true)
+                                .help("URL printed by the relay"),
+                        )
+                        .arg(
+                            Arg::with_name("CODE")
+                                .required(true)
+                                .help("Share code printed by the sender"),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .short("o")
+                                .long("output")
+                                .value_name("FILE")
+                                .help("Where to store the file instead of the sender's file name"),
+                        ),
+                ),
+        )
+        .subcommand(SubCommand::with_name("neighbors").about(
+            "List the devices currently visible on the network of an interface, to check \
+             whether the recipient is on the same network",
+        ))
+        .subcommand(
+            SubCommand::with_name("trash")
+                .about(
+                    "List or restore files rustbelt moved to the trash, like rejected uploads \
+                     and the sources of --move",
+                )
+                .subcommand(
+                    SubCommand::with_name("list").about("List the trash, most recent first"),
+                )
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .about("Restore the most recently trashed item with the given name or path")
+                        .arg(
+                            Arg::with_name("ITEM")
+                                .required(true)
+                                .help("File name or original path of the item"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("qr")
+                .about(
+                    "Show a QR code for any text, like Wi-Fi credentials, a URL or a TOTP seed, \
+                     without starting a server",
+                )
+                .arg(
+                    Arg::with_name("TEXT")
+                        .required(true)
+                        .help("Text to encode, or - to read it from stdin"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help(
+                            "Write the QR code to FILE instead of the terminal. The format \
+                             follows the extension, .png or .svg",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["terminal", "png", "svg"])
+                        .help("Format of the QR code, regardless of the extension of FILE"),
+                ),
+        )
         .get_matches();
 
-    if matches.occurrences_of("verbose") >= 1 {
-        println!("Arguments: {:?}", matches);
+    if matches.occurrences_of("verbose") >= 2 {
+        eprintln!("Arguments: {:?}", matches);
     }
 
-    rustbelt::run_rustbelt(&matches)
+    if let Err(e) = rustbelt::run_rustbelt(&matches) {
+        eprintln!("Error: {}", e);
+        process::exit(rustbelt::get_exit_code(e.as_ref()));
+    }
 }
@@ -0,0 +1,190 @@
+//! Listings of all files below a directory together with their size, modification time and hash
+
+use crate::receive;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Describes all shared files for scripts on the receiving side, see `create_json`
+pub const MANIFEST_JSON_PATH: &str = "/manifest.json";
+
+/// A single file in a manifest. `path` is relative to the manifest's root and always uses `/` as
+/// separator, independent of the platform.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub path: String,
+    pub size: u64,
+    pub modified: u64,
+    pub sha256: String,
+}
+
+/// Walks `root` recursively and describes every regular file in it, sorted by path. Files still
+/// being written are left out.
+pub fn create_manifest(root: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    collect_entries(root, "", &mut entries)?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+fn collect_entries(dir: &Path, prefix: &str, entries: &mut Vec<Entry>) -> io::Result<()> {
+    for dir_entry in fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        let path = format!("{}{}", prefix, name);
+        let file_type = dir_entry.file_type()?;
+        if file_type.is_dir() {
+            collect_entries(&dir_entry.path(), &format!("{}/", path), entries)?;
+        } else if file_type.is_file() && !receive::is_temp_file(&dir_entry.path()) {
+            entries.push(create_entry(&dir_entry.path(), path)?);
+        }
+    }
+    Ok(())
+}
+
+/// Describes the file at `file` under the manifest path `path`.
+pub fn create_entry(file: &Path, path: String) -> io::Result<Entry> {
+    let metadata = fs::metadata(file)?;
+    let modified = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+    Ok(Entry {
+        path,
+        size: metadata.len(),
+        modified,
+        sha256: hash_file(file)?,
+    })
+}
+
+/// Returns the hex encoded SHA-256 hash of a file's content.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    #[serde(flatten)]
+    entry: &'a Entry,
+    url: &'a str,
+}
+
+/// Renders `/manifest.json` from entries and the URL path each one is downloaded from, with the
+/// number of files and their total size, so recipients can plan downloads and check that they got
+/// everything.
+pub fn create_json(entries: &[(Entry, String)]) -> String {
+    let files = entries
+        .iter()
+        .map(|(entry, url)| JsonEntry { entry, url })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "files": entries.len(),
+        "size": entries.iter().map(|(e, _)| e.size).sum::<u64>(),
+        "entries": files,
+    })
+    .to_string()
+}
+
+/// Formats entries like the output of `sha256sum`, so recipients can check them with
+/// `sha256sum -c`. Names containing backslashes or line breaks are escaped the same way.
+pub fn create_sha256sums(entries: &[Entry]) -> String {
+    let mut sums = String::new();
+    for entry in entries {
+        if entry.path.contains(&['\\', '\n', '\r'][..]) {
+            let path = entry
+                .path
+                .replace('\\', "\\\\")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r");
+            sums.push_str(&format!("\\{}  {}\n", entry.sha256, path));
+        } else {
+            sums.push_str(&format!("{}  {}\n", entry.sha256, entry.path));
+        }
+    }
+    sums
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_sha256sums_one_line_per_entry(paths in proptest::collection::vec("\\PC*", 0..10)) {
+            let entries = paths
+                .iter()
+                .map(|p| Entry { path: p.clone(), size: 0, modified: 0, sha256: String::from("0") })
+                .collect::<Vec<_>>();
+            prop_assert_eq!(entries.len(), create_sha256sums(&entries).lines().count());
+        }
+    }
+
+    #[test]
+    fn test_sha256sums_escaping() {
+        let entry = |path: &str| Entry {
+            path: String::from(path),
+            size: 0,
+            modified: 0,
+            sha256: String::from("ab"),
+        };
+        assert_eq!(
+            "ab  a b.txt\n\\ab  x\\ny\\\\z\n",
+            create_sha256sums(&[entry("a b.txt"), entry("x\ny\\z")])
+        );
+    }
+
+    #[test]
+    fn test_create_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub").join("b.txt"), "abc").unwrap();
+        fs::write(dir.path().join("a.txt"), "").unwrap();
+
+        let manifest = create_manifest(dir.path()).unwrap();
+        let paths = manifest.iter().map(|e| e.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["a.txt", "sub/b.txt"], paths);
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            manifest[0].sha256
+        );
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            manifest[1].sha256
+        );
+        assert_eq!(3, manifest[1].size);
+    }
+
+    #[test]
+    fn test_create_json() {
+        let entry = |path: &str, size| Entry {
+            path: String::from(path),
+            size,
+            modified: 0,
+            sha256: String::from("ab"),
+        };
+        let entries = vec![
+            (entry("a.txt", 3), String::from("/files/a.txt")),
+            (entry("b c.txt", 4), String::from("/files/b%20c.txt")),
+        ];
+        let json: serde_json::Value = serde_json::from_str(&create_json(&entries)).unwrap();
+        assert_eq!(2, json["files"]);
+        assert_eq!(7, json["size"]);
+        assert_eq!("b c.txt", json["entries"][1]["path"]);
+        assert_eq!("ab", json["entries"][1]["sha256"]);
+        assert_eq!("/files/b%20c.txt", json["entries"][1]["url"]);
+    }
+}
@@ -0,0 +1,124 @@
+//! Which HTTP methods a share answers
+//!
+//! `--mode send|receive|exchange` says which way files go, and every mode stands for a fixed set
+//! of methods: sending allows reading with GET and HEAD, receiving adds PUT and POST for uploads,
+//! an exchange allows both. Requests with any other method are refused with `405 Method Not
+//! Allowed` and an Allow header before they reach the handler of the share, so a handler never
+//! has to tell whether it may write. Mounts have a mode of their own, see `mounts`.
+
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::fmt;
+use std::str::FromStr;
+
+/// Which way files go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    Send,
+    Receive,
+    Exchange,
+}
+
+const SEND_METHODS: [Method; 3] = [Method::GET, Method::HEAD, Method::OPTIONS];
+/// The upload page and the offsets of interrupted uploads are read with GET.
+const RECEIVE_METHODS: [Method; 5] = [
+    Method::GET,
+    Method::HEAD,
+    Method::PUT,
+    Method::POST,
+    Method::OPTIONS,
+];
+
+impl FromStr for AccessMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "send" => Ok(AccessMode::Send),
+            "receive" => Ok(AccessMode::Receive),
+            "exchange" => Ok(AccessMode::Exchange),
+            _ => Err(format!("Unknown mode {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for AccessMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AccessMode::Send => "send",
+            AccessMode::Receive => "receive",
+            AccessMode::Exchange => "exchange",
+        })
+    }
+}
+
+impl AccessMode {
+    /// The methods clients may use
+    pub fn get_methods(self) -> &'static [Method] {
+        match self {
+            AccessMode::Send => &SEND_METHODS,
+            AccessMode::Receive | AccessMode::Exchange => &RECEIVE_METHODS,
+        }
+    }
+}
+
+/// Value of the Allow header for `methods`
+pub fn format_allow(methods: &[Method]) -> String {
+    methods
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Refuses `req` unless its method is one of `allowed`.
+pub fn check<T>(allowed: &[Method], req: &Request<T>) -> Option<Response<Body>> {
+    if allowed.contains(req.method()) {
+        return None;
+    }
+    Some(
+        Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, format_allow(allowed))
+            .body(Body::from("Method not allowed"))
+            .unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_exchange_allows_what_send_and_receive_allow(method in "[A-Z]{1,8}") {
+            let method = Method::from_bytes(method.as_bytes()).unwrap();
+            let allowed = |mode: AccessMode| mode.get_methods().contains(&method);
+            prop_assert_eq!(
+                allowed(AccessMode::Send) || allowed(AccessMode::Receive),
+                allowed(AccessMode::Exchange)
+            );
+            prop_assert!(!allowed(AccessMode::Send) || allowed(AccessMode::Receive));
+        }
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        for mode in &[AccessMode::Send, AccessMode::Receive, AccessMode::Exchange] {
+            assert_eq!(Ok(*mode), mode.to_string().parse());
+        }
+        assert!("upload".parse::<AccessMode>().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let request = |method: Method| Request::builder().method(method).body(()).unwrap();
+        let allowed = AccessMode::Send.get_methods();
+        assert!(check(allowed, &request(Method::HEAD)).is_none());
+        let response = check(allowed, &request(Method::PUT)).unwrap();
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+        assert_eq!("GET, HEAD, OPTIONS", response.headers()[header::ALLOW]);
+        assert!(check(AccessMode::Receive.get_methods(), &request(Method::PUT)).is_none());
+        assert!(check(AccessMode::Exchange.get_methods(), &request(Method::DELETE)).is_some());
+    }
+}
@@ -0,0 +1,316 @@
+//! Statistics for the node_exporter textfile collector with `--metrics-textfile`
+//!
+//! Users with a Prometheus setup already run node_exporter on their machines. Started with
+//! `--metrics-textfile FILE`, the server writes its counters to FILE every `WRITE_INTERVAL` in the
+//! text format the collector picks up from its `--collector.textfile.directory`: requests, bytes
+//! sent and received, clients and when the share started, labeled with the mode and the name of
+//! the share. The file is replaced in one rename, so the collector never reads half of it, and
+//! removed when the server stops, so stopped shares don't show up as frozen ones.
+
+use bytes::Bytes;
+use futures::stream::Stream;
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Method, Request, Response};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the file is written
+pub const WRITE_INTERVAL: Duration = Duration::from_secs(15);
+/// The collector ignores files with other extensions.
+const EXTENSION: &str = "prom";
+
+/// What the server reports besides the bytes it counts itself
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sample {
+    pub requests: u64,
+    pub clients: usize,
+}
+
+/// The counters of a share and the file they are written to
+pub struct Textfile {
+    path: PathBuf,
+    labels: String,
+    started: SystemTime,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Textfile {
+    pub fn new(path: &Path, mode: &str, share: &str) -> Textfile {
+        Textfile {
+            path: path.to_path_buf(),
+            labels: format!(
+                "mode=\"{}\",share=\"{}\"",
+                escape_label(mode),
+                escape_label(share)
+            ),
+            started: SystemTime::now(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        }
+    }
+
+    /// Why the collector won't read the file, if it won't
+    pub fn check_path(&self) -> Option<String> {
+        if self.path.extension().is_some_and(|e| e == EXTENSION) {
+            return None;
+        }
+        Some(format!(
+            "node_exporter only reads files ending in .{}, it will ignore {}",
+            EXTENSION,
+            self.path.display()
+        ))
+    }
+
+    /// Counts the bytes of an upload. Requests that don't upload are left alone.
+    pub fn count_request(self: &Arc<Self>, req: Request<Body>) -> Request<Body> {
+        if !matches!(*req.method(), Method::PUT | Method::POST) {
+            return req;
+        }
+        let (parts, body) = req.into_parts();
+        let body = CountedBody {
+            body,
+            textfile: self.clone(),
+            direction: Direction::Received,
+        };
+        Request::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    /// Counts the bytes of a response body as they are sent.
+    pub fn count_response(self: &Arc<Self>, response: Response<Body>) -> Response<Body> {
+        if HttpBody::is_end_stream(response.body()) {
+            return response;
+        }
+        let (mut parts, body) = response.into_parts();
+        // A wrapped body loses its length, which is announced in the header instead.
+        if !parts.headers.contains_key(header::CONTENT_LENGTH) {
+            if let Some(length) = HttpBody::size_hint(&body).exact() {
+                parts
+                    .headers
+                    .insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+            }
+        }
+        let body = CountedBody {
+            body,
+            textfile: self.clone(),
+            direction: Direction::Sent,
+        };
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    /// The file's content in the Prometheus text format
+    pub fn format(&self, sample: Sample) -> String {
+        let started = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let metrics = [
+            (
+                "rustbelt_requests_total",
+                "counter",
+                "Requests the share answered.",
+                sample.requests,
+            ),
+            (
+                "rustbelt_sent_bytes_total",
+                "counter",
+                "Bytes of response bodies sent.",
+                self.sent.load(Ordering::SeqCst),
+            ),
+            (
+                "rustbelt_received_bytes_total",
+                "counter",
+                "Bytes of uploads received.",
+                self.received.load(Ordering::SeqCst),
+            ),
+            (
+                "rustbelt_clients",
+                "gauge",
+                "Clients that sent a request.",
+                sample.clients as u64,
+            ),
+            (
+                "rustbelt_start_time_seconds",
+                "gauge",
+                "When the share started, in seconds since the Unix epoch.",
+                started,
+            ),
+        ];
+        metrics
+            .iter()
+            .map(|(name, kind, help, value)| {
+                format!(
+                    "# HELP {name} {help}\n# TYPE {name} {kind}\n{name}{{{labels}}} {value}\n",
+                    name = name,
+                    help = help,
+                    kind = kind,
+                    labels = self.labels,
+                    value = value
+                )
+            })
+            .collect()
+    }
+
+    /// Replaces the file with the current values. The new content is written next to it first, so
+    /// the collector reads either the old or the new file.
+    pub fn write(&self, sample: Sample) -> io::Result<()> {
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, self.format(sample))?;
+        fs::rename(&temporary, &self.path)
+    }
+
+    /// Removes the file once the share stopped.
+    pub fn remove(&self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("Could not remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Escapes a label value, in which backslashes, quotes and line breaks have to be escaped.
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+enum Direction {
+    Sent,
+    Received,
+}
+
+/// A body adding the length of every chunk to a counter
+struct CountedBody {
+    body: Body,
+    textfile: Arc<Textfile>,
+    direction: Direction,
+}
+
+impl Stream for CountedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            let counter = match this.direction {
+                Direction::Sent => &this.textfile.sent,
+                Direction::Received => &this.textfile.received,
+            };
+            counter.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn formats_the_metrics() {
+        let textfile = Textfile::new(Path::new("rustbelt.prom"), "send", "report.pdf");
+        textfile.sent.store(2048, Ordering::SeqCst);
+        let text = textfile.format(Sample {
+            requests: 3,
+            clients: 1,
+        });
+        assert!(text.contains(
+            "# HELP rustbelt_requests_total Requests the share answered.\n\
+             # TYPE rustbelt_requests_total counter\n\
+             rustbelt_requests_total{mode=\"send\",share=\"report.pdf\"} 3\n"
+        ));
+        assert!(
+            text.contains("rustbelt_sent_bytes_total{mode=\"send\",share=\"report.pdf\"} 2048\n")
+        );
+        assert!(
+            text.contains("rustbelt_received_bytes_total{mode=\"send\",share=\"report.pdf\"} 0\n")
+        );
+        assert!(text.contains("rustbelt_clients{mode=\"send\",share=\"report.pdf\"} 1\n"));
+        assert!(text.contains("# TYPE rustbelt_start_time_seconds gauge\n"));
+    }
+
+    #[test]
+    fn escapes_labels() {
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+
+    #[test]
+    fn checks_the_extension() {
+        let textfile = Textfile::new(Path::new("/var/lib/node/rustbelt.prom"), "send", "a");
+        assert_eq!(textfile.check_path(), None);
+        let textfile = Textfile::new(Path::new("/var/lib/node/rustbelt.txt"), "send", "a");
+        assert!(textfile.check_path().is_some());
+    }
+
+    #[test]
+    fn replaces_and_removes_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rustbelt.prom");
+        let textfile = Textfile::new(&path, "receive", "inbox");
+        textfile.write(Sample::default()).unwrap();
+        textfile
+            .write(Sample {
+                requests: 5,
+                clients: 2,
+            })
+            .unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("rustbelt_requests_total{mode=\"receive\",share=\"inbox\"} 5\n"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        textfile.remove();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn counts_uploads_and_responses() {
+        let textfile = Arc::new(Textfile::new(Path::new("rustbelt.prom"), "receive", "a"));
+        let req = Request::put("/file").body(Body::from("12345")).unwrap();
+        let req = textfile.count_request(req);
+        hyper::body::to_bytes(req.into_body()).await.unwrap();
+        let response = textfile.count_response(Response::new(Body::from("abc")));
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "3");
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(textfile.received.load(Ordering::SeqCst), 5);
+        assert_eq!(textfile.sent.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn leaves_empty_responses_alone() {
+        let textfile = Arc::new(Textfile::new(Path::new("rustbelt.prom"), "send", "a"));
+        let response = textfile.count_response(Response::new(Body::empty()));
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        let req = textfile.count_request(Request::get("/").body(Body::from("x")).unwrap());
+        hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(textfile.received.load(Ordering::SeqCst), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn escaped_labels_have_no_line_breaks_or_bare_quotes(value in "(?s).*") {
+            let escaped = escape_label(&value);
+            prop_assert!(!escaped.contains('\n'));
+            let unescaped = escaped.replace("\\\\", "").replace("\\\"", "");
+            prop_assert!(!unescaped.contains('"'));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Other addresses of the same share, for clients to fall back to
+//!
+//! A server listening on several addresses, with `--both-families` or after its interface got a
+//! new one, names the others in `Link: <URL>; rel=duplicate` headers (RFC 6249) of its file
+//! downloads. `rustbelt get` collects them and moves on to the next address when one fails, even
+//! in the middle of a download.
+
+use hyper::header::{self, HeaderMap, HeaderValue};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Adds a duplicate link to `path` on every address in `urls` but the one in `host`.
+pub fn add_links(headers: &mut HeaderMap, urls: &[String], host: Option<&str>, path: &str) {
+    for url in urls {
+        let authority = url.split("://").nth(1).unwrap_or(url.as_str());
+        if Some(authority) == host {
+            continue;
+        }
+        let link = format!("<{}{}>; rel=duplicate", url, path);
+        if let Ok(value) = HeaderValue::from_str(&link) {
+            headers.append(header::LINK, value);
+        }
+    }
+}
+
+/// The URLs of the duplicate links in `headers`
+pub fn parse_links(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|link| {
+            let mut parts = link.split(';').map(str::trim);
+            let url = parts.next()?.strip_prefix('<')?.strip_suffix('>')?;
+            let duplicate = parts.any(|p| {
+                let p = p.replace(' ', "");
+                p.eq_ignore_ascii_case("rel=duplicate")
+                    || p.eq_ignore_ascii_case("rel=\"duplicate\"")
+            });
+            if duplicate && url.starts_with("http://") {
+                Some(url.to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The addresses a download can use, the one that worked last is used next
+#[derive(Debug)]
+pub struct Mirrors {
+    urls: Mutex<Vec<String>>,
+    current: AtomicUsize,
+}
+
+impl Mirrors {
+    pub fn new(url: &str) -> Mirrors {
+        Mirrors {
+            urls: Mutex::new(vec![url.trim_end_matches('/').to_string()]),
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds the addresses named by the `Link` headers of a response, returns how many were new.
+    pub fn add_links(&self, headers: &HeaderMap) -> usize {
+        let mut urls = self.urls.lock().unwrap();
+        let mut added = 0;
+        for url in parse_links(headers) {
+            let url = url.trim_end_matches('/').to_string();
+            if !urls.contains(&url) {
+                urls.push(url);
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// The index and URL of the address to use next
+    pub fn get(&self) -> (usize, String) {
+        let urls = self.urls.lock().unwrap();
+        let index = self.current.load(Ordering::SeqCst) % urls.len();
+        (index, urls[index].clone())
+    }
+
+    /// Moves on to the next address after the one at `index` failed. Parallel requests that
+    /// failed on the same address only move on once.
+    pub fn fail(&self, index: usize) {
+        let count = self.urls.lock().unwrap().len();
+        let _ = self.current.compare_exchange(
+            index,
+            (index + 1) % count,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_links_roundtrip(ports in proptest::collection::vec(1u16.., 0..5)) {
+            let urls = ports
+                .iter()
+                .map(|port| format!("http://10.0.0.1:{}", port))
+                .collect::<Vec<_>>();
+            let mut headers = HeaderMap::new();
+            add_links(&mut headers, &urls, None, "/abc/");
+            let expected = urls.iter().map(|u| format!("{}/abc/", u)).collect::<Vec<_>>();
+            prop_assert_eq!(expected, parse_links(&headers));
+        }
+    }
+
+    #[test]
+    fn test_add_links_skips_own_address() {
+        let urls = vec![
+            String::from("http://192.168.1.5:8080"),
+            String::from("http://[fd00::5]:8080"),
+        ];
+        let mut headers = HeaderMap::new();
+        add_links(&mut headers, &urls, Some("192.168.1.5:8080"), "/");
+        assert_eq!(vec!["http://[fd00::5]:8080/"], parse_links(&headers));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::LINK,
+            "<http://a/>; rel=\"duplicate\", <http://b/>; rel=next, <ftp://c/>; rel=duplicate"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(vec!["http://a/"], parse_links(&headers));
+    }
+
+    #[test]
+    fn test_failover() {
+        let mirrors = Mirrors::new("http://a/");
+        assert_eq!((0, String::from("http://a")), mirrors.get());
+        mirrors.fail(0);
+        assert_eq!(0, mirrors.get().0);
+
+        let mut headers = HeaderMap::new();
+        add_links(
+            &mut headers,
+            &[String::from("http://a"), String::from("http://b")],
+            None,
+            "/",
+        );
+        assert_eq!(1, mirrors.add_links(&headers));
+        assert_eq!(0, mirrors.add_links(&headers));
+        mirrors.fail(0);
+        mirrors.fail(0);
+        assert_eq!((1, String::from("http://b")), mirrors.get());
+        mirrors.fail(1);
+        assert_eq!(0, mirrors.get().0);
+    }
+}
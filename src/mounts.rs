@@ -0,0 +1,462 @@
+//! Serving several directories under their own URL prefixes
+//!
+//! Every mount is given as `NAME=DIR[,OPTION…]` and appears at `/NAME/`. Mounts are read-only
+//! unless `rw` is given, which allows uploading files with PUT. `mode=send|receive|exchange` says
+//! the same explicitly, a mount in receive mode only takes uploads and can't be listed or read,
+//! there's no upload page to show. `auth=USER:PASSWORD` protects a mount with HTTP basic
+//! authentication. Single paths can additionally be protected with a PIN,
+//! see `access`.
+
+use crate::access::{self, AccessRules};
+use crate::methods::AccessMode;
+use crate::{
+    create_content_disposition, create_status_response, help, html, manifest, output, paths,
+    serve_manifest_json, sync, transfer,
+};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// A directory served below `/<name>/`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mount {
+    pub name: String,
+    pub root: PathBuf,
+    pub mode: AccessMode,
+    /// `USER:PASSWORD` required to access the mount
+    pub credentials: Option<String>,
+    /// Rules from the access file in the mounted directory
+    pub access: AccessRules,
+}
+
+impl FromStr for Mount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mount, String> {
+        let (name, rest) = match s.find('=') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return Err(format!("Expected NAME=DIR[,OPTION…]: {}", s)),
+        };
+        if name.is_empty() || name == "." || name == ".." || name.contains(&['/', '\\', '%'][..]) {
+            return Err(format!("Not a valid mount name: {}", name));
+        }
+        let mut parts = rest.split(',');
+        let root = PathBuf::from(parts.next().unwrap_or_default());
+        if !root.is_dir() {
+            return Err(format!("Not a directory: {}", root.display()));
+        }
+        let mut mount = Mount {
+            name: name.to_string(),
+            root,
+            mode: AccessMode::Send,
+            credentials: None,
+            access: AccessRules::default(),
+        };
+        for option in parts {
+            match option {
+                "ro" => mount.mode = AccessMode::Send,
+                "rw" => mount.mode = AccessMode::Exchange,
+                _ if option.starts_with("mode=") => mount.mode = option["mode=".len()..].parse()?,
+                _ if option.starts_with("auth=") && option.contains(':') => {
+                    mount.credentials = Some(option["auth=".len()..].to_string())
+                }
+                _ => return Err(format!("Unknown mount option: {}", option)),
+            }
+        }
+        Ok(mount)
+    }
+}
+
+impl Mount {
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let credentials = match &self.credentials {
+            Some(c) => c,
+            None => return true,
+        };
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|a| a.to_str().ok())
+            .and_then(|a| a.strip_prefix("Basic "))
+            .and_then(|a| base64::decode(a.trim()).ok())
+            .is_some_and(|decoded| decoded == credentials.as_bytes())
+    }
+}
+
+/// All mounts of the server. Further directories can be added while it is running.
+pub struct MountTable {
+    mounts: RwLock<Vec<Mount>>,
+    /// Rules for paths starting with the mount name
+    protect: AccessRules,
+}
+
+impl MountTable {
+    /// Creates the table and loads the access file of every mount.
+    pub fn new(mut mounts: Vec<Mount>, protect: AccessRules) -> io::Result<MountTable> {
+        for mount in &mut mounts {
+            mount.access = AccessRules::default().load(&mount.root)?;
+        }
+        Ok(MountTable {
+            mounts: RwLock::new(mounts),
+            protect,
+        })
+    }
+
+    fn find(&self, name: &str) -> Option<Mount> {
+        let mounts = self.mounts.read().unwrap();
+        mounts.iter().find(|m| m.name == name).cloned()
+    }
+
+    /// Returns name and directory of every mount.
+    pub fn get_mounts(&self) -> Vec<(String, PathBuf)> {
+        let mounts = self.mounts.read().unwrap();
+        mounts
+            .iter()
+            .map(|m| (m.name.clone(), m.root.clone()))
+            .collect()
+    }
+
+    /// The requests the mounts answer. Mounts in send mode are only read, ones in receive mode
+    /// only take uploads.
+    pub fn get_routes(&self) -> Vec<help::Route<Route>> {
+        use help::Route as R;
+        let mut routes = vec![
+            R::new(
+                Method::GET,
+                manifest::MANIFEST_JSON_PATH,
+                "Paths, sizes, SHA-256 and URLs of the files open to everyone as JSON",
+                Route::Manifest,
+            ),
+            R::new(Method::GET, "/", "Lists the mounts", Route::Index),
+        ];
+        for mount in self.mounts.read().unwrap().iter() {
+            let prefix = format!("/{}", mount.name);
+            let path = format!("{}/<path>", prefix);
+            if mount.mode != AccessMode::Receive {
+                routes.push(
+                    R::new(Method::GET, &path, "Downloads a file", Route::Mount).below(&prefix),
+                );
+            }
+            if mount.mode != AccessMode::Send {
+                routes.push(
+                    R::new(
+                        Method::PUT,
+                        &path,
+                        "Stores the body as the file",
+                        Route::Mount,
+                    )
+                    .below(&prefix),
+                );
+            }
+        }
+        routes
+    }
+
+    /// Whether a mount takes the path `/help` for itself
+    pub fn has_help_mount(&self) -> bool {
+        self.find("help").is_some()
+    }
+
+    /// Lists the files of every mount that is open to everyone, leaving out PIN protected paths.
+    fn create_manifest(&self) -> io::Result<Vec<(manifest::Entry, String)>> {
+        let mut entries = Vec::new();
+        for mount in self.mounts.read().unwrap().iter() {
+            if mount.credentials.is_some() {
+                continue;
+            }
+            for entry in manifest::create_manifest(&mount.root)? {
+                let path = format!("{}/{}", mount.name, entry.path);
+                if mount.access.is_protected(&entry.path) || self.protect.is_protected(&path) {
+                    continue;
+                }
+                let url = format!("/{}", paths::percent_encode_path(&path));
+                entries.push((entry, url));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Adds a read-only mount of `root`, named after the directory, and returns the name.
+    pub fn add_directory(&self, root: PathBuf) -> Result<String, String> {
+        if !root.is_dir() {
+            return Err(format!("Not a directory: {}", root.display()));
+        }
+        let access = AccessRules::default()
+            .load(&root)
+            .map_err(|e| format!("Could not read {}: {}", access::ACCESS_FILE, e))?;
+        let base = match root
+            .canonicalize()
+            .ok()
+            .and_then(|r| r.file_name().map(|n| n.to_owned()))
+        {
+            Some(n) => n.to_string_lossy().replace(&['/', '\\', '%'][..], "_"),
+            None => String::from("root"),
+        };
+        let mut mounts = self.mounts.write().unwrap();
+        let mut name = base.clone();
+        let mut counter = 1;
+        while mounts.iter().any(|m| m.name == name) {
+            counter += 1;
+            name = format!("{}-{}", base, counter);
+        }
+        mounts.push(Mount {
+            name: name.clone(),
+            root,
+            mode: AccessMode::Send,
+            credentials: None,
+            access,
+        });
+        Ok(name)
+    }
+}
+
+/// Lists a directory with subdirectories first, each marked by a trailing slash.
+fn list_directory(dir: &Path) -> io::Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == access::ACCESS_FILE {
+            continue;
+        }
+        let is_dir = entry.file_type()?.is_dir();
+        entries.push((!is_dir, name));
+    }
+    entries.sort();
+    Ok(entries
+        .into_iter()
+        .map(|(is_file, name)| {
+            let suffix = if is_file { "" } else { "/" };
+            (
+                format!("{}{}", paths::percent_encode(&name), suffix),
+                format!("{}{}", name, suffix),
+            )
+        })
+        .collect())
+}
+
+fn create_page_response(title: &str, links: &[(String, String)]) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(html::create_listing_page(title, links)))
+        .unwrap()
+}
+
+/// What a request of the mounts is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Index,
+    Manifest,
+    Mount,
+}
+
+pub async fn handle_request(
+    table: Arc<MountTable>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    match help::find_route(&table.get_routes(), &req) {
+        Ok(Route::Manifest) => {
+            return Ok(serve_manifest_json(move || table.create_manifest(), &req).await)
+        }
+        Ok(Route::Index) => {
+            let links = table
+                .get_mounts()
+                .into_iter()
+                .map(|(name, _)| (format!("/{}/", name), format!("{}/", name)))
+                .collect::<Vec<_>>();
+            return Ok(create_page_response("rustbelt", &links));
+        }
+        Ok(Route::Mount) => {}
+        Err(response) => return Ok(response),
+    }
+    let uri_path = req.uri().path().to_string();
+
+    let trimmed = uri_path.trim_start_matches('/');
+    let (name, rest) = match trimmed.find('/') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => (trimmed, ""),
+    };
+    let mount = match table.find(name) {
+        Some(m) => m,
+        None => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    if !mount.is_authorized(&req) {
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(
+                header::WWW_AUTHENTICATE,
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", mount.name),
+            )
+            .body(Body::from("Unauthorized"))
+            .unwrap());
+    }
+    let relative = match rest.trim_end_matches('/') {
+        "" => Some(PathBuf::new()),
+        r => paths::get_relative_path(r),
+    };
+    let (path, decoded) = match relative {
+        Some(r) => (mount.root.join(&r), r.to_string_lossy().replace('\\', "/")),
+        None => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let denied = table
+        .protect
+        .check(&format!("{}/{}", mount.name, decoded), &req)
+        .or_else(|| mount.access.check(&decoded, &req));
+    if let Some(response) = denied {
+        return Ok(response);
+    }
+
+    match *req.method() {
+        Method::GET if path.is_dir() => {
+            if !uri_path.ends_with('/') {
+                return Ok(Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header(header::LOCATION, format!("{}/", uri_path))
+                    .body(Body::empty())
+                    .unwrap());
+            }
+            let title = paths::percent_decode(&uri_path).unwrap_or_default();
+            match list_directory(&path) {
+                Ok(links) => Ok(create_page_response(&title, &links)),
+                Err(_) => Ok(create_status_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Could not read directory",
+                )),
+            }
+        }
+        Method::GET => Ok(serve_mounted_file(&path).await),
+        Method::PUT if !rest.is_empty() && !rest.ends_with('/') => {
+            match sync::write_body(&path, req.into_body()).await {
+                Ok(_) => {
+                    output::print_event(&format!("Received {} in {}", rest, mount.name));
+                    Ok(create_status_response(StatusCode::CREATED, "Received"))
+                }
+                Err(e) => {
+                    eprintln!("Failed to receive {} in {}: {}", rest, mount.name, e);
+                    Ok(create_status_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Failed to store the file",
+                    ))
+                }
+            }
+        }
+        _ => Ok(create_status_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+        )),
+    }
+}
+
+async fn serve_mounted_file(path: &Path) -> Response<Body> {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(f) => f,
+        Err(_) => return create_status_response(StatusCode::NOT_FOUND, "File not found"),
+    };
+    let length = match file.metadata().await {
+        Ok(m) => m.len(),
+        Err(_) => {
+            return create_status_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not read file")
+        }
+    };
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, length)
+        .header(
+            header::CONTENT_DISPOSITION,
+            create_content_disposition(&file_name),
+        )
+        .body(Body::wrap_stream(transfer::FileStream::new(file)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy();
+        assert_eq!(
+            Ok(Mount {
+                name: String::from("docs"),
+                root: dir.path().to_path_buf(),
+                mode: AccessMode::Exchange,
+                credentials: Some(String::from("me:se:cret")),
+                access: AccessRules::default(),
+            }),
+            format!("docs={},rw,auth=me:se:cret", root).parse()
+        );
+        assert!(format!("docs={},ro", root)
+            .parse::<Mount>()
+            .map(|m| m.mode == AccessMode::Send && m.credentials.is_none())
+            .unwrap());
+        assert_eq!(
+            Ok(AccessMode::Receive),
+            format!("inbox={},mode=receive", root)
+                .parse::<Mount>()
+                .map(|m| m.mode)
+        );
+        assert!(format!("docs={},mode=upload", root)
+            .parse::<Mount>()
+            .is_err());
+        assert!(format!("a/b={}", root).parse::<Mount>().is_err());
+        assert!(format!("..={}", root).parse::<Mount>().is_err());
+        assert!(format!("docs={},fast", root).parse::<Mount>().is_err());
+        assert!("docs".parse::<Mount>().is_err());
+    }
+
+    #[test]
+    fn test_authorization() {
+        let mount = Mount {
+            name: String::from("docs"),
+            root: PathBuf::new(),
+            mode: AccessMode::Send,
+            credentials: Some(String::from("me:secret")),
+            access: AccessRules::default(),
+        };
+        let request = |authorization: &str| {
+            Request::get("/docs/")
+                .header(header::AUTHORIZATION, authorization)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert!(mount.is_authorized(&request(&format!("Basic {}", base64::encode("me:secret")))));
+        assert!(!mount.is_authorized(&request(&format!("Basic {}", base64::encode("me:wrong")))));
+        assert!(!mount.is_authorized(&Request::new(Body::empty())));
+    }
+
+    #[test]
+    fn test_add_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let docs = dir.path().join("docs");
+        fs::create_dir(&docs).unwrap();
+        let table = MountTable::new(Vec::new(), AccessRules::default()).unwrap();
+        assert_eq!(Ok(String::from("docs")), table.add_directory(docs.clone()));
+        assert_eq!(
+            Ok(String::from("docs-2")),
+            table.add_directory(docs.clone())
+        );
+        assert!(table.add_directory(docs.join("missing")).is_err());
+        assert_eq!(2, table.get_mounts().len());
+    }
+
+    #[test]
+    fn test_list_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("z")).unwrap();
+        fs::write(dir.path().join("a b"), "").unwrap();
+        fs::write(dir.path().join(access::ACCESS_FILE), "").unwrap();
+        assert_eq!(
+            vec![
+                (String::from("z/"), String::from("z/")),
+                (String::from("a%20b"), String::from("a b"))
+            ],
+            list_directory(dir.path()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! The devices the kernel currently sees on a network interface
+//!
+//! When a phone can't open the URL, it is often on another network, like a guest Wi-Fi. If it
+//! doesn't show up among the neighbors after trying, the problem is the network, not rustbelt.
+//! The table is read from `ip neigh`, falling back to `/proc/net/arp` without IPv6.
+
+use crate::interfaces::NetworkInterface;
+use ipnetwork::IpNetwork;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Neighbor {
+    pub ip: IpAddr,
+    pub mac: Option<String>,
+    /// Like `reachable`, `stale` or `failed`
+    pub state: String,
+}
+
+/// Parses the output of `ip neigh show dev <interface>`.
+fn parse_ip_neigh(output: &str) -> Vec<Neighbor> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let ip = fields.first()?.parse().ok()?;
+            let mac = fields
+                .iter()
+                .position(|&f| f == "lladdr")
+                .and_then(|i| fields.get(i + 1))
+                .map(|m| m.to_string());
+            let state = fields.last().filter(|_| fields.len() > 1)?.to_lowercase();
+            Some(Neighbor { ip, mac, state })
+        })
+        .collect()
+}
+
+/// Parses `/proc/net/arp`, keeping the entries of `interface`.
+fn parse_proc_arp(content: &str, interface: &str) -> Vec<Neighbor> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() < 6 || fields[5] != interface {
+                return None;
+            }
+            // Flags 0x2 mark complete entries, incomplete ones have an all zero address.
+            let complete = u8::from_str_radix(fields[2].trim_start_matches("0x"), 16).ok()? & 2;
+            Some(Neighbor {
+                ip: fields[0].parse().ok()?,
+                mac: Some(fields[3].to_string()).filter(|_| complete != 0),
+                state: String::from(if complete != 0 {
+                    "reachable"
+                } else {
+                    "incomplete"
+                }),
+            })
+        })
+        .collect()
+}
+
+/// The neighbors of `interface`, as far as they can be read.
+pub fn get_neighbors(interface: &str) -> Vec<Neighbor> {
+    let output = Command::new("ip")
+        .args(["neigh", "show", "dev", interface])
+        .stdin(Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            parse_ip_neigh(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => fs::read_to_string("/proc/net/arp")
+            .map(|c| parse_proc_arp(&c, interface))
+            .unwrap_or_default(),
+    }
+}
+
+/// Keeps the neighbors in one of the subnets of the interface that answered at some point.
+fn filter_neighbors(neighbors: Vec<Neighbor>, networks: &[IpNetwork]) -> Vec<Neighbor> {
+    neighbors
+        .into_iter()
+        .filter(|n| n.state != "failed" && n.state != "incomplete")
+        .filter(|n| networks.iter().any(|net| net.contains(n.ip)))
+        .collect()
+}
+
+/// Lists the devices visible on the subnets of `interface`.
+pub fn write_neighbors<W: Write>(interface: &NetworkInterface, out: &mut W) -> io::Result<()> {
+    let neighbors = filter_neighbors(get_neighbors(&interface.name), &interface.ips);
+    if neighbors.is_empty() {
+        return writeln!(
+            out,
+            "No devices visible on {} yet. If the recipient can't connect, make sure it is on \
+             the same network and not on a guest Wi-Fi.",
+            interface.name
+        );
+    }
+    writeln!(out, "Devices visible on {}:", interface.name)?;
+    for neighbor in neighbors {
+        writeln!(
+            out,
+            "  {:<40} {:<18} {}",
+            neighbor.ip,
+            neighbor.mac.as_deref().unwrap_or("-"),
+            neighbor.state
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_parse_ip_neigh_lines(ip in any::<IpAddr>(), mac in any::<[u8; 6]>()) {
+            let mac = mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":");
+            let output = format!("{} lladdr {} router STALE\n", ip, mac);
+            let expected = Neighbor { ip, mac: Some(mac), state: String::from("stale") };
+            prop_assert_eq!(vec![expected], parse_ip_neigh(&output));
+        }
+    }
+
+    #[test]
+    fn test_parse_ip_neigh() {
+        let output = "192.168.1.1 lladdr aa:bb:cc:dd:ee:ff REACHABLE\n\
+                      192.168.1.9 FAILED\n\
+                      fe80::1 lladdr aa:bb:cc:dd:ee:01 router DELAY\n";
+        let neighbors = parse_ip_neigh(output);
+        assert_eq!(3, neighbors.len());
+        assert_eq!(None, neighbors[1].mac);
+        assert_eq!("failed", neighbors[1].state);
+        assert_eq!("delay", neighbors[2].state);
+    }
+
+    #[test]
+    fn test_parse_proc_arp() {
+        let content = "IP address       HW type     Flags       HW address            Mask     Device\n\
+                       192.168.1.1      0x1         0x2         aa:bb:cc:dd:ee:ff     *        wlan0\n\
+                       192.168.1.9      0x1         0x0         00:00:00:00:00:00     *        wlan0\n\
+                       10.0.0.1         0x1         0x2         aa:bb:cc:dd:ee:01     *        eth0\n";
+        let neighbors = parse_proc_arp(content, "wlan0");
+        assert_eq!(2, neighbors.len());
+        assert_eq!(Some(String::from("aa:bb:cc:dd:ee:ff")), neighbors[0].mac);
+        assert_eq!("incomplete", neighbors[1].state);
+    }
+
+    #[test]
+    fn test_filter_neighbors() {
+        let neighbor = |ip: &str, state: &str| Neighbor {
+            ip: ip.parse().unwrap(),
+            mac: None,
+            state: state.to_string(),
+        };
+        let networks = vec!["192.168.1.20/24".parse().unwrap()];
+        let neighbors = vec![
+            neighbor("192.168.1.1", "reachable"),
+            neighbor("192.168.1.2", "failed"),
+            neighbor("10.0.0.1", "reachable"),
+        ];
+        assert_eq!(
+            vec![neighbor("192.168.1.1", "reachable")],
+            filter_neighbors(neighbors, &networks)
+        );
+    }
+}
@@ -0,0 +1,440 @@
+//! The network the server runs on
+//!
+//! Listing the interfaces and accepting connections goes through the `Network` trait. The server
+//! uses `SystemNetwork`, the interfaces of this machine and TCP sockets. Tests use
+//! `mock::MockNetwork` instead, which has made-up interfaces and connects clients over in-memory
+//! pipes, so requests can be sent through the whole server without touching the network.
+//!
+//! With `--dual-stack` a single IPv6 socket serves both IP families: it is bound to the unspecified
+//! address with `IPV6_V6ONLY` turned off, and to the chosen interface with `SO_BINDTODEVICE`, so it
+//! accepts connections to every address of that interface and no other.
+
+use crate::interfaces::{self, NetworkInterface};
+use crate::latency::Probe;
+use bytes::Buf;
+use futures::stream::{self, Stream};
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
+use socket2::{Domain, Protocol, Type};
+use std::ffi::CString;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Connections waiting to be accepted by a dual-stack socket
+const BACKLOG: i32 = 1024;
+/// How long a dual-stack socket stops accepting after an error like running out of file
+/// descriptors, like hyper does for the other sockets
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Connections accepted on a bound socket
+pub type Listener = Pin<Box<dyn Stream<Item = io::Result<Connection>> + Send>>;
+
+pub trait Network: Send + Sync {
+    fn interfaces(&self) -> Vec<NetworkInterface>;
+
+    /// Starts listening on `socket`, sending TCP keep-alive probes after `keep_alive` where
+    /// supported.
+    fn bind(&self, socket: SocketAddr, keep_alive: Option<Duration>) -> io::Result<Listener>;
+
+    /// Starts listening on `port` of every address of `interface`, of both IP families, with a
+    /// single socket.
+    fn bind_dual_stack(
+        &self,
+        interface: &str,
+        port: u16,
+        keep_alive: Option<Duration>,
+    ) -> io::Result<Listener>;
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(test)]
+    Memory(tokio::io::DuplexStream),
+}
+
+/// A connection from a client
+pub struct Connection {
+    stream: Socket,
+    remote_address: SocketAddr,
+    /// Round-trip time and loss, read from TCP sockets while they are used
+    probe: Arc<Probe>,
+}
+
+impl Connection {
+    pub fn remote_address(&self) -> SocketAddr {
+        self.remote_address
+    }
+
+    pub fn get_probe(&self) -> Arc<Probe> {
+        self.probe.clone()
+    }
+
+    fn sample(&self) {
+        match &self.stream {
+            Socket::Tcp(stream) => self.probe.sample(stream.as_raw_fd()),
+            #[cfg(test)]
+            Socket::Memory(_) => {}
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.sample();
+        match &mut self.get_mut().stream {
+            Socket::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(test)]
+            Socket::Memory(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.sample();
+        match &mut self.get_mut().stream {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(test)]
+            Socket::Memory(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    // TCP streams write all chunks of a response at once.
+    fn poll_write_buf<B: Buf>(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>> {
+        self.sample();
+        match &mut self.get_mut().stream {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write_buf(cx, buf),
+            #[cfg(test)]
+            Socket::Memory(stream) => Pin::new(stream).poll_write_buf(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Socket::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(test)]
+            Socket::Memory(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Socket::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(test)]
+            Socket::Memory(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The interfaces of this machine and TCP sockets on them
+pub struct SystemNetwork;
+
+impl Network for SystemNetwork {
+    fn interfaces(&self) -> Vec<NetworkInterface> {
+        interfaces::interfaces()
+    }
+
+    fn bind(&self, socket: SocketAddr, keep_alive: Option<Duration>) -> io::Result<Listener> {
+        let mut incoming = AddrIncoming::bind(&socket).map_err(io::Error::other)?;
+        incoming.set_keepalive(keep_alive);
+        Ok(Box::pin(stream::poll_fn(move |cx| {
+            Pin::new(&mut incoming).poll_accept(cx).map_ok(|stream| {
+                let remote_address = stream.remote_addr();
+                let probe = Arc::new(Probe::default());
+                probe.sample(stream.as_raw_fd());
+                Connection {
+                    stream: Socket::Tcp(stream.into_inner()),
+                    remote_address,
+                    probe,
+                }
+            })
+        })))
+    }
+
+    fn bind_dual_stack(
+        &self,
+        interface: &str,
+        port: u16,
+        keep_alive: Option<Duration>,
+    ) -> io::Result<Listener> {
+        let socket = socket2::Socket::new(Domain::ipv6(), Type::stream(), Some(Protocol::tcp()))?;
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        let name = CString::new(interface).map_err(io::Error::other)?;
+        socket.bind_device(Some(&name))?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.listen(BACKLOG)?;
+        socket.set_nonblocking(true)?;
+        Ok(Box::pin(DualStackListener {
+            listener: TcpListener::from_std(socket.into_tcp_listener())?,
+            keep_alive,
+            delay: None,
+        }))
+    }
+}
+
+/// Accepts connections of both IP families on one socket
+struct DualStackListener {
+    listener: TcpListener,
+    keep_alive: Option<Duration>,
+    /// Pause after an error, which would likely happen again right away
+    delay: Option<tokio::time::Delay>,
+}
+
+impl Stream for DualStackListener {
+    type Item = io::Result<Connection>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(delay) = &mut this.delay {
+                if Pin::new(delay).poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.delay = None;
+            }
+            match this.listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, remote_address))) => {
+                    // Failing to set keep-alive only costs noticing dead connections late.
+                    let _ = stream.set_keepalive(this.keep_alive);
+                    let probe = Arc::new(Probe::default());
+                    probe.sample(stream.as_raw_fd());
+                    return Poll::Ready(Some(Ok(Connection {
+                        stream: Socket::Tcp(stream),
+                        remote_address: canonicalize(remote_address),
+                        probe,
+                    })));
+                }
+                // The client gave up before the connection was accepted.
+                Poll::Ready(Err(e)) if is_connection_error(&e) => {}
+                Poll::Ready(Err(e)) => {
+                    eprintln!("Could not accept a connection: {}", e);
+                    this.delay = Some(tokio::time::delay_for(ACCEPT_ERROR_DELAY));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// IPv4 clients of a dual-stack socket have IPv4-mapped addresses (`::ffff:a.b.c.d`), which are
+/// turned back into IPv4 addresses, so access rules and the client list see the same addresses as
+/// with separate sockets.
+fn canonicalize(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::from((v4, v6.port())),
+            None => address,
+        },
+        v4 => v4,
+    }
+}
+
+/// A network for tests
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc;
+
+    /// Bytes a pipe buffers in each direction
+    const PIPE_SIZE: usize = 64 * 1024;
+
+    type Listeners = Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Connection>>>>;
+
+    /// Interfaces given by the test, and sockets clients connect to through `connect`
+    #[derive(Default)]
+    pub struct MockNetwork {
+        interfaces: Mutex<Vec<NetworkInterface>>,
+        listeners: Listeners,
+    }
+
+    impl MockNetwork {
+        pub fn new(interfaces: Vec<NetworkInterface>) -> MockNetwork {
+            MockNetwork {
+                interfaces: Mutex::new(interfaces),
+                listeners: Listeners::default(),
+            }
+        }
+
+        /// Replaces the interfaces, like addresses changing while the server runs.
+        pub fn set_interfaces(&self, interfaces: Vec<NetworkInterface>) {
+            *self.interfaces.lock().unwrap() = interfaces;
+        }
+
+        pub fn is_bound(&self, socket: SocketAddr) -> bool {
+            self.listeners.lock().unwrap().contains_key(&socket)
+        }
+
+        /// Connects `from` to the server listening on `socket`, returning the client's end.
+        pub fn connect(&self, socket: SocketAddr, from: SocketAddr) -> io::Result<DuplexStream> {
+            let refused = || io::Error::from(io::ErrorKind::ConnectionRefused);
+            let listeners = self.listeners.lock().unwrap();
+            let dual_stack = SocketAddr::from((Ipv6Addr::UNSPECIFIED, socket.port()));
+            let listener = listeners
+                .get(&socket)
+                .or_else(|| listeners.get(&dual_stack))
+                .ok_or_else(refused)?;
+            let (client, server) = tokio::io::duplex(PIPE_SIZE);
+            let connection = Connection {
+                stream: Socket::Memory(server),
+                remote_address: from,
+                probe: Arc::default(),
+            };
+            listener.send(connection).map_err(|_| refused())?;
+            Ok(client)
+        }
+    }
+
+    /// Accepts connections until it is dropped, which frees the socket.
+    struct MockListener {
+        socket: SocketAddr,
+        receiver: mpsc::UnboundedReceiver<Connection>,
+        listeners: Listeners,
+    }
+
+    impl Stream for MockListener {
+        type Item = io::Result<Connection>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+            self.get_mut().receiver.poll_recv(cx).map(|c| c.map(Ok))
+        }
+    }
+
+    impl Drop for MockListener {
+        fn drop(&mut self) {
+            self.listeners.lock().unwrap().remove(&self.socket);
+        }
+    }
+
+    impl Network for MockNetwork {
+        fn interfaces(&self) -> Vec<NetworkInterface> {
+            self.interfaces.lock().unwrap().clone()
+        }
+
+        fn bind(&self, socket: SocketAddr, _: Option<Duration>) -> io::Result<Listener> {
+            let mut listeners = self.listeners.lock().unwrap();
+            if listeners.contains_key(&socket) {
+                return Err(io::Error::from(io::ErrorKind::AddrInUse));
+            }
+            let (sender, receiver) = mpsc::unbounded_channel();
+            listeners.insert(socket, sender);
+            Ok(Box::pin(MockListener {
+                socket,
+                receiver,
+                listeners: self.listeners.clone(),
+            }))
+        }
+
+        /// Listens on the unspecified address, which `connect` falls back to for any address.
+        fn bind_dual_stack(
+            &self,
+            _: &str,
+            port: u16,
+            keep_alive: Option<Duration>,
+        ) -> io::Result<Listener> {
+            self.bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), keep_alive)
+        }
+    }
+
+    /// An interface called `name` with the addresses `ips`, like `"192.168.1.2/24"`
+    pub fn create_interface(name: &str, index: u32, ips: &[&str]) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            index,
+            mac: None,
+            ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            flags: interfaces::FLAG_UP | interfaces::FLAG_BROADCAST,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::*;
+    use super::*;
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_mock_connections() {
+        let network = MockNetwork::new(vec![create_interface("eth0", 2, &["10.0.0.1/24"])]);
+        assert_eq!("eth0", network.interfaces()[0].name);
+        let socket = "10.0.0.1:8080".parse().unwrap();
+        let client = "10.0.0.2:50000".parse().unwrap();
+        assert!(network.connect(socket, client).is_err());
+
+        let mut listener = network.bind(socket, None).unwrap();
+        assert!(network.bind(socket, None).is_err());
+        let mut client_end = network.connect(socket, client).unwrap();
+        let mut server_end = listener.next().await.unwrap().unwrap();
+        assert_eq!(client, server_end.remote_address());
+        client_end.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        server_end.read_exact(&mut received).await.unwrap();
+        assert_eq!(b"ping", &received);
+
+        drop(listener);
+        assert!(!network.is_bound(socket));
+        assert!(network.connect(socket, client).is_err());
+        assert!(network.bind(socket, None).is_ok());
+    }
+
+    #[test]
+    fn test_canonicalize_mapped_addresses() {
+        let mapped = "[::ffff:192.168.1.20]:50000".parse().unwrap();
+        assert_eq!(
+            "192.168.1.20:50000".parse::<SocketAddr>().unwrap(),
+            canonicalize(mapped)
+        );
+        let v6 = "[fd00::20]:50000".parse().unwrap();
+        assert_eq!(v6, canonicalize(v6));
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_accepts_both_families() {
+        let port = std::net::TcpListener::bind("[::1]:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        // Binding to a device needs a kernel from 5.7 on or the CAP_NET_RAW capability.
+        let mut listener = match SystemNetwork.bind_dual_stack("lo", port, None) {
+            Ok(listener) => listener,
+            Err(e) => return eprintln!("Skipping, could not bind a dual-stack socket: {}", e),
+        };
+        for ip in &["127.0.0.1", "::1"] {
+            let ip = ip.parse().unwrap();
+            let _client = TcpStream::connect(SocketAddr::new(ip, port)).await.unwrap();
+            let connection = listener.next().await.unwrap().unwrap();
+            assert_eq!(ip, connection.remote_address().ip());
+        }
+    }
+}
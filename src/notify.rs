@@ -0,0 +1,289 @@
+//! Telling the operator about received files
+//!
+//! A long running drop box can run a command for every accepted upload, with `{file}` and `{ip}`
+//! replaced by the path and the uploader's address, or send an email through an SMTP relay. Only
+//! plain SMTP without authentication is spoken, as offered by a local MTA or a relay in the LAN.
+
+use crate::receive::shell_quote;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_SMTP_SERVER: &str = "localhost:25";
+const SMTP_PORT: u16 = 25;
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    /// Host and port of the SMTP relay
+    pub server: String,
+    /// Recipient, which is also used as the sender
+    pub address: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    pub command: Option<String>,
+    pub email: Option<Email>,
+}
+
+/// Inserts the quoted path and address into the notification command. Without any placeholder
+/// both are appended.
+fn create_notify_command(command: &str, path: &Path, ip: Option<IpAddr>) -> String {
+    let file = shell_quote(&path.to_string_lossy());
+    let ip = shell_quote(&ip.map(|ip| ip.to_string()).unwrap_or_default());
+    if !command.contains("{file}") && !command.contains("{ip}") {
+        return format!("{} {} {}", command, file, ip);
+    }
+    // In one pass, so a path containing `{ip}` is not replaced again.
+    let mut result = String::new();
+    let mut rest = command;
+    while let Some(i) = rest.find('{') {
+        result.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("{file}") {
+            result.push_str(&file);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("{ip}") {
+            result.push_str(&ip);
+            rest = after;
+        } else {
+            result.push('{');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Builds the email about a received file, with the lines of the body ending in CRLF and leading
+/// dots doubled as SMTP requires.
+fn create_message(
+    address: &str,
+    path: &Path,
+    ip: Option<IpAddr>,
+    time: chrono::DateTime<chrono::Local>,
+) -> String {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let sender = ip.map_or_else(|| String::from("an unknown address"), |ip| ip.to_string());
+    let body = format!(
+        "Received {}\nfrom {}\nat {}\n",
+        path.display(),
+        sender,
+        time.format("%Y-%m-%d %H:%M:%S")
+    );
+    let mut message = format!(
+        "From: rustbelt <{0}>\r\nTo: <{0}>\r\nSubject: rustbelt received {1}\r\nDate: {2}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\r\n",
+        address,
+        name.replace(['\r', '\n'], " "),
+        time.to_rfc2822()
+    );
+    for line in body.lines() {
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+/// Reads a possibly multi-line reply and returns its code.
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<u16> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The SMTP server closed the connection",
+            ));
+        }
+        let code = line
+            .get(..3)
+            .and_then(|c| c.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("Invalid SMTP reply: {}", line.trim())))?;
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(code);
+        }
+    }
+}
+
+/// Sends `command` and fails unless the reply code starts with `expected`.
+fn send_command<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    command: &str,
+    expected: u16,
+) -> io::Result<()> {
+    if !command.is_empty() {
+        writer.write_all(command.as_bytes())?;
+        writer.write_all(b"\r\n")?;
+        writer.flush()?;
+    }
+    let code = read_reply(reader)?;
+    if code / 100 != expected {
+        return Err(io::Error::other(format!(
+            "The SMTP server answered {} with {}",
+            command.split(':').next().unwrap_or("the greeting"),
+            code
+        )));
+    }
+    Ok(())
+}
+
+fn send_email(email: &Email, message: &str) -> io::Result<()> {
+    let server = if email.server.contains(':') {
+        email.server.to_socket_addrs()?
+    } else {
+        (email.server.as_str(), SMTP_PORT).to_socket_addrs()?
+    }
+    .next()
+    .ok_or_else(|| io::Error::other(format!("Could not resolve {}", email.server)))?;
+    let stream = TcpStream::connect_timeout(&server, SMTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    send_command(&mut reader, &mut writer, "", 2)?;
+    send_command(&mut reader, &mut writer, "EHLO rustbelt", 2)?;
+    let mail_from = format!("MAIL FROM:<{}>", email.address);
+    send_command(&mut reader, &mut writer, &mail_from, 2)?;
+    let rcpt_to = format!("RCPT TO:<{}>", email.address);
+    send_command(&mut reader, &mut writer, &rcpt_to, 2)?;
+    send_command(&mut reader, &mut writer, "DATA", 3)?;
+    let data = format!("{}.", message);
+    send_command(&mut reader, &mut writer, &data, 2)?;
+    send_command(&mut reader, &mut writer, "QUIT", 2)
+}
+
+impl Notifier {
+    /// Notifies about `path` received from `ip` in the background.
+    pub fn notify(&self, path: PathBuf, ip: Option<IpAddr>) {
+        if let Some(command) = &self.command {
+            let command = create_notify_command(command, &path, ip);
+            tokio::spawn(async move {
+                let status = tokio::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(&command)
+                    .status()
+                    .await;
+                match status {
+                    Ok(status) if !status.success() => {
+                        eprintln!("The notification command failed with {}", status)
+                    }
+                    Err(e) => eprintln!("Could not run the notification command: {}", e),
+                    _ => {}
+                }
+            });
+        }
+        if let Some(email) = self.email.clone() {
+            let message = create_message(&email.address, &path, ip, chrono::Local::now());
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = send_email(&email, &message) {
+                    eprintln!("Could not send the notification email: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    proptest! {
+        #[test]
+        fn test_message_lines_end_in_crlf(name in "[a-z.]{1,8}", ip in any::<IpAddr>()) {
+            let path = Path::new("/srv/drop").join(format!(".{}", name));
+            let message = create_message("ops@example.com", &path, Some(ip), chrono::Local::now());
+            prop_assert!(message.ends_with("\r\n"));
+            prop_assert!(!message.replace("\r\n", "").contains('\n'));
+            let from = format!("from {}\r\n", ip);
+            prop_assert!(message.contains(&from));
+        }
+    }
+
+    #[test]
+    fn test_notify_command() {
+        let ip = Some(IpAddr::from([192, 168, 1, 5]));
+        assert_eq!(
+            "script.sh '/tmp/a b.jpg' '192.168.1.5'",
+            create_notify_command("script.sh {file} {ip}", Path::new("/tmp/a b.jpg"), ip)
+        );
+        assert_eq!(
+            "notify '/tmp/x' ''",
+            create_notify_command("notify", Path::new("/tmp/x"), None)
+        );
+        assert_eq!(
+            "n {'/tmp/{ip}' '' {x}",
+            create_notify_command("n {{file} {ip} {x}", Path::new("/tmp/{ip}"), None)
+        );
+    }
+
+    #[test]
+    fn test_read_multiline_reply() {
+        let mut reply = "250-mail.example.com\r\n250-SIZE 1000\r\n250 HELP\r\n".as_bytes();
+        assert_eq!(250, read_reply(&mut reply).unwrap());
+        assert!(read_reply(&mut "oops\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_send_email() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let relay = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = Vec::new();
+            writer.write_all(b"220 ready\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        received.push(line);
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-hello\r\n250 OK\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+            received
+        });
+        let email = Email {
+            server,
+            address: String::from("ops@example.com"),
+        };
+        let message = create_message(
+            &email.address,
+            Path::new("/srv/drop/report.pdf"),
+            None,
+            chrono::Local::now(),
+        );
+        send_email(&email, &message).unwrap();
+        let received = relay.join().unwrap();
+        assert!(received.contains(&String::from("Subject: rustbelt received report.pdf\r\n")));
+    }
+}
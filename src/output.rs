@@ -0,0 +1,296 @@
+//! Formatting what rustbelt tells the user in the terminal
+//!
+//! Labels and values are aligned in columns and cut to the width of the terminal, transfer events
+//! start with the time they happened. Colors are left out if `NO_COLOR` is set or stderr is no
+//! terminal. Only the QR code keeps its colors, it can't be scanned without them.
+//!
+//! Colors never carry information of their own, warnings say so in words. With `--ascii-qr` QR
+//! codes are drawn with `#` and spaces for monochrome terminals and screen readers that skip
+//! block characters. `--high-contrast` draws them on bright white instead of the terminal's
+//! plain white, which is often a light gray, and leaves out faint and red text.
+
+use crate::interfaces::NetworkInterface;
+use colored::Colorize;
+use ipnetwork::IpNetwork;
+use std::cell::RefCell;
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const DEFAULT_WIDTH: usize = 80;
+/// Space between the label and the value column
+const COLUMN_GAP: usize = 2;
+
+static ASCII_QR: AtomicBool = AtomicBool::new(false);
+static HIGH_CONTRAST: AtomicBool = AtomicBool::new(false);
+
+/// Draws QR codes with `#` and spaces and without colors from now on.
+pub fn use_ascii_qr() {
+    ASCII_QR.store(true, Ordering::Relaxed);
+}
+
+pub fn is_ascii_qr() -> bool {
+    ASCII_QR.load(Ordering::Relaxed)
+}
+
+/// Draws QR codes on bright white and leaves out faint and red text from now on.
+pub fn use_high_contrast() {
+    HIGH_CONTRAST.store(true, Ordering::Relaxed);
+}
+
+fn is_high_contrast() -> bool {
+    HIGH_CONTRAST.load(Ordering::Relaxed)
+}
+
+/// Whether output may be colored, see <https://no-color.org>.
+pub fn use_color() -> bool {
+    let disabled = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    !disabled && io::stderr().is_terminal()
+}
+
+/// The number of columns of the terminal, from `COLUMNS` or the terminal on stderr.
+pub fn get_terminal_width() -> usize {
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .or_else(query_terminal_width)
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+#[cfg(unix)]
+fn query_terminal_width() -> Option<usize> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // Safe, TIOCGWINSZ only writes the winsize it is given.
+    match unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col > 0 => Some(size.ws_col as usize),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn query_terminal_width() -> Option<usize> {
+    None
+}
+
+/// Cuts `text` to at most `width` characters, marking the cut with an ellipsis.
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut cut = text
+        .chars()
+        .take(width.saturating_sub(1))
+        .collect::<String>();
+    cut.push('…');
+    cut
+}
+
+/// Formats rows of a label and a value, with all values starting in the same column. Rows with
+/// an empty label continue the value of the row above.
+pub fn format_table(rows: &[(&str, String)], width: usize) -> String {
+    let label_width = rows
+        .iter()
+        .map(|(l, _)| l.chars().count())
+        .max()
+        .unwrap_or(0);
+    let value_width = width.saturating_sub(label_width + COLUMN_GAP).max(1);
+    rows.iter()
+        .map(|(label, value)| {
+            let line = format!(
+                "{:<label_width$}{:gap$}{}",
+                label,
+                "",
+                truncate(value, value_width),
+                label_width = label_width,
+                gap = COLUMN_GAP
+            );
+            line.trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_addresses(ips: &[IpNetwork]) -> String {
+    if ips.is_empty() {
+        return String::from("no addresses");
+    }
+    ips.iter()
+        .map(|ip| ip.ip().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Describes an interface in the list to choose from, with its addresses after its name.
+pub fn format_interface_choice(name: &str, ips: &[IpNetwork], name_width: usize) -> String {
+    let line = format!(
+        "{:<name_width$}  {}",
+        name,
+        format_addresses(ips),
+        name_width = name_width
+    );
+    // Room for the number in front of it
+    truncate(&line, get_terminal_width().saturating_sub(6))
+}
+
+/// Describes the details of an interface, for `-v`.
+pub fn format_interface(interface: &NetworkInterface) -> String {
+    let state = if interface.is_up() { "up" } else { "down" };
+    let mut rows = vec![
+        ("Interface", format!("{} ({})", interface.name, state)),
+        ("Index", interface.index.to_string()),
+        (
+            "MAC",
+            interface
+                .mac
+                .map_or_else(|| String::from("none"), |m| m.to_string()),
+        ),
+    ];
+    let mut flags = Vec::new();
+    for (set, name) in [
+        (interface.is_loopback(), "loopback"),
+        (interface.is_broadcast(), "broadcast"),
+        (interface.is_multicast(), "multicast"),
+        (interface.is_point_to_point(), "point to point"),
+    ] {
+        if set {
+            flags.push(name);
+        }
+    }
+    rows.push(("Flags", flags.join(", ")));
+    for (index, ip) in interface.ips.iter().enumerate() {
+        rows.push((if index == 0 { "Addresses" } else { "" }, ip.to_string()));
+    }
+    format_table(&rows, get_terminal_width())
+}
+
+fn paint_qr_line(line: &str, ascii: bool, high_contrast: bool) -> String {
+    if ascii {
+        line.to_string()
+    } else if high_contrast {
+        line.black().on_bright_white().to_string()
+    } else {
+        line.black().on_white().to_string()
+    }
+}
+
+/// Colors a QR code rendered for the terminal dark on light, unless it is drawn in ASCII.
+pub fn paint_qr_code(rendered: &str) -> String {
+    rendered
+        .split('\n')
+        .map(|line| paint_qr_line(line, is_ascii_qr(), is_high_contrast()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Prints the summary of what is served and where.
+pub fn print_summary(rows: &[(&str, String)]) {
+    eprintln!("{}", format_table(rows, get_terminal_width()));
+}
+
+/// Receives the events of an embedded server, see `embed`
+pub type EventSink = Arc<dyn Fn(&str) + Send + Sync>;
+
+thread_local! {
+    /// Where the events printed on this thread go as well, set on the threads of an embedded
+    /// server
+    static EVENT_SINK: RefCell<Option<EventSink>> = RefCell::new(None);
+}
+
+/// Passes the events printed on the current thread to `sink` from now on.
+pub fn set_event_sink(sink: EventSink) {
+    EVENT_SINK.with(|s| *s.borrow_mut() = Some(sink));
+}
+
+/// Prints something that happened to a transfer, after the current time.
+pub fn print_event(message: &str) {
+    EVENT_SINK.with(|s| {
+        if let Some(sink) = &*s.borrow() {
+            sink(message);
+        }
+    });
+    let time = chrono::Local::now().format("%H:%M:%S").to_string();
+    let time = if use_color() && !is_high_contrast() {
+        time.dimmed().to_string()
+    } else {
+        time
+    };
+    eprintln!("{} {}", time, message);
+}
+
+/// Asks a yes or no question on the terminal, no being the default.
+pub fn confirm(question: &str) -> io::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Prints a warning that is easy to spot between the rest of the output.
+pub fn print_warning(warning: &str) {
+    let message = format!("Warning: {}", warning);
+    if use_color() && is_high_contrast() {
+        eprintln!("{}", message.bold());
+    } else if use_color() {
+        eprintln!("{}", message.red().bold());
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_table_fits_width(
+            rows in proptest::collection::vec(("[A-Za-z ]{0,12}", "\\PC{0,100}"), 1..6),
+            width in 20usize..200,
+        ) {
+            let rows = rows.iter().map(|(l, v)| (l.trim(), v.clone())).collect::<Vec<_>>();
+            for line in format_table(&rows, width).lines() {
+                let label_width = rows.iter().map(|(l, _)| l.chars().count()).max().unwrap();
+                prop_assert!(line.chars().count() <= width.max(label_width + COLUMN_GAP + 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_table() {
+        let rows = [
+            ("Listening on", String::from("http://10.0.0.2:8080")),
+            ("Mode", String::from("send")),
+            ("", String::from("continued")),
+        ];
+        assert_eq!(
+            "Listening on  http://10.0.0.2:8080\nMode          send\n              continued",
+            format_table(&rows, 80)
+        );
+        assert_eq!(
+            "Mode  a very…",
+            format_table(&[("Mode", String::from("a very long value"))], 13)
+        );
+    }
+
+    #[test]
+    fn test_ascii_qr_line_is_plain() {
+        let line = "  ##  ####";
+        assert_eq!(line, paint_qr_line(line, true, true));
+        assert!(paint_qr_line(line, false, true).contains(line));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!("short", truncate("short", 5));
+        assert_eq!("shor…", truncate("shorter", 5));
+        assert_eq!("äö…", truncate("äöüß", 3));
+    }
+}
@@ -0,0 +1,102 @@
+//! Conversion between URL paths and paths relative to a shared directory
+
+use std::path::PathBuf;
+
+/// Characters that are kept as they are when percent encoding a path segment
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_' || b == b'~'
+}
+
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if is_unreserved(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// Percent encodes every segment of a `/` separated path, keeping the separators.
+pub fn percent_encode_path(path: &str) -> String {
+    path.split('/')
+        .map(percent_encode)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Decodes a single path segment, refusing anything that isn't a plain file or directory name.
+pub fn get_path_segment(segment: &str) -> Option<String> {
+    let name = percent_decode(segment)?;
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\\') {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Turns a `/` separated URL path into a relative path that can't escape the directory it is
+/// joined to.
+pub fn get_relative_path(uri_path: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for segment in uri_path.trim_start_matches('/').split('/') {
+        path.push(get_path_segment(segment)?);
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_percent_decode_roundtrip(a in "\\PC*") {
+            prop_assert_eq!(Some(a.clone()), percent_decode(&percent_encode(&a)));
+        }
+
+        #[test]
+        fn test_path_segment_rejects_separators(a in "[^/]*", b in "[^/]*") {
+            prop_assert_eq!(None, get_path_segment(&format!("{}%2F{}", a, b)));
+        }
+
+        #[test]
+        fn test_relative_path_roundtrip(a in "[a-z ]{1,8}", b in "[a-z%]{1,8}") {
+            let path = format!("{}/{}", a, b);
+            let relative = get_relative_path(&percent_encode_path(&path));
+            prop_assert_eq!(Some(PathBuf::from(&a).join(&b)), relative);
+        }
+    }
+
+    #[test]
+    fn test_get_relative_path() {
+        assert_eq!(
+            Some(PathBuf::from("a b").join("c.txt")),
+            get_relative_path("/a%20b/c.txt")
+        );
+        assert_eq!(None, get_relative_path("/a/../b"));
+        assert_eq!(None, get_relative_path("/%2e%2e/b"));
+        assert_eq!(None, get_relative_path("/a//b"));
+        assert_eq!(None, get_relative_path("/"));
+    }
+}
@@ -0,0 +1,151 @@
+//! Splitting a shared file into fixed-size pieces with individual hashes
+//!
+//! The manifest is served at `/pieces` and every piece at `/pieces/<index>`, so a client can
+//! fetch pieces in parallel, verify each one and retry only those that failed.
+
+use crate::create_status_response;
+use crate::transfer;
+use hyper::{header, Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+pub const DEFAULT_PIECE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Description of a file split into pieces
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PieceManifest {
+    pub file_name: String,
+    pub size: u64,
+    pub piece_size: u64,
+    pub hashes: Vec<String>,
+}
+
+impl PieceManifest {
+    /// Returns offset and length of the piece with the given index.
+    pub fn get_piece_range(&self, index: usize) -> (u64, u64) {
+        let offset = index as u64 * self.piece_size;
+        (offset, self.piece_size.min(self.size - offset))
+    }
+}
+
+pub fn hash_piece(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Reads the file once and hashes every piece of it.
+pub fn create_piece_manifest(
+    path: &Path,
+    file_name: String,
+    piece_size: u64,
+) -> io::Result<PieceManifest> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hashes = Vec::new();
+    let mut buffer = Vec::with_capacity(piece_size as usize);
+    loop {
+        buffer.clear();
+        let n = (&mut file).take(piece_size).read_to_end(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hashes.push(hash_piece(&buffer));
+    }
+    Ok(PieceManifest {
+        file_name,
+        size,
+        piece_size,
+        hashes,
+    })
+}
+
+/// Answers requests below `/pieces`. `rest` is the request path with that prefix removed.
+pub async fn serve_pieces(path: PathBuf, manifest: &PieceManifest, rest: &str) -> Response<Body> {
+    if rest.is_empty() {
+        return Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(manifest).unwrap()))
+            .unwrap();
+    }
+    let index = match rest.trim_start_matches('/').parse::<usize>() {
+        Ok(i) if i < manifest.hashes.len() => i,
+        _ => return create_status_response(StatusCode::NOT_FOUND, "No such piece"),
+    };
+    let (offset, length) = manifest.get_piece_range(index);
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => {
+            return create_status_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not read piece",
+            )
+        }
+    };
+    if file.seek(SeekFrom::Start(offset)).await.is_err() {
+        return create_status_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not read piece");
+    }
+    // Streamed like the whole file, a piece is never held in memory at once.
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, length)
+        .body(Body::wrap_stream(transfer::FileStream::new(
+            file.take(length),
+        )))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_piece_ranges_cover_file(size in 1u64..10_000, piece_size in 1u64..1000) {
+            let count = size.div_ceil(piece_size);
+            let manifest = PieceManifest {
+                file_name: String::new(),
+                size,
+                piece_size,
+                hashes: vec![String::new(); count as usize],
+            };
+            let mut expected_offset = 0;
+            for i in 0..manifest.hashes.len() {
+                let (offset, length) = manifest.get_piece_range(i);
+                prop_assert_eq!(expected_offset, offset);
+                prop_assert!(length > 0 && length <= piece_size);
+                expected_offset += length;
+            }
+            prop_assert_eq!(size, expected_offset);
+        }
+    }
+
+    #[test]
+    fn test_create_piece_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"abcdefg").unwrap();
+        let manifest = create_piece_manifest(&path, String::from("file"), 3).unwrap();
+        assert_eq!(7, manifest.size);
+        assert_eq!(
+            vec![hash_piece(b"abc"), hash_piece(b"def"), hash_piece(b"g")],
+            manifest.hashes
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_piece() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"abcdefg").unwrap();
+        let manifest = create_piece_manifest(&path, String::from("file"), 3).unwrap();
+        let response = serve_pieces(path.clone(), &manifest, "/1").await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&b"def"[..], &body[..]);
+        let last = serve_pieces(path, &manifest, "/2").await;
+        assert_eq!("1", last.headers()[header::CONTENT_LENGTH]);
+    }
+}
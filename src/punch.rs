@@ -0,0 +1,282 @@
+//! Direct connections between `relay send` and `relay get` through NATs
+//!
+//! Both sides first meet at the relay's rendezvous endpoint, each from a fixed local port, and the
+//! relay tells each side the public address it saw for the other one. Then both connect to each
+//! other from that same port while accepting connections on it. Most NATs keep the port mapping
+//! made for the relay, so the simultaneous attempts get through and the file goes directly. The
+//! sender greets on the first connection it gets and the receiver answers on the one it is
+//! greeted on. If that doesn't happen in time, both sides fall back to the relay.
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long both sides try to reach each other
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the sender waits for the receiver to answer its greeting
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(3);
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// Sent by the sender on the chosen connection and echoed by the receiver
+const GREETING: u8 = b'R';
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Send,
+    Get,
+}
+
+impl Role {
+    pub fn get_name(self) -> &'static str {
+        match self {
+            Role::Send => "send",
+            Role::Get => "get",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name {
+            "send" => Some(Role::Send),
+            "get" => Some(Role::Get),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a rendezvous path `/rendezvous/<channel>/<role>` into channel and role.
+pub fn parse_rendezvous_path(path: &str) -> Option<(&str, Role)> {
+    let rest = path.strip_prefix("/rendezvous/")?;
+    let (channel, role) = rest.split_at(rest.find('/')?);
+    if channel.is_empty() {
+        return None;
+    }
+    Some((channel, Role::from_name(&role[1..])?))
+}
+
+/// The peer address from the relay's raw HTTP response to a rendezvous request.
+fn parse_rendezvous_response(response: &str) -> Option<SocketAddr> {
+    let status = response.lines().next()?;
+    if status.split_whitespace().nth(1)? != "200" {
+        return None;
+    }
+    let (_, body) = response.split_at(response.find("\r\n\r\n")? + 4);
+    body.trim().parse().ok()
+}
+
+/// Host and port of the relay at `relay_url`, for example `http://example.org:8080`.
+fn get_relay_host(relay_url: &str) -> Option<(String, u16)> {
+    let uri = relay_url.parse::<hyper::Uri>().ok()?;
+    if uri.scheme_str().unwrap_or("http") != "http" {
+        return None;
+    }
+    let host = uri.host()?.trim_start_matches('[').trim_end_matches(']');
+    Some((host.to_string(), uri.port_u16().unwrap_or(80)))
+}
+
+fn create_socket(local: SocketAddr) -> io::Result<Socket> {
+    let domain = match local {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SockAddr::from(local))?;
+    Ok(socket)
+}
+
+fn get_unspecified(relay: SocketAddr, port: u16) -> SocketAddr {
+    let ip = match relay.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, port)
+}
+
+/// Meets the other side at the relay and returns the local address used and the peer's public
+/// address. Blocks until the other side arrives.
+fn rendezvous(relay_url: &str, channel: &str, role: Role) -> io::Result<(SocketAddr, SocketAddr)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Unsupported relay URL");
+    let (host, port) = get_relay_host(relay_url).ok_or_else(invalid)?;
+    let relay = (host.as_str(), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(invalid)?;
+    let socket = create_socket(get_unspecified(relay, 0))?;
+    socket.connect(&SockAddr::from(relay))?;
+    let local = socket
+        .local_addr()?
+        .as_std()
+        .ok_or_else(|| io::Error::other("The local address is not an IP address"))?;
+    let mut stream = socket.into_tcp_stream();
+    write!(
+        stream,
+        "POST /rendezvous/{}/{} HTTP/1.1\r\nHost: {}\r\n\
+         Content-Length: 0\r\nConnection: close\r\n\r\n",
+        channel,
+        role.get_name(),
+        host
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let peer = parse_rendezvous_response(&response)
+        .ok_or_else(|| io::Error::other("The relay doesn't support direct transfers"))?;
+    Ok((get_unspecified(relay, local.port()), peer))
+}
+
+/// Connects to `peer` from `local` and accepts connections on `local` until `deadline`, passing
+/// every established connection to `found`.
+fn connect_simultaneously(
+    local: SocketAddr,
+    peer: SocketAddr,
+    deadline: Instant,
+    found: mpsc::Sender<TcpStream>,
+) -> io::Result<()> {
+    let listener = create_socket(local)?;
+    listener.listen(8)?;
+    listener.set_nonblocking(true)?;
+    let accepted = found.clone();
+    thread::spawn(move || {
+        while Instant::now() < deadline {
+            match listener.accept() {
+                Ok((socket, _)) => {
+                    let stream = socket.into_tcp_stream();
+                    if stream.set_nonblocking(false).is_err() || accepted.send(stream).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => thread::sleep(RETRY_INTERVAL),
+            }
+        }
+    });
+    thread::spawn(move || {
+        while Instant::now() < deadline {
+            let attempt = create_socket(local).and_then(|s| {
+                s.connect_timeout(&SockAddr::from(peer), CONNECT_TIMEOUT)
+                    .map(|_| s)
+            });
+            match attempt {
+                Ok(socket) => {
+                    let _ = found.send(socket.into_tcp_stream());
+                    return;
+                }
+                Err(_) => thread::sleep(RETRY_INTERVAL),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn get_remaining(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+/// Greets on the first connection and waits for the receiver's answer.
+fn choose_as_sender(found: mpsc::Receiver<TcpStream>, deadline: Instant) -> Option<TcpStream> {
+    let mut stream = found.recv_timeout(get_remaining(deadline)).ok()?;
+    stream.write_all(&[GREETING]).ok()?;
+    stream.set_read_timeout(Some(ANSWER_TIMEOUT)).ok()?;
+    let mut answer = [0u8];
+    stream.read_exact(&mut answer).ok()?;
+    stream.set_read_timeout(None).ok()?;
+    Some(stream).filter(|_| answer[0] == GREETING)
+}
+
+/// Answers on the connection the sender greets on.
+fn choose_as_receiver(found: mpsc::Receiver<TcpStream>, deadline: Instant) -> Option<TcpStream> {
+    // The sender may pick a connection just before the deadline and greet a little later.
+    let greeting_deadline = deadline + ANSWER_TIMEOUT;
+    let (greeted_tx, greeted_rx) = mpsc::channel();
+    thread::spawn(move || {
+        for mut stream in found.iter() {
+            let greeted = greeted_tx.clone();
+            thread::spawn(move || {
+                let mut greeting = [0u8];
+                let timeout = get_remaining(greeting_deadline).max(RETRY_INTERVAL);
+                if stream.set_read_timeout(Some(timeout)).is_ok()
+                    && stream.read_exact(&mut greeting).is_ok()
+                    && greeting[0] == GREETING
+                {
+                    let _ = greeted.send(stream);
+                }
+            });
+        }
+    });
+    let mut stream = greeted_rx
+        .recv_timeout(get_remaining(greeting_deadline))
+        .ok()?;
+    stream.write_all(&[GREETING]).ok()?;
+    stream.set_read_timeout(None).ok()?;
+    Some(stream)
+}
+
+/// Tries to connect directly to the other side of `channel` on the relay at `relay_url`. Returns
+/// `None` if both sides have to use the relay.
+pub fn connect_directly(relay_url: &str, channel: &str, role: Role) -> Option<TcpStream> {
+    let (local, peer) = rendezvous(relay_url, channel, role).ok()?;
+    let deadline = Instant::now() + PUNCH_TIMEOUT;
+    let (found_tx, found_rx) = mpsc::channel();
+    connect_simultaneously(local, peer, deadline, found_tx).ok()?;
+    match role {
+        Role::Send => choose_as_sender(found_rx, deadline),
+        Role::Get => choose_as_receiver(found_rx, deadline),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_parse_rendezvous_response(ip in any::<IpAddr>(), port in any::<u16>()) {
+            let peer = SocketAddr::new(ip, port);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
+                peer.to_string().len(),
+                peer
+            );
+            prop_assert_eq!(Some(peer), parse_rendezvous_response(&response));
+        }
+    }
+
+    #[test]
+    fn test_parse_rendezvous_path() {
+        assert_eq!(
+            Some(("abc", Role::Send)),
+            parse_rendezvous_path("/rendezvous/abc/send")
+        );
+        assert_eq!(
+            Some(("abc", Role::Get)),
+            parse_rendezvous_path("/rendezvous/abc/get")
+        );
+        assert_eq!(None, parse_rendezvous_path("/rendezvous/abc/other"));
+        assert_eq!(None, parse_rendezvous_path("/rendezvous//send"));
+        assert_eq!(None, parse_rendezvous_path("/rendezvous/abc"));
+        assert_eq!(None, parse_rendezvous_path("/abc"));
+    }
+
+    #[test]
+    fn test_parse_failed_rendezvous() {
+        let response = "HTTP/1.1 404 Not Found\r\ncontent-length: 9\r\n\r\n127.0.0.1:1";
+        assert_eq!(None, parse_rendezvous_response(response));
+    }
+
+    #[test]
+    fn test_get_relay_host() {
+        assert_eq!(
+            Some((String::from("example.org"), 8080)),
+            get_relay_host("http://example.org:8080/")
+        );
+        assert_eq!(
+            Some((String::from("::1"), 80)),
+            get_relay_host("http://[::1]")
+        );
+        assert_eq!(None, get_relay_host("https://example.org"));
+    }
+}
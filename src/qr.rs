@@ -0,0 +1,159 @@
+//! `rustbelt qr`, QR codes for any text
+//!
+//! Renders Wi-Fi credentials, URLs or TOTP seeds without starting a server. The terminal output
+//! looks like the codes printed for shared files, PNG and SVG files are for printing or for other
+//! screens. With `-` as the text, it is read from stdin, which keeps secrets out of the shell
+//! history.
+
+use qrcode::render::svg;
+use qrcode::QrCode;
+use std::error;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Pixels per module in PNG files, and user units in SVG files
+const MODULE_SIZE: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Terminal,
+    Png,
+    Svg,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "terminal" => Ok(Format::Terminal),
+            "png" => Ok(Format::Png),
+            "svg" => Ok(Format::Svg),
+            _ => Err(format!("Unknown format {}", s)),
+        }
+    }
+}
+
+impl Format {
+    /// The format of a file named `path`, from its extension
+    fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "png" => Some(Format::Png),
+            "svg" => Some(Format::Svg),
+            _ => None,
+        }
+    }
+}
+
+fn create_svg(code: &QrCode) -> String {
+    code.render::<svg::Color>()
+        .module_dimensions(MODULE_SIZE, MODULE_SIZE)
+        .build()
+}
+
+fn create_png(code: &QrCode) -> io::Result<Vec<u8>> {
+    let image = code
+        .render::<image::Luma<u8>>()
+        .module_dimensions(MODULE_SIZE, MODULE_SIZE)
+        .build();
+    let mut png = Vec::new();
+    image::DynamicImage::ImageLuma8(image)
+        .write_to(&mut png, image::ImageOutputFormat::PNG)
+        .map_err(io::Error::other)?;
+    Ok(png)
+}
+
+/// The QR code for `text` as a PNG file, like the ones `rustbelt qr` writes
+pub fn create_png_for(text: &str) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    Ok(create_png(&QrCode::new(text.as_bytes())?)?)
+}
+
+/// Reads the text to encode, from stdin if it is `-`. A trailing line break from stdin is
+/// dropped, it is rarely part of a password.
+pub fn read_text(text: &str, input: &mut dyn Read) -> io::Result<String> {
+    if text != "-" {
+        return Ok(text.to_string());
+    }
+    let mut text = String::new();
+    input.read_to_string(&mut text)?;
+    let length = text.trim_end_matches(&['\r', '\n'][..]).len();
+    text.truncate(length);
+    Ok(text)
+}
+
+pub fn run_qr(matches: &clap::ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let text = read_text(matches.value_of("TEXT").unwrap(), &mut io::stdin())?;
+    let code = QrCode::new(text.as_bytes())?;
+    let output = matches.value_of("output").map(Path::new);
+    let format = match matches.value_of("format") {
+        Some(format) => format.parse()?,
+        None => output
+            .and_then(Format::from_path)
+            .unwrap_or(Format::Terminal),
+    };
+    match (format, output) {
+        (Format::Terminal, None) => {
+            let rendered = crate::render_qr_code(&code);
+            println!("{}", crate::output::paint_qr_code(&rendered));
+        }
+        (Format::Terminal, Some(path)) => fs::write(path, crate::render_qr_code(&code))?,
+        (Format::Svg, None) => println!("{}", create_svg(&code)),
+        (Format::Svg, Some(path)) => fs::write(path, create_svg(&code))?,
+        (Format::Png, Some(path)) => fs::write(path, create_png(&code)?)?,
+        (Format::Png, None) => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PNG needs a file to write to, give one with --output",
+            )))
+        }
+    }
+    if let Some(path) = output {
+        eprintln!("Wrote the QR code to {}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_read_text_keeps_arguments(text in "[^-].*") {
+            prop_assert_eq!(text.clone(), read_text(&text, &mut io::empty()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_read_text_from_stdin() {
+        let mut input = &b"WIFI:S:home;T:WPA;P:secret;;\r\n"[..];
+        assert_eq!(
+            "WIFI:S:home;T:WPA;P:secret;;",
+            read_text("-", &mut input).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Some(Format::Png), Format::from_path(Path::new("code.PNG")));
+        assert_eq!(Some(Format::Svg), Format::from_path(Path::new("a/b.svg")));
+        assert_eq!(None, Format::from_path(Path::new("code.txt")));
+        assert_eq!(None, Format::from_path(Path::new("code")));
+    }
+
+    #[test]
+    fn test_png_and_svg() {
+        let code = QrCode::new(b"otpauth://totp/rustbelt?secret=JBSWY3DPEHPK3PXP").unwrap();
+        assert!(create_svg(&code).starts_with("<?xml"));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("code.png");
+        fs::write(&path, create_png(&code).unwrap()).unwrap();
+        let image = image::open(&path).unwrap().to_luma();
+        assert_eq!(image.width(), image.height());
+        assert_eq!(0, image.width() % MODULE_SIZE);
+    }
+}
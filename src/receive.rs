@@ -0,0 +1,1710 @@
+//! Receiving files uploaded by a client
+//!
+//! Uploads are written to a hidden temporary file next to their destination, synced to disk and
+//! only then renamed to their name, so an interrupted upload never looks like a complete file.
+//! Uploads by `rustbelt send` keep their temporary file when interrupted and continue it on the
+//! next attempt, see `resume`. The length they reached is saved with `--state-file`, so after a
+//! crash they continue from data that made it to disk.
+//!
+//! With `--list-received`, a PIN protected page at `/.received` lists what has arrived so far, so
+//! uploaders can check that their files made it.
+//!
+//! With `--porcelain`, every accepted upload is also reported as a line of JSON on stdout, with
+//! its path, size, SHA-256, sender and duration, for pipelines processing files as they arrive.
+//!
+//! With `--to-clipboard`, short text sent to `/.clipboard` goes onto the clipboard of this machine
+//! instead, see `clipboard`.
+//!
+//! With an append log, every line of a request body is instead appended to a single file as JSON
+//! together with the time and the address of the sender, to collect logs from devices and scripts.
+
+use crate::create_status_response;
+use crate::resume::{self, UploadId};
+use crate::scan::{self, Verdict};
+use crate::space::SpaceMonitor;
+use crate::state::PartialUpload;
+use crate::storage::{self, Storage};
+use crate::{
+    access, broadcast, clipboard, help, html, manifest, notify, output, paths, tokens, transfer,
+};
+use futures::stream::StreamExt;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fs;
+use std::io::{self, SeekFrom};
+use std::net;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+
+const UPLOAD_PAGE: &str = include_str!("upload.html");
+/// The name field of the upload page, hidden unless uploaders are asked for their name
+const HIDDEN_NAME_FIELD: &str = "<p id=\"identity\" hidden>";
+/// The text field of the upload page, hidden unless text is put onto the clipboard
+const HIDDEN_CLIPBOARD_FIELD: &str = "<div id=\"clipboard\" hidden>";
+pub const LISTING_PATH: &str = "/.received";
+/// The service worker the upload page registers to send uploads again after the connection dropped
+pub const WORKER_PATH: &str = "/.upload-worker.js";
+const UPLOAD_WORKER: &str = include_str!("upload-worker.js");
+/// Start and end of the names of files that are still being uploaded
+const TEMP_PREFIX: &str = ".rustbelt-upload-";
+const TEMP_SUFFIX: &str = ".part";
+
+/// The largest request body accepted for the append log
+const MAX_LOG_BODY: usize = 1024 * 1024;
+
+/// A newline delimited JSON file lines are appended to
+pub struct AppendLog {
+    path: PathBuf,
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl AppendLog {
+    pub fn new(path: PathBuf) -> AppendLog {
+        AppendLog {
+            path,
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Appends all lines at once, so lines of concurrent requests never interleave.
+    async fn append(&self, lines: &str) -> io::Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(lines.as_bytes()).await?;
+        file.flush().await
+    }
+}
+
+/// What happens to an upload whose content is already in the destination directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dedupe {
+    /// Drop the upload
+    Skip,
+    /// Store the upload under its name as a hard link to the existing file
+    Link,
+}
+
+impl FromStr for Dedupe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dedupe, String> {
+        match s {
+            "skip" => Ok(Dedupe::Skip),
+            "link" => Ok(Dedupe::Link),
+            _ => Err(format!("Unknown deduplication mode: {}", s)),
+        }
+    }
+}
+
+/// A limit on the bytes stored in the destination directory, including what was there before
+pub struct Quota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl Quota {
+    pub fn new(limit: u64, used: u64) -> Quota {
+        Quota {
+            limit,
+            used: AtomicU64::new(used),
+        }
+    }
+
+    fn get_remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used.load(Ordering::SeqCst))
+    }
+
+    /// Counts `bytes` as used, unless that would exceed the limit.
+    fn try_reserve(&self, bytes: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&u| u <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: u64) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
+}
+
+/// The total size of the files below `dir`, 0 if it doesn't exist yet.
+pub fn get_directory_size(dir: &Path) -> io::Result<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += get_directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Answers an upload the quota or the disk has no room for, with a page for browsers.
+fn create_quota_response<T>(req: &Request<T>, status: StatusCode, message: &str) -> Response<Body> {
+    let wants_html = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|a| a.to_str().ok())
+        .is_some_and(|a| a.contains("text/html"));
+    if !wants_html {
+        return create_status_response(status, message);
+    }
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(html::create_message_page(
+            "Storage full",
+            message,
+        )))
+        .unwrap()
+}
+
+/// Where received files are stored and how they are checked before being accepted
+pub struct Inbox {
+    destination: PathBuf,
+    quarantine: Option<PathBuf>,
+    on_receive: Option<String>,
+    append_log: Option<AppendLog>,
+    dedupe: Option<Dedupe>,
+    /// Store the files of every uploader in a folder named after them
+    ask_name: bool,
+    notifier: notify::Notifier,
+    quota: Option<Quota>,
+    /// Protects the listing of received files, if it is served
+    listing: Option<access::AccessRules>,
+    /// Checks files for malware while they are in quarantine
+    scanner: Option<scan::Scanner>,
+    /// Report accepted uploads as JSON lines on stdout
+    porcelain: bool,
+    /// Where uploads go instead of the destination, with `--store-to`
+    storage: Option<Arc<dyn Storage>>,
+    /// Temporary files of interrupted resumable uploads, with their length synced to disk
+    interrupted: Mutex<BTreeMap<PathBuf, u64>>,
+    /// Checks that uploads fit on the disk of the destination
+    space: Option<Arc<SpaceMonitor>>,
+    /// Text waiting to be copied to the clipboard, with `--to-clipboard`
+    clipboard: Option<Arc<clipboard::Clipboard>>,
+}
+
+impl Inbox {
+    pub fn new(
+        destination: PathBuf,
+        quarantine: Option<PathBuf>,
+        on_receive: Option<String>,
+        append_log: Option<AppendLog>,
+        dedupe: Option<Dedupe>,
+        ask_name: bool,
+        notifier: notify::Notifier,
+    ) -> Inbox {
+        Inbox {
+            destination,
+            quarantine,
+            on_receive,
+            append_log,
+            dedupe,
+            ask_name,
+            notifier,
+            quota: None,
+            listing: None,
+            scanner: None,
+            porcelain: false,
+            storage: None,
+            interrupted: Mutex::default(),
+            space: None,
+            clipboard: None,
+        }
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.destination
+    }
+
+    /// Whether uploads are appended to a log rather than stored as files
+    pub fn is_appending(&self) -> bool {
+        self.append_log.is_some()
+    }
+
+    /// Whether the received files are listed at `LISTING_PATH`
+    pub fn has_listing(&self) -> bool {
+        self.listing.is_some()
+    }
+
+    /// Serves the listing of received files to those who know `pin`.
+    pub fn with_listing(mut self, pin: &str) -> Result<Inbox, String> {
+        let rule = format!("{}:{}", LISTING_PATH, pin).parse()?;
+        self.listing = Some(access::AccessRules::new(vec![rule]));
+        Ok(self)
+    }
+
+    async fn serve_listing(&self, req: &Request<Body>) -> Response<Body> {
+        if let Some(denied) = self
+            .listing
+            .as_ref()
+            .and_then(|l| l.check(LISTING_PATH, req))
+        {
+            return denied;
+        }
+        let destination = self.destination.clone();
+        let quarantine = self.quarantine.clone();
+        let files = tokio::task::spawn_blocking(move || {
+            list_received(&destination, &destination, quarantine.as_deref())
+        })
+        .await;
+        match files {
+            Ok(Ok(mut files)) => {
+                files.sort_by_key(|f| std::cmp::Reverse(f.modified));
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                    .body(Body::from(create_listing_page(&files)))
+                    .unwrap()
+            }
+            _ => create_status_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not list the received files",
+            ),
+        }
+    }
+
+    pub fn with_quota(mut self, quota: Quota) -> Inbox {
+        self.quota = Some(quota);
+        self
+    }
+
+    pub fn with_scanner(mut self, scanner: scan::Scanner) -> Inbox {
+        self.scanner = Some(scanner);
+        self
+    }
+
+    pub fn with_porcelain(mut self) -> Inbox {
+        self.porcelain = true;
+        self
+    }
+
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Inbox {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn with_clipboard(mut self) -> Inbox {
+        self.clipboard = Some(Arc::default());
+        self
+    }
+
+    pub fn get_clipboard(&self) -> Option<&clipboard::Clipboard> {
+        self.clipboard.as_deref()
+    }
+
+    /// Refuses uploads that don't fit on the disk and warns when it gets below `min_free`.
+    pub fn with_space_check(mut self, min_free: u64) -> Inbox {
+        let monitor = SpaceMonitor::new(self.destination.clone(), min_free);
+        self.space = Some(Arc::new(monitor));
+        self
+    }
+
+    /// The free space check of the destination, unless uploads go to storage elsewhere
+    pub fn get_space_monitor(&self) -> Option<Arc<SpaceMonitor>> {
+        match self.storage {
+            Some(_) => None,
+            None => self.space.clone(),
+        }
+    }
+
+    /// The interrupted uploads, for the state file
+    pub fn get_interrupted_uploads(&self) -> Vec<PartialUpload> {
+        let interrupted = self.interrupted.lock().unwrap();
+        interrupted
+            .iter()
+            .map(|(path, &offset)| PartialUpload {
+                path: path.clone(),
+                offset,
+            })
+            .collect()
+    }
+
+    /// Takes over the interrupted uploads of an earlier run. What was written after their saved
+    /// length may not have reached the disk before a crash, so it is cut off. Returns how many
+    /// uploads can be continued.
+    pub fn restore_uploads(&self, uploads: &[PartialUpload]) -> io::Result<usize> {
+        let mut interrupted = self.interrupted.lock().unwrap();
+        for upload in uploads {
+            if !upload.path.starts_with(&self.destination) || !is_temp_file(&upload.path) {
+                continue;
+            }
+            let length = match fs::metadata(&upload.path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if length > upload.offset {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(&upload.path)?
+                    .set_len(upload.offset)?;
+            }
+            interrupted.insert(upload.path.clone(), length.min(upload.offset));
+        }
+        Ok(interrupted.len())
+    }
+
+    /// Where uploads go if that isn't the destination
+    pub fn describe_storage(&self) -> Option<String> {
+        self.storage.as_ref().map(|s| s.describe())
+    }
+
+    /// The folder named after the uploader, if they gave a usable name
+    fn get_folder<T>(&self, req: &Request<T>) -> Option<String> {
+        broadcast::parse_name(req.uri().query())
+            .as_deref()
+            .and_then(create_folder_name)
+    }
+
+    /// The directory an upload goes to, `None` if the uploader didn't give the name they were
+    /// asked for.
+    fn get_destination<T>(&self, req: &Request<T>) -> Option<PathBuf> {
+        if !self.ask_name {
+            return Some(self.destination.clone());
+        }
+        self.get_folder(req)
+            .map(|folder| self.destination.join(folder))
+    }
+
+    /// Where uploads to `destination` are written before they are accepted
+    fn get_staging_dir<'a>(&'a self, destination: &'a Path) -> &'a Path {
+        match &self.quarantine {
+            Some(q) => q,
+            None => destination,
+        }
+    }
+
+    fn create_upload_page(&self) -> String {
+        let mut page = UPLOAD_PAGE.to_string();
+        if self.ask_name {
+            page = page.replace(HIDDEN_NAME_FIELD, "<p id=\"identity\">");
+        }
+        if self.clipboard.is_some() {
+            page = page.replace(HIDDEN_CLIPBOARD_FIELD, "<div id=\"clipboard\">");
+        }
+        page
+    }
+}
+
+/// Turns the name of an uploader into the name of their folder, replacing characters that aren't
+/// allowed in file names.
+pub fn create_folder_name(name: &str) -> Option<String> {
+    let folder = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let folder = folder.trim().trim_start_matches('.').trim_end_matches('.');
+    Some(folder.to_string()).filter(|f| !f.is_empty())
+}
+
+/// A file in the destination directory, for the listing
+#[derive(Debug, PartialEq)]
+struct Received {
+    /// Path relative to the destination directory
+    path: String,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Lists the files below `dir` with paths relative to `root`, leaving out the quarantine.
+fn list_received(root: &Path, dir: &Path, quarantine: Option<&Path>) -> io::Result<Vec<Received>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        if Some(path.as_path()) == quarantine || is_temp_file(&path) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            files.extend(list_received(root, &path, quarantine)?);
+        } else if metadata.is_file() {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            files.push(Received {
+                path: relative.to_string_lossy().replace('\\', "/"),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+    }
+    Ok(files)
+}
+
+fn create_listing_page(files: &[Received]) -> String {
+    if files.is_empty() {
+        return html::create_message_page("Received files", "Nothing has been received yet.");
+    }
+    let mut rows = String::new();
+    for file in files {
+        let modified = chrono::DateTime::<chrono::Local>::from(file.modified);
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            html::escape(&file.path),
+            transfer::format_size(file.size),
+            modified.format("%Y-%m-%d %H:%M:%S")
+        ));
+    }
+    html::create_page(
+        "Received files",
+        &format!(
+            "<table>\n<tr><th>File</th><th>Size</th><th>Received</th></tr>\n{}</table>\n",
+            rows
+        ),
+    )
+}
+
+enum Outcome {
+    Accepted(PathBuf),
+    /// The upload didn't fit into the quota and was deleted
+    OverQuota,
+    Rejected(PathBuf),
+    /// The scanner found the named malware and the upload was deleted
+    Infected(String),
+    /// The content already exists at the first path, the second is the link to it if one was made
+    Duplicate(PathBuf, Option<PathBuf>),
+}
+
+/// What a request of an inbox is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Page,
+    Listing,
+    Worker,
+    Clipboard,
+    Append,
+    Upload,
+    Offset,
+}
+
+/// The requests `inbox` answers
+pub fn get_routes(inbox: &Inbox) -> Vec<help::Route<Route>> {
+    use help::Route as R;
+    let mut routes = vec![
+        R::new(Method::GET, "/", "Upload page", Route::Page),
+        R::new(
+            Method::GET,
+            WORKER_PATH,
+            "Service worker sending uploads again after the connection dropped",
+            Route::Worker,
+        ),
+    ];
+    if inbox.has_listing() {
+        routes.push(R::new(
+            Method::GET,
+            LISTING_PATH,
+            "Lists the received files",
+            Route::Listing,
+        ));
+    }
+    if !inbox.is_appending() {
+        routes.push(
+            R::new(
+                Method::HEAD,
+                "/<name>",
+                &format!(
+                    "With {} and {}, answers with the {} to continue an interrupted upload at",
+                    resume::SIZE_HEADER,
+                    resume::HEAD_HASH_HEADER,
+                    resume::OFFSET_HEADER
+                ),
+                Route::Offset,
+            )
+            .when(|headers| UploadId::from_headers(headers).is_some()),
+        );
+    }
+    routes.extend(get_upload_routes(inbox));
+    routes
+}
+
+/// The requests of `inbox` that upload something, which an exchange takes as well
+pub fn get_upload_routes(inbox: &Inbox) -> Vec<help::Route<Route>> {
+    use help::Route as R;
+    let mut routes = Vec::new();
+    if inbox.clipboard.is_some() {
+        for method in [Method::PUT, Method::POST] {
+            routes.push(R::new(
+                method,
+                clipboard::CLIPBOARD_PATH,
+                "Puts the text in the body onto the clipboard, once the receiver accepts it",
+                Route::Clipboard,
+            ));
+        }
+    }
+    if inbox.is_appending() {
+        for method in [Method::PUT, Method::POST] {
+            routes
+                .push(R::new(method, "/", "Appends the body to the log", Route::Append).any_path());
+        }
+    } else {
+        let description = format!(
+            "Uploads the body as a file. With {} and {}, {} continues an interrupted upload",
+            resume::SIZE_HEADER,
+            resume::HEAD_HASH_HEADER,
+            resume::OFFSET_HEADER
+        );
+        for method in [Method::PUT, Method::POST] {
+            routes.push(R::new(method, "/<name>", &description, Route::Upload).any_path());
+        }
+    }
+    routes
+}
+
+pub async fn handle_request(
+    inbox: Arc<Inbox>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    match help::find_route(&get_routes(&inbox), &req) {
+        Ok(Route::Page) => Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(inbox.create_upload_page()))
+            .unwrap()),
+        Ok(Route::Listing) => Ok(inbox.serve_listing(&req).await),
+        Ok(Route::Worker) => Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "text/javascript; charset=utf-8")
+            // Browsers check for a new worker with every visit anyway.
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::from(UPLOAD_WORKER))
+            .unwrap()),
+        Ok(Route::Clipboard) => {
+            let sender = match req.extensions().get::<net::SocketAddr>() {
+                Some(address) => address.ip().to_string(),
+                None => String::from("Someone"),
+            };
+            let clipboard = inbox.clipboard.as_ref().unwrap();
+            clipboard::handle_upload(clipboard, &sender, req.into_body()).await
+        }
+        Ok(Route::Append) => {
+            let source = req.extensions().get::<net::SocketAddr>().map(|a| a.ip());
+            append_body(inbox.append_log.as_ref().unwrap(), source, req.into_body()).await
+        }
+        Ok(Route::Upload) => {
+            let file_name = match paths::get_path_segment(req.uri().path().trim_start_matches('/'))
+            {
+                Some(n) => n,
+                None => {
+                    return Ok(create_status_response(
+                        StatusCode::BAD_REQUEST,
+                        "Invalid file name",
+                    ))
+                }
+            };
+            let length = req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|l| l.to_str().ok())
+                .and_then(|l| l.parse::<u64>().ok());
+            if let Some(quota) = &inbox.quota {
+                let remaining = quota.get_remaining();
+                if remaining == 0 {
+                    return Ok(create_quota_response(
+                        &req,
+                        StatusCode::INSUFFICIENT_STORAGE,
+                        "The storage quota of this server is used up, no more uploads are \
+                         accepted",
+                    ));
+                }
+                if length.is_some_and(|l| l > remaining) {
+                    return Ok(create_quota_response(
+                        &req,
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        &get_too_large_message(remaining),
+                    ));
+                }
+            }
+            if let (Some(space), Some(length)) = (inbox.get_space_monitor(), length) {
+                let free = tokio::task::spawn_blocking(move || space.check()).await;
+                if let Some(free) = free.ok().flatten().filter(|&free| length > free) {
+                    output::print_event(&format!(
+                        "Refused {} of {}, only {} are free",
+                        file_name,
+                        transfer::format_size(length),
+                        transfer::format_size(free)
+                    ));
+                    return Ok(create_quota_response(
+                        &req,
+                        StatusCode::INSUFFICIENT_STORAGE,
+                        &format!(
+                            "There is not enough space left on this server for the file, only \
+                             {} are free",
+                            transfer::format_size(free)
+                        ),
+                    ));
+                }
+            }
+            if let Some(storage) = &inbox.storage {
+                return Ok(store_upload(&inbox, storage.as_ref(), &file_name, req).await);
+            }
+            let source = req.extensions().get::<net::SocketAddr>().map(|a| a.ip());
+            let destination = match inbox.get_destination(&req) {
+                Some(d) => d,
+                None => {
+                    return Ok(create_status_response(
+                        StatusCode::BAD_REQUEST,
+                        "Please enter your name",
+                    ))
+                }
+            };
+            let upload = UploadId::from_headers(req.headers());
+            let offset = match resume::get_offset(req.headers()) {
+                Some(o) => o,
+                None => {
+                    return Ok(create_status_response(
+                        StatusCode::BAD_REQUEST,
+                        "Invalid upload offset",
+                    ))
+                }
+            };
+            if offset > 0 {
+                let offered = match &upload {
+                    Some(id) => get_resume_offset(&inbox, &destination, &file_name, id).await,
+                    None => Ok(0),
+                };
+                match offered {
+                    Ok(offered) if offered == offset => {}
+                    Ok(offered) => {
+                        let mut response = create_status_response(
+                            StatusCode::CONFLICT,
+                            "The upload can't be continued at this offset",
+                        );
+                        response
+                            .headers_mut()
+                            .insert(resume::OFFSET_HEADER, offered.into());
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        eprintln!("Could not check the upload of {}: {}", file_name, e);
+                        return Ok(create_status_response(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Failed to store the file",
+                        ));
+                    }
+                }
+            }
+            let body = req.into_body();
+            let started = Instant::now();
+            match receive_file(&inbox, &destination, &file_name, body, upload, offset).await {
+                Ok(Outcome::Accepted(path)) => {
+                    output::print_event(&format!("Received {}", path.display()));
+                    if let Some(space) = inbox.get_space_monitor() {
+                        tokio::task::spawn_blocking(move || space.check());
+                    }
+                    if inbox.porcelain {
+                        print_received_event(&path, source, started.elapsed()).await;
+                    }
+                    inbox.notifier.notify(path, source);
+                    Ok(create_status_response(StatusCode::CREATED, "Received"))
+                }
+                Ok(Outcome::OverQuota) => {
+                    output::print_event(&format!("Dropped {}, it exceeded the quota", file_name));
+                    let remaining = inbox.quota.as_ref().map_or(0, Quota::get_remaining);
+                    Ok(create_status_response(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        &get_too_large_message(remaining),
+                    ))
+                }
+                Ok(Outcome::Rejected(path)) => {
+                    match crate::trash::move_to_trash(&path) {
+                        Ok(()) => output::print_event(&format!(
+                            "The on-receive command rejected {}, it was moved to the trash",
+                            path.display()
+                        )),
+                        Err(e) => eprintln!(
+                            "The on-receive command rejected {}, it was kept in quarantine: {}",
+                            path.display(),
+                            e
+                        ),
+                    }
+                    Ok(create_status_response(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "The file was not accepted",
+                    ))
+                }
+                Ok(Outcome::Infected(malware)) => {
+                    output::print_event(&format!(
+                        "Deleted {}, the scanner found {}",
+                        file_name, malware
+                    ));
+                    Ok(create_status_response(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "The file was not accepted, it contains malware",
+                    ))
+                }
+                Ok(Outcome::Duplicate(existing, link)) => {
+                    match link {
+                        Some(link) => output::print_event(&format!(
+                            "{} has the same content as {}, it was linked to it",
+                            link.display(),
+                            existing.display()
+                        )),
+                        None => output::print_event(&format!(
+                            "Skipped {}, it has the same content as {}",
+                            file_name,
+                            existing.display()
+                        )),
+                    }
+                    Ok(create_status_response(StatusCode::OK, "Already have it"))
+                }
+                Err(e) => {
+                    eprintln!("Failed to receive {}: {}", file_name, e);
+                    Ok(create_status_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Failed to store the file",
+                    ))
+                }
+            }
+        }
+        Ok(Route::Offset) => {
+            let upload = UploadId::from_headers(req.headers()).unwrap();
+            let (file_name, destination) = match (
+                paths::get_path_segment(req.uri().path().trim_start_matches('/')),
+                inbox.get_destination(&req),
+            ) {
+                (Some(f), Some(d)) => (f, d),
+                _ => {
+                    return Ok(create_status_response(
+                        StatusCode::BAD_REQUEST,
+                        "Invalid upload",
+                    ))
+                }
+            };
+            let offset = get_resume_offset(&inbox, &destination, &file_name, &upload)
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("Could not check the upload of {}: {}", file_name, e);
+                    0
+                });
+            Ok(Response::builder()
+                .header(resume::OFFSET_HEADER, offset)
+                .body(Body::empty())
+                .unwrap())
+        }
+        Err(response) => Ok(response),
+    }
+}
+
+/// Passes an upload to the storage instead of the destination.
+async fn store_upload(
+    inbox: &Inbox,
+    storage: &dyn Storage,
+    file_name: &str,
+    req: Request<Body>,
+) -> Response<Body> {
+    let source = req.extensions().get::<net::SocketAddr>().map(|a| a.ip());
+    let name = if inbox.ask_name {
+        match inbox.get_folder(&req) {
+            Some(folder) => format!("{}/{}", folder, file_name),
+            None => {
+                return create_status_response(StatusCode::BAD_REQUEST, "Please enter your name")
+            }
+        }
+    } else {
+        file_name.to_string()
+    };
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse::<u64>().ok());
+    let started = Instant::now();
+    let (upload, summary) = storage::Upload::new(req.into_body(), length);
+    match storage.store(&name, upload).await {
+        Ok(location) => {
+            output::print_event(&format!("Received {}", location));
+            if inbox.porcelain {
+                print_json_line(&create_received_event(
+                    &location,
+                    summary.get_size(),
+                    &summary.get_sha256(),
+                    source,
+                    started.elapsed(),
+                ));
+            }
+            inbox.notifier.notify(PathBuf::from(location), source);
+            create_status_response(StatusCode::CREATED, "Received")
+        }
+        Err(e) => {
+            eprintln!("Failed to store {}: {}", name, e);
+            create_status_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to store the file",
+            )
+        }
+    }
+}
+
+fn print_json_line(event: &serde_json::Value) {
+    println!("{}", event);
+    let _ = io::Write::flush(&mut io::stdout());
+}
+
+/// The JSON line reporting the upload to `location` with `--porcelain`
+fn create_received_event(
+    location: &str,
+    size: u64,
+    sha256: &str,
+    sender: Option<net::IpAddr>,
+    duration: Duration,
+) -> serde_json::Value {
+    serde_json::json!({
+        "event": "received",
+        "path": location,
+        "size": size,
+        "sha256": sha256,
+        "sender": sender.map(|s| s.to_string()),
+        "duration": duration.as_secs_f64(),
+    })
+}
+
+/// Hashes the received file and prints its event, before the uploader is answered, so a script
+/// has seen every file once the upload finished.
+async fn print_received_event(path: &Path, sender: Option<net::IpAddr>, duration: Duration) {
+    let file = path.to_path_buf();
+    let hashed = tokio::task::spawn_blocking(move || {
+        Ok::<_, io::Error>((fs::metadata(&file)?.len(), manifest::hash_file(&file)?))
+    })
+    .await
+    .map_err(io::Error::other);
+    match hashed {
+        Ok(Ok((size, sha256))) => {
+            let location = path.to_string_lossy();
+            print_json_line(&create_received_event(
+                &location, size, &sha256, sender, duration,
+            ));
+        }
+        Ok(Err(e)) | Err(e) => eprintln!("Could not hash {}: {}", path.display(), e),
+    }
+}
+
+fn get_too_large_message(remaining: u64) -> String {
+    format!(
+        "The file is larger than the {} left on this server",
+        transfer::format_size(remaining)
+    )
+}
+
+async fn append_body(
+    log: &AppendLog,
+    source: Option<net::IpAddr>,
+    mut body: Body,
+) -> Result<Response<Body>, Infallible> {
+    let mut content = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(c) => c,
+            Err(_) => {
+                return Ok(create_status_response(
+                    StatusCode::BAD_REQUEST,
+                    "Failed to read the request",
+                ))
+            }
+        };
+        if content.len() + chunk.len() > MAX_LOG_BODY {
+            return Ok(create_status_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The request is too large",
+            ));
+        }
+        content.extend_from_slice(&chunk);
+    }
+    let lines = create_log_lines(
+        &String::from_utf8_lossy(&content),
+        chrono::Local::now(),
+        source,
+    );
+    if lines.is_empty() {
+        return Ok(create_status_response(
+            StatusCode::BAD_REQUEST,
+            "Nothing to append",
+        ));
+    }
+    match log.append(&lines).await {
+        Ok(()) => Ok(create_status_response(StatusCode::OK, "Appended")),
+        Err(e) => {
+            eprintln!("Failed to append to {}: {}", log.path.display(), e);
+            Ok(create_status_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to append",
+            ))
+        }
+    }
+}
+
+/// Turns every non-empty line of `content` into a JSON object with the time and source. Lines that
+/// are JSON themselves are embedded as they are, others as a string.
+fn create_log_lines(
+    content: &str,
+    time: chrono::DateTime<chrono::Local>,
+    source: Option<net::IpAddr>,
+) -> String {
+    let mut lines = String::new();
+    for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let message = serde_json::from_str(line)
+            .unwrap_or_else(|_| serde_json::Value::String(line.to_string()));
+        let entry = serde_json::json!({
+            "time": time.to_rfc3339(),
+            "source": source.map(|s| s.to_string()),
+            "message": message,
+        });
+        lines.push_str(&entry.to_string());
+        lines.push('\n');
+    }
+    lines
+}
+
+/// Receives an upload. Resumable uploads continue their temporary file at `offset`, which the
+/// caller has checked, and keep it if they fail.
+async fn receive_file(
+    inbox: &Inbox,
+    destination: &Path,
+    file_name: &str,
+    body: Body,
+    upload: Option<UploadId>,
+    offset: u64,
+) -> io::Result<Outcome> {
+    let staging_dir = inbox.get_staging_dir(destination);
+    tokio::fs::create_dir_all(staging_dir).await?;
+    let temp_path = staging_dir.join(match &upload {
+        Some(id) => create_resumable_temp_name(file_name, id),
+        None => create_temp_name(),
+    });
+    // Bytes kept from earlier attempts are still reserved in the quota.
+    let mut written = offset;
+    let result = write_body(inbox, &temp_path, offset, body, &mut written).await;
+    let result = match (&upload, result) {
+        (Some(id), Ok(true)) if written != id.size => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("The upload ended after {} of {} bytes", written, id.size),
+        )),
+        (_, result) => result,
+    };
+    match result {
+        Ok(true) => {
+            inbox.interrupted.lock().unwrap().remove(&temp_path);
+        }
+        Err(e) if upload.is_some() => {
+            if let Ok(metadata) = tokio::fs::metadata(&temp_path).await {
+                let mut interrupted = inbox.interrupted.lock().unwrap();
+                interrupted.insert(temp_path.clone(), metadata.len());
+            }
+            eprintln!(
+                "Kept {} of {} to continue the upload later",
+                transfer::format_size(written),
+                file_name
+            );
+            return Err(e);
+        }
+        result => {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            release(inbox, written);
+            return result.map(|_| Outcome::OverQuota);
+        }
+    }
+    let staging_path = move_to_unique_path(&temp_path, staging_dir, file_name).await?;
+    sync_directory(staging_dir)?;
+
+    if let Some(dedupe) = inbox.dedupe {
+        let dir = destination.to_path_buf();
+        let staging = staging_path.clone();
+        let existing = tokio::task::spawn_blocking(move || find_duplicate(&dir, &staging))
+            .await
+            .map_err(io::Error::other)??;
+        if let Some(existing) = existing {
+            tokio::fs::remove_file(&staging_path).await?;
+            release(inbox, written);
+            let link = match dedupe {
+                Dedupe::Skip => None,
+                Dedupe::Link => Some(link_to_unique_path(&existing, destination, file_name).await?),
+            };
+            return Ok(Outcome::Duplicate(existing, link));
+        }
+    }
+
+    if let Some(scanner) = inbox.scanner.clone() {
+        let path = staging_path.clone();
+        let verdict = tokio::task::spawn_blocking(move || scanner.scan(&path))
+            .await
+            .map_err(io::Error::other)?
+            .map_err(|e| {
+                io::Error::other(format!(
+                    "Could not scan it, it was kept in quarantine: {}",
+                    e
+                ))
+            })?;
+        if let Verdict::Infected(malware) = verdict {
+            tokio::fs::remove_file(&staging_path).await?;
+            release(inbox, written);
+            return Ok(Outcome::Infected(malware));
+        }
+    }
+
+    if let Some(command) = &inbox.on_receive {
+        if !run_on_receive(command, &staging_path).await? {
+            release(inbox, written);
+            return Ok(Outcome::Rejected(staging_path));
+        }
+    }
+
+    if inbox.quarantine.is_some() {
+        tokio::fs::create_dir_all(destination).await?;
+        let final_path = move_to_unique_path(&staging_path, destination, file_name).await?;
+        sync_directory(destination)?;
+        Ok(Outcome::Accepted(final_path))
+    } else {
+        Ok(Outcome::Accepted(staging_path))
+    }
+}
+
+/// A name for a temporary file that nothing else uses and that `is_temp_file` recognises
+pub fn create_temp_name() -> String {
+    format!("{}{}{}", TEMP_PREFIX, tokens::generate_token(), TEMP_SUFFIX)
+}
+
+/// The name of the temporary file of a resumable upload, the same for every attempt
+fn create_resumable_temp_name(file_name: &str, upload: &UploadId) -> String {
+    format!(
+        "{}{}{}",
+        TEMP_PREFIX,
+        upload.get_key(file_name),
+        TEMP_SUFFIX
+    )
+}
+
+/// Returns where the upload of `file_name` to `destination` can continue.
+async fn get_resume_offset(
+    inbox: &Inbox,
+    destination: &Path,
+    file_name: &str,
+    upload: &UploadId,
+) -> io::Result<u64> {
+    let path = inbox
+        .get_staging_dir(destination)
+        .join(create_resumable_temp_name(file_name, upload));
+    let upload = upload.clone();
+    tokio::task::spawn_blocking(move || resume::get_resume_offset(&path, &upload))
+        .await
+        .map_err(io::Error::other)?
+}
+
+pub fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(TEMP_PREFIX) && n.ends_with(TEMP_SUFFIX))
+}
+
+/// Streams `body` into the file at `path` from `offset` on and syncs it to disk. Returns false if
+/// it doesn't fit into the quota. `written` counts the bytes reserved in the quota so far.
+async fn write_body(
+    inbox: &Inbox,
+    path: &Path,
+    offset: u64,
+    mut body: Body,
+    written: &mut u64,
+) -> io::Result<bool> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await?;
+    // A restarted upload drops what an earlier attempt left behind.
+    let existing = file.metadata().await?.len();
+    if existing > offset {
+        release(inbox, existing - offset);
+    }
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                // Writes still in flight would otherwise land after the offset is reported.
+                file.flush().await?;
+                file.sync_data().await?;
+                return Err(io::Error::other(e));
+            }
+        };
+        let length = chunk.len() as u64;
+        if !inbox.quota.as_ref().is_none_or(|q| q.try_reserve(length)) {
+            return Ok(false);
+        }
+        *written += length;
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+    Ok(true)
+}
+
+/// Makes renames in `dir` survive a crash.
+#[cfg(unix)]
+pub fn sync_directory(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_directory(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Gives the bytes of an upload that isn't kept back to the quota.
+fn release(inbox: &Inbox, written: u64) {
+    if let Some(quota) = &inbox.quota {
+        quota.release(written);
+    }
+}
+
+/// Looks for a file in `dir` with the same content as `file`, which itself is ignored if it is in
+/// `dir`. Only files of the same size are hashed.
+fn find_duplicate(dir: &Path, file: &Path) -> io::Result<Option<PathBuf>> {
+    let length = fs::metadata(file)?.len();
+    let mut hash = None;
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file()
+            || metadata.len() != length
+            || entry.path() == file
+            || is_temp_file(&entry.path())
+        {
+            continue;
+        }
+        if hash.is_none() {
+            hash = Some(manifest::hash_file(file)?);
+        }
+        if hash.as_ref() == Some(&manifest::hash_file(&entry.path())?) {
+            return Ok(Some(entry.path()));
+        }
+    }
+    Ok(None)
+}
+
+async fn run_on_receive(command: &str, path: &Path) -> io::Result<bool> {
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(create_hook_command(command, path))
+        .status()
+        .await?;
+    Ok(status.success())
+}
+
+/// Inserts the path of a received file into a user command.
+///
+/// Every `{file}` in the command is replaced by the quoted path. Without a placeholder the path is
+/// appended as the last argument.
+fn create_hook_command(command: &str, path: &Path) -> String {
+    let quoted = shell_quote(&path.to_string_lossy());
+    if command.contains("{file}") {
+        command.replace("{file}", &quoted)
+    } else {
+        format!("{} {}", command, quoted)
+    }
+}
+
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// The path for `file_name` in `dir` with `counter` appended to its stem, unless it is 0
+fn get_numbered_path(dir: &Path, file_name: &str, counter: u32) -> PathBuf {
+    if counter == 0 {
+        return dir.join(file_name);
+    }
+    let (stem, extension) = match file_name.rfind('.') {
+        Some(i) if i > 0 => (&file_name[..i], &file_name[i..]),
+        _ => (file_name, ""),
+    };
+    dir.join(format!("{} ({}){}", stem, counter, extension))
+}
+
+/// Hard links `from` to the first free name for `file_name` in `dir`, appending a counter if
+/// needed. Creating the link claims the name, so uploads of the same name never take the same one.
+async fn link_to_unique_path(from: &Path, dir: &Path, file_name: &str) -> io::Result<PathBuf> {
+    let mut counter = 0;
+    loop {
+        let candidate = get_numbered_path(dir, file_name, counter);
+        match tokio::fs::hard_link(from, &candidate).await {
+            Ok(()) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => counter += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Moves `from` to the first free name for `file_name` in `dir`, appending a counter if needed.
+/// On file systems without hard links an empty file claims the name until `from` replaces it.
+async fn move_to_unique_path(from: &Path, dir: &Path, file_name: &str) -> io::Result<PathBuf> {
+    if let Ok(path) = link_to_unique_path(from, dir, file_name).await {
+        tokio::fs::remove_file(from).await?;
+        return Ok(path);
+    }
+    let mut counter = 0;
+    loop {
+        let candidate = get_numbered_path(dir, file_name, counter);
+        let claimed = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+            .await;
+        match claimed {
+            Ok(_) => {
+                tokio::fs::rename(from, &candidate).await?;
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => counter += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_hook_command_placeholder(a in "[a-z ]*") {
+            let command = create_hook_command(&format!("{}{{file}}", a), Path::new("x y"));
+            prop_assert_eq!(format!("{}'x y'", a), command);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_log_lines(messages in proptest::collection::vec("[^\\r\\n]*[^\\s]", 0..5)) {
+            let source = Some(net::IpAddr::from([192, 168, 0, 2]));
+            let lines = create_log_lines(&messages.join("\r\n\n"), chrono::Local::now(), source);
+            prop_assert_eq!(messages.len(), lines.lines().count());
+            for line in lines.lines() {
+                let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+                prop_assert_eq!("192.168.0.2", entry["source"].as_str().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_received_event() {
+        let event = create_received_event(
+            "in/photo.jpg",
+            3,
+            "abc",
+            Some("192.168.1.5".parse().unwrap()),
+            Duration::from_millis(1500),
+        );
+        assert_eq!(
+            serde_json::json!({
+                "event": "received",
+                "path": "in/photo.jpg",
+                "size": 3,
+                "sha256": "abc",
+                "sender": "192.168.1.5",
+                "duration": 1.5,
+            }),
+            event
+        );
+        assert!(!event.to_string().contains('\n'));
+    }
+
+    #[test]
+    fn test_log_lines_embed_json() {
+        let lines = create_log_lines("{\"temp\": 21.5}\nplain", chrono::Local::now(), None);
+        let entries = lines
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(21.5, entries[0]["message"]["temp"]);
+        assert_eq!("plain", entries[1]["message"]);
+        assert!(entries[1]["source"].is_null());
+    }
+
+    #[test]
+    fn test_find_duplicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let upload = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.jpg"), "photo").unwrap();
+        fs::write(dir.path().join("b.jpg"), "other").unwrap();
+        fs::write(upload.path().join("new.jpg"), "photo").unwrap();
+        fs::write(upload.path().join("unique.jpg"), "fresh").unwrap();
+        assert_eq!(
+            Some(dir.path().join("a.jpg")),
+            find_duplicate(dir.path(), &upload.path().join("new.jpg")).unwrap()
+        );
+        assert_eq!(
+            None,
+            find_duplicate(dir.path(), &upload.path().join("unique.jpg")).unwrap()
+        );
+        assert_eq!(
+            None,
+            find_duplicate(dir.path(), &dir.path().join("b.jpg")).unwrap()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_folder_name_stays_in_directory(name in "\\PC{0,30}") {
+            if let Some(folder) = create_folder_name(&name) {
+                prop_assert_eq!(Some(folder.clone()), paths::get_path_segment(&paths::percent_encode(&folder)));
+                prop_assert!(!folder.starts_with('.'));
+            }
+        }
+    }
+
+    #[test]
+    fn test_folder_name() {
+        assert_eq!(Some(String::from("Ada L")), create_folder_name(" Ada L "));
+        assert_eq!(Some(String::from("a_b")), create_folder_name("a/b"));
+        assert_eq!(Some(String::from("_x")), create_folder_name("../x"));
+        assert_eq!(None, create_folder_name(".."));
+        assert!(Inbox::new(
+            PathBuf::new(),
+            None,
+            None,
+            None,
+            None,
+            true,
+            notify::Notifier::default()
+        )
+        .create_upload_page()
+        .contains("<p id=\"identity\">"));
+        assert!(UPLOAD_PAGE.contains(HIDDEN_NAME_FIELD));
+    }
+
+    proptest! {
+        #[test]
+        fn test_quota_never_exceeded(limit in 0u64..1000, sizes in proptest::collection::vec(0u64..300, 0..20)) {
+            let quota = Quota::new(limit, 0);
+            let mut used = 0;
+            for size in sizes {
+                if quota.try_reserve(size) {
+                    used += size;
+                }
+                prop_assert!(used <= limit);
+                prop_assert_eq!(limit - used, quota.get_remaining());
+            }
+        }
+    }
+
+    #[test]
+    fn test_quota() {
+        let quota = Quota::new(100, 60);
+        assert!(!quota.try_reserve(41));
+        assert!(quota.try_reserve(40));
+        assert_eq!(0, quota.get_remaining());
+        quota.release(30);
+        assert_eq!(30, quota.get_remaining());
+    }
+
+    #[test]
+    fn test_directory_size() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "12345").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub").join("b"), "123").unwrap();
+        assert_eq!(8, get_directory_size(dir.path()).unwrap());
+        assert_eq!(0, get_directory_size(&dir.path().join("missing")).unwrap());
+    }
+
+    #[test]
+    fn test_list_received() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("Ada")).unwrap();
+        fs::create_dir_all(dir.path().join(".quarantine")).unwrap();
+        fs::write(dir.path().join("Ada").join("essay.pdf"), "12345").unwrap();
+        fs::write(dir.path().join("photo.jpg"), "1").unwrap();
+        fs::write(dir.path().join(".quarantine").join("unchecked"), "1").unwrap();
+        let quarantine = dir.path().join(".quarantine");
+        let mut files = list_received(dir.path(), dir.path(), Some(&quarantine)).unwrap();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let paths = files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["Ada/essay.pdf", "photo.jpg"], paths);
+        assert_eq!(5, files[0].size);
+        assert!(create_listing_page(&files).contains("<td>Ada/essay.pdf</td><td>5 bytes</td>"));
+    }
+
+    #[tokio::test]
+    async fn test_listing_requires_pin() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = Inbox::new(
+            dir.path().to_path_buf(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            notify::Notifier::default(),
+        )
+        .with_listing("1234")
+        .unwrap();
+        let request = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let denied = inbox.serve_listing(&request(LISTING_PATH)).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, denied.status());
+        let allowed = inbox.serve_listing(&request("/.received?pin=1234")).await;
+        assert_eq!(StatusCode::OK, allowed.status());
+    }
+
+    #[tokio::test]
+    async fn test_serves_upload_worker() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = Arc::new(Inbox::new(
+            dir.path().to_path_buf(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            notify::Notifier::default(),
+        ));
+        let page = inbox.create_upload_page();
+        assert!(page.contains(&format!("\".{}\"", WORKER_PATH)));
+        let req = Request::get(WORKER_PATH).body(Body::empty()).unwrap();
+        let response = handle_request(inbox, req).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            "text/javascript; charset=utf-8",
+            response.headers()[header::CONTENT_TYPE]
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("addEventListener(\"sync\""));
+    }
+
+    #[tokio::test]
+    async fn test_uploads_go_to_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory = Arc::new(storage::memory::MemoryStorage::default());
+        let inbox = Arc::new(
+            Inbox::new(
+                dir.path().to_path_buf(),
+                None,
+                None,
+                None,
+                None,
+                true,
+                notify::Notifier::default(),
+            )
+            .with_storage(memory.clone()),
+        );
+        let upload = |uri: &str| Request::put(uri).body(Body::from("data")).unwrap();
+        let response = handle_request(inbox.clone(), upload("/a.txt?name=Alice"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, response.status());
+        assert_eq!(Some(b"data".to_vec()), memory.get("Alice/a.txt"));
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+
+        let response = handle_request(inbox, upload("/b.txt")).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!(None, memory.get("b.txt"));
+    }
+
+    #[test]
+    fn test_temp_names() {
+        let name = create_temp_name();
+        assert!(is_temp_file(Path::new(&name)));
+        assert!(!is_temp_file(Path::new("photo.jpg.part")));
+        assert_ne!(name, create_temp_name());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_upload_leaves_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = Inbox::new(
+            dir.path().to_path_buf(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            notify::Notifier::default(),
+        );
+        let (mut sender, body) = Body::channel();
+        sender
+            .send_data(bytes::Bytes::from("partial"))
+            .await
+            .unwrap();
+        sender.abort();
+        assert!(receive_file(&inbox, dir.path(), "a.txt", body, None, 0)
+            .await
+            .is_err());
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+        let outcome = receive_file(&inbox, dir.path(), "a.txt", Body::from("complete"), None, 0)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Accepted(_)));
+        assert_eq!(
+            "complete",
+            fs::read_to_string(dir.path().join("a.txt")).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_uploads_of_one_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = Inbox::new(
+            dir.path().to_path_buf(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            notify::Notifier::default(),
+        );
+        for _ in 0..10 {
+            let (first, second) = futures::join!(
+                receive_file(&inbox, dir.path(), "a.txt", Body::from("first"), None, 0),
+                receive_file(&inbox, dir.path(), "a.txt", Body::from("second"), None, 0),
+            );
+            assert!(matches!(first.unwrap(), Outcome::Accepted(_)));
+            assert!(matches!(second.unwrap(), Outcome::Accepted(_)));
+        }
+        let mut contents = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| fs::read_to_string(e.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        contents.sort();
+        assert_eq!(20, contents.len());
+        assert!(contents[..10].iter().all(|c| c == "first"));
+        assert!(contents[10..].iter().all(|c| c == "second"));
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = vec![7u8; 3 * 1024 * 1024];
+        let source = dir.path().join("source.bin");
+        fs::write(&source, &content).unwrap();
+        let upload = UploadId {
+            size: content.len() as u64,
+            head_sha256: resume::hash_head(&source).unwrap(),
+        };
+        let destination = dir.path().join("inbox");
+        let inbox = Inbox::new(
+            destination.clone(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            notify::Notifier::default(),
+        );
+        let half = content.len() / 2;
+        let body = Body::wrap_stream(futures::stream::iter(vec![
+            Ok(content[..half].to_vec()),
+            Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+        ]));
+        let result = receive_file(&inbox, &destination, "a.bin", body, Some(upload.clone()), 0);
+        assert!(result.await.is_err());
+        let interrupted = inbox.get_interrupted_uploads();
+        assert_eq!(1, interrupted.len());
+        assert_eq!(half as u64, interrupted[0].offset);
+        let offset = get_resume_offset(&inbox, &destination, "a.bin", &upload)
+            .await
+            .unwrap();
+        assert_eq!(half as u64, offset);
+        let rest = Body::from(content[half..].to_vec());
+        let outcome = receive_file(&inbox, &destination, "a.bin", rest, Some(upload), offset)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Accepted(_)));
+        assert_eq!(content, fs::read(destination.join("a.bin")).unwrap());
+        assert_eq!(1, fs::read_dir(&destination).unwrap().count());
+        assert!(inbox.get_interrupted_uploads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_restore_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = vec![7u8; 3 * 1024 * 1024];
+        let source = dir.path().join("source.bin");
+        fs::write(&source, &content).unwrap();
+        let upload = UploadId {
+            size: content.len() as u64,
+            head_sha256: resume::hash_head(&source).unwrap(),
+        };
+        let destination = dir.path().join("inbox");
+        fs::create_dir(&destination).unwrap();
+        let half = content.len() / 2;
+        // What was written after the saved length didn't necessarily make it to disk.
+        let path = destination.join(create_resumable_temp_name("a.bin", &upload));
+        fs::write(&path, &content[..half + 100]).unwrap();
+        let uploads = [
+            PartialUpload {
+                path: path.clone(),
+                offset: half as u64,
+            },
+            PartialUpload {
+                path: destination.join(create_temp_name()),
+                offset: 10,
+            },
+            PartialUpload {
+                path: source.clone(),
+                offset: 10,
+            },
+        ];
+        let inbox = Inbox::new(
+            destination.clone(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            notify::Notifier::default(),
+        );
+        assert_eq!(1, inbox.restore_uploads(&uploads).unwrap());
+        assert_eq!(half as u64, fs::metadata(&path).unwrap().len());
+        assert_eq!(content.len() as u64, fs::metadata(&source).unwrap().len());
+        let offset = get_resume_offset(&inbox, &destination, "a.bin", &upload)
+            .await
+            .unwrap();
+        assert_eq!(half as u64, offset);
+        let rest = Body::from(content[half..].to_vec());
+        let outcome = receive_file(&inbox, &destination, "a.bin", rest, Some(upload), offset)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Accepted(_)));
+        assert_eq!(content, fs::read(destination.join("a.bin")).unwrap());
+        assert!(inbox.get_interrupted_uploads().is_empty());
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!("'it'\\''s'", shell_quote("it's"));
+    }
+}
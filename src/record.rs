@@ -0,0 +1,508 @@
+//! Recording sessions with `--record` and replaying them with `rustbelt replay`
+//!
+//! A user reporting a failed transfer rarely knows which requests their device sent. Started with
+//! `--record FILE`, the server appends a JSON line to FILE for every request once its response is
+//! over: when it arrived and from where, method, URI and headers, the status and headers of the
+//! response, how long the response took to start and to finish, how many bytes of its body were
+//! sent and whether the client received all of it. Credentials in Authorization and Cookie headers
+//! are replaced by `REDACTED`. Bodies aren't recorded, except request bodies up to
+//! `MAX_RECORDED_BODY` with `--record-bodies`.
+//!
+//! `rustbelt replay FILE URL` sends the recorded requests to another server, like a test instance
+//! started with the same options, at the same pace as they were recorded. Requests whose body
+//! wasn't recorded get as many zero bytes as their Content-Length announces, and responses the
+//! client broke off are broken off after as many bytes. Every answer is printed next to the
+//! recorded one.
+
+use crate::transfer;
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Client, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Bytes of a request body that are recorded with `--record-bodies`
+pub const MAX_RECORDED_BODY: usize = 1024 * 1024;
+/// What credentials are replaced with
+const REDACTED: &str = "REDACTED";
+const REDACTED_HEADERS: [header::HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+
+/// A request and its response, one line of the recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// Milliseconds since the server started
+    pub at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<IpAddr>,
+    pub method: String,
+    pub uri: String,
+    pub request_headers: Vec<(String, String)>,
+    /// The start of the request body, base64 encoded, with `--record-bodies`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<String>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    /// Milliseconds until the response started
+    pub response_time: u64,
+    /// Milliseconds until the response body was sent or broken off
+    pub duration: u64,
+    /// Bytes of the response body that were sent
+    pub sent: u64,
+    /// Whether the whole response body was sent
+    pub completed: bool,
+}
+
+fn save_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(name) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.as_str().to_string(), value)
+        })
+        .collect()
+}
+
+/// The file a session is recorded to
+pub struct Recorder {
+    file: Mutex<fs::File>,
+    started: Instant,
+    /// Record request bodies, with `--record-bodies`
+    bodies: bool,
+}
+
+impl Recorder {
+    /// Appends to the recording at `path`, creating it readable only by the owner.
+    pub fn create(path: &Path, bodies: bool) -> io::Result<Recorder> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+            started: Instant::now(),
+            bodies,
+        })
+    }
+
+    fn write(&self, exchange: &Exchange) {
+        let line = serde_json::to_string(exchange).unwrap() + "\n";
+        // A line is written at once, so those of concurrent requests never interleave.
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            eprintln!("Could not record a request: {}", e);
+        }
+    }
+
+    /// Starts recording `req`, whose body is kept if request bodies are recorded.
+    pub fn start(self: &Arc<Self>, req: Request<Body>) -> (Request<Body>, Recording) {
+        let received = Instant::now();
+        let exchange = Exchange {
+            at: received.duration_since(self.started).as_millis() as u64,
+            client: req
+                .extensions()
+                .get::<std::net::SocketAddr>()
+                .map(|a| a.ip()),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            request_headers: save_headers(req.headers()),
+            request_body: None,
+            status: 0,
+            response_headers: Vec::new(),
+            response_time: 0,
+            duration: 0,
+            sent: 0,
+            completed: false,
+        };
+        let mut recording = Recording {
+            recorder: self.clone(),
+            received,
+            exchange,
+            body: None,
+        };
+        if !self.bodies {
+            return (req, recording);
+        }
+        let body = Arc::new(Mutex::new(Vec::new()));
+        recording.body = Some(body.clone());
+        let (parts, inner) = req.into_parts();
+        let tee = inner.map(move |chunk| {
+            if let Ok(chunk) = &chunk {
+                let mut body = body.lock().unwrap();
+                let room = MAX_RECORDED_BODY - body.len();
+                body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            }
+            chunk
+        });
+        (
+            Request::from_parts(parts, Body::wrap_stream(tee)),
+            recording,
+        )
+    }
+}
+
+/// A request being answered, written to the recording once its response is over
+pub struct Recording {
+    recorder: Arc<Recorder>,
+    received: Instant,
+    exchange: Exchange,
+    body: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+impl Recording {
+    /// Records the response and follows its body to the end.
+    pub fn finish(mut self, response: Response<Body>) -> Response<Body> {
+        self.exchange.status = response.status().as_u16();
+        self.exchange.response_headers = save_headers(response.headers());
+        self.exchange.response_time = self.received.elapsed().as_millis() as u64;
+        // Answers to HEAD and those without a body have nothing to follow.
+        if self.exchange.method == "HEAD" || [204, 304].contains(&self.exchange.status) {
+            self.write(true);
+            return response;
+        }
+        let (mut parts, body) = response.into_parts();
+        // A wrapped body loses its length, which is announced in the header instead.
+        let length = match parts.headers.get(header::CONTENT_LENGTH) {
+            Some(length) => length.to_str().ok().and_then(|l| l.parse().ok()),
+            None => {
+                let length = HttpBody::size_hint(&body).exact();
+                if let Some(length) = length {
+                    parts
+                        .headers
+                        .insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+                }
+                length
+            }
+        };
+        let stream = RecordedBody {
+            inner: body,
+            length,
+            recording: Some(self),
+        };
+        Response::from_parts(parts, Body::wrap_stream(stream))
+    }
+
+    fn write(mut self, completed: bool) {
+        self.exchange.duration = self.received.elapsed().as_millis() as u64;
+        self.exchange.completed = completed;
+        if let Some(body) = &self.body {
+            self.exchange.request_body = Some(base64::encode(&*body.lock().unwrap()));
+        }
+        self.recorder.write(&self.exchange);
+    }
+}
+
+/// A response body that writes the recording when it ends or is dropped. hyper stops polling a
+/// body once its Content-Length is reached, so reaching it counts as the end.
+struct RecordedBody {
+    inner: Body,
+    length: Option<u64>,
+    recording: Option<Recording>,
+}
+
+impl RecordedBody {
+    fn end(&mut self, completed: bool) {
+        if let Some(recording) = self.recording.take() {
+            recording.write(completed);
+        }
+    }
+}
+
+impl Stream for RecordedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(recording) = &mut this.recording {
+                    recording.exchange.sent += chunk.len() as u64;
+                    if Some(recording.exchange.sent) == this.length {
+                        this.end(true);
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.end(false),
+            Poll::Ready(None) => this.end(true),
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+impl Drop for RecordedBody {
+    fn drop(&mut self) {
+        let completed = self.length == Some(0);
+        self.end(completed);
+    }
+}
+
+/// Reads the exchanges recorded in `path`.
+pub fn read_recording(path: &Path) -> Result<Vec<Exchange>, Box<dyn error::Error>> {
+    let file = io::BufReader::new(fs::File::open(path)?);
+    let mut exchanges = Vec::new();
+    for (number, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let exchange = serde_json::from_str(&line)
+            .map_err(|e| format!("Line {} of {}: {}", number + 1, path.display(), e))?;
+        exchanges.push(exchange);
+    }
+    Ok(exchanges)
+}
+
+/// The body a recorded request is sent again with: the recorded one if it is complete, or zero
+/// bytes as many as its Content-Length announces.
+fn create_body(exchange: &Exchange) -> Body {
+    let length = exchange
+        .request_headers
+        .iter()
+        .find(|(name, _)| name == header::CONTENT_LENGTH.as_str())
+        .and_then(|(_, value)| value.parse::<u64>().ok());
+    let recorded = exchange
+        .request_body
+        .as_deref()
+        .and_then(|b| base64::decode(b).ok());
+    match (recorded, length) {
+        (Some(body), Some(length)) if body.len() as u64 == length => Body::from(body),
+        (Some(body), None) if !body.is_empty() => Body::from(body),
+        (_, Some(length)) => {
+            let chunk = Bytes::from(vec![0; 64 * 1024]);
+            let chunks = stream::iter((0..length).step_by(chunk.len()).map(move |offset| {
+                let size = (length - offset).min(chunk.len() as u64) as usize;
+                Ok::<_, io::Error>(chunk.slice(..size))
+            }));
+            Body::wrap_stream(chunks)
+        }
+        _ => Body::empty(),
+    }
+}
+
+fn create_request(
+    base: &str,
+    exchange: &Exchange,
+) -> Result<Request<Body>, Box<dyn error::Error + Send + Sync>> {
+    let mut request = Request::builder()
+        .method(exchange.method.as_str())
+        .uri(format!("{}{}", base, exchange.uri));
+    for (name, value) in &exchange.request_headers {
+        if name == header::HOST.as_str() || value == REDACTED {
+            continue;
+        }
+        request = request.header(name.as_str(), value.as_str());
+    }
+    Ok(request.body(create_body(exchange))?)
+}
+
+/// Sends a recorded request and reads the response, breaking it off where the recorded client
+/// did. Returns the status, the bytes read and how long that took.
+async fn replay_exchange(
+    client: &Client<hyper::client::HttpConnector>,
+    base: &str,
+    exchange: &Exchange,
+) -> Result<(u16, u64, Duration), Box<dyn error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let mut response = client.request(create_request(base, exchange)?).await?;
+    let status = response.status().as_u16();
+    let mut received = 0;
+    while let Some(chunk) = response.body_mut().data().await {
+        received += chunk?.len() as u64;
+        if !exchange.completed && received >= exchange.sent {
+            break;
+        }
+    }
+    Ok((status, received, started.elapsed()))
+}
+
+/// Sends the requests recorded in `path` to the server at `url`, at their recorded pace unless
+/// `fast`.
+#[tokio::main]
+pub async fn run_replay(path: &Path, url: &str, fast: bool) -> Result<(), Box<dyn error::Error>> {
+    let exchanges = read_recording(path)?;
+    let base = Arc::new(url.trim_end_matches('/').to_string());
+    let client = Client::new();
+    let started = Instant::now();
+    // Requests are started in order, but may overlap like they did when recorded.
+    let handles = exchanges
+        .into_iter()
+        .map(|exchange| {
+            let client = client.clone();
+            let base = base.clone();
+            tokio::spawn(async move {
+                if !fast {
+                    let at = Duration::from_millis(exchange.at);
+                    tokio::time::delay_for(at.checked_sub(started.elapsed()).unwrap_or_default())
+                        .await;
+                }
+                let result = replay_exchange(&client, &base, &exchange)
+                    .await
+                    .map_err(|e| e.to_string());
+                let recorded = format!(
+                    "recorded {}, {}{}",
+                    exchange.status,
+                    transfer::format_size(exchange.sent),
+                    if exchange.completed {
+                        ""
+                    } else {
+                        " before breaking off"
+                    }
+                );
+                let matches = match &result {
+                    Ok((status, _, elapsed)) => {
+                        println!(
+                            "{} {}: {} in {} ms, {}",
+                            exchange.method,
+                            exchange.uri,
+                            status,
+                            elapsed.as_millis(),
+                            recorded
+                        );
+                        *status == exchange.status
+                    }
+                    Err(e) => {
+                        println!(
+                            "{} {}: failed: {}, {}",
+                            exchange.method, exchange.uri, e, recorded
+                        );
+                        false
+                    }
+                };
+                matches
+            })
+        })
+        .collect::<Vec<_>>();
+    let total = handles.len();
+    let mut same = 0;
+    for handle in handles {
+        if handle.await? {
+            same += 1;
+        }
+    }
+    println!(
+        "{} of {} requests were answered with the recorded status",
+        same, total
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn create_exchange(headers: &[(&str, &str)], body: Option<&[u8]>) -> Exchange {
+        Exchange {
+            at: 0,
+            client: None,
+            method: String::from("PUT"),
+            uri: String::from("/a.txt"),
+            request_headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            request_body: body.map(base64::encode),
+            status: 201,
+            response_headers: Vec::new(),
+            response_time: 1,
+            duration: 2,
+            sent: 8,
+            completed: true,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_filler_body_has_recorded_length(length in 0u64..300_000) {
+            let exchange = create_exchange(&[("content-length", &length.to_string())], None);
+            let mut runtime = tokio::runtime::Runtime::new().unwrap();
+            let body = runtime.block_on(hyper::body::to_bytes(create_body(&exchange))).unwrap();
+            prop_assert_eq!(length, body.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_credentials_are_redacted() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Basic YW55Ojk=".parse().unwrap());
+        headers.insert(header::RANGE, "bytes=10-".parse().unwrap());
+        assert_eq!(
+            vec![
+                (String::from("authorization"), String::from(REDACTED)),
+                (String::from("range"), String::from("bytes=10-")),
+            ],
+            save_headers(&headers)
+        );
+        let exchange = create_exchange(
+            &[
+                ("authorization", REDACTED),
+                ("host", "a"),
+                ("range", "bytes=10-"),
+            ],
+            None,
+        );
+        let request = create_request("http://10.0.0.1:8080", &exchange).unwrap();
+        assert_eq!("http://10.0.0.1:8080/a.txt", request.uri().to_string());
+        assert_eq!(1, request.headers().len());
+    }
+
+    #[tokio::test]
+    async fn test_record_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.jsonl");
+        let recorder = Arc::new(Recorder::create(&path, true).unwrap());
+
+        let request = Request::put("/a.txt")
+            .header(header::CONTENT_LENGTH, "5")
+            .body(Body::from("hello"))
+            .unwrap();
+        let (request, recording) = recorder.start(request);
+        let uploaded = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        assert_eq!(&b"hello"[..], &uploaded[..]);
+        let response = recording.finish(Response::new(Body::from("Received")));
+        assert_eq!("8", response.headers()[header::CONTENT_LENGTH]);
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let (_, recording) = recorder.start(Request::get("/big").body(Body::empty()).unwrap());
+        let response = recording.finish(Response::new(Body::from("abcdef")));
+        let mut body = response.into_body();
+        body.data().await.unwrap().unwrap();
+        drop(body);
+
+        let exchanges = read_recording(&path).unwrap();
+        assert_eq!(2, exchanges.len());
+        let expected = create_exchange(&[("content-length", "5")], Some(b"hello"));
+        assert_eq!(
+            (expected.method, expected.uri, expected.request_body),
+            (
+                exchanges[0].method.clone(),
+                exchanges[0].uri.clone(),
+                exchanges[0].request_body.clone()
+            )
+        );
+        assert!(exchanges[0].completed);
+        assert_eq!(200, exchanges[0].status);
+        assert_eq!((6, true), (exchanges[1].sent, exchanges[1].completed));
+    }
+}
@@ -0,0 +1,612 @@
+//! Transfers through a relay for machines that can't reach each other directly
+//!
+//! `rustbelt relay` runs on a machine both sides can reach. The sender PUTs and the receiver GETs
+//! the same channel, and the relay pipes one request body into the other response. The channel
+//! name and the encryption key are both derived from a random share code that never reaches the
+//! relay, so it only sees the channel name and ChaCha20-Poly1305 encrypted frames. Before that,
+//! both sides meet at `/rendezvous/<channel>/<role>` and try to connect directly, see `punch`.
+//!
+//! Every frame is a flag byte, the big endian length of the ciphertext and the ciphertext. The
+//! first frame holds the file name, the rest the content, and the final frame is marked by the
+//! flag. The frame counter and the flag are part of the nonce, so frames can't be reordered,
+//! dropped or cut off unnoticed.
+
+use crate::get::get_safe_file_name;
+use crate::punch::{connect_directly, parse_rendezvous_path, Role};
+use crate::sync::PeerResponseError;
+use crate::{create_status_response, help};
+use bytes::Bytes;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use futures::stream::{Stream, StreamExt};
+use hyper::{header, Body, Client, Method, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::oneshot;
+
+/// Answer to requests for anything but a channel
+const NOT_A_CHANNEL: &str = "This is a rustbelt relay, use rustbelt relay send or get";
+/// Size of the plaintext in a content frame
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Size of the ChaCha20-Poly1305 authentication tag
+const TAG_SIZE: usize = 16;
+const FRAME_HEADER_SIZE: usize = 5;
+const FLAG_FINAL: u8 = 1;
+
+#[derive(Debug)]
+struct RelayError {
+    message: &'static str,
+}
+
+impl error::Error for RelayError {}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl RelayError {
+    fn new(message: &'static str) -> RelayError {
+        RelayError { message }
+    }
+}
+
+fn derive(purpose: &str, code: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(purpose.as_bytes());
+    hasher.update([0]);
+    hasher.update(code.as_bytes());
+    hasher.finalize().into()
+}
+
+/// The name of the relay channel for a share code
+fn derive_channel(code: &str) -> String {
+    derive("rustbelt relay channel", code)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn create_cipher(code: &str) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(&Key::from(derive("rustbelt relay key", code)))
+}
+
+fn create_nonce(counter: u64, flag: u8) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = flag;
+    Nonce::from(nonce)
+}
+
+/// Encrypts the frames of a transfer.
+struct Sealer {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Sealer {
+    fn new(code: &str) -> Sealer {
+        Sealer {
+            cipher: create_cipher(code),
+            counter: 0,
+        }
+    }
+
+    fn seal(&mut self, plaintext: &[u8], is_final: bool) -> Vec<u8> {
+        let flag = if is_final { FLAG_FINAL } else { 0 };
+        let ciphertext = self
+            .cipher
+            .encrypt(&create_nonce(self.counter, flag), plaintext)
+            .expect("encryption can't fail for frames of this size");
+        self.counter += 1;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + ciphertext.len());
+        frame.push(flag);
+        frame.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&ciphertext);
+        frame
+    }
+}
+
+/// Decrypts the frames of a transfer as they arrive in arbitrary pieces.
+struct Opener {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl Opener {
+    fn new(code: &str) -> Opener {
+        Opener {
+            cipher: create_cipher(code),
+            counter: 0,
+            buffer: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Adds received data and returns the plaintext of all frames completed by it.
+    fn push(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>, RelayError> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        while self.buffer.len() >= FRAME_HEADER_SIZE {
+            if self.finished {
+                return Err(RelayError::new("Data after the final frame"));
+            }
+            let flag = self.buffer[0];
+            let mut length = [0u8; 4];
+            length.copy_from_slice(&self.buffer[1..FRAME_HEADER_SIZE]);
+            let length = u32::from_be_bytes(length) as usize;
+            if length > CHUNK_SIZE + TAG_SIZE || flag > FLAG_FINAL {
+                return Err(RelayError::new("Invalid frame"));
+            }
+            if self.buffer.len() < FRAME_HEADER_SIZE + length {
+                break;
+            }
+            let frame = self
+                .buffer
+                .drain(..FRAME_HEADER_SIZE + length)
+                .collect::<Vec<_>>();
+            let plaintext = self
+                .cipher
+                .decrypt(
+                    &create_nonce(self.counter, flag),
+                    &frame[FRAME_HEADER_SIZE..],
+                )
+                .map_err(|_| {
+                    RelayError::new("The transfer could not be decrypted, is the code correct?")
+                })?;
+            self.counter += 1;
+            self.finished = flag == FLAG_FINAL;
+            frames.push(plaintext);
+        }
+        Ok(frames)
+    }
+}
+
+/// A side of a channel waiting for its counterpart
+enum Waiting {
+    Sender(Body, oneshot::Sender<()>),
+    Receiver(oneshot::Sender<(Body, oneshot::Sender<()>)>),
+}
+
+/// A side waiting at the rendezvous for its counterpart's address
+struct Arrival {
+    role: Role,
+    address: SocketAddr,
+    peer: oneshot::Sender<SocketAddr>,
+}
+
+/// The channels of a relay
+#[derive(Default)]
+pub struct Relay {
+    waiting: Mutex<HashMap<String, Waiting>>,
+    arrivals: Mutex<HashMap<String, Arrival>>,
+}
+
+/// A body piped from the sender to the receiver, telling the sender's request once it is done
+struct RelayedBody {
+    body: Body,
+    done: Option<oneshot::Sender<()>>,
+}
+
+impl Stream for RelayedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = Pin::new(&mut this.body).poll_next(cx);
+        if let Poll::Ready(None) = item {
+            if let Some(done) = this.done.take() {
+                let _ = done.send(());
+            }
+        }
+        item
+    }
+}
+
+fn create_relayed_response(body: Body, done: oneshot::Sender<()>) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body(Body::wrap_stream(RelayedBody {
+            body,
+            done: Some(done),
+        }))
+        .unwrap()
+}
+
+/// Tells both sides of a channel the public address of the other one, for connecting directly.
+async fn handle_rendezvous(
+    relay: &Relay,
+    channel: &str,
+    role: Role,
+    address: SocketAddr,
+) -> Response<Body> {
+    let peer_rx = {
+        let mut arrivals = relay.arrivals.lock().unwrap();
+        match arrivals.remove(channel) {
+            Some(arrival) if arrival.role != role => {
+                // The other side may have given up in the meantime.
+                let peer = arrival.address;
+                let delivered = arrival.peer.send(address).is_ok();
+                if delivered {
+                    return create_status_response(StatusCode::OK, &peer.to_string());
+                }
+            }
+            Some(arrival) => {
+                arrivals.insert(channel.to_string(), arrival);
+                return create_status_response(
+                    StatusCode::CONFLICT,
+                    "Someone is already waiting on this channel",
+                );
+            }
+            None => {}
+        }
+        let (tx, rx) = oneshot::channel();
+        let arrival = Arrival {
+            role,
+            address,
+            peer: tx,
+        };
+        arrivals.insert(channel.to_string(), arrival);
+        rx
+    };
+    match peer_rx.await {
+        Ok(peer) => create_status_response(StatusCode::OK, &peer.to_string()),
+        Err(_) => create_status_response(StatusCode::GONE, "The channel was closed"),
+    }
+}
+
+/// What a request of the relay is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Send,
+    Receive,
+    Rendezvous,
+}
+
+/// The requests the relay answers
+pub fn get_routes() -> Vec<help::Route<Route>> {
+    use help::Route as R;
+    vec![
+        R::new(
+            Method::POST,
+            "/rendezvous/<channel>/<role>",
+            "Meets the other side to try a direct connection",
+            Route::Rendezvous,
+        ),
+        R::new(
+            Method::PUT,
+            "/<channel>",
+            "Sends the body to the receiver",
+            Route::Send,
+        ),
+        R::new(
+            Method::GET,
+            "/<channel>",
+            "Receives the body of the sender",
+            Route::Receive,
+        ),
+    ]
+}
+
+pub async fn handle_request(
+    relay: Arc<Relay>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let route = match help::find_route(&get_routes(), &req) {
+        Ok(route) => route,
+        Err(response) if response.status() == StatusCode::NOT_FOUND => {
+            return Ok(create_status_response(StatusCode::NOT_FOUND, NOT_A_CHANNEL))
+        }
+        Err(response) => return Ok(response),
+    };
+    if route == Route::Rendezvous {
+        let address = req.extensions().get::<SocketAddr>().copied();
+        return Ok(match (parse_rendezvous_path(req.uri().path()), address) {
+            (Some((channel, role)), Some(address)) => {
+                handle_rendezvous(&relay, channel, role, address).await
+            }
+            _ => create_status_response(StatusCode::NOT_FOUND, NOT_A_CHANNEL),
+        });
+    }
+    let channel = req.uri().path().trim_start_matches('/').to_string();
+    if channel.contains('/') {
+        return Ok(create_status_response(StatusCode::NOT_FOUND, NOT_A_CHANNEL));
+    }
+    match route {
+        Route::Send => {
+            let (done_tx, done_rx) = oneshot::channel();
+            let mut pending = Some((req.into_body(), done_tx));
+            {
+                let mut waiting = relay.waiting.lock().unwrap();
+                if let Some(Waiting::Receiver(receiver)) = waiting.remove(&channel) {
+                    // The receiver may have given up in the meantime.
+                    pending = receiver.send(pending.take().unwrap()).err();
+                }
+                if let Some((body, done_tx)) = pending {
+                    if waiting.contains_key(&channel) {
+                        return Ok(create_status_response(
+                            StatusCode::CONFLICT,
+                            "Someone is already sending on this channel",
+                        ));
+                    }
+                    waiting.insert(channel, Waiting::Sender(body, done_tx));
+                }
+            }
+            Ok(match done_rx.await {
+                Ok(()) => create_status_response(StatusCode::OK, "Delivered"),
+                Err(_) => {
+                    create_status_response(StatusCode::BAD_GATEWAY, "The receiver disconnected")
+                }
+            })
+        }
+        _ => {
+            let receiver_rx = {
+                let mut waiting = relay.waiting.lock().unwrap();
+                match waiting.remove(&channel) {
+                    Some(Waiting::Sender(body, done)) => {
+                        return Ok(create_relayed_response(body, done))
+                    }
+                    Some(receiver) => {
+                        waiting.insert(channel, receiver);
+                        return Ok(create_status_response(
+                            StatusCode::CONFLICT,
+                            "Someone is already receiving on this channel",
+                        ));
+                    }
+                    None => {
+                        let (tx, rx) = oneshot::channel();
+                        waiting.insert(channel, Waiting::Receiver(tx));
+                        rx
+                    }
+                }
+            };
+            Ok(match receiver_rx.await {
+                Ok((body, done)) => create_relayed_response(body, done),
+                Err(_) => create_status_response(StatusCode::GONE, "The channel was closed"),
+            })
+        }
+    }
+}
+
+fn get_channel_url(relay_url: &str, code: &str) -> String {
+    format!(
+        "{}/{}",
+        relay_url.trim_end_matches('/'),
+        derive_channel(code)
+    )
+}
+
+/// Encrypts the file into frames while it is read.
+fn create_frame_stream(
+    file: tokio::fs::File,
+    file_name: String,
+    code: &str,
+) -> impl Stream<Item = io::Result<Vec<u8>>> {
+    let mut sealer = Sealer::new(code);
+    let first = sealer.seal(file_name.as_bytes(), false);
+    let state = Some((file, sealer, Some(first)));
+    futures::stream::unfold(state, |state| async move {
+        let (mut file, mut sealer, first) = state?;
+        if let Some(first) = first {
+            return Some((Ok(first), Some((file, sealer, None))));
+        }
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let mut filled = 0;
+        while filled < CHUNK_SIZE {
+            match file.read(&mut chunk[filled..]).await {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) => return Some((Err(e), None)),
+            }
+        }
+        let is_final = filled < CHUNK_SIZE;
+        let frame = sealer.seal(&chunk[..filled], is_final);
+        let next = if is_final {
+            None
+        } else {
+            Some((file, sealer, None))
+        };
+        Some((Ok(frame), next))
+    })
+}
+
+/// Opens a direct connection to the other side, waiting until it arrives at the relay.
+async fn open_direct_stream(
+    relay_url: &str,
+    code: &str,
+    role: Role,
+) -> Result<Option<tokio::net::TcpStream>, Box<dyn error::Error>> {
+    let relay_url = relay_url.to_string();
+    let channel = derive_channel(code);
+    let stream =
+        tokio::task::spawn_blocking(move || connect_directly(&relay_url, &channel, role)).await?;
+    match stream {
+        Some(stream) => Ok(Some(tokio::net::TcpStream::from_std(stream)?)),
+        None => {
+            eprintln!("Could not connect directly, going through the relay");
+            Ok(None)
+        }
+    }
+}
+
+/// Sends `path` to the receiver, directly if possible and through the relay at `relay_url`
+/// otherwise.
+#[tokio::main]
+pub async fn run_relay_send(relay_url: &str, path: &Path) -> Result<(), Box<dyn error::Error>> {
+    let code = crate::tokens::generate_token();
+    let file = tokio::fs::File::open(path).await?;
+    let file_name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => String::from("download"),
+    };
+    println!("Share code: {}", code);
+    println!(
+        "On the receiving machine run: rustbelt relay get {} {}",
+        relay_url, code
+    );
+    eprintln!("Waiting for the receiver…");
+
+    let frames = create_frame_stream(file, file_name, &code);
+    if let Some(mut stream) = open_direct_stream(relay_url, &code, Role::Send).await? {
+        futures::pin_mut!(frames);
+        while let Some(frame) = frames.next().await {
+            stream.write_all(&frame?).await?;
+        }
+        stream.shutdown(std::net::Shutdown::Write)?;
+        // The receiver closes the connection once it has everything.
+        stream.read_to_end(&mut Vec::new()).await?;
+        eprintln!("Delivered {} directly", path.display());
+        return Ok(());
+    }
+
+    let url = get_channel_url(relay_url, &code);
+    let body = Body::wrap_stream(frames);
+    let request = Request::put(url.as_str()).body(body)?;
+    let response = Client::new().request(request).await?;
+    if !response.status().is_success() {
+        return Err(PeerResponseError::new(url, response.status()).into());
+    }
+    eprintln!("Delivered {}", path.display());
+    Ok(())
+}
+
+/// Writes the decrypted frames of a transfer to a file named by the first frame.
+struct Receiving {
+    opener: Opener,
+    file: Option<tokio::fs::File>,
+    path: Option<PathBuf>,
+}
+
+impl Receiving {
+    fn new(code: &str, output: Option<PathBuf>) -> Receiving {
+        Receiving {
+            opener: Opener::new(code),
+            file: None,
+            path: output,
+        }
+    }
+
+    async fn push(&mut self, chunk: &[u8]) -> Result<(), Box<dyn error::Error>> {
+        for plaintext in self.opener.push(chunk)? {
+            match &mut self.file {
+                Some(file) => file.write_all(&plaintext).await?,
+                None => {
+                    let output = self.path.get_or_insert_with(|| {
+                        get_safe_file_name(&String::from_utf8_lossy(&plaintext))
+                    });
+                    self.file = Some(tokio::fs::File::create(&output).await?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<PathBuf, Box<dyn error::Error>> {
+        match (self.file, self.path, self.opener.finished) {
+            (Some(mut file), Some(path), true) => {
+                file.flush().await?;
+                Ok(path)
+            }
+            _ => Err(RelayError::new("The transfer was cut off before it was complete").into()),
+        }
+    }
+}
+
+/// Receives the transfer with share code `code`, directly if possible and through the relay at
+/// `relay_url` otherwise.
+#[tokio::main]
+pub async fn run_relay_get(
+    relay_url: &str,
+    code: &str,
+    output: Option<PathBuf>,
+) -> Result<(), Box<dyn error::Error>> {
+    eprintln!("Waiting for the sender…");
+    let mut receiving = Receiving::new(code, output);
+    if let Some(mut stream) = open_direct_stream(relay_url, code, Role::Get).await? {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            match stream.read(&mut chunk).await? {
+                0 => break,
+                n => receiving.push(&chunk[..n]).await?,
+            }
+        }
+        let path = receiving.finish().await?;
+        eprintln!("Received {} directly", path.display());
+        return Ok(());
+    }
+
+    let url = get_channel_url(relay_url, code);
+    let mut response = Client::new().get(url.parse()?).await?;
+    if !response.status().is_success() {
+        return Err(PeerResponseError::new(url, response.status()).into());
+    }
+    while let Some(chunk) = response.body_mut().next().await {
+        receiving.push(&chunk?).await?;
+    }
+    let path = receiving.finish().await?;
+    eprintln!("Received {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_frames_roundtrip(frames in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..200), 1..5), split in 1usize..50) {
+            let mut sealer = Sealer::new("code");
+            let mut data = Vec::new();
+            for (index, frame) in frames.iter().enumerate() {
+                data.extend(sealer.seal(frame, index + 1 == frames.len()));
+            }
+            let mut opener = Opener::new("code");
+            let mut opened = Vec::new();
+            for piece in data.chunks(split) {
+                opened.extend(opener.push(piece).unwrap());
+            }
+            prop_assert_eq!(frames, opened);
+            prop_assert!(opener.finished);
+        }
+    }
+
+    #[test]
+    fn test_wrong_code() {
+        let frame = Sealer::new("right").seal(b"name", false);
+        assert!(Opener::new("wrong").push(&frame).is_err());
+    }
+
+    #[test]
+    fn test_reordered_frames() {
+        let mut sealer = Sealer::new("code");
+        let first = sealer.seal(b"a", false);
+        let second = sealer.seal(b"b", true);
+        let mut opener = Opener::new("code");
+        assert!(opener.push(&second).is_err());
+        let mut opener = Opener::new("code");
+        assert_eq!(vec![b"a".to_vec()], opener.push(&first).unwrap());
+        assert!(!opener.finished);
+    }
+
+    #[test]
+    fn test_channel_hides_code() {
+        let channel = derive_channel("secret");
+        assert_eq!(64, channel.len());
+        assert!(!channel.contains("secret"));
+        assert_ne!(channel, derive_channel("secret2"));
+    }
+}
@@ -0,0 +1,248 @@
+//! Resuming interrupted uploads
+//!
+//! `rustbelt send URL FILE` uploads to an instance running with `--receive`. An upload is
+//! identified by the file name, its size and the SHA-256 of its first MiB, announced in the
+//! `X-Upload-Size` and `X-Upload-Head-Sha256` headers. The receiver keeps the temporary file of
+//! such an upload if the connection drops and names it after this identity. Before every attempt
+//! the client asks with a HEAD request how much of the upload the receiver already has, and then
+//! PUTs only the rest, starting at the offset given in `X-Upload-Offset`.
+
+use crate::paths;
+use crate::sync::PeerResponseError;
+use hyper::header::HeaderMap;
+use hyper::{header, Body, Client, Request, StatusCode};
+use sha2::{Digest, Sha256};
+use std::error;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+pub const SIZE_HEADER: &str = "x-upload-size";
+pub const HEAD_HASH_HEADER: &str = "x-upload-head-sha256";
+pub const OFFSET_HEADER: &str = "x-upload-offset";
+/// Length of the start of a file that is hashed to recognise it
+const HEAD_LENGTH: u64 = 1024 * 1024;
+const MAX_ATTEMPTS: usize = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(3);
+
+/// What identifies an upload besides its file name
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadId {
+    pub size: u64,
+    pub head_sha256: String,
+}
+
+impl UploadId {
+    /// Reads the identity of an upload from the headers of a resumable request.
+    pub fn from_headers(headers: &HeaderMap) -> Option<UploadId> {
+        let size = headers.get(SIZE_HEADER)?.to_str().ok()?.parse().ok()?;
+        let head_sha256 = headers.get(HEAD_HASH_HEADER)?.to_str().ok()?;
+        if head_sha256.len() != 64 || !head_sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(UploadId {
+            size,
+            head_sha256: head_sha256.to_ascii_lowercase(),
+        })
+    }
+
+    /// A key for the temporary file of the upload of `file_name`, the same for every attempt.
+    pub fn get_key(&self, file_name: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(file_name.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.size.to_be_bytes());
+        hasher.update(self.head_sha256.as_bytes());
+        format!("{:x}", hasher.finalize())[..32].to_string()
+    }
+}
+
+/// Returns the offset an upload continues at, 0 for requests that don't resume.
+pub fn get_offset(headers: &HeaderMap) -> Option<u64> {
+    match headers.get(OFFSET_HEADER) {
+        Some(offset) => offset.to_str().ok()?.parse().ok(),
+        None => Some(0),
+    }
+}
+
+/// Hashes the first `HEAD_LENGTH` bytes of a file.
+pub fn hash_head(path: &Path) -> io::Result<String> {
+    let mut head = Vec::new();
+    fs::File::open(path)?
+        .take(HEAD_LENGTH)
+        .read_to_end(&mut head)?;
+    Ok(format!("{:x}", Sha256::digest(&head)))
+}
+
+/// Returns how much of the upload the temporary file at `path` holds, or 0 if there is none or
+/// its start doesn't match the upload.
+pub fn get_resume_offset(path: &Path, id: &UploadId) -> io::Result<u64> {
+    let length = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    if length > id.size || length < id.size.min(HEAD_LENGTH) || hash_head(path)? != id.head_sha256 {
+        return Ok(0);
+    }
+    Ok(length)
+}
+
+/// Asks the receiver where to continue the upload.
+async fn query_offset(
+    client: &Client<hyper::client::HttpConnector>,
+    url: &str,
+    id: &UploadId,
+) -> Result<u64, Box<dyn error::Error>> {
+    let request = Request::head(url)
+        .header(SIZE_HEADER, id.size)
+        .header(HEAD_HASH_HEADER, id.head_sha256.as_str())
+        .body(Body::empty())?;
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        return Err(PeerResponseError::new(url.to_string(), response.status()).into());
+    }
+    // Receivers that can't resume don't answer with an offset.
+    Ok(get_offset(response.headers()).unwrap_or(0).min(id.size))
+}
+
+async fn send_from(
+    client: &Client<hyper::client::HttpConnector>,
+    url: &str,
+    path: &Path,
+    id: &UploadId,
+    offset: u64,
+) -> Result<StatusCode, Box<dyn error::Error>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    let request = Request::put(url)
+        .header(header::CONTENT_LENGTH, id.size - offset)
+        .header(SIZE_HEADER, id.size)
+        .header(HEAD_HASH_HEADER, id.head_sha256.as_str())
+        .header(OFFSET_HEADER, offset)
+        .body(Body::wrap_stream(crate::transfer::FileStream::new(file)))?;
+    Ok(client.request(request).await?.status())
+}
+
+/// Uploads `path` to the receiving rustbelt instance at `url`, continuing where an interrupted
+/// attempt stopped.
+#[tokio::main]
+pub async fn run_send(
+    url: &str,
+    path: &Path,
+    name: Option<&str>,
+) -> Result<(), Box<dyn error::Error>> {
+    let file_name = match path.file_name() {
+        Some(n) => n.to_string_lossy().into_owned(),
+        None => String::from("upload"),
+    };
+    let mut url = format!(
+        "{}/{}",
+        url.trim_end_matches('/'),
+        paths::percent_encode(&file_name)
+    );
+    if let Some(name) = name {
+        url = format!("{}?name={}", url, paths::percent_encode(name));
+    }
+    let head_path = path.to_path_buf();
+    let id = UploadId {
+        size: tokio::fs::metadata(path).await?.len(),
+        head_sha256: tokio::task::spawn_blocking(move || hash_head(&head_path)).await??,
+    };
+    let client = Client::new();
+
+    let mut attempt = 1;
+    loop {
+        let result = match query_offset(&client, &url, &id).await {
+            Ok(offset) => {
+                if offset > 0 {
+                    eprintln!(
+                        "Resuming {} at {} of {}",
+                        file_name,
+                        crate::transfer::format_size(offset),
+                        crate::transfer::format_size(id.size)
+                    );
+                }
+                send_from(&client, &url, path, &id, offset).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(status) if status.is_success() => {
+                eprintln!("Sent {}", path.display());
+                return Ok(());
+            }
+            // The receiver has less than it offered, which the next offset accounts for.
+            Ok(StatusCode::CONFLICT) if attempt < MAX_ATTEMPTS => {}
+            Ok(status) if status.is_client_error() || attempt == MAX_ATTEMPTS => {
+                return Err(PeerResponseError::new(url, status).into())
+            }
+            Ok(status) => eprintln!("The receiver answered {} (attempt {})", status, attempt),
+            Err(e) if attempt == MAX_ATTEMPTS => return Err(e),
+            Err(e) => eprintln!("Upload failed: {} (attempt {})", e, attempt),
+        }
+        attempt += 1;
+        tokio::time::delay_for(RETRY_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::io::Write;
+
+    fn create_headers(size: &str, hash: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(SIZE_HEADER, size.parse().unwrap());
+        headers.insert(HEAD_HASH_HEADER, hash.parse().unwrap());
+        headers
+    }
+
+    proptest! {
+        #[test]
+        fn test_keys_differ(a in "[a-z]{1,8}", b in "[a-z]{1,8}", size in 0u64..1000) {
+            let id = UploadId { size, head_sha256: "0".repeat(64) };
+            prop_assert_eq!(a == b, id.get_key(&a) == id.get_key(&b));
+            let other = UploadId { size: size + 1, ..id.clone() };
+            prop_assert_ne!(id.get_key(&a), other.get_key(&a));
+        }
+    }
+
+    #[test]
+    fn test_upload_id_from_headers() {
+        let hash = "AB".repeat(32);
+        let id = UploadId::from_headers(&create_headers("42", &hash)).unwrap();
+        assert_eq!(42, id.size);
+        assert_eq!("ab".repeat(32), id.head_sha256);
+        assert!(UploadId::from_headers(&create_headers("42", "abc")).is_none());
+        assert!(UploadId::from_headers(&create_headers("many", &hash)).is_none());
+        assert!(UploadId::from_headers(&HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn test_resume_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, b"the whole content").unwrap();
+        let id = UploadId {
+            size: 17,
+            head_sha256: hash_head(&source).unwrap(),
+        };
+        let temp = dir.path().join("temp");
+        assert_eq!(0, get_resume_offset(&temp, &id).unwrap());
+        // Small files are only recognised once complete, their head is the whole file.
+        fs::write(&temp, b"the whole").unwrap();
+        assert_eq!(0, get_resume_offset(&temp, &id).unwrap());
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&temp)
+            .unwrap()
+            .write_all(b" content")
+            .unwrap();
+        assert_eq!(17, get_resume_offset(&temp, &id).unwrap());
+        fs::write(&temp, b"the other content").unwrap();
+        assert_eq!(0, get_resume_offset(&temp, &id).unwrap());
+    }
+}
@@ -0,0 +1,74 @@
+//! Keeping shares reachable from the internet out of search indexes
+//!
+//! Shares served under a domain or on a public address get a deny-all `robots.txt` and an
+//! `X-Robots-Tag` header on every response, unless `--allow-indexing` is given.
+
+use hyper::header::HeaderValue;
+use hyper::{header, Body, Response};
+use std::net::IpAddr;
+
+pub const ROBOTS_PATH: &str = "/robots.txt";
+const ROBOTS_TXT: &str = "User-agent: *\nDisallow: /\n";
+const ROBOTS_TAG: &str = "noindex, nofollow, noarchive";
+
+/// Whether `ip` is reachable from the internet, in contrast to loopback, private and link-local
+/// addresses.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local, fc00::/7
+                || first & 0xfe00 == 0xfc00
+                // Link-local, fe80::/10
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+pub fn create_robots_response() -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(ROBOTS_TXT))
+        .unwrap()
+}
+
+pub fn add_robots_tag(mut response: Response<Body>) -> Response<Body> {
+    response
+        .headers_mut()
+        .insert("x-robots-tag", HeaderValue::from_static(ROBOTS_TAG));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_private_ranges_are_not_public(b: u8, c: u8, d: u8) {
+            prop_assert!(!is_public(IpAddr::from([10, b, c, d])));
+            prop_assert!(!is_public(IpAddr::from([192, 168, c, d])));
+            prop_assert!(!is_public(IpAddr::from([127, b, c, d])));
+        }
+    }
+
+    #[test]
+    fn test_is_public() {
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(!is_public("100.100.1.1".parse().unwrap()));
+        assert!(is_public("2001:db8::1".parse().unwrap()));
+        assert!(!is_public("fd12:3456::1".parse().unwrap()));
+        assert!(!is_public("fe80::1".parse().unwrap()));
+    }
+}
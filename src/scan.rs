@@ -0,0 +1,350 @@
+//! Scanning received files for malware
+//!
+//! With `--scan`, every upload is sent to a local clamd, through its unix socket or TCP port, or
+//! to an ICAP server while it is in quarantine. Infected files are deleted. If the scanner can't
+//! be reached, the file stays in quarantine, so nothing unscanned ever reaches the destination.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const CLAMD_PORT: u16 = 3310;
+const ICAP_PORT: u16 = 1344;
+/// Size of the chunks a file is streamed to the scanner in
+const CHUNK_SIZE: usize = 64 * 1024;
+const SCAN_TIMEOUT: Duration = Duration::from_secs(120);
+/// Response headers ICAP servers name the found malware in
+const INFECTION_HEADERS: &[&str] = &["x-infection-found", "x-virus-id", "x-violations-found"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scanner {
+    /// Path of the unix socket of clamd
+    ClamdSocket(PathBuf),
+    /// Host and port of clamd
+    ClamdTcp(String),
+    Icap {
+        server: String,
+        service: String,
+    },
+}
+
+impl FromStr for Scanner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Scanner, String> {
+        if let Some(target) = s.strip_prefix("clamd:") {
+            if target.starts_with('/') {
+                Ok(Scanner::ClamdSocket(PathBuf::from(target)))
+            } else if target.is_empty() {
+                Err(String::from("The clamd socket or address is missing"))
+            } else {
+                Ok(Scanner::ClamdTcp(with_port(target, CLAMD_PORT)))
+            }
+        } else if let Some(rest) = s.strip_prefix("icap://") {
+            let (server, service) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            if server.is_empty() || service.len() < 2 {
+                return Err(String::from(
+                    "ICAP scanners are given as icap://host[:port]/service",
+                ));
+            }
+            Ok(Scanner::Icap {
+                server: with_port(server, ICAP_PORT),
+                service: service[1..].to_string(),
+            })
+        } else {
+            Err(format!(
+                "Unknown scanner {}, use clamd:/path/to/socket, clamd:host[:port] or \
+                 icap://host[:port]/service",
+                s
+            ))
+        }
+    }
+}
+
+impl fmt::Display for Scanner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scanner::ClamdSocket(path) => write!(f, "clamd at {}", path.display()),
+            Scanner::ClamdTcp(server) => write!(f, "clamd at {}", server),
+            Scanner::Icap { server, service } => write!(f, "icap://{}/{}", server, service),
+        }
+    }
+}
+
+/// Adds the default port to a host or IP address without one.
+fn with_port(server: &str, port: u16) -> String {
+    // Bare IPv6 addresses contain several colons, bracketed ones may end in a port.
+    let has_port = server.rfind(':').is_some_and(|i| {
+        !server[i..].contains(']') && (server.starts_with('[') || server.matches(':').count() == 1)
+    });
+    if has_port {
+        server.to_string()
+    } else if server.contains(':') && !server.starts_with('[') {
+        format!("[{}]:{}", server, port)
+    } else {
+        format!("{}:{}", server, port)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Clean,
+    /// The name of the malware found
+    Infected(String),
+}
+
+fn connect_tcp(server: &str) -> io::Result<TcpStream> {
+    let address = server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("Could not resolve {}", server)))?;
+    let stream = TcpStream::connect_timeout(&address, SCAN_TIMEOUT)?;
+    stream.set_read_timeout(Some(SCAN_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCAN_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Streams `file` with clamd's INSTREAM command and parses the answer.
+fn scan_with_clamd<S: Read + Write, R: Read>(mut stream: S, mut file: R) -> io::Result<Verdict> {
+    stream.write_all(b"zINSTREAM\0")?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buffer)?;
+        stream.write_all(&(n as u32).to_be_bytes())?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&buffer[..n])?;
+    }
+    stream.flush()?;
+    // Replies to commands starting with z end in a null byte.
+    let mut reply = Vec::new();
+    BufReader::new(stream).read_until(b'\0', &mut reply)?;
+    parse_clamd_reply(&String::from_utf8_lossy(&reply))
+}
+
+fn parse_clamd_reply(reply: &str) -> io::Result<Verdict> {
+    let result = reply
+        .trim_end_matches(['\0', '\n'])
+        .strip_prefix("stream: ")
+        .unwrap_or(reply);
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(name) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(name.to_string()))
+    } else {
+        Err(io::Error::other(format!("clamd answered: {}", result)))
+    }
+}
+
+/// Sends `file` as the body of an HTTP response to the ICAP RESPMOD service and parses the
+/// answer. 204 means the file is clean, a modified response means it was blocked.
+fn scan_with_icap<S: Read + Write, R: Read>(
+    mut stream: S,
+    mut file: R,
+    server: &str,
+    service: &str,
+) -> io::Result<Verdict> {
+    let http_header = "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\r\n";
+    write!(
+        stream,
+        "RESPMOD icap://{0}/{1} ICAP/1.0\r\nHost: {0}\r\nAllow: 204\r\n\
+         Encapsulated: res-hdr=0, res-body={2}\r\n\r\n{3}",
+        server,
+        service,
+        http_header.len(),
+        http_header
+    )?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        write!(stream, "{:x}\r\n", n)?;
+        stream.write_all(&buffer[..n])?;
+        stream.write_all(b"\r\n")?;
+    }
+    stream.write_all(b"0\r\n\r\n")?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| io::Error::other(format!("Invalid ICAP reply: {}", status_line.trim())))?;
+    let mut infection = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if INFECTION_HEADERS.contains(&name.trim().to_ascii_lowercase().as_str()) {
+                infection = Some(value.trim().to_string());
+            }
+        }
+    }
+    match status {
+        204 => Ok(Verdict::Clean),
+        200 | 403 => {
+            Ok(Verdict::Infected(infection.unwrap_or_else(|| {
+                String::from("blocked by the ICAP server")
+            })))
+        }
+        _ => Err(io::Error::other(format!(
+            "The ICAP server answered {}",
+            status_line.trim()
+        ))),
+    }
+}
+
+impl Scanner {
+    /// Scans the file at `path`, blocking until the scanner answers.
+    pub fn scan(&self, path: &Path) -> io::Result<Verdict> {
+        let file = fs::File::open(path)?;
+        match self {
+            #[cfg(unix)]
+            Scanner::ClamdSocket(socket) => {
+                let stream = std::os::unix::net::UnixStream::connect(socket)?;
+                stream.set_read_timeout(Some(SCAN_TIMEOUT))?;
+                scan_with_clamd(stream, file)
+            }
+            #[cfg(not(unix))]
+            Scanner::ClamdSocket(_) => Err(io::Error::other(
+                "clamd sockets are only supported on unix, use clamd:host:port",
+            )),
+            Scanner::ClamdTcp(server) => scan_with_clamd(connect_tcp(server)?, file),
+            Scanner::Icap { server, service } => {
+                scan_with_icap(connect_tcp(server)?, file, server, service)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    proptest! {
+        #[test]
+        fn test_clamd_reply_names_malware(name in "[A-Za-z0-9.-]{1,30}") {
+            let reply = format!("stream: {} FOUND\0", name);
+            prop_assert_eq!(Verdict::Infected(name), parse_clamd_reply(&reply).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parse_scanner() {
+        assert_eq!(
+            Ok(Scanner::ClamdSocket(PathBuf::from("/run/clamav/clamd.ctl"))),
+            "clamd:/run/clamav/clamd.ctl".parse()
+        );
+        assert_eq!(
+            Ok(Scanner::ClamdTcp(String::from("localhost:3310"))),
+            "clamd:localhost".parse()
+        );
+        assert_eq!(
+            Ok(Scanner::ClamdTcp(String::from("10.0.0.2:9999"))),
+            "clamd:10.0.0.2:9999".parse()
+        );
+        assert_eq!(
+            Ok(Scanner::Icap {
+                server: String::from("av.lan:1344"),
+                service: String::from("avscan"),
+            }),
+            "icap://av.lan/avscan".parse()
+        );
+        assert_eq!(
+            Ok(Scanner::ClamdTcp(String::from("[::1]:3310"))),
+            "clamd:::1".parse()
+        );
+        assert!("icap://av.lan".parse::<Scanner>().is_err());
+        assert!("clamav".parse::<Scanner>().is_err());
+    }
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(Verdict::Clean, parse_clamd_reply("stream: OK\0").unwrap());
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+
+    #[test]
+    fn test_scan_with_clamd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let clamd = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0u8; 10];
+            stream.read_exact(&mut command).unwrap();
+            let mut content = Vec::new();
+            loop {
+                let mut length = [0u8; 4];
+                stream.read_exact(&mut length).unwrap();
+                let length = u32::from_be_bytes(length) as usize;
+                if length == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; length];
+                stream.read_exact(&mut chunk).unwrap();
+                content.extend(chunk);
+            }
+            let reply: &[u8] = if content == b"EICAR" {
+                b"stream: Eicar-Test-Signature FOUND\0"
+            } else {
+                b"stream: OK\0"
+            };
+            stream.write_all(reply).unwrap();
+            command
+        });
+        let verdict = scan_with_clamd(connect_tcp(&server).unwrap(), &b"EICAR"[..]).unwrap();
+        assert_eq!(
+            Verdict::Infected(String::from("Eicar-Test-Signature")),
+            verdict
+        );
+        assert_eq!(b"zINSTREAM\0", &clamd.join().unwrap());
+    }
+
+    #[test]
+    fn test_scan_with_icap() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let icap = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                request.push_str(&line);
+                if line == "0\r\n" {
+                    break;
+                }
+            }
+            writer
+                .write_all(b"ICAP/1.0 200 OK\r\nX-Infection-Found: Type=0; Threat=Eicar;\r\n\r\n")
+                .unwrap();
+            request
+        });
+        let stream = connect_tcp(&server).unwrap();
+        let verdict = scan_with_icap(stream, &b"content"[..], &server, "avscan").unwrap();
+        assert_eq!(
+            Verdict::Infected(String::from("Type=0; Threat=Eicar;")),
+            verdict
+        );
+        let request = icap.join().unwrap();
+        assert!(request.starts_with(&format!("RESPMOD icap://{}/avscan ICAP/1.0\r\n", server)));
+        assert!(request.contains("\r\n7\r\ncontent\r\n"));
+    }
+}
@@ -0,0 +1,133 @@
+//! Restricting the times at which the share is available
+//!
+//! Outside the window every request is answered with a page saying when the share opens or that
+//! it has expired, so a long-running instance can be started ahead of time.
+
+use crate::html;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use hyper::{header, Body, Response, StatusCode};
+
+const TIME_FORMATS: &[&str] = &["%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"];
+const DISPLAY_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+/// Parses a local time such as `2024-07-01 09:00`.
+pub fn parse_time(s: &str) -> Result<DateTime<Local>, String> {
+    for format in TIME_FORMATS {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(s.trim(), format) {
+            return Local
+                .from_local_datetime(&naive)
+                .single()
+                .ok_or_else(|| format!("Ambiguous or nonexistent local time: {}", s));
+        }
+    }
+    Err(format!("Not a time in the format YYYY-MM-DD HH:MM: {}", s))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Availability {
+    Open,
+    NotYet(DateTime<Local>),
+    Expired(DateTime<Local>),
+}
+
+/// The time span during which the share is available. Both ends are optional.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Window {
+    from: Option<DateTime<Local>>,
+    until: Option<DateTime<Local>>,
+}
+
+impl Window {
+    pub fn new(
+        from: Option<DateTime<Local>>,
+        until: Option<DateTime<Local>>,
+    ) -> Result<Window, String> {
+        if let (Some(f), Some(u)) = (from, until) {
+            if u <= f {
+                return Err(String::from(
+                    "The end of the window must be after its start",
+                ));
+            }
+        }
+        Ok(Window { from, until })
+    }
+
+    pub fn get_until(&self) -> Option<DateTime<Local>> {
+        self.until
+    }
+
+    pub fn check(&self, now: DateTime<Local>) -> Availability {
+        match (self.from, self.until) {
+            (Some(from), _) if now < from => Availability::NotYet(from),
+            (_, Some(until)) if now >= until => Availability::Expired(until),
+            _ => Availability::Open,
+        }
+    }
+}
+
+/// Renders the page shown outside the window, or `None` if the share is available.
+pub fn create_unavailable_response(availability: Availability) -> Option<Response<Body>> {
+    let (status, title, message) = match availability {
+        Availability::Open => return None,
+        Availability::NotYet(from) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Not yet available",
+            format!("This share opens at {}.", from.format(DISPLAY_FORMAT)),
+        ),
+        Availability::Expired(until) => (
+            StatusCode::GONE,
+            "No longer available",
+            format!("This share expired at {}.", until.format(DISPLAY_FORMAT)),
+        ),
+    };
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store");
+    if let Availability::NotYet(from) = availability {
+        let seconds = (from - Local::now()).num_seconds().max(0);
+        response = response.header(header::RETRY_AFTER, seconds);
+    }
+    Some(
+        response
+            .body(Body::from(html::create_message_page(title, &message)))
+            .unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_parse_time_roundtrip(y in 2000i32..2100, mo in 1u32..13, d in 1u32..29, h in 0u32..24, mi in 0u32..60) {
+            let s = format!("{:04}-{:02}-{:02} {:02}:{:02}", y, mo, d, h, mi);
+            // Local times skipped by a DST change don't exist and are rejected.
+            if let Ok(time) = parse_time(&s) {
+                prop_assert_eq!(s, time.format(DISPLAY_FORMAT).to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_time_invalid() {
+        assert!(parse_time("2024-13-01 09:00").is_err());
+        assert!(parse_time("tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_window_check() {
+        let now = Local::now();
+        let from = now + Duration::hours(1);
+        let until = now + Duration::hours(2);
+        let window = Window::new(Some(from), Some(until)).unwrap();
+        assert_eq!(Availability::NotYet(from), window.check(now));
+        assert_eq!(Availability::Open, window.check(from));
+        assert_eq!(Availability::Expired(until), window.check(until));
+        assert_eq!(Availability::Open, Window::default().check(now));
+        assert!(Window::new(Some(until), Some(from)).is_err());
+    }
+}
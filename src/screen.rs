@@ -0,0 +1,514 @@
+//! Showing the screen to a browser, with `--screen`
+//!
+//! The screen, or a region of it, is captured by an external tool writing Motion JPEG to stdout,
+//! `ffmpeg` grabbing the X11 display or `wf-recorder` on Wayland compositors that offer wlroots
+//! screencopy. GNOME and KDE only hand out the screen through xdg-desktop-portal and PipeWire,
+//! which needs a D-Bus client rustbelt doesn't have, so there `ffmpeg` only sees XWayland windows.
+//! The output is split into JPEG frames that are sent to every browser as a
+//! `multipart/x-mixed-replace` stream, which an `<img>` shows without any script. Capturing
+//! starts with the first viewer and stops with the last one, and a viewer that can't keep up
+//! skips frames rather than falling behind, so what the tablet shows stays close to the screen.
+
+use crate::{create_status_response, help, html, output};
+use bytes::Bytes;
+use futures::stream::{self, Stream};
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::error;
+use std::fmt;
+use std::io;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::sync::broadcast;
+
+pub const STREAM_PATH: &str = "/stream";
+pub const FRAME_PATH: &str = "/frame.jpg";
+const FRAME_RATE: u32 = 10;
+const BOUNDARY: &str = "rustbelt-frame";
+/// Frames a viewer may fall behind before it skips to the newest one
+const CHANNEL_CAPACITY: usize = 2;
+/// Data without a complete frame after which the capture output is considered garbage
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+/// Time `/frame.jpg` waits for the capture to deliver a frame
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+const START_OF_IMAGE: [u8; 2] = [0xff, 0xd8];
+const END_OF_IMAGE: [u8; 2] = [0xff, 0xd9];
+
+#[derive(Debug, PartialEq)]
+pub struct RegionError(String);
+
+impl error::Error for RegionError {}
+
+impl fmt::Display for RegionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is no screen region, give it as WIDTHxHEIGHT+X+Y",
+            self.0
+        )
+    }
+}
+
+/// A part of the screen in X11 geometry notation, `WIDTHxHEIGHT+X+Y`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl FromStr for Region {
+    type Err = RegionError;
+
+    fn from_str(s: &str) -> Result<Region, RegionError> {
+        let error = || RegionError(s.to_string());
+        let (size, offset) = match s.find('+') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, "0+0"),
+        };
+        let mut size = size.splitn(2, 'x').map(str::parse::<u32>);
+        let mut offset = offset.splitn(2, '+').map(str::parse::<u32>);
+        let region = match (size.next(), size.next(), offset.next(), offset.next()) {
+            (Some(Ok(width)), Some(Ok(height)), Some(Ok(x)), Some(Ok(y))) => Region {
+                width,
+                height,
+                x,
+                y,
+            },
+            _ => return Err(error()),
+        };
+        if region.width == 0 || region.height == 0 {
+            return Err(error());
+        }
+        Ok(region)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}+{}+{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+/// Splits the output of the capture tool into JPEG frames at their start and end markers.
+#[derive(Default)]
+struct FrameSplitter {
+    buffer: Vec<u8>,
+}
+
+fn find_marker(data: &[u8], marker: [u8; 2]) -> Option<usize> {
+    data.windows(2).position(|w| w == marker)
+}
+
+impl FrameSplitter {
+    /// Adds `data` and returns the frames completed by it.
+    fn push(&mut self, data: &[u8]) -> Vec<Bytes> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        loop {
+            let start = match find_marker(&self.buffer, START_OF_IMAGE) {
+                Some(start) => start,
+                None => {
+                    // Keep a trailing 0xff, it may start the marker of the next frame.
+                    let keep = usize::from(self.buffer.last() == Some(&0xff));
+                    self.buffer.drain(..self.buffer.len() - keep);
+                    break;
+                }
+            };
+            self.buffer.drain(..start);
+            // Entropy coded data escapes 0xff, so the first end marker ends the frame.
+            match find_marker(&self.buffer[START_OF_IMAGE.len()..], END_OF_IMAGE) {
+                Some(end) => {
+                    let end = START_OF_IMAGE.len() + end + END_OF_IMAGE.len();
+                    frames.push(Bytes::from(self.buffer.drain(..end).collect::<Vec<_>>()));
+                }
+                None => {
+                    if self.buffer.len() > MAX_FRAME {
+                        self.buffer.clear();
+                    }
+                    break;
+                }
+            }
+        }
+        frames
+    }
+}
+
+/// The commands that write the screen as Motion JPEG to stdout, in the order they are tried
+fn get_capture_commands(region: Option<Region>) -> Vec<(&'static str, Vec<String>)> {
+    let rate = FRAME_RATE.to_string();
+    let display = std::env::var("DISPLAY").unwrap_or_else(|_| String::from(":0"));
+    let mut ffmpeg = vec!["-loglevel", "error", "-f", "x11grab", "-framerate", &rate]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    match region {
+        Some(r) => ffmpeg.extend(vec![
+            String::from("-video_size"),
+            format!("{}x{}", r.width, r.height),
+            String::from("-i"),
+            format!("{}+{},{}", display, r.x, r.y),
+        ]),
+        None => ffmpeg.extend(vec![String::from("-i"), display]),
+    }
+    ffmpeg.extend(
+        ["-f", "mjpeg", "-q:v", "7", "pipe:1"]
+            .iter()
+            .map(|a| a.to_string()),
+    );
+    let mut commands = Vec::new();
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        let mut wf_recorder = ["-c", "mjpeg", "-m", "mjpeg", "-x", "yuvj420p", "-r", &rate]
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>();
+        if let Some(r) = region {
+            wf_recorder.push(String::from("-g"));
+            wf_recorder.push(format!("{},{} {}x{}", r.x, r.y, r.width, r.height));
+        }
+        wf_recorder.extend(vec![String::from("-f"), String::from("pipe:1")]);
+        commands.push(("wf-recorder", wf_recorder));
+    }
+    commands.push(("ffmpeg", ffmpeg));
+    commands
+}
+
+fn spawn_capture(region: Option<Region>) -> io::Result<(&'static str, tokio::process::Child)> {
+    for (program, args) in get_capture_commands(region) {
+        match tokio::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => return Ok((program, child)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no screen capture tool found, install ffmpeg or wf-recorder",
+    ))
+}
+
+/// A frame or `None` once capturing failed
+type Update = Option<Bytes>;
+
+/// The captured screen, shared by everyone watching
+pub struct ScreenStream {
+    region: Option<Region>,
+    started: AtomicBool,
+    sender: broadcast::Sender<Update>,
+    latest: Mutex<Option<Bytes>>,
+}
+
+impl ScreenStream {
+    pub fn new(region: Option<Region>) -> ScreenStream {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        ScreenStream {
+            region,
+            started: AtomicBool::new(false),
+            sender,
+            latest: Mutex::new(None),
+        }
+    }
+
+    /// What is shown, for the summary
+    pub fn describe(&self) -> String {
+        match self.region {
+            Some(region) => format!("the screen region {}", region),
+            None => String::from("the screen"),
+        }
+    }
+
+    /// Subscribes to the frames, starting to capture for the first viewer.
+    fn subscribe(self: &Arc<Self>) -> broadcast::Receiver<Update> {
+        let receiver = self.sender.subscribe();
+        if !self.started.swap(true, Ordering::SeqCst) {
+            tokio::spawn(capture(self.clone()));
+        }
+        receiver
+    }
+
+    /// Whether nobody is watching anymore, in which case capturing stops until the next viewer.
+    fn stop_if_idle(&self) -> bool {
+        if self.sender.receiver_count() > 0 {
+            return false;
+        }
+        self.started.store(false, Ordering::SeqCst);
+        // A viewer subscribing meanwhile either started a new capture or relies on this one.
+        self.sender.receiver_count() == 0 || self.started.swap(true, Ordering::SeqCst)
+    }
+}
+
+async fn capture(screen: Arc<ScreenStream>) {
+    match read_frames(&screen).await {
+        Ok(true) => {}
+        Ok(false) => {
+            output::print_event("Stopped capturing the screen, nobody is watching");
+            return;
+        }
+        Err(e) => eprintln!("Could not capture the screen: {}", e),
+    }
+    *screen.latest.lock().unwrap() = None;
+    let _ = screen.sender.send(None);
+    // Viewers connecting later try again.
+    screen.started.store(false, Ordering::SeqCst);
+}
+
+/// Runs the capture tool and publishes its frames. Returns whether the tool ended, rather than
+/// capturing stopping for lack of viewers.
+async fn read_frames(screen: &ScreenStream) -> io::Result<bool> {
+    let (program, mut child) = spawn_capture(screen.region)?;
+    output::print_event(&format!("Capturing {} with {}", screen.describe(), program));
+    let mut stdout = child.stdout.take().unwrap();
+    let mut splitter = FrameSplitter::default();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = stdout.read(&mut buffer).await?;
+        if n == 0 {
+            let status = child.await?;
+            return Err(io::Error::other(format!(
+                "{} exited with {}",
+                program, status
+            )));
+        }
+        for frame in splitter.push(&buffer[..n]) {
+            *screen.latest.lock().unwrap() = Some(frame.clone());
+            // Sending fails while nobody is connected, which the check below notices.
+            let _ = screen.sender.send(Some(frame));
+        }
+        if screen.stop_if_idle() {
+            return Ok(false);
+        }
+    }
+}
+
+fn create_part(frame: &Bytes) -> Bytes {
+    let mut part = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        frame.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(frame);
+    part.extend_from_slice(b"\r\n");
+    Bytes::from(part)
+}
+
+fn create_part_stream(
+    receiver: broadcast::Receiver<Update>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(Some(frame)) => return Some((Ok(create_part(&frame)), receiver)),
+                Ok(None) | Err(broadcast::RecvError::Closed) => return None,
+                Err(broadcast::RecvError::Lagged(_)) => continue,
+            }
+        }
+    })
+}
+
+fn create_viewer_page(screen: &ScreenStream) -> String {
+    html::create_page(
+        "Screen",
+        &format!(
+            "<p>Showing {}. <a href=\"{}\">Save a still image</a></p>\n\
+             <img src=\"{}\" alt=\"The shared screen\" style=\"max-width: 100%; height: auto\">\n",
+            html::escape(&screen.describe()),
+            FRAME_PATH.trim_start_matches('/'),
+            STREAM_PATH.trim_start_matches('/')
+        ),
+    )
+}
+
+/// The newest frame, waiting for the capture to deliver one if it isn't running.
+async fn get_frame(screen: &Arc<ScreenStream>) -> Option<Bytes> {
+    if let Some(frame) = screen.latest.lock().unwrap().clone() {
+        return Some(frame);
+    }
+    let mut receiver = screen.subscribe();
+    loop {
+        match tokio::time::timeout(FRAME_TIMEOUT, receiver.recv()).await {
+            Ok(Ok(Some(frame))) => return Some(frame),
+            Ok(Err(broadcast::RecvError::Lagged(_))) => continue,
+            _ => return None,
+        }
+    }
+}
+
+/// What a request of the screen share is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Page,
+    Stream,
+    Frame,
+}
+
+/// The requests the screen share answers
+pub fn get_routes() -> Vec<help::Route<Route>> {
+    vec![
+        help::Route::new(Method::GET, "/", "Page showing the screen", Route::Page),
+        help::Route::new(
+            Method::GET,
+            STREAM_PATH,
+            "The screen as a Motion JPEG stream, multipart/x-mixed-replace",
+            Route::Stream,
+        ),
+        help::Route::new(
+            Method::GET,
+            FRAME_PATH,
+            "The current screen as JPEG",
+            Route::Frame,
+        ),
+    ]
+}
+
+pub async fn handle_request(
+    screen: Arc<ScreenStream>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match help::find_route(&get_routes(), &req) {
+        Ok(Route::Page) => Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(create_viewer_page(&screen)))
+            .unwrap(),
+        Ok(Route::Stream) => {
+            let parts = create_part_stream(screen.subscribe());
+            let content_type = format!("multipart/x-mixed-replace; boundary={}", BOUNDARY);
+            Response::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::wrap_stream(parts))
+                .unwrap()
+        }
+        Ok(Route::Frame) => match get_frame(&screen).await {
+            Some(frame) => Response::builder()
+                .header(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"))
+                .body(Body::from(frame))
+                .unwrap(),
+            None => create_status_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The screen could not be captured",
+            ),
+        },
+        Err(response) => response,
+    };
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+    use proptest::prelude::*;
+
+    fn create_frame(content: &[u8]) -> Vec<u8> {
+        let mut frame = START_OF_IMAGE.to_vec();
+        frame.extend_from_slice(content);
+        frame.extend_from_slice(&END_OF_IMAGE);
+        frame
+    }
+
+    proptest! {
+        #[test]
+        fn test_region_roundtrip(width in 1u32.., height in 1u32.., x: u32, y: u32) {
+            let region = Region { width, height, x, y };
+            prop_assert_eq!(Ok(region), region.to_string().parse());
+        }
+
+        #[test]
+        fn test_splits_frames_at_any_chunk_size(
+            contents in proptest::collection::vec(
+                proptest::collection::vec(0u8..0xff, 0..100), 1..5),
+            chunk_size in 1usize..50,
+        ) {
+            let frames = contents.iter().map(|c| create_frame(c)).collect::<Vec<_>>();
+            let mut data = b"garbage".to_vec();
+            for frame in &frames {
+                data.extend_from_slice(frame);
+            }
+            let mut splitter = FrameSplitter::default();
+            let mut split = Vec::new();
+            for chunk in data.chunks(chunk_size) {
+                split.extend(splitter.push(chunk));
+            }
+            prop_assert_eq!(
+                frames,
+                split.iter().map(|f| f.to_vec()).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_region() {
+        assert_eq!(
+            Ok(Region {
+                width: 800,
+                height: 600,
+                x: 0,
+                y: 0
+            }),
+            "800x600".parse()
+        );
+        assert_eq!(
+            Ok(Region {
+                width: 1280,
+                height: 720,
+                x: 1920,
+                y: 10
+            }),
+            "1280x720+1920+10".parse()
+        );
+        assert!("0x600".parse::<Region>().is_err());
+        assert!("800".parse::<Region>().is_err());
+        assert!("800x600+10".parse::<Region>().is_err());
+        assert!("800x600-10-10".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn test_capture_commands() {
+        let region = "640x480+100+50".parse().ok();
+        let commands = get_capture_commands(region);
+        let (program, args) = commands.last().unwrap();
+        assert_eq!("ffmpeg", *program);
+        assert!(args.contains(&String::from("640x480")));
+        assert!(args.iter().any(|a| a.ends_with("+100,50")));
+        assert_eq!("pipe:1", args.last().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_stream_sends_frames_as_parts() {
+        let (sender, receiver) = broadcast::channel(CHANNEL_CAPACITY);
+        let mut parts = Box::pin(create_part_stream(receiver));
+        sender
+            .send(Some(Bytes::from(create_frame(b"one"))))
+            .unwrap();
+        let part = parts.next().await.unwrap().unwrap();
+        assert!(part.ends_with(b"one\xff\xd9\r\n"));
+        let part = String::from_utf8_lossy(&part);
+        assert!(part.starts_with(&format!("--{}\r\n", BOUNDARY)));
+        assert!(part.contains("Content-Type: image/jpeg\r\nContent-Length: 7\r\n\r\n"));
+
+        // A viewer that fell behind continues with the newest frames.
+        for n in 0..5u8 {
+            sender.send(Some(Bytes::from(create_frame(&[n])))).unwrap();
+        }
+        let part = parts.next().await.unwrap().unwrap();
+        assert!(part.ends_with(&[3, 0xff, 0xd9, b'\r', b'\n']));
+        let part = parts.next().await.unwrap().unwrap();
+        assert!(part.ends_with(&[4, 0xff, 0xd9, b'\r', b'\n']));
+        sender.send(None).unwrap();
+        assert!(parts.next().await.is_none());
+    }
+}
@@ -0,0 +1,84 @@
+//! Remembering the network interface and IP address chosen last time
+//!
+//! The choice is stored in the XDG state directory and offered as the default of the next prompt,
+//! so pressing Enter picks it again.
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+const SELECTION_FILE: &str = "last-selection.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Selection {
+    pub interface: String,
+    pub ip: String,
+}
+
+/// `$XDG_STATE_HOME/rustbelt`, falling back to `~/.local/state/rustbelt`
+fn get_state_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_STATE_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+    Some(base.join("rustbelt"))
+}
+
+/// Returns the last selection, if one was stored and can be read.
+pub fn load() -> Option<Selection> {
+    let content = fs::read_to_string(get_state_dir()?.join(SELECTION_FILE)).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+pub fn save(selection: &Selection) -> io::Result<()> {
+    let dir = match get_state_dir() {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(SELECTION_FILE), serde_json::to_string(selection)?)
+}
+
+pub fn forget() -> io::Result<()> {
+    let path = match get_state_dir() {
+        Some(d) => d.join(SELECTION_FILE),
+        None => return Ok(()),
+    };
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Index of the remembered choice in `choices`, to be preselected.
+pub fn find_default(choices: &[String], remembered: Option<&str>) -> Option<usize> {
+    let remembered = remembered?;
+    choices.iter().position(|c| c == remembered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_selection_roundtrip(interface in "\\PC*", ip in "\\PC*") {
+            let selection = Selection { interface, ip };
+            let json = serde_json::to_string(&selection).unwrap();
+            prop_assert_eq!(selection, serde_json::from_str::<Selection>(&json).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_find_default() {
+        let choices = vec![String::from("eth0"), String::from("wlan0")];
+        assert_eq!(Some(1), find_default(&choices, Some("wlan0")));
+        assert_eq!(None, find_default(&choices, Some("usb0")));
+        assert_eq!(None, find_default(&choices, None));
+    }
+}
@@ -0,0 +1,348 @@
+//! Printable share sheets, with `--print-sheet FILE`
+//!
+//! At events where people fetch materials over hours, a sheet on the wall works better than a
+//! laptop screen. The sheet is a PDF with one A4 page per link, each with the QR code, the URL to
+//! type, the PINs needed and the time the share ends. The PDF is written directly, with the QR
+//! code drawn as filled squares and the text set in the standard fonts every PDF viewer has, so
+//! it prints sharply at any size without a PDF library.
+
+use qrcode::QrCode;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const PAGE_WIDTH: f64 = 595.0;
+const PAGE_HEIGHT: f64 = 842.0;
+const MARGIN: f64 = 60.0;
+const QR_SIZE: f64 = 380.0;
+/// Light modules around the code, which scanners need to find it
+const QUIET_ZONE: usize = 4;
+const URL_FONT_SIZE: f64 = 18.0;
+const MIN_URL_FONT_SIZE: f64 = 8.0;
+/// Width of every character of Courier, relative to the font size
+const COURIER_WIDTH: f64 = 0.6;
+
+/// Fonts every PDF viewer provides, in the order of their object numbers
+const FONTS: [&str; 3] = ["Helvetica", "Helvetica-Bold", "Courier"];
+const TEXT_FONT: &str = "/F1";
+const TITLE_FONT: &str = "/F2";
+const URL_FONT: &str = "/F3";
+
+/// A link on the sheet, the URL to type and the text of its QR code
+pub struct SheetLink {
+    pub url: String,
+    pub qr_text: String,
+}
+
+pub struct Sheet {
+    pub title: String,
+    pub links: Vec<SheetLink>,
+    /// Lines like `PIN: 1234` printed below the URL
+    pub notes: Vec<String>,
+}
+
+/// Escapes `text` as a PDF string in WinAnsiEncoding, with `?` for characters it lacks.
+fn encode_text(text: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(text.len() + 2);
+    encoded.push(b'(');
+    for c in text.chars() {
+        let byte = match c as u32 {
+            0x20..=0x7e | 0xa0..=0xff => c as u32 as u8,
+            _ => b'?',
+        };
+        if matches!(byte, b'(' | b')' | b'\\') {
+            encoded.push(b'\\');
+        }
+        encoded.push(byte);
+    }
+    encoded.push(b')');
+    encoded
+}
+
+/// Splits `url` into lines that fit the page in Courier and returns them with their font size.
+fn fit_url(url: &str) -> (f64, Vec<String>) {
+    let width = PAGE_WIDTH - 2.0 * MARGIN;
+    let chars = url.chars().collect::<Vec<_>>();
+    let size = (width / (COURIER_WIDTH * chars.len().max(1) as f64)).min(URL_FONT_SIZE);
+    // Rounded down to the precision sizes are written with, rounding up could overflow the page.
+    let size = (size * 100.0).floor() / 100.0;
+    if size >= MIN_URL_FONT_SIZE {
+        return (size, vec![url.to_string()]);
+    }
+    let per_line = (width / (COURIER_WIDTH * MIN_URL_FONT_SIZE)) as usize;
+    let lines = chars
+        .chunks(per_line)
+        .map(|line| line.iter().collect())
+        .collect();
+    (MIN_URL_FONT_SIZE, lines)
+}
+
+fn push_text(content: &mut Vec<u8>, font: &str, size: f64, x: f64, y: f64, text: &str) {
+    content
+        .extend_from_slice(format!("BT {} {:.2} Tf {:.2} {:.2} Td ", font, size, x, y).as_bytes());
+    content.extend_from_slice(&encode_text(text));
+    content.extend_from_slice(b" Tj ET\n");
+}
+
+/// Draws the dark modules of `code` as filled squares in a square of `QR_SIZE` at `x`, `y`.
+fn push_qr_code(content: &mut Vec<u8>, code: &QrCode, x: f64, y: f64) {
+    let width = code.width();
+    let module = QR_SIZE / (width + 2 * QUIET_ZONE) as f64;
+    let origin = module * QUIET_ZONE as f64;
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color != qrcode::Color::Dark {
+            continue;
+        }
+        let (column, row) = (i % width, i / width);
+        // PDF counts from the bottom, the code from the top.
+        content.extend_from_slice(
+            format!(
+                "{:.2} {:.2} {:.2} {:.2} re\n",
+                x + origin + column as f64 * module,
+                y + QR_SIZE - origin - (row + 1) as f64 * module,
+                module,
+                module
+            )
+            .as_bytes(),
+        );
+    }
+    content.extend_from_slice(b"f\n");
+}
+
+impl Sheet {
+    /// The drawing commands of the page for link `index`.
+    fn create_page_content(&self, index: usize) -> io::Result<Vec<u8>> {
+        let link = &self.links[index];
+        let code = QrCode::new(&link.qr_text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let mut content = Vec::new();
+        let mut y = PAGE_HEIGHT - MARGIN - 28.0;
+        push_text(&mut content, TITLE_FONT, 28.0, MARGIN, y, &self.title);
+        if self.links.len() > 1 {
+            y -= 24.0;
+            let subtitle = format!("Link {} of {}", index + 1, self.links.len());
+            push_text(&mut content, TEXT_FONT, 14.0, MARGIN, y, &subtitle);
+        }
+        y -= 20.0 + QR_SIZE;
+        push_qr_code(&mut content, &code, (PAGE_WIDTH - QR_SIZE) / 2.0, y);
+        y -= 36.0;
+        let intro = "Scan the code or open this address:";
+        push_text(&mut content, TEXT_FONT, 14.0, MARGIN, y, intro);
+        let (size, lines) = fit_url(&link.url);
+        for line in lines {
+            y -= size * 1.4;
+            push_text(&mut content, URL_FONT, size, MARGIN, y, &line);
+        }
+        y -= 14.0;
+        for note in &self.notes {
+            y -= 20.0;
+            push_text(&mut content, TEXT_FONT, 14.0, MARGIN, y, note);
+        }
+        let footer =
+            "Shared with rustbelt, from a device that has to be on the same network as yours";
+        push_text(&mut content, TEXT_FONT, 9.0, MARGIN, MARGIN / 2.0, footer);
+        Ok(content)
+    }
+
+    /// The sheet as a PDF document
+    fn create_pdf(&self) -> io::Result<Vec<u8>> {
+        // Objects: 1 the catalog, 2 the page tree, then the fonts, then every page followed by
+        // its content stream.
+        let first_page = 3 + FONTS.len();
+        let page_ids = (0..self.links.len())
+            .map(|i| first_page + 2 * i)
+            .collect::<Vec<_>>();
+        let mut objects: Vec<Vec<u8>> = vec![
+            b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                page_ids
+                    .iter()
+                    .map(|id| format!("{} 0 R", id))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                page_ids.len()
+            )
+            .into_bytes(),
+        ];
+        for font in &FONTS {
+            objects.push(
+                format!(
+                    "<< /Type /Font /Subtype /Type1 /BaseFont /{} /Encoding /WinAnsiEncoding >>",
+                    font
+                )
+                .into_bytes(),
+            );
+        }
+        let fonts = (0..FONTS.len())
+            .map(|i| format!("/F{} {} 0 R", i + 1, 3 + i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        for (index, id) in page_ids.iter().enumerate() {
+            objects.push(
+                format!(
+                    "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                     /Resources << /Font << {} >> >> /Contents {} 0 R >>",
+                    PAGE_WIDTH,
+                    PAGE_HEIGHT,
+                    fonts,
+                    id + 1
+                )
+                .into_bytes(),
+            );
+            let content = self.create_page_content(index)?;
+            let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+            stream.extend_from_slice(&content);
+            stream.extend_from_slice(b"\nendstream");
+            objects.push(stream);
+        }
+
+        let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = Vec::with_capacity(objects.len());
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+            pdf.extend_from_slice(object);
+            pdf.extend_from_slice(b"\nendobj\n");
+        }
+        let xref = pdf.len();
+        pdf.extend_from_slice(
+            format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes(),
+        );
+        for offset in offsets {
+            pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        pdf.extend_from_slice(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref
+            )
+            .as_bytes(),
+        );
+        Ok(pdf)
+    }
+
+    /// Writes the sheet to `path` as PDF.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.create_pdf()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn create_sheet(title: &str, urls: &[&str]) -> Sheet {
+        Sheet {
+            title: title.to_string(),
+            links: urls
+                .iter()
+                .map(|url| SheetLink {
+                    url: url.to_string(),
+                    qr_text: url.to_string(),
+                })
+                .collect(),
+            notes: vec![String::from("PIN: 1234")],
+        }
+    }
+
+    /// The PDF with every byte outside ASCII replaced, so offsets stay the same
+    fn to_ascii(pdf: &[u8]) -> String {
+        pdf.iter()
+            .map(|&b| if b.is_ascii() { b as char } else { '?' })
+            .collect()
+    }
+
+    /// Checks that the cross-reference table points at the objects.
+    fn check_xref(pdf: &[u8]) -> usize {
+        let text = to_ascii(pdf);
+        let start = text.rfind("startxref\n").unwrap() + "startxref\n".len();
+        let xref = text[start..]
+            .lines()
+            .next()
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        assert!(text[xref..].starts_with("xref\n"));
+        let entries = text[xref..]
+            .lines()
+            .skip(3)
+            .take_while(|l| !l.starts_with("trailer"))
+            .collect::<Vec<_>>();
+        for (i, entry) in entries.iter().enumerate() {
+            let offset = entry[..10].parse::<usize>().unwrap();
+            assert!(text[offset..].starts_with(&format!("{} 0 obj\n", i + 1)));
+        }
+        entries.len()
+    }
+
+    proptest! {
+        #[test]
+        fn test_text_stays_one_string(text in "\\PC*") {
+            let encoded = encode_text(&text);
+            let mut depth = 0;
+            let mut escaped = false;
+            for (i, byte) in encoded.iter().enumerate() {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'(' => depth += 1,
+                    b')' => {
+                        depth -= 1;
+                        prop_assert!(depth > 0 || i == encoded.len() - 1);
+                    }
+                    _ => {}
+                }
+            }
+            prop_assert_eq!(0, depth);
+        }
+
+        #[test]
+        fn test_long_urls_fit_the_page(url in "http://[a-z0-9./]{1,400}") {
+            let (size, lines) = fit_url(&url);
+            prop_assert_eq!(url, lines.concat());
+            for line in lines {
+                let width = line.chars().count() as f64 * COURIER_WIDTH * size;
+                prop_assert!(width <= PAGE_WIDTH - 2.0 * MARGIN);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_text() {
+        assert_eq!(b"(a \\(b\\) \\\\)".to_vec(), encode_text("a (b) \\"));
+        assert_eq!(b"(\xfc?)".to_vec(), encode_text("ü→"));
+    }
+
+    #[test]
+    fn test_pdf_has_a_page_per_link() {
+        let urls = [
+            "http://192.168.1.2:8080/t/abc",
+            "http://192.168.1.2:8080/t/def",
+        ];
+        let pdf = create_sheet("Slides (final)", &urls).create_pdf().unwrap();
+        assert!(pdf.starts_with(b"%PDF-1.4\n"));
+        assert_eq!(3 + FONTS.len() + 2 * urls.len(), check_xref(&pdf) + 1);
+        let text = to_ascii(&pdf);
+        assert!(text.contains("/Count 2"));
+        assert!(text.contains("(Slides \\(final\\))"));
+        assert!(text.contains("(Link 2 of 2)"));
+        assert!(text.contains("(PIN: 1234)"));
+        assert!(text.contains("(http://192.168.1.2:8080/t/def)"));
+        assert_eq!(2, text.matches(" re\nf\n").count());
+    }
+
+    #[test]
+    fn test_writes_pdf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sheet.pdf");
+        create_sheet("Files", &["http://10.0.0.1:8080/"])
+            .write(&path)
+            .unwrap();
+        let pdf = fs::read(&path).unwrap();
+        assert!(pdf.ends_with(b"%%EOF\n"));
+        assert!(!to_ascii(&pdf).contains("Link 1 of"));
+    }
+}
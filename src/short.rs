@@ -0,0 +1,223 @@
+//! Short URLs for typing instead of scanning
+//!
+//! With `--short`, `/s/<code>` redirects to the share, or to one of its one-time links, so the
+//! token in the real path never has to be typed. The code uses characters that can't be confused
+//! with each other, and the URL is printed in large letters that can be read from across a room.
+
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use rand::Rng;
+
+pub const SHORT_PREFIX: &str = "/s/";
+const CODE_LENGTH: usize = 4;
+/// Characters of a code, without those that look alike in the large font, like 0 and d
+const CODE_ALPHABET: &[u8] = b"2346789acefhjknprtuxy";
+
+/// Height of a glyph of the large font in pixels
+const GLYPH_HEIGHT: usize = 5;
+/// Characters in a line of large text, so it fits into 80 columns
+const MAX_LINE_LENGTH: usize = 19;
+
+pub fn generate_code() -> String {
+    let mut rng = rand::rngs::OsRng;
+    (0..CODE_LENGTH)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0, CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// The codes and the paths they redirect to
+#[derive(Debug)]
+pub struct ShortLinks {
+    links: Vec<(String, String)>,
+}
+
+impl ShortLinks {
+    /// Creates a code for every path, all different.
+    pub fn new(paths: Vec<String>) -> ShortLinks {
+        let mut links: Vec<(String, String)> = Vec::with_capacity(paths.len());
+        for path in paths {
+            let mut code = generate_code();
+            while links.iter().any(|(c, _)| *c == code) {
+                code = generate_code();
+            }
+            links.push((code, path));
+        }
+        ShortLinks { links }
+    }
+
+    /// Redirects requests for a short URL and leaves all others to the mode.
+    pub fn handle_request(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if req.method() != Method::GET {
+            return None;
+        }
+        let code = req.uri().path().strip_prefix(SHORT_PREFIX)?;
+        let code = code.trim_end_matches('/').to_ascii_lowercase();
+        let response = match self.links.iter().find(|(c, _)| *c == code) {
+            Some((_, path)) => Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, path.as_str())
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(Body::empty())
+                .unwrap(),
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Unknown short URL"))
+                .unwrap(),
+        };
+        Some(response)
+    }
+
+    /// Prints every short URL, in normal and in large letters.
+    pub fn print(&self, url: &str) {
+        for (code, _) in &self.links {
+            let short_url = format!("{}{}{}", url, SHORT_PREFIX, code);
+            eprintln!("Short URL: {}", short_url);
+            let typed = short_url.trim_start_matches("http://");
+            match render_large(typed) {
+                Some(large) => eprintln!("{}", large),
+                None => eprintln!("{}", typed),
+            }
+        }
+    }
+}
+
+/// The pixels of a character in the large font, 3 wide and 5 high
+fn get_glyph(c: char) -> Option<[&'static str; GLYPH_HEIGHT]> {
+    let glyph = match c.to_ascii_lowercase() {
+        '0' => ["###", "#.#", "#.#", "#.#", "###"],
+        '1' => [".#.", "##.", ".#.", ".#.", "###"],
+        '2' => ["###", "..#", "###", "#..", "###"],
+        '3' => ["###", "..#", ".##", "..#", "###"],
+        '4' => ["#.#", "#.#", "###", "..#", "..#"],
+        '5' | 's' => ["###", "#..", "###", "..#", "###"],
+        '6' => ["###", "#..", "###", "#.#", "###"],
+        '7' => ["###", "..#", "..#", ".#.", ".#."],
+        '8' => ["###", "#.#", "###", "#.#", "###"],
+        '9' => ["###", "#.#", "###", "..#", "###"],
+        'a' => [".#.", "#.#", "###", "#.#", "#.#"],
+        'b' => ["##.", "#.#", "##.", "#.#", "##."],
+        'c' => ["###", "#..", "#..", "#..", "###"],
+        'd' => ["##.", "#.#", "#.#", "#.#", "##."],
+        'e' => ["###", "#..", "###", "#..", "###"],
+        'f' => ["###", "#..", "###", "#..", "#.."],
+        'h' => ["#.#", "#.#", "###", "#.#", "#.#"],
+        'j' => ["..#", "..#", "..#", "#.#", "###"],
+        'k' => ["#.#", "#.#", "##.", "#.#", "#.#"],
+        'n' => ["###", "#.#", "#.#", "#.#", "#.#"],
+        'p' => ["###", "#.#", "###", "#..", "#.."],
+        'r' => ["##.", "#.#", "##.", "#.#", "#.#"],
+        't' => ["###", ".#.", ".#.", ".#.", ".#."],
+        'u' => ["#.#", "#.#", "#.#", "#.#", "###"],
+        'x' => ["#.#", "#.#", ".#.", "#.#", "#.#"],
+        'y' => ["#.#", "#.#", ".#.", ".#.", ".#."],
+        '.' => ["...", "...", "...", "...", ".#."],
+        ':' => ["...", ".#.", "...", ".#.", "..."],
+        '/' => ["..#", "..#", ".#.", "#..", "#.."],
+        '[' => ["##.", "#..", "#..", "#..", "##."],
+        ']' => [".##", "..#", "..#", "..#", ".##"],
+        _ => return None,
+    };
+    Some(glyph)
+}
+
+/// Splits `text` into lines of at most `MAX_LINE_LENGTH` characters, preferably after `/`, `:`
+/// or `.`.
+fn wrap(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while rest.len() > MAX_LINE_LENGTH {
+        let split = rest[..MAX_LINE_LENGTH]
+            .rfind(['/', ':', '.'])
+            .map_or(MAX_LINE_LENGTH, |i| i + 1);
+        lines.push(&rest[..split]);
+        rest = &rest[split..];
+    }
+    lines.push(rest);
+    lines
+}
+
+/// Renders `text` in the large font, two pixel rows per line of half blocks. Returns `None` if
+/// the font lacks a character.
+pub fn render_large(text: &str) -> Option<String> {
+    let mut output = Vec::new();
+    for line in wrap(text) {
+        let glyphs = line.chars().map(get_glyph).collect::<Option<Vec<_>>>()?;
+        for top in (0..GLYPH_HEIGHT).step_by(2) {
+            let mut row = String::new();
+            for glyph in &glyphs {
+                let lower = glyph.get(top + 1).map(|r| r.as_bytes());
+                for (x, pixel) in glyph[top].bytes().enumerate() {
+                    let below = lower.is_some_and(|r| r[x] == b'#');
+                    row.push(match (pixel == b'#', below) {
+                        (true, true) => '█',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (false, false) => ' ',
+                    });
+                }
+                row.push(' ');
+            }
+            output.push(row.trim_end().to_string());
+        }
+        output.push(String::new());
+    }
+    Some(output.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_wrap(text in "[0-9./:]{0,60}") {
+            let lines = wrap(&text);
+            prop_assert_eq!(text.clone(), lines.concat());
+            prop_assert!(lines.iter().all(|l| l.len() <= MAX_LINE_LENGTH));
+        }
+    }
+
+    #[test]
+    fn test_codes_can_be_rendered() {
+        for &c in CODE_ALPHABET {
+            assert!(get_glyph(c as char).is_some());
+        }
+        let code = generate_code();
+        assert_eq!(CODE_LENGTH, code.len());
+        assert!(render_large(&format!("192.168.1.2:8080/s/{}", code)).is_some());
+        assert!(render_large("g").is_none());
+    }
+
+    #[test]
+    fn test_glyphs_of_codes_differ() {
+        for (i, &a) in CODE_ALPHABET.iter().enumerate() {
+            for &b in &CODE_ALPHABET[i + 1..] {
+                assert_ne!(get_glyph(a as char), get_glyph(b as char));
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_large() {
+        assert_eq!("▄█\n █\n▀▀▀\n", render_large("1").unwrap());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let links = ShortLinks::new(vec![String::from("/token/")]);
+        let code = links.links[0].0.to_ascii_uppercase();
+        let req = Request::get(format!("/s/{}", code))
+            .body(Body::empty())
+            .unwrap();
+        let response = links.handle_request(&req).unwrap();
+        assert_eq!(StatusCode::FOUND, response.status());
+        assert_eq!("/token/", response.headers()[header::LOCATION]);
+        let req = Request::get("/s/zzzz").body(Body::empty()).unwrap();
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            links.handle_request(&req).unwrap().status()
+        );
+        let req = Request::get("/other").body(Body::empty()).unwrap();
+        assert!(links.handle_request(&req).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! Previewing a built static website from a directory
+//!
+//! Directories are answered with their `index.html`, and requested without a trailing slash they
+//! are redirected to one, so relative links to assets resolve like on a real web server. In
+//! single page application mode, unknown paths that don't look like files are answered with the
+//! root `index.html`, so client side routing via the history API works on reload.
+
+use crate::access::AccessRules;
+use crate::{create_status_response, paths, transfer};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const INDEX_FILE: &str = "index.html";
+
+/// A directory served as a website
+pub struct Site {
+    root: PathBuf,
+    history_fallback: bool,
+    access: AccessRules,
+}
+
+impl Site {
+    pub fn new(root: PathBuf, history_fallback: bool, access: AccessRules) -> Site {
+        Site {
+            root,
+            history_fallback,
+            access,
+        }
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether the directory contains an `index.html` that can be served at `/`
+    pub fn has_index(&self) -> bool {
+        self.root.join(INDEX_FILE).is_file()
+    }
+}
+
+/// Guesses the Content-Type of a file from its extension.
+pub fn get_content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+/// What a request path refers to inside the site
+#[derive(Debug, PartialEq)]
+enum Target {
+    File(PathBuf),
+    Redirect(String),
+    NotFound,
+}
+
+fn resolve(site: &Site, uri_path: &str) -> Target {
+    let trimmed = uri_path.trim_matches('/');
+    let relative = if trimmed.is_empty() {
+        Some(PathBuf::new())
+    } else {
+        paths::get_relative_path(trimmed)
+    };
+    if let Some(relative) = relative {
+        let path = site.root.join(relative);
+        if path.is_file() && !uri_path.ends_with('/') {
+            return Target::File(path);
+        }
+        if path.is_dir() {
+            if !uri_path.ends_with('/') {
+                return Target::Redirect(format!("{}/", uri_path));
+            }
+            if path.join(INDEX_FILE).is_file() {
+                return Target::File(path.join(INDEX_FILE));
+            }
+        }
+    }
+    let last_segment = trimmed.rsplit('/').next().unwrap_or_default();
+    if site.history_fallback && !last_segment.contains('.') && site.has_index() {
+        Target::File(site.root.join(INDEX_FILE))
+    } else {
+        Target::NotFound
+    }
+}
+
+pub async fn handle_request(
+    site: Arc<Site>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET {
+        return Ok(create_status_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+        ));
+    }
+    let path = match resolve(&site, req.uri().path()) {
+        Target::File(path) => path,
+        Target::Redirect(location) => {
+            return Ok(Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, location)
+                .body(Body::empty())
+                .unwrap())
+        }
+        Target::NotFound => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let relative = path.strip_prefix(&site.root).unwrap_or(&path);
+    if let Some(response) = site
+        .access
+        .check(&relative.to_string_lossy().replace('\\', "/"), &req)
+    {
+        return Ok(response);
+    }
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(f) => f,
+        Err(_) => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, get_content_type(&path))
+        .header(header::CACHE_CONTROL, "no-cache");
+    if let Ok(metadata) = file.metadata().await {
+        response = response.header(header::CONTENT_LENGTH, metadata.len());
+    }
+    Ok(response
+        .body(Body::wrap_stream(transfer::FileStream::new(file)))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            "text/html; charset=utf-8",
+            get_content_type(Path::new("a/INDEX.HTML"))
+        );
+        assert_eq!("application/wasm", get_content_type(Path::new("x.wasm")));
+        assert_eq!(
+            "application/octet-stream",
+            get_content_type(Path::new("Makefile"))
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("index.html"), "").unwrap();
+        fs::write(dir.path().join("docs").join("index.html"), "").unwrap();
+        fs::write(dir.path().join("app.js"), "").unwrap();
+
+        let site = Site::new(dir.path().to_path_buf(), false, AccessRules::default());
+        assert_eq!(
+            Target::File(dir.path().join("index.html")),
+            resolve(&site, "/")
+        );
+        assert_eq!(
+            Target::File(dir.path().join("app.js")),
+            resolve(&site, "/app.js")
+        );
+        assert_eq!(
+            Target::Redirect(String::from("/docs/")),
+            resolve(&site, "/docs")
+        );
+        assert_eq!(
+            Target::File(dir.path().join("docs").join("index.html")),
+            resolve(&site, "/docs/")
+        );
+        assert_eq!(Target::NotFound, resolve(&site, "/users/42"));
+        assert_eq!(Target::NotFound, resolve(&site, "/../etc/passwd"));
+
+        let spa = Site::new(dir.path().to_path_buf(), true, AccessRules::default());
+        assert_eq!(
+            Target::File(dir.path().join("index.html")),
+            resolve(&spa, "/users/42")
+        );
+        assert_eq!(Target::NotFound, resolve(&spa, "/missing.png"));
+    }
+}
@@ -0,0 +1,132 @@
+//! Adding up the size of shared directories
+//!
+//! Walking a large directory tree takes a while, so it starts in a thread of its own before the
+//! interface is chosen. The summary before serving waits for it, otherwise the result is printed
+//! once it is there while the server already runs. Shares larger than `--warn-size` get a
+//! warning, sharing a whole disk is one mistyped path away.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+pub const DEFAULT_WARN_SIZE: &str = "10G";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Totals {
+    pub files: u64,
+    pub size: u64,
+}
+
+/// Counts the files below `path` and adds up their sizes. A single file counts as one.
+pub fn count_files(path: &Path) -> io::Result<Totals> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(Totals {
+            files: 1,
+            size: metadata.len(),
+        });
+    }
+    let mut totals = Totals::default();
+    for entry in fs::read_dir(path)? {
+        let entry_totals = count_files(&entry?.path())?;
+        totals.files += entry_totals.files;
+        totals.size += entry_totals.size;
+    }
+    Ok(totals)
+}
+
+/// Formats a number of files, like "1 file" or "3 files"
+pub fn format_file_count(files: u64) -> String {
+    match files {
+        1 => String::from("1 file"),
+        n => format!("{} files", n),
+    }
+}
+
+/// The totals of directories being counted in the background
+pub struct SizeCalculation {
+    handle: thread::JoinHandle<HashMap<PathBuf, Totals>>,
+}
+
+impl SizeCalculation {
+    /// Starts counting the directories among `paths`. Paths that can't be read are left out.
+    pub fn start(paths: Vec<PathBuf>) -> SizeCalculation {
+        let handle = thread::spawn(move || {
+            paths
+                .into_iter()
+                .filter(|p| p.is_dir())
+                .filter_map(|p| count_files(&p).ok().map(|t| (p, t)))
+                .collect()
+        });
+        SizeCalculation { handle }
+    }
+
+    /// Waits until every directory is counted.
+    pub fn wait(self) -> HashMap<PathBuf, Totals> {
+        self.handle.join().unwrap_or_default()
+    }
+}
+
+/// Returns a warning if the directory at `path` is larger than `limit`.
+pub fn check_size(path: &Path, totals: Totals, limit: u64) -> Option<String> {
+    if totals.size <= limit {
+        return None;
+    }
+    Some(format!(
+        "{} holds {} in {}, more than {}. Press Ctrl-C if you didn't mean to share all of it",
+        path.display(),
+        crate::transfer::format_size(totals.size),
+        format_file_count(totals.files),
+        crate::transfer::format_size(limit)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_warn_above_limit(size in 0u64..1 << 40, limit in 0u64..1 << 40) {
+            let totals = Totals { files: 3, size };
+            prop_assert_eq!(size > limit, check_size(Path::new("/srv"), totals, limit).is_some());
+        }
+    }
+
+    #[test]
+    fn test_count_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), b"12345").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub").join("b"), b"123").unwrap();
+        assert_eq!(
+            Totals { files: 2, size: 8 },
+            count_files(dir.path()).unwrap()
+        );
+        assert_eq!(
+            Totals { files: 1, size: 5 },
+            count_files(&dir.path().join("a")).unwrap()
+        );
+        assert!(count_files(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_format_file_count() {
+        assert_eq!("0 files", format_file_count(0));
+        assert_eq!("1 file", format_file_count(1));
+        assert_eq!("3 files", format_file_count(3));
+    }
+
+    #[test]
+    fn test_calculation_skips_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a");
+        fs::write(&file, b"12").unwrap();
+        let totals = SizeCalculation::start(vec![dir.path().to_path_buf(), file]).wait();
+        assert_eq!(1, totals.len());
+        assert_eq!(Some(&Totals { files: 1, size: 2 }), totals.get(dir.path()));
+    }
+}
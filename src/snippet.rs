@@ -0,0 +1,230 @@
+//! Small text shown in the browser instead of downloaded
+//!
+//! Most of the time a tiny file is a snippet or a key that is going to be pasted somewhere. Text
+//! files up to `MAX_INLINE_SIZE` and text given with `--text` are shown to browsers on a page
+//! with a button copying them to the clipboard, and a link to download them all the same. Other
+//! clients, like curl, get the text itself.
+//!
+//! Shares whose downloads are counted, with one-time links, a broadcast or an encryption, are
+//! always downloaded, since looking at the page doesn't use up anything.
+
+use crate::source::{self, MemorySource};
+use crate::transfer;
+use crate::{help, html};
+use bytes::Bytes;
+use hyper::{header, Body, Method, Request, Response};
+use std::convert::Infallible;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Largest file that is shown inline
+pub const MAX_INLINE_SIZE: u64 = 64 * 1024;
+/// Name the text of `--text` is downloaded under unless `--name` is given
+pub const DEFAULT_NAME: &str = "text.txt";
+/// Query parameter of the link that downloads rather than shows the text
+const DOWNLOAD_PARAMETER: &str = "download";
+
+/// Copies the text with the Clipboard API, which browsers only offer on HTTPS pages, and falls
+/// back to selecting it and copying the selection on plain HTTP.
+const SCRIPT: &str = r#"<script>
+const button = document.getElementById("copy");
+const text = document.getElementById("text");
+const copied = () => button.textContent = "Copied";
+const copySelection = () => {
+  const area = document.createElement("textarea");
+  area.value = text.textContent;
+  document.body.appendChild(area);
+  area.select();
+  const done = document.execCommand("copy");
+  area.remove();
+  if (done) {
+    copied();
+  } else {
+    button.textContent = "Select the text to copy it";
+  }
+};
+button.onclick = () => {
+  if (navigator.clipboard) {
+    navigator.clipboard.writeText(text.textContent).then(copied, copySelection);
+  } else {
+    copySelection();
+  }
+};
+</script>
+"#;
+
+/// Text shared with `--text`
+pub struct Snippet {
+    name: String,
+    text: String,
+}
+
+impl Snippet {
+    pub fn new(name: &str, text: String) -> Snippet {
+        Snippet {
+            name: name.to_string(),
+            text,
+        }
+    }
+
+    /// The name and size of the text, for the summary at the start
+    pub fn describe(&self) -> String {
+        format!(
+            "{} ({} of text)",
+            self.name,
+            transfer::format_size(self.text.len() as u64)
+        )
+    }
+}
+
+/// The content of the file at `path` if it is small text, `None` for anything else.
+pub fn read_inline_text(path: &Path) -> Option<String> {
+    let file = fs::File::open(path).ok()?;
+    if !file.metadata().ok()?.is_file() {
+        return None;
+    }
+    let mut content = Vec::new();
+    file.take(MAX_INLINE_SIZE + 1)
+        .read_to_end(&mut content)
+        .ok()?;
+    if content.len() as u64 > MAX_INLINE_SIZE || content.contains(&0) {
+        return None;
+    }
+    String::from_utf8(content).ok()
+}
+
+/// Whether `req` comes from a browser that gets the page, rather than asking for the download
+pub fn wants_page<T>(req: &Request<T>) -> bool {
+    let accepts_html = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|a| a.to_str().ok())
+        .is_some_and(|a| a.contains("text/html"));
+    let download = req.uri().query().is_some_and(|query| {
+        query
+            .split('&')
+            .any(|p| p.split('=').next() == Some(DOWNLOAD_PARAMETER))
+    });
+    req.method() == Method::GET && accepts_html && !download
+}
+
+/// The page showing `text` with a copy button and a link to download it as `name`
+pub fn create_page(name: &str, text: &str) -> String {
+    let content = format!(
+        "<pre id=\"text\">{}</pre>\n\
+         <p><button id=\"copy\" type=\"button\">Copy to clipboard</button>\n\
+         <a href=\"?{}\" download=\"{}\">Download</a></p>\n{}",
+        html::escape(text),
+        DOWNLOAD_PARAMETER,
+        html::escape(name),
+        SCRIPT
+    );
+    html::create_page(name, &content)
+}
+
+/// Answers with the page showing `text`.
+pub fn serve_page(name: &str, text: &str) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(create_page(name, text)))
+        .unwrap()
+}
+
+/// Serves the text of `--text`, as a page to browsers and as it is to everyone else.
+/// The requests a shared text answers, under any path
+pub fn get_routes() -> Vec<help::Route<()>> {
+    vec![help::Route::new(
+        Method::GET,
+        "/",
+        "The text, as a page for browsers and as plain text otherwise",
+        (),
+    )
+    .any_path()]
+}
+
+pub async fn handle_request(
+    snippet: Arc<Snippet>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if let Err(response) = help::find_route(&get_routes(), &req) {
+        return Ok(response);
+    }
+    if wants_page(&req) {
+        return Ok(serve_page(&snippet.name, &snippet.text));
+    }
+    // The download link saves the text, everything else may show it.
+    let content_type = if req.uri().query().is_some() {
+        None
+    } else {
+        Some("text/plain; charset=utf-8")
+    };
+    let content = Bytes::from(snippet.text.clone());
+    let source = MemorySource::new(snippet.name.clone(), content, content_type);
+    Ok(source::serve(Arc::new(source), &req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_page_contains_no_markup_of_the_text(text in "\\PC*") {
+            let page = create_page("key.txt", &text);
+            let start = page.find("<pre id=\"text\">").unwrap();
+            let end = page.find("</pre>").unwrap();
+            prop_assert!(!page[start + 15..end].contains('<'));
+        }
+    }
+
+    #[test]
+    fn test_read_inline_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.pub");
+        fs::write(&path, "ssh-ed25519 AAAA user@host\n").unwrap();
+        assert_eq!(
+            Some(String::from("ssh-ed25519 AAAA user@host\n")),
+            read_inline_text(&path)
+        );
+        fs::write(&path, b"\x89PNG\r\n\x1a\n\0\0").unwrap();
+        assert_eq!(None, read_inline_text(&path));
+        fs::write(&path, vec![b'a'; MAX_INLINE_SIZE as usize + 1]).unwrap();
+        assert_eq!(None, read_inline_text(&path));
+        assert_eq!(None, read_inline_text(dir.path()));
+    }
+
+    #[tokio::test]
+    async fn test_browsers_get_the_page() {
+        let snippet = Arc::new(Snippet::new("key.txt", String::from("se<cret")));
+        let req = Request::get("/")
+            .header(header::ACCEPT, "text/html,application/xhtml+xml")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle_request(snippet.clone(), req).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let page = String::from_utf8(body.to_vec()).unwrap();
+        assert!(page.contains("<pre id=\"text\">se&lt;cret</pre>"));
+
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let response = handle_request(snippet.clone(), req).await.unwrap();
+        assert_eq!(
+            "text/plain; charset=utf-8",
+            response.headers()[header::CONTENT_TYPE]
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&b"se<cret"[..], &body[..]);
+
+        let req = Request::get("/?download")
+            .header(header::ACCEPT, "text/html")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle_request(snippet, req).await.unwrap();
+        assert_eq!(
+            "attachment; filename=\"key.txt\"",
+            response.headers()[header::CONTENT_DISPOSITION]
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! What a download reads from
+//!
+//! Files, archive entries, remote files, devices and generated text are all served through the
+//! `ContentSource` trait. `prepare` answers a GET request for any of them the same way: sources
+//! that know their size get Range and If-Range requests, a Content-Length and their ETag, sources
+//! that don't are streamed as they come. `hash_source` computes the SHA-256 of any source that can
+//! be read more than once, for checksums.
+
+use crate::transfer::{self, ByteRange};
+use crate::{create_content_disposition, create_inline_disposition, create_status_response};
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use hyper::http::response::Builder;
+use hyper::Body;
+use hyper::{header, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+
+pub type SourceFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+pub type ContentStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceInfo {
+    /// Name the content is downloaded under
+    pub name: String,
+    /// Only sources that know their size are served in ranges
+    pub size: Option<u64>,
+    /// ETag of the current version, if it can tell one version from another
+    pub version: Option<String>,
+    /// Shown in the browser if set, downloaded as `application/octet-stream` otherwise
+    pub content_type: Option<String>,
+}
+
+pub trait ContentSource: Send + Sync {
+    fn get_info(&self) -> SourceFuture<'_, SourceInfo>;
+
+    /// Reads the bytes from `start` to `end` inclusive, or everything without a range. Ranges are
+    /// only asked of sources that know their size.
+    fn open(self: Arc<Self>, range: Option<(u64, u64)>) -> SourceFuture<'static, ContentStream>;
+
+    /// Whether reading the content again yields the same bytes, unlike a device
+    fn is_replayable(&self) -> bool {
+        true
+    }
+
+    /// The answer when the content can't be read
+    fn create_error_response(&self, error: &io::Error) -> Response<Body> {
+        match error.kind() {
+            io::ErrorKind::NotFound => {
+                create_status_response(StatusCode::NOT_FOUND, "File not found")
+            }
+            _ => create_status_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not read file"),
+        }
+    }
+}
+
+/// An answer to a GET request whose body hasn't been attached yet, so it can be wrapped
+pub struct Prepared {
+    pub response: Builder,
+    pub body: ContentStream,
+    /// Bytes the body is going to have, if known
+    pub count: Option<u64>,
+    /// Whether the body goes on until the end of the content, rather than being an earlier part
+    pub reaches_end: bool,
+    /// Whether the body is a range of the content
+    pub partial: bool,
+    pub version: Option<String>,
+}
+
+impl Prepared {
+    pub fn into_response(self) -> Response<Body> {
+        self.response.body(Body::wrap_stream(self.body)).unwrap()
+    }
+}
+
+/// Answers `req` with the content of `source`, or the part of it that was asked for.
+pub async fn prepare<S>(source: Arc<S>, req: &Request<Body>) -> Result<Prepared, Response<Body>>
+where
+    S: ContentSource + ?Sized + 'static,
+{
+    let info = match source.get_info().await {
+        Ok(info) => info,
+        Err(e) => return Err(source.create_error_response(&e)),
+    };
+    // A device waking up continues the download on the same link, as long as the content is the
+    // same.
+    let if_range = req.headers().get(header::IF_RANGE);
+    let range = match (info.size, req.headers().get(header::RANGE)) {
+        (Some(size), Some(value))
+            if if_range.is_none_or(|v| {
+                Some(v.as_bytes()) == info.version.as_deref().map(str::as_bytes)
+            }) =>
+        {
+            transfer::parse_range(value.to_str().unwrap_or_default(), size)
+        }
+        _ => ByteRange::Whole,
+    };
+    let (range, count) = match (range, info.size) {
+        (ByteRange::Whole, size) => (None, size),
+        (ByteRange::Part(start, end), _) => (Some((start, end)), Some(end - start + 1)),
+        (ByteRange::Unsatisfiable, size) => {
+            return Err(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes */{}", size.unwrap_or_default()),
+                )
+                .body(Body::empty())
+                .unwrap());
+        }
+    };
+    let replayable = source.is_replayable();
+    let body = match source.clone().open(range).await {
+        Ok(body) => body,
+        Err(e) => return Err(source.create_error_response(&e)),
+    };
+
+    let mut response = match &info.content_type {
+        Some(content_type) => Response::builder()
+            .header(header::CONTENT_TYPE, content_type.as_str())
+            .header(
+                header::CONTENT_DISPOSITION,
+                create_inline_disposition(&info.name),
+            ),
+        None => Response::builder()
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(
+                header::CONTENT_DISPOSITION,
+                create_content_disposition(&info.name),
+            ),
+    };
+    if let Some(count) = count {
+        response = response.header(header::CONTENT_LENGTH, count);
+    }
+    if let Some(size) = info.size {
+        response = response.header(header::ACCEPT_RANGES, "bytes");
+        if let Some((start, end)) = range {
+            response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, size),
+            );
+        }
+    }
+    if let Some(version) = &info.version {
+        response = response.header(header::ETAG, version.as_str());
+    }
+    if !replayable {
+        response = response.header(header::CACHE_CONTROL, "no-cache");
+    }
+    let reaches_end = match (range, info.size) {
+        (Some((_, end)), Some(size)) => end + 1 >= size,
+        _ => true,
+    };
+    Ok(Prepared {
+        response,
+        body,
+        count,
+        reaches_end,
+        partial: range.is_some(),
+        version: info.version,
+    })
+}
+
+/// Answers `req` with the content of `source`.
+pub async fn serve<S>(source: Arc<S>, req: &Request<Body>) -> Response<Body>
+where
+    S: ContentSource + ?Sized + 'static,
+{
+    match prepare(source, req).await {
+        Ok(prepared) => prepared.into_response(),
+        Err(response) => response,
+    }
+}
+
+/// The hex encoded SHA-256 of the whole content of `source`
+pub async fn hash_source<S>(source: Arc<S>) -> io::Result<String>
+where
+    S: ContentSource + ?Sized + 'static,
+{
+    if !source.is_replayable() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The content can only be read once",
+        ));
+    }
+    let mut body = source.open(None).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = body.next().await {
+        hasher.update(&chunk?);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Keeps `count` bytes of `body` after skipping `start`, for sources that can't seek.
+pub fn slice_stream(body: ContentStream, start: u64, count: u64) -> ContentStream {
+    let sliced = stream::unfold(
+        (body, start, count),
+        |(mut body, mut skip, mut left)| async move {
+            while left > 0 {
+                let mut chunk = match body.next().await? {
+                    Ok(chunk) => chunk,
+                    Err(e) => return Some((Err(e), (body, skip, 0))),
+                };
+                if skip >= chunk.len() as u64 {
+                    skip -= chunk.len() as u64;
+                    continue;
+                }
+                let _ = chunk.split_to(skip as usize);
+                skip = 0;
+                chunk.truncate(left.min(chunk.len() as u64) as usize);
+                left -= chunk.len() as u64;
+                return Some((Ok(chunk), (body, skip, left)));
+            }
+            None
+        },
+    );
+    Box::pin(sliced)
+}
+
+/// A file on disk
+pub struct FileSource {
+    path: PathBuf,
+    name: String,
+}
+
+impl FileSource {
+    pub fn new(path: PathBuf, name: String) -> FileSource {
+        FileSource { path, name }
+    }
+}
+
+impl ContentSource for FileSource {
+    fn get_info(&self) -> SourceFuture<'_, SourceInfo> {
+        Box::pin(async move {
+            let metadata = tokio::fs::metadata(&self.path).await?;
+            Ok(SourceInfo {
+                name: self.name.clone(),
+                size: Some(metadata.len()),
+                version: Some(crate::create_etag(&metadata)),
+                content_type: None,
+            })
+        })
+    }
+
+    fn open(self: Arc<Self>, range: Option<(u64, u64)>) -> SourceFuture<'static, ContentStream> {
+        Box::pin(async move {
+            let mut file = tokio::fs::File::open(&self.path).await?;
+            let body: ContentStream = match range {
+                Some((start, end)) => {
+                    file.seek(io::SeekFrom::Start(start)).await?;
+                    Box::pin(transfer::FileStream::new(file.take(end - start + 1)))
+                }
+                None => Box::pin(transfer::FileStream::new(file)),
+            };
+            Ok(body)
+        })
+    }
+}
+
+/// Content held in memory, like generated text
+pub struct MemorySource {
+    name: String,
+    content: Bytes,
+    content_type: Option<String>,
+}
+
+impl MemorySource {
+    pub fn new(name: String, content: Bytes, content_type: Option<&str>) -> MemorySource {
+        MemorySource {
+            name,
+            content,
+            content_type: content_type.map(String::from),
+        }
+    }
+}
+
+impl ContentSource for MemorySource {
+    fn get_info(&self) -> SourceFuture<'_, SourceInfo> {
+        Box::pin(async move {
+            Ok(SourceInfo {
+                name: self.name.clone(),
+                size: Some(self.content.len() as u64),
+                version: None,
+                content_type: self.content_type.clone(),
+            })
+        })
+    }
+
+    fn open(self: Arc<Self>, range: Option<(u64, u64)>) -> SourceFuture<'static, ContentStream> {
+        let content = match range {
+            Some((start, end)) => self.content.slice(start as usize..end as usize + 1),
+            None => self.content.clone(),
+        };
+        Box::pin(async move {
+            let body: ContentStream = Box::pin(stream::once(async move { Ok(content) }));
+            Ok(body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    async fn read_all(body: ContentStream) -> Vec<u8> {
+        body.map(|c| c.unwrap().to_vec()).concat().await
+    }
+
+    fn chunked(data: &[u8], size: usize) -> ContentStream {
+        let chunks = data
+            .chunks(size.max(1))
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect::<Vec<_>>();
+        Box::pin(stream::iter(chunks))
+    }
+
+    proptest! {
+        #[test]
+        fn test_slice_matches_range(
+            data in proptest::collection::vec(any::<u8>(), 1..200),
+            chunk in 1usize..50,
+            a in any::<prop::sample::Index>(),
+            b in any::<prop::sample::Index>(),
+        ) {
+            let (start, end) = {
+                let (a, b) = (a.index(data.len()), b.index(data.len()));
+                (a.min(b), a.max(b))
+            };
+            let body = slice_stream(chunked(&data, chunk), start as u64, (end - start + 1) as u64);
+            let sliced = futures::executor::block_on(read_all(body));
+            prop_assert_eq!(&data[start..=end], &sliced[..]);
+        }
+    }
+
+    fn create_request(range: Option<&str>) -> Request<Body> {
+        let mut request = Request::get("/").body(Body::empty()).unwrap();
+        if let Some(range) = range {
+            request
+                .headers_mut()
+                .insert(header::RANGE, range.parse().unwrap());
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn test_ranges_of_any_source() {
+        let source = Arc::new(MemorySource::new(
+            String::from("notes.txt"),
+            Bytes::from("0123456789"),
+            Some("text/plain"),
+        ));
+        let prepared = prepare(source.clone(), &create_request(Some("bytes=2-4")))
+            .await
+            .ok()
+            .unwrap();
+        assert!(prepared.partial);
+        assert!(!prepared.reaches_end);
+        let response = prepared.into_response();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!("bytes 2-4/10", response.headers()[header::CONTENT_RANGE]);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&b"234"[..], &body[..]);
+
+        let response = serve(source.clone(), &create_request(Some("bytes=10-"))).await;
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, response.status());
+        let response = serve(source.clone(), &create_request(None)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("10", response.headers()[header::CONTENT_LENGTH]);
+        assert_eq!(
+            "inline; filename=\"notes.txt\"",
+            response.headers()[header::CONTENT_DISPOSITION]
+        );
+        assert_eq!(
+            "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
+            hash_source(source).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.bin");
+        std::fs::write(&path, b"abcdef").unwrap();
+        let source = Arc::new(FileSource::new(path, String::from("a.bin")));
+        let info = source.get_info().await.unwrap();
+        let version = info.version.unwrap();
+        let mut request = create_request(Some("bytes=3-"));
+        request
+            .headers_mut()
+            .insert(header::IF_RANGE, version.parse().unwrap());
+        let response = serve(source.clone(), &request).await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&b"def"[..], &body[..]);
+
+        // An old version gets the whole file.
+        request
+            .headers_mut()
+            .insert(header::IF_RANGE, "\"0-0.0\"".parse().unwrap());
+        let response = serve(source.clone(), &request).await;
+        assert_eq!(StatusCode::OK, response.status());
+
+        let missing = Arc::new(FileSource::new(dir.path().join("b"), String::from("b")));
+        let response = serve(missing, &create_request(None)).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+}
@@ -0,0 +1,156 @@
+//! Free space of the disk received files are written to
+//!
+//! An upload that can't fit is refused before its first byte is written, when it announces its
+//! length with Content-Length, instead of failing once the disk is full. While the server runs,
+//! the free space is checked every `CHECK_INTERVAL` and after every received file, and the
+//! terminal is told once it drops below `--min-free` and again once there is enough again.
+//!
+//! Uploads handed to `--store-to` don't touch the local disk and aren't checked.
+
+use crate::{output, transfer};
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Time between two checks while no upload arrives
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The bytes an unprivileged process may still write to the file system holding `path`. A path
+/// that doesn't exist yet is looked up by the closest directory above it that does.
+pub fn get_free_space(path: &Path) -> io::Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let path = CString::new(existing.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The fields are narrower on 32-bit targets.
+    #[allow(clippy::useless_conversion)]
+    let free = u64::from(stats.f_bavail).saturating_mul(u64::from(stats.f_frsize));
+    Ok(free)
+}
+
+/// Watches the free space below the destination of uploads
+pub struct SpaceMonitor {
+    path: PathBuf,
+    /// Free bytes below which the terminal is warned
+    min_free: u64,
+    /// Whether the free space was below `min_free` at the last check
+    low: AtomicBool,
+}
+
+impl SpaceMonitor {
+    pub fn new(path: PathBuf, min_free: u64) -> SpaceMonitor {
+        SpaceMonitor {
+            path,
+            min_free,
+            low: AtomicBool::new(false),
+        }
+    }
+
+    /// Reads the free space and reports when it crossed `min_free`. Returns `None` if the free
+    /// space can't be read, uploads aren't held back then.
+    pub fn check(&self) -> Option<u64> {
+        match get_free_space(&self.path) {
+            Ok(free) => {
+                if let Some(message) = self.update(free) {
+                    output::print_event(&message);
+                }
+                Some(free)
+            }
+            Err(e) => {
+                eprintln!(
+                    "Could not check the free space of {}: {}",
+                    self.path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// The message to print if `free` is on the other side of `min_free` than the last check.
+    fn update(&self, free: u64) -> Option<String> {
+        let low = free < self.min_free;
+        if self.low.swap(low, Ordering::SeqCst) == low {
+            return None;
+        }
+        Some(if low {
+            format!(
+                "Only {} free in {}, uploads will be refused once it is full",
+                transfer::format_size(free),
+                self.path.display()
+            )
+        } else {
+            format!(
+                "{} free in {} again",
+                transfer::format_size(free),
+                self.path.display()
+            )
+        })
+    }
+}
+
+/// Checks the free space every `CHECK_INTERVAL` while the server runs.
+pub async fn watch(monitor: Arc<SpaceMonitor>) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let monitor = monitor.clone();
+        let _ = tokio::task::spawn_blocking(move || monitor.check()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_warns_once_per_crossing(frees in proptest::collection::vec(0u64..200, 1..30)) {
+            let monitor = SpaceMonitor::new(PathBuf::from("/srv"), 100);
+            let mut low = false;
+            for free in frees {
+                let message = monitor.update(free);
+                prop_assert_eq!(low != (free < 100), message.is_some());
+                low = free < 100;
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let free = get_free_space(dir.path()).unwrap();
+        assert!(free > 0);
+        let missing = dir.path().join("not/yet/created");
+        let _ = get_free_space(&missing).unwrap();
+        let _ = get_free_space(Path::new("relative")).unwrap();
+    }
+
+    #[test]
+    fn test_update() {
+        let monitor = SpaceMonitor::new(PathBuf::from("/srv"), 1024);
+        assert_eq!(None, monitor.update(2048));
+        assert_eq!(
+            Some(String::from(
+                "Only 512 bytes free in /srv, uploads will be refused once it is full"
+            )),
+            monitor.update(512)
+        );
+        assert_eq!(None, monitor.update(100));
+        assert!(monitor
+            .update(4096)
+            .unwrap()
+            .ends_with("free in /srv again"));
+    }
+}
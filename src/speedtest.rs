@@ -0,0 +1,216 @@
+//! Measuring the link to the recipient before a large transfer
+//!
+//! With `--speedtest`, `/speedtest` serves a page that times a few small requests, a download of
+//! generated data and an upload that is thrown away, and shows the throughput in both directions.
+//! The data is random so compression along the way can't make the link look faster than it is.
+
+use crate::{help, html};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use rand::RngCore;
+use std::convert::Infallible;
+
+pub const SPEEDTEST_PATH: &str = "/speedtest";
+const DOWNLOAD_PATH: &str = "/speedtest/download";
+const UPLOAD_PATH: &str = "/speedtest/upload";
+const PING_PATH: &str = "/speedtest/ping";
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Size of the download unless the page asks for another one
+const DEFAULT_DOWNLOAD_SIZE: u64 = 32 * 1024 * 1024;
+/// Largest download and upload, so the test can't be used to keep the link busy for long
+const MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+const SCRIPT: &str = r#"<p id="status">Measuring…</p>
+<table>
+<tr><td>Latency</td><td id="latency">–</td></tr>
+<tr><td>Download</td><td id="download">–</td></tr>
+<tr><td>Upload</td><td id="upload">–</td></tr>
+</table>
+<p id="estimate"></p>
+<script>
+const show = (id, text) => document.getElementById(id).textContent = text;
+const mbits = (bytes, ms) => bytes * 8 / ms / 1000;
+async function run() {
+  const pings = [];
+  for (let i = 0; i < 5; i++) {
+    const start = performance.now();
+    await fetch("/speedtest/ping", {cache: "no-store"});
+    pings.push(performance.now() - start);
+  }
+  pings.sort((a, b) => a - b);
+  show("latency", pings[2].toFixed(0) + " ms");
+  let start = performance.now();
+  const data = await (await fetch("/speedtest/download", {cache: "no-store"})).arrayBuffer();
+  const down = mbits(data.byteLength, performance.now() - start);
+  show("download", down.toFixed(1) + " Mbit/s");
+  const upload = new Uint8Array(data.byteLength / 2);
+  upload.set(new Uint8Array(data, 0, upload.length));
+  start = performance.now();
+  await fetch("/speedtest/upload", {method: "POST", body: upload});
+  const up = mbits(upload.length, performance.now() - start);
+  show("upload", up.toFixed(1) + " Mbit/s");
+  show("status", "Done");
+  const minutes = 8 * 1000 / Math.min(down, up) / 60;
+  show("estimate", "A 1 GB file takes about " + minutes.toFixed(1) + " minutes over this link.");
+}
+run().catch(e => show("status", "The test failed: " + e));
+</script>
+"#;
+
+/// What a request of the speed test is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Page,
+    Ping,
+    Download,
+    Upload,
+}
+
+/// The requests the speed test answers, below the share's own
+pub fn get_routes() -> Vec<help::Route<Route>> {
+    vec![
+        help::Route::new(
+            Method::GET,
+            SPEEDTEST_PATH,
+            "Measures the link in both directions",
+            Route::Page,
+        ),
+        help::Route::new(
+            Method::GET,
+            PING_PATH,
+            "Answers right away, for the latency",
+            Route::Ping,
+        ),
+        help::Route::new(
+            Method::GET,
+            DOWNLOAD_PATH,
+            "Random data of the size asked for with ?bytes=",
+            Route::Download,
+        ),
+        help::Route::new(
+            Method::POST,
+            UPLOAD_PATH,
+            "Throws the body away and answers with its size",
+            Route::Upload,
+        ),
+    ]
+}
+
+/// Reads the requested size from a query like `bytes=1048576`.
+fn parse_size(query: Option<&str>) -> u64 {
+    query
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .find_map(|pair| pair.strip_prefix("bytes="))
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_DOWNLOAD_SIZE)
+        .min(MAX_SIZE)
+}
+
+/// Streams `size` bytes of random data, repeating a single random chunk.
+fn create_data_stream(size: u64) -> impl futures::Stream<Item = Result<Bytes, Infallible>> {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    rand::rngs::OsRng.fill_bytes(&mut chunk);
+    let chunk = Bytes::from(chunk);
+    let chunks = size.div_ceil(CHUNK_SIZE as u64);
+    stream::iter((0..chunks).map(move |i| {
+        let left = size - i * CHUNK_SIZE as u64;
+        Ok(chunk.slice(..left.min(CHUNK_SIZE as u64) as usize))
+    }))
+}
+
+/// Reads and discards the body, answering with the number of bytes received.
+async fn absorb_upload(mut body: Body) -> Response<Body> {
+    let mut received = 0;
+    while let Some(chunk) = body.next().await {
+        match chunk {
+            Ok(chunk) => received += chunk.len() as u64,
+            Err(_) => break,
+        }
+        if received > MAX_SIZE {
+            return Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Body::from("Upload too large"))
+                .unwrap();
+        }
+    }
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(
+            serde_json::json!({ "bytes": received }).to_string(),
+        ))
+        .unwrap()
+}
+
+pub async fn handle_request(req: Request<Body>) -> Response<Body> {
+    let response = match help::find_route(&get_routes(), &req) {
+        Ok(Route::Page) => Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(html::create_page("Speed test", SCRIPT))),
+        Ok(Route::Ping) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty()),
+        Ok(Route::Download) => {
+            let size = parse_size(req.uri().query());
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(header::CONTENT_LENGTH, size)
+                .body(Body::wrap_stream(create_data_stream(size)))
+        }
+        Ok(Route::Upload) => return absorb_upload(req.into_body()).await,
+        Err(response) => Ok(response),
+    };
+    let mut response = response.unwrap();
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("no-store"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_size_is_bounded(n: u64) {
+            let query = format!("bytes={}", n);
+            prop_assert_eq!(n.min(MAX_SIZE), parse_size(Some(&query)));
+        }
+    }
+
+    #[test]
+    fn test_default_size() {
+        assert_eq!(DEFAULT_DOWNLOAD_SIZE, parse_size(None));
+        assert_eq!(DEFAULT_DOWNLOAD_SIZE, parse_size(Some("bytes=lots")));
+        assert_eq!(100, parse_size(Some("x=1&bytes=100")));
+    }
+
+    #[tokio::test]
+    async fn test_download_has_requested_size() {
+        let size = 3 * CHUNK_SIZE as u64 + 7;
+        let chunks = create_data_stream(size).collect::<Vec<_>>().await;
+        assert_eq!(4, chunks.len());
+        let total = chunks
+            .iter()
+            .map(|c| c.as_ref().unwrap().len() as u64)
+            .sum::<u64>();
+        assert_eq!(size, total);
+        assert!(create_data_stream(0).collect::<Vec<_>>().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upload_is_counted() {
+        let req = Request::post(UPLOAD_PATH)
+            .body(Body::from(vec![0; 1000]))
+            .unwrap();
+        let response = handle_request(req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&b"{\"bytes\":1000}"[..], &body[..]);
+    }
+}
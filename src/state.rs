@@ -0,0 +1,146 @@
+//! Surviving restarts with `--state-file`
+//!
+//! A long running instance saves what it would lose when it is restarted or crashes: the
+//! one-time links of the share and whether they were used or revoked, how many requests it
+//! answered, and how far interrupted resumable uploads got. On the next start with the same state
+//! file and PATH, the links are valid again, used links stay used, and uploads continue where
+//! their data was last synced to disk. A state file saved for another PATH is ignored and
+//! replaced.
+//!
+//! The upload links of `rustbelt inbox` don't need it, they are kept in their own token file.
+
+use crate::tokens::{self, SavedLink};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often a running instance checks whether its state changed
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An upload kept to be continued, with the length of its temporary file that is on disk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartialUpload {
+    pub path: PathBuf,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// What was shared or received into, the state of something else isn't restored
+    pub path: PathBuf,
+    #[serde(default)]
+    pub requests: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<SavedLink>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uploads: Vec<PartialUpload>,
+}
+
+/// The file the state is saved to, readable only by the owner as it holds the links
+pub struct StateFile {
+    path: PathBuf,
+    /// The shared path, absolute so that a restart from another directory finds its state
+    shared: PathBuf,
+    /// The state of an earlier run for the same path
+    restored: Option<Snapshot>,
+    /// The last state written, to only write changes
+    saved: Mutex<Option<Snapshot>>,
+}
+
+impl StateFile {
+    /// Opens the state file at `path` for the share of `shared` and reads the state of the last
+    /// run, if it was for the same path.
+    pub fn open(path: PathBuf, shared: &Path) -> io::Result<StateFile> {
+        let shared = fs::canonicalize(shared).unwrap_or_else(|_| shared.to_path_buf());
+        let restored = match fs::read_to_string(&path) {
+            Ok(content) => Some(serde_json::from_str::<Snapshot>(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let restored = match restored {
+            Some(snapshot) if snapshot.path != shared => {
+                eprintln!(
+                    "Ignoring the state in {}, it was saved for {}",
+                    path.display(),
+                    snapshot.path.display()
+                );
+                None
+            }
+            restored => restored,
+        };
+        Ok(StateFile {
+            path,
+            shared,
+            saved: Mutex::new(restored.clone()),
+            restored,
+        })
+    }
+
+    pub fn get_restored(&self) -> Option<&Snapshot> {
+        self.restored.as_ref()
+    }
+
+    /// A snapshot of this share, to be filled by the caller
+    pub fn create_snapshot(&self) -> Snapshot {
+        Snapshot {
+            path: self.shared.clone(),
+            ..Snapshot::default()
+        }
+    }
+
+    /// Writes `snapshot` unless it is what was written last. The file is replaced atomically, so
+    /// a crash while saving leaves the previous state.
+    pub fn save(&self, snapshot: Snapshot) -> io::Result<()> {
+        let mut saved = self.saved.lock().unwrap();
+        if saved.as_ref() == Some(&snapshot) {
+            return Ok(());
+        }
+        tokens::write_private(
+            &self.path,
+            serde_json::to_string_pretty(&snapshot)?.as_bytes(),
+        )?;
+        *saved = Some(snapshot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokens::{LinkSet, LinkState};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_save_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let shared = dir.path().join("file.iso");
+        fs::write(&shared, b"data").unwrap();
+
+        let state = StateFile::open(path.clone(), &shared).unwrap();
+        assert_eq!(None, state.get_restored());
+        let links = LinkSet::new(2);
+        links.get_links()[1].mark_used();
+        let mut snapshot = state.create_snapshot();
+        snapshot.requests = 5;
+        snapshot.links = links.save();
+        snapshot.uploads = vec![PartialUpload {
+            path: dir.path().join(".rustbelt-upload-a.part"),
+            offset: 1024,
+        }];
+        state.save(snapshot.clone()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        let restored = StateFile::open(path.clone(), &shared).unwrap();
+        assert_eq!(Some(&snapshot), restored.get_restored());
+        let links = LinkSet::restore(snapshot.links);
+        assert_eq!(LinkState::Used, links.get_links()[1].get_state());
+
+        let other = dir.path().join("other.iso");
+        assert_eq!(None, StateFile::open(path, &other).unwrap().get_restored());
+    }
+}
@@ -0,0 +1,332 @@
+//! Where received files go instead of the receive directory
+//!
+//! With `--store-to`, the upload handler passes every file to a `Storage` rather than writing it
+//! below PATH. `s3://bucket/prefix` puts it into S3 under the prefix, using the credentials
+//! described in `remote`. `exec:COMMAND` pipes it into a shell command, which finds the name of
+//! the file in `RUSTBELT_FILE` and in place of `{file}`.
+//!
+//! The receive directory remains the default. Quarantine, deduplication, scanning, quotas and
+//! resumable uploads work on files there, so they aren't available with another storage.
+
+use crate::receive::shell_quote;
+use crate::remote::{self, Credentials};
+use bytes::Bytes;
+use chrono::Utc;
+use futures::stream::{Stream, StreamExt};
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::error;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::AsyncWriteExt;
+
+/// What `Storage::store` eventually answers, where the file was stored
+pub type StoreFuture<'a> = Pin<Box<dyn Future<Output = io::Result<String>> + Send + 'a>>;
+
+pub trait Storage: Send + Sync {
+    /// Stores `upload` under `name`, which may start with the folder of the uploader, like
+    /// `alice/photo.jpg`.
+    fn store<'a>(&'a self, name: &'a str, upload: Upload) -> StoreFuture<'a>;
+
+    /// Where files end up, for the summary
+    fn describe(&self) -> String;
+}
+
+/// The size and SHA-256 of an upload, complete once its `Upload` has been read to the end
+#[derive(Clone, Default)]
+pub struct UploadSummary {
+    read: Arc<Mutex<(u64, Sha256)>>,
+}
+
+impl UploadSummary {
+    pub fn get_size(&self) -> u64 {
+        self.read.lock().unwrap().0
+    }
+
+    pub fn get_sha256(&self) -> String {
+        format!("{:x}", self.read.lock().unwrap().1.clone().finalize())
+    }
+}
+
+/// The body of an upload, counted and hashed while a storage reads it
+pub struct Upload {
+    body: Body,
+    /// The Content-Length the uploader announced
+    length: Option<u64>,
+    summary: UploadSummary,
+}
+
+impl Upload {
+    pub fn new(body: Body, length: Option<u64>) -> (Upload, UploadSummary) {
+        let summary = UploadSummary::default();
+        let upload = Upload {
+            body,
+            length,
+            summary: summary.clone(),
+        };
+        (upload, summary)
+    }
+}
+
+impl Stream for Upload {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let mut read = this.summary.read.lock().unwrap();
+                read.0 += chunk.len() as u64;
+                read.1.update(&chunk);
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(io::Error::other(e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Opens the storage given to `--store-to`.
+pub fn open(target: &str) -> Result<Arc<dyn Storage>, Box<dyn error::Error>> {
+    if let Some(location) = target.strip_prefix("s3://") {
+        Ok(Arc::new(S3Storage::new(location)?))
+    } else if let Some(command) = target.strip_prefix("exec:") {
+        Ok(Arc::new(CommandStorage {
+            command: command.to_string(),
+        }))
+    } else {
+        Err(format!("Expected s3://BUCKET/PREFIX or exec:COMMAND: {}", target).into())
+    }
+}
+
+/// Puts files into an S3 bucket. A file with the same key is replaced.
+pub struct S3Storage {
+    bucket: String,
+    /// Put in front of every name, ends with a slash unless it is empty
+    prefix: String,
+    credentials: Credentials,
+    endpoint: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+/// Splits `bucket/prefix` into the bucket and a prefix that keys can be appended to.
+fn parse_s3_location(location: &str) -> Option<(&str, String)> {
+    let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+    if bucket.is_empty() {
+        return None;
+    }
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        Some((bucket, String::new()))
+    } else {
+        Some((bucket, format!("{}/", prefix)))
+    }
+}
+
+impl S3Storage {
+    fn new(location: &str) -> Result<S3Storage, Box<dyn error::Error>> {
+        let (bucket, prefix) = parse_s3_location(location).ok_or_else(|| {
+            format!(
+                "Not an S3 location like s3://bucket/prefix: s3://{}",
+                location
+            )
+        })?;
+        Ok(S3Storage {
+            bucket: bucket.to_string(),
+            prefix,
+            credentials: Credentials::from_env()?,
+            endpoint: env::var("AWS_ENDPOINT_URL").ok().filter(|e| !e.is_empty()),
+            client: remote::create_client(),
+        })
+    }
+
+    async fn put(&self, key: String, upload: Upload) -> io::Result<String> {
+        // S3 refuses uploads without a Content-Length, the upload page always sends one.
+        let length = upload.length.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "S3 needs the size of the file")
+        })?;
+        let url = remote::get_s3_url(
+            &self.bucket,
+            &key,
+            &self.credentials.region,
+            self.endpoint.as_deref(),
+        );
+        let url = url.parse::<Uri>().map_err(io::Error::other)?;
+        let host = url.authority().map_or("", |a| a.as_str());
+        let headers = remote::create_s3_headers(
+            &self.credentials,
+            &Method::PUT,
+            host,
+            url.path(),
+            None,
+            "UNSIGNED-PAYLOAD",
+            Utc::now(),
+        );
+        let mut request = Request::put(url.clone()).header(header::CONTENT_LENGTH, length);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let request = request
+            .body(Body::wrap_stream(upload))
+            .map_err(io::Error::other)?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(io::Error::other)?;
+        if !response.status().is_success() {
+            return Err(io::Error::other(format!(
+                "S3 answered {}",
+                response.status()
+            )));
+        }
+        Ok(format!("s3://{}/{}", self.bucket, key))
+    }
+}
+
+impl Storage for S3Storage {
+    fn store<'a>(&'a self, name: &'a str, upload: Upload) -> StoreFuture<'a> {
+        Box::pin(self.put(format!("{}{}", self.prefix, name), upload))
+    }
+
+    fn describe(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+/// Pipes every file into a shell command
+pub struct CommandStorage {
+    command: String,
+}
+
+impl CommandStorage {
+    async fn run(&self, name: &str, mut upload: Upload) -> io::Result<String> {
+        let mut child = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(self.command.replace("{file}", &shell_quote(name)))
+            .env("RUSTBELT_FILE", name)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        while let Some(chunk) = upload.next().await {
+            stdin.write_all(&chunk?).await?;
+        }
+        // The command only sees the end of the file once its stdin is closed.
+        drop(stdin);
+        let status = child.await?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "{} exited with {}",
+                self.command, status
+            )));
+        }
+        Ok(name.to_string())
+    }
+}
+
+impl Storage for CommandStorage {
+    fn store<'a>(&'a self, name: &'a str, upload: Upload) -> StoreFuture<'a> {
+        Box::pin(self.run(name, upload))
+    }
+
+    fn describe(&self) -> String {
+        format!("the command {}", self.command)
+    }
+}
+
+/// A storage for tests, keeping files in memory
+#[cfg(test)]
+pub mod memory {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    pub struct MemoryStorage {
+        files: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    impl MemoryStorage {
+        pub fn get(&self, name: &str) -> Option<Vec<u8>> {
+            self.files.lock().unwrap().get(name).cloned()
+        }
+    }
+
+    impl Storage for MemoryStorage {
+        fn store<'a>(&'a self, name: &'a str, mut upload: Upload) -> StoreFuture<'a> {
+            Box::pin(async move {
+                let mut content = Vec::new();
+                while let Some(chunk) = upload.next().await {
+                    content.extend_from_slice(&chunk?);
+                }
+                self.files.lock().unwrap().insert(name.to_string(), content);
+                Ok(format!("memory:{}", name))
+            })
+        }
+
+        fn describe(&self) -> String {
+            String::from("memory")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_s3_location() {
+        assert_eq!(
+            Some(("bucket", String::from("in/box/"))),
+            parse_s3_location("bucket/in/box")
+        );
+        assert_eq!(
+            Some(("bucket", String::from("in/"))),
+            parse_s3_location("bucket/in/")
+        );
+        assert_eq!(Some(("bucket", String::new())), parse_s3_location("bucket"));
+        assert_eq!(None, parse_s3_location("/key"));
+        assert!(open("ftp://host/dir").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_summary() {
+        let (mut upload, summary) = Upload::new(Body::from("hello"), Some(5));
+        while let Some(chunk) = upload.next().await {
+            chunk.unwrap();
+        }
+        assert_eq!(5, summary.get_size());
+        assert_eq!(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            summary.get_sha256()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = CommandStorage {
+            command: format!("cat > {}/\"$RUSTBELT_FILE\"", dir.path().display()),
+        };
+        let (upload, _) = Upload::new(Body::from("piped"), None);
+        assert_eq!("a.txt", storage.store("a.txt", upload).await.unwrap());
+        assert_eq!(
+            "piped",
+            std::fs::read_to_string(dir.path().join("a.txt")).unwrap()
+        );
+
+        let failing = CommandStorage {
+            command: String::from("cat > /dev/null; exit 3"),
+        };
+        let (upload, _) = Upload::new(Body::from("piped"), None);
+        assert!(failing.store("a.txt", upload).await.is_err());
+    }
+}
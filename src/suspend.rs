@@ -0,0 +1,98 @@
+//! Noticing that the computer was suspended
+//!
+//! A laptop that sleeps through a long receive session often wakes up on another network, or
+//! with another address from DHCP, and the printed QR code leads nowhere. Linux stops
+//! `CLOCK_MONOTONIC` while suspended but keeps counting `CLOCK_BOOTTIME`, so the difference
+//! between them grows by the time spent asleep. Comparing them whenever the interface is checked
+//! notices a resume without listening to logind over D-Bus, and works without a session bus as
+//! well. Other systems count no such difference and never report a resume.
+
+use std::time::Duration;
+
+/// Time asleep below which a resume isn't reported, the clocks drift apart a little anyway
+pub const MIN_SLEEP: Duration = Duration::from_secs(2);
+
+#[cfg(target_os = "linux")]
+fn read_clock(clock: libc::clockid_t) -> Option<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe, clock_gettime only writes the timespec it is given.
+    if unsafe { libc::clock_gettime(clock, &mut time) } != 0 {
+        return None;
+    }
+    Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+/// The time the system spent suspended since it booted.
+#[cfg(target_os = "linux")]
+fn get_suspended_time() -> Option<Duration> {
+    let boot = read_clock(libc::CLOCK_BOOTTIME)?;
+    let monotonic = read_clock(libc::CLOCK_MONOTONIC)?;
+    boot.checked_sub(monotonic)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_suspended_time() -> Option<Duration> {
+    None
+}
+
+/// Tells how long the system slept since the last check
+#[derive(Debug)]
+pub struct SleepClock {
+    suspended: Option<Duration>,
+}
+
+impl Default for SleepClock {
+    fn default() -> SleepClock {
+        SleepClock {
+            suspended: get_suspended_time(),
+        }
+    }
+}
+
+impl SleepClock {
+    /// The time spent asleep since the last check, if the system was suspended in between.
+    pub fn check(&mut self) -> Option<Duration> {
+        self.update(get_suspended_time())
+    }
+
+    fn update(&mut self, suspended: Option<Duration>) -> Option<Duration> {
+        let previous = std::mem::replace(&mut self.suspended, suspended);
+        let asleep = suspended?.checked_sub(previous?)?;
+        Some(asleep).filter(|a| *a >= MIN_SLEEP)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_reports_every_long_sleep(sleeps in proptest::collection::vec(0u64..10_000, 1..20)) {
+            let mut clock = SleepClock { suspended: Some(Duration::from_secs(5)) };
+            let mut total = Duration::from_secs(5);
+            for sleep in sleeps {
+                let sleep = Duration::from_millis(sleep);
+                total += sleep;
+                let reported = clock.update(Some(total));
+                prop_assert_eq!(if sleep >= MIN_SLEEP { Some(sleep) } else { None }, reported);
+            }
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let mut clock = SleepClock::default();
+        assert_eq!(None, clock.check());
+        assert_eq!(None, clock.update(None));
+        assert_eq!(None, clock.update(Some(Duration::from_secs(60))));
+        assert_eq!(
+            Some(Duration::from_secs(3600)),
+            clock.update(Some(Duration::from_secs(3660)))
+        );
+    }
+}
@@ -0,0 +1,462 @@
+//! Two-way synchronisation of a directory with another rustbelt instance
+//!
+//! One side runs `rustbelt sync DIR` and serves its manifest and files, the other side runs
+//! `rustbelt sync DIR --with URL`, compares both manifests and transfers every file that is
+//! missing or differs in the direction chosen by the conflict policy. Deletions are not
+//! propagated.
+
+use crate::manifest::{self, Entry};
+use crate::{
+    create_status_response, help, output, paths, receive, serve_manifest_json, serve_sha256sums,
+    transfer,
+};
+use futures::stream::StreamExt;
+use hyper::{header, Body, Client, Method, Request, Response, StatusCode};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// Which side wins if a file exists on both sides with different content
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    Newer,
+    Local,
+    Remote,
+    Skip,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ConflictPolicy, String> {
+        match s {
+            "newer" => Ok(ConflictPolicy::Newer),
+            "local" => Ok(ConflictPolicy::Local),
+            "remote" => Ok(ConflictPolicy::Remote),
+            "skip" => Ok(ConflictPolicy::Skip),
+            _ => Err(format!("Unknown conflict policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Upload(String),
+    Download(String),
+    Conflict(String),
+}
+
+#[derive(Debug)]
+pub struct PeerResponseError {
+    url: String,
+    status: StatusCode,
+}
+
+impl error::Error for PeerResponseError {}
+
+impl fmt::Display for PeerResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The peer answered {} for {}", self.status, self.url)
+    }
+}
+
+impl PeerResponseError {
+    pub fn new(url: String, status: StatusCode) -> PeerResponseError {
+        PeerResponseError { url, status }
+    }
+
+    pub fn get_status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+/// The directory offered to a peer by the serving side of a sync session
+pub struct SyncRoot {
+    root: PathBuf,
+}
+
+impl SyncRoot {
+    pub fn new(root: PathBuf) -> SyncRoot {
+        SyncRoot { root }
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+}
+
+/// What a request of a synced directory is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Manifest,
+    Sums,
+    ManifestJson,
+    Download,
+    Upload,
+}
+
+/// The requests a synced directory answers
+pub fn get_routes() -> Vec<help::Route<Route>> {
+    use help::Route as R;
+    vec![
+        R::new(
+            Method::GET,
+            "/manifest",
+            "Paths, sizes and hashes of all files",
+            Route::Manifest,
+        ),
+        R::new(
+            Method::GET,
+            "/SHA256SUMS",
+            "Checksums in the format of sha256sum",
+            Route::Sums,
+        ),
+        R::new(
+            Method::GET,
+            manifest::MANIFEST_JSON_PATH,
+            "Paths, sizes, SHA-256 and URLs of all files as JSON",
+            Route::ManifestJson,
+        ),
+        R::new(
+            Method::GET,
+            "/files/<path>",
+            "Downloads a file",
+            Route::Download,
+        ),
+        R::new(
+            Method::PUT,
+            "/files/<path>",
+            "Stores the body as the file",
+            Route::Upload,
+        ),
+    ]
+}
+
+pub async fn handle_request(
+    sync_root: Arc<SyncRoot>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let route = match help::find_route(&get_routes(), &req) {
+        Ok(route) => route,
+        Err(response) => return Ok(response),
+    };
+    match route {
+        Route::Manifest => return Ok(serve_manifest(sync_root.root.clone()).await),
+        Route::Sums => {
+            let root = sync_root.root.clone();
+            return Ok(serve_sha256sums(move || manifest::create_manifest(&root), &req).await);
+        }
+        Route::ManifestJson => {
+            let root = sync_root.root.clone();
+            return Ok(serve_manifest_json(
+                move || {
+                    let entries = manifest::create_manifest(&root)?;
+                    Ok(entries
+                        .into_iter()
+                        .map(|e| {
+                            let url = format!("/files/{}", paths::percent_encode_path(&e.path));
+                            (e, url)
+                        })
+                        .collect())
+                },
+                &req,
+            )
+            .await);
+        }
+        Route::Download | Route::Upload => {}
+    }
+    let path = req.uri().path().to_string();
+    let relative = match path
+        .strip_prefix("/files/")
+        .and_then(paths::get_relative_path)
+    {
+        Some(r) => r,
+        None => return Ok(create_status_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let file_path = sync_root.root.join(&relative);
+    if route == Route::Download {
+        return Ok(serve_sync_file(&file_path).await);
+    }
+    match write_body(&file_path, req.into_body()).await {
+        Ok(_) => {
+            output::print_event(&format!("Received {}", relative.display()));
+            Ok(create_status_response(StatusCode::CREATED, "Received"))
+        }
+        Err(e) => {
+            eprintln!("Failed to receive {}: {}", relative.display(), e);
+            Ok(create_status_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to store the file",
+            ))
+        }
+    }
+}
+
+async fn serve_manifest(root: PathBuf) -> Response<Body> {
+    let manifest = tokio::task::spawn_blocking(move || manifest::create_manifest(&root)).await;
+    match manifest {
+        Ok(Ok(entries)) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&entries).unwrap()))
+            .unwrap(),
+        _ => create_status_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Could not create manifest",
+        ),
+    }
+}
+
+async fn serve_sync_file(path: &Path) -> Response<Body> {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(f) => f,
+        Err(_) => return create_status_response(StatusCode::NOT_FOUND, "File not found"),
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body(Body::wrap_stream(transfer::FileStream::new(file)))
+        .unwrap()
+}
+
+/// Stores a request or response body at `path`, creating missing parent directories. The body
+/// goes into a temporary file next to `path` first, which only replaces it once it is complete.
+pub async fn write_body(path: &Path, body: Body) -> io::Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    tokio::fs::create_dir_all(dir).await?;
+    let temp_path = dir.join(receive::create_temp_name());
+    let result = match write_temp_file(&temp_path, body).await {
+        Ok(()) => tokio::fs::rename(&temp_path, path).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+    receive::sync_directory(dir)
+}
+
+/// Streams `body` into the new file at `path` and syncs it to disk.
+async fn write_temp_file(path: &Path, mut body: Body) -> io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await?;
+    while let Some(chunk) = body.next().await {
+        file.write_all(&chunk.map_err(io::Error::other)?).await?;
+    }
+    file.flush().await?;
+    file.sync_all().await
+}
+
+/// Drops the entries of the peer's manifest whose path would lead out of the synchronised
+/// directory, like `../x` or `/etc/x`.
+fn remove_unsafe_entries(remote: Vec<Entry>) -> Vec<Entry> {
+    remote
+        .into_iter()
+        .filter(|entry| {
+            let safe = !entry.path.starts_with('/')
+                && paths::get_relative_path(&paths::percent_encode_path(&entry.path)).is_some();
+            if !safe {
+                eprintln!(
+                    "Skipped {:?} from the peer, it leads out of the directory",
+                    entry.path
+                );
+            }
+            safe
+        })
+        .collect()
+}
+
+/// Decides what to transfer given the manifests of both sides.
+fn plan_sync(local: &[Entry], remote: &[Entry], policy: ConflictPolicy) -> Vec<Action> {
+    let mut pairs = BTreeMap::<&str, (Option<&Entry>, Option<&Entry>)>::new();
+    for entry in local {
+        pairs.entry(&entry.path).or_default().0 = Some(entry);
+    }
+    for entry in remote {
+        pairs.entry(&entry.path).or_default().1 = Some(entry);
+    }
+
+    let mut actions = Vec::new();
+    for (path, pair) in pairs {
+        let path = path.to_string();
+        match pair {
+            (Some(_), None) => actions.push(Action::Upload(path)),
+            (None, Some(_)) => actions.push(Action::Download(path)),
+            (Some(l), Some(r)) if l.sha256 != r.sha256 => actions.push(match policy {
+                ConflictPolicy::Local => Action::Upload(path),
+                ConflictPolicy::Remote => Action::Download(path),
+                ConflictPolicy::Newer if l.modified > r.modified => Action::Upload(path),
+                ConflictPolicy::Newer if l.modified < r.modified => Action::Download(path),
+                _ => Action::Conflict(path),
+            }),
+            _ => {}
+        }
+    }
+    actions
+}
+
+/// Synchronises `root` with the rustbelt instance serving `peer`.
+#[tokio::main]
+pub async fn run_sync_client(
+    root: &Path,
+    peer: &str,
+    policy: ConflictPolicy,
+) -> Result<(), Box<dyn error::Error>> {
+    let peer = peer.trim_end_matches('/');
+    let client = Client::new();
+
+    let manifest_root = root.to_path_buf();
+    let local =
+        tokio::task::spawn_blocking(move || manifest::create_manifest(&manifest_root)).await??;
+    let manifest_url = format!("{}/manifest", peer);
+    let response = client.get(manifest_url.parse()?).await?;
+    if !response.status().is_success() {
+        return Err(PeerResponseError::new(manifest_url, response.status()).into());
+    }
+    let remote: Vec<Entry> = serde_json::from_slice(
+        &transfer::read_limited(response.into_body(), transfer::MAX_JSON_SIZE).await?,
+    )?;
+    let remote = remove_unsafe_entries(remote);
+
+    let (mut uploaded, mut downloaded, mut conflicts) = (0, 0, 0);
+    for action in plan_sync(&local, &remote, policy) {
+        match action {
+            Action::Upload(path) => {
+                let url = format!("{}/files/{}", peer, paths::percent_encode_path(&path));
+                let file = tokio::fs::File::open(root.join(&path)).await?;
+                let length = file.metadata().await?.len();
+                let request = Request::put(url.as_str())
+                    .header(header::CONTENT_LENGTH, length)
+                    .body(Body::wrap_stream(transfer::FileStream::new(file)))?;
+                let response = client.request(request).await?;
+                if !response.status().is_success() {
+                    return Err(PeerResponseError::new(url, response.status()).into());
+                }
+                eprintln!("Sent {}", path);
+                uploaded += 1;
+            }
+            Action::Download(path) => {
+                let url = format!("{}/files/{}", peer, paths::percent_encode_path(&path));
+                let response = client.get(url.parse()?).await?;
+                if !response.status().is_success() {
+                    return Err(PeerResponseError::new(url, response.status()).into());
+                }
+                write_body(&root.join(&path), response.into_body()).await?;
+                eprintln!("Received {}", path);
+                downloaded += 1;
+            }
+            Action::Conflict(path) => {
+                eprintln!("Skipped conflicting file {}", path);
+                conflicts += 1;
+            }
+        }
+    }
+
+    eprintln!(
+        "Sent {} and received {} files, {} conflicts skipped",
+        uploaded, downloaded, conflicts
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn entry(path: &str, modified: u64, sha256: &str) -> Entry {
+        Entry {
+            path: String::from(path),
+            size: 0,
+            modified,
+            sha256: String::from(sha256),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_plan_sync_identical(paths in proptest::collection::btree_set("[a-z]{1,8}", 0..10)) {
+            let entries = paths.iter().map(|p| entry(p, 0, p)).collect::<Vec<_>>();
+            prop_assert!(plan_sync(&entries, &entries, ConflictPolicy::Newer).is_empty());
+        }
+
+        #[test]
+        fn test_plan_sync_conflict_newer(local_time: u64, remote_time: u64) {
+            let actions = plan_sync(
+                &[entry("a", local_time, "1")],
+                &[entry("a", remote_time, "2")],
+                ConflictPolicy::Newer,
+            );
+            let expected = if local_time > remote_time {
+                Action::Upload(String::from("a"))
+            } else if local_time < remote_time {
+                Action::Download(String::from("a"))
+            } else {
+                Action::Conflict(String::from("a"))
+            };
+            prop_assert_eq!(vec![expected], actions);
+        }
+    }
+
+    #[test]
+    fn test_plan_sync_one_sided() {
+        let actions = plan_sync(
+            &[entry("local", 0, "1")],
+            &[entry("remote", 0, "2")],
+            ConflictPolicy::Skip,
+        );
+        assert_eq!(
+            vec![
+                Action::Upload(String::from("local")),
+                Action::Download(String::from("remote"))
+            ],
+            actions
+        );
+    }
+
+    #[test]
+    fn test_remove_unsafe_entries() {
+        let remote = vec![
+            entry("../../.bashrc", 0, "1"),
+            entry("/home/u/.ssh/authorized_keys", 0, "2"),
+            entry("a/../../b", 0, "3"),
+            entry("a//b", 0, "4"),
+            entry("a\\..\\b", 0, "5"),
+            entry("docs/100% done.txt", 0, "6"),
+        ];
+        let actions = plan_sync(&[], &remove_unsafe_entries(remote), ConflictPolicy::Skip);
+        assert_eq!(
+            vec![Action::Download(String::from("docs/100% done.txt"))],
+            actions
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_body_keeps_file_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "old").unwrap();
+        let body = Body::wrap_stream(futures::stream::iter(vec![
+            Ok(b"ne".to_vec()),
+            Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+        ]));
+        assert!(write_body(&path, body).await.is_err());
+        assert_eq!("old", std::fs::read_to_string(&path).unwrap());
+        write_body(&path, Body::from("new")).await.unwrap();
+        assert_eq!("new", std::fs::read_to_string(&path).unwrap());
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_conflict_policy_from_str() {
+        assert_eq!(Ok(ConflictPolicy::Remote), "remote".parse());
+        assert!("other".parse::<ConflictPolicy>().is_err());
+    }
+}
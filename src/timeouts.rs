@@ -0,0 +1,431 @@
+//! Timeouts for connections to clients that went away
+//!
+//! Phones often go to sleep in the middle of a download and leave the connection open without
+//! reading from it. hyper keeps such connections forever. Every connection is wrapped into a
+//! `TimeoutStream` that fails once the client didn't accept data for `--write-timeout` seconds, or
+//! neither side sent anything for `--read-timeout` seconds while waiting for the client. TCP
+//! keep-alive probes notice clients that vanished from the network. A woken phone then continues
+//! the download with a range request on the same link.
+//!
+//! Clients that trickle in their request keep a connection open just as well. They get
+//! `--header-timeout` seconds to send the headers of a request, from its first byte or from
+//! connecting, and the body of a request may not stall for longer than `--body-timeout` seconds.
+//! Connections waiting for the headers of a request count as half-open, new connections are
+//! refused while `--max-half-open` of them are. Every dropped connection is reported.
+
+use crate::output;
+use bytes::Bytes;
+use futures::stream::Stream;
+use hyper::body::HttpBody;
+use hyper::{Body, Request};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::num::ParseIntError;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Delay, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    /// How long a connection may stay idle while waiting for the client
+    pub read: Option<Duration>,
+    /// How long the client may leave data unaccepted
+    pub write: Option<Duration>,
+    /// Idle time before TCP keep-alive probes are sent
+    pub keep_alive: Option<Duration>,
+    /// How long a client may take to send the headers of a request
+    pub header: Option<Duration>,
+    /// How long the body of a request may stall
+    pub body: Option<Duration>,
+    /// How long running transfers may take to finish once the server shuts down
+    pub drain: Option<Duration>,
+}
+
+/// Parses a number of seconds, where 0 stands for no timeout.
+pub fn parse_seconds(seconds: &str) -> Result<Option<Duration>, ParseIntError> {
+    match seconds.parse()? {
+        0 => Ok(None),
+        seconds => Ok(Some(Duration::from_secs(seconds))),
+    }
+}
+
+/// Polls `delay`, moved to `deadline` first, and returns whether it has passed.
+fn poll_deadline(delay: &mut Option<Delay>, deadline: Instant, cx: &mut Context) -> bool {
+    let delay = delay.get_or_insert_with(|| tokio::time::delay_until(deadline));
+    if delay.deadline() != deadline {
+        delay.reset(deadline);
+    }
+    Pin::new(delay).poll(cx).is_ready()
+}
+
+/// What a connection is doing, shared between the connection and the requests on it
+pub struct ConnectionState {
+    peer: Option<SocketAddr>,
+    /// Requests on the connection that are being handled
+    requests: AtomicUsize,
+    /// Since when the connection waits for the headers of a request, while it does
+    waiting_since: Mutex<Option<Instant>>,
+    /// Connections of the server waiting for headers
+    half_open: Arc<AtomicUsize>,
+}
+
+impl ConnectionState {
+    fn new(peer: Option<SocketAddr>, half_open: Arc<AtomicUsize>) -> ConnectionState {
+        let state = ConnectionState {
+            peer,
+            requests: AtomicUsize::new(0),
+            waiting_since: Mutex::new(None),
+            half_open,
+        };
+        // Clients connect to send a request, so they are waited for right away.
+        state.wait_for_headers();
+        state
+    }
+
+    fn wait_for_headers(&self) {
+        let mut waiting_since = self.waiting_since.lock().unwrap();
+        if waiting_since.is_none() {
+            *waiting_since = Some(Instant::now());
+            self.half_open.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn stop_waiting(&self) {
+        if self.waiting_since.lock().unwrap().take().is_some() {
+            self.half_open.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn get_waiting_since(&self) -> Option<Instant> {
+        *self.waiting_since.lock().unwrap()
+    }
+
+    /// Counts a request whose headers have arrived until the returned guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> Arc<RequestGuard> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        self.stop_waiting();
+        Arc::new(RequestGuard(self.clone()))
+    }
+
+    /// Reports that the connection, or what it carried, was dropped.
+    fn report_drop(&self, what: &str, reason: &str) {
+        if let Some(peer) = self.peer {
+            output::print_event(&format!("Dropped {} of {}: {}", what, peer.ip(), reason));
+        }
+    }
+}
+
+impl Drop for ConnectionState {
+    fn drop(&mut self) {
+        self.stop_waiting();
+    }
+}
+
+/// Keeps a request counted while it is handled or its body is read
+pub struct RequestGuard(Arc<ConnectionState>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A request body failing once the client stopped sending it for too long
+struct ProgressBody {
+    body: Body,
+    timeout: Option<Duration>,
+    delay: Option<Delay>,
+    request: Arc<RequestGuard>,
+}
+
+impl Stream for ProgressBody {
+    type Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.body).poll_next(cx);
+        if result.is_ready() {
+            this.delay = None;
+            return result.map(|item| item.map(|chunk| chunk.map_err(|e| e.into())));
+        }
+        let timeout = match this.timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+        // The delay starts when the body is waited for, senders paired with a late recipient
+        // aren't read before.
+        let deadline = match &this.delay {
+            Some(delay) => delay.deadline(),
+            None => Instant::now() + timeout,
+        };
+        if poll_deadline(&mut this.delay, deadline, cx) {
+            let reason = format!("no data for {} seconds", timeout.as_secs());
+            this.request.0.report_drop("the request body", &reason);
+            return Poll::Ready(Some(Err(Box::new(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The client stopped sending",
+            )))));
+        }
+        Poll::Pending
+    }
+}
+
+/// Applies the body timeout to the body of `req`, which keeps the request counted until the body
+/// has been read.
+pub fn watch_body(
+    req: Request<Body>,
+    timeout: Option<Duration>,
+    request: Arc<RequestGuard>,
+) -> Request<Body> {
+    if req.body().is_end_stream() {
+        return req;
+    }
+    let (parts, body) = req.into_parts();
+    let body = Body::wrap_stream(ProgressBody {
+        body,
+        timeout,
+        delay: None,
+        request,
+    });
+    Request::from_parts(parts, body)
+}
+
+/// A connection failing reads and writes once the client stalls for too long
+pub struct TimeoutStream<S> {
+    inner: S,
+    timeouts: Timeouts,
+    state: Arc<ConnectionState>,
+    header_delay: Option<Delay>,
+    last_activity: Instant,
+    read_delay: Option<Delay>,
+    /// When the pending write started
+    write_pending: Option<Instant>,
+    write_delay: Option<Delay>,
+}
+
+impl<S> TimeoutStream<S> {
+    pub fn new(inner: S, timeouts: Timeouts) -> TimeoutStream<S> {
+        TimeoutStream {
+            inner,
+            timeouts,
+            state: Arc::new(ConnectionState::new(None, Arc::default())),
+            header_delay: None,
+            last_activity: Instant::now(),
+            read_delay: None,
+            write_pending: None,
+            write_delay: None,
+        }
+    }
+
+    /// Reports drops of the connection from `peer`, counting it in `half_open` while it waits
+    /// for headers.
+    pub fn with_peer(mut self, peer: SocketAddr, half_open: Arc<AtomicUsize>) -> TimeoutStream<S> {
+        self.state = Arc::new(ConnectionState::new(Some(peer), half_open));
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_state(&self) -> Arc<ConnectionState> {
+        self.state.clone()
+    }
+
+    /// Fails the connection if the client took too long to send the headers of a request.
+    fn check_headers(&mut self, read: &Poll<io::Result<usize>>, cx: &mut Context) -> bool {
+        if let Poll::Ready(Ok(n)) = read {
+            // Data while no request is handled starts the next one.
+            if *n > 0 && self.state.requests.load(Ordering::SeqCst) == 0 {
+                self.state.wait_for_headers();
+            }
+        }
+        let (timeout, since) = match (self.timeouts.header, self.state.get_waiting_since()) {
+            (Some(timeout), Some(since)) => (timeout, since),
+            _ => return false,
+        };
+        poll_deadline(&mut self.header_delay, since + timeout, cx)
+    }
+
+    /// Turns the result of a write into a timeout error if the client accepted nothing for too
+    /// long.
+    fn check_write<T>(
+        &mut self,
+        result: Poll<io::Result<T>>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<T>> {
+        if result.is_ready() {
+            self.write_pending = None;
+            self.last_activity = Instant::now();
+            return result;
+        }
+        let timeout = match self.timeouts.write {
+            Some(timeout) => timeout,
+            None => return result,
+        };
+        let since = *self.write_pending.get_or_insert_with(Instant::now);
+        if poll_deadline(&mut self.write_delay, since + timeout, cx) {
+            self.state
+                .report_drop("the connection", "it stopped receiving");
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The client stopped receiving",
+            )));
+        }
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if this.check_headers(&result, cx) {
+            this.state
+                .report_drop("the connection", "it didn't send its request in time");
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The client didn't send its request in time",
+            )));
+        }
+        if result.is_ready() {
+            this.last_activity = Instant::now();
+            return result;
+        }
+        let timeout = match this.timeouts.read {
+            Some(timeout) => timeout,
+            None => return result,
+        };
+        // Writes keep the connection alive as well, so the deadline moves with them.
+        if poll_deadline(&mut this.read_delay, this.last_activity + timeout, cx) {
+            this.state
+                .report_drop("the connection", "it was idle for too long");
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The connection was idle for too long",
+            )));
+        }
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.check_write(result, cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_flush(cx);
+        this.check_write(result, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    proptest! {
+        #[test]
+        fn test_parse_seconds(seconds in 1u64..1_000_000) {
+            prop_assert_eq!(
+                Ok(Some(Duration::from_secs(seconds))),
+                parse_seconds(&seconds.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_zero_disables() {
+        assert_eq!(Ok(None), parse_seconds("0"));
+        assert!(parse_seconds("soon").is_err());
+    }
+
+    async fn connect() -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::net::TcpStream::connect(address).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_idle_read_times_out() {
+        let (_client, server) = connect().await;
+        let timeouts = Timeouts {
+            read: Some(Duration::from_millis(50)),
+            ..Timeouts::default()
+        };
+        let mut stream = TimeoutStream::new(server, timeouts);
+        let mut buffer = [0u8; 4];
+        let error = stream.read(&mut buffer).await.unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body_fails() {
+        let (mut sender, body) = Body::channel();
+        let state = Arc::new(ConnectionState::new(None, Arc::default()));
+        let request = state.start_request();
+        let req = Request::put("/").body(body).unwrap();
+        let req = watch_body(req, Some(Duration::from_millis(50)), request);
+        sender.send_data(Bytes::from("data")).await.unwrap();
+        let mut body = req.into_body();
+        assert_eq!(Bytes::from("data"), body.data().await.unwrap().unwrap());
+        assert!(body.data().await.unwrap().is_err());
+        assert_eq!(1, state.requests.load(Ordering::SeqCst));
+        drop(body);
+        assert_eq!(0, state.requests.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_half_open_connections_are_counted() {
+        let half_open = Arc::new(AtomicUsize::new(0));
+        let state = Arc::new(ConnectionState::new(None, half_open.clone()));
+        let other = ConnectionState::new(None, half_open.clone());
+        assert_eq!(2, half_open.load(Ordering::SeqCst));
+        let request = state.start_request();
+        assert_eq!(1, half_open.load(Ordering::SeqCst));
+        drop(request);
+        state.wait_for_headers();
+        drop((state, other));
+        assert_eq!(0, half_open.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_active_connection_stays_open() {
+        let (mut client, server) = connect().await;
+        let timeouts = Timeouts {
+            read: Some(Duration::from_millis(200)),
+            write: Some(Duration::from_millis(200)),
+            ..Timeouts::default()
+        };
+        let mut stream = TimeoutStream::new(server, timeouts);
+        tokio::spawn(async move {
+            for _ in 0..3u8 {
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+                client.write_all(b"x").await.unwrap();
+            }
+        });
+        let mut buffer = [0u8; 3];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"xxx", &buffer);
+    }
+}
@@ -0,0 +1,420 @@
+//! Random tokens granting access below `/<token>/`
+//!
+//! A `Token` grants one `Scope`, optionally until it expires, and may carry the name of whom it
+//! was given to. Tokens that have to outlive the process, like the upload links of `rustbelt
+//! inbox`, are kept in a `TokenStore` backed by a file.
+//!
+//! With `--tokens N` the file is only served below `/<token>/` for N random tokens. Every link
+//! can be used for one complete download and is tracked and revoked on its own, so each
+//! recipient in a group gets their own link. With `--state-file` the links and their states are
+//! saved, so they stay valid, or used, across restarts.
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of random bytes in a token, encoded as 16 URL safe characters
+const TOKEN_BYTES: usize = 12;
+
+/// Creates a random token that can be used as a URL path segment.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Splits a request path into the token and the path below it.
+pub fn split_token(path: &str) -> Option<(&str, &str)> {
+    let path = path.strip_prefix('/')?;
+    match path.find('/') {
+        Some(i) => Some((&path[..i], &path[i..])),
+        None => Some((path, "/")),
+    }
+}
+
+/// Replaces the file at `path` with `content` that only the owner may read. It is written to a
+/// temporary file created with these permissions and renamed over `path`, so it is never readable
+/// by others, and a crash while saving leaves the previous version.
+pub fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    // The mode only applies to new files, a leftover of a crash may have others.
+    match fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// What a token grants access to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Download,
+    Upload,
+    /// Everything the other scopes grant, and managing the share
+    Admin,
+}
+
+fn get_unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    pub value: String,
+    pub scope: Scope,
+    /// Whom the token was given to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Seconds since the Unix epoch from which on the token is rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// Where uploads through the token are stored, like `photos/{date}/{original}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+}
+
+impl Token {
+    pub fn new(scope: Scope) -> Token {
+        Token {
+            value: generate_token(),
+            scope,
+            label: None,
+            expires: None,
+            destination: None,
+        }
+    }
+
+    pub fn with_label(mut self, label: &str) -> Token {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Lets the token expire after `lifetime`, or never if that is beyond what time can hold.
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Token {
+        self.expires = SystemTime::now().checked_add(lifetime).map(get_unix_time);
+        self
+    }
+
+    pub fn with_destination(mut self, destination: &str) -> Token {
+        self.destination = Some(destination.to_string());
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| get_unix_time(SystemTime::now()) >= expires)
+    }
+
+    /// Whether the token is valid and grants `scope`
+    pub fn grants(&self, scope: Scope) -> bool {
+        !self.is_expired() && (self.scope == scope || self.scope == Scope::Admin)
+    }
+
+    /// The URL below which the token grants access
+    pub fn get_url(&self, base_url: &str) -> String {
+        format!("{}/{}/", base_url, self.value)
+    }
+}
+
+/// Tokens kept in a file, readable only by the owner as they grant access
+pub struct TokenStore {
+    path: PathBuf,
+    tokens: Mutex<Vec<Token>>,
+}
+
+impl TokenStore {
+    /// Loads the tokens stored at `path`, without the expired ones.
+    pub fn open(path: PathBuf) -> io::Result<TokenStore> {
+        let tokens = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str::<Vec<Token>>(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(TokenStore {
+            path,
+            tokens: Mutex::new(tokens.into_iter().filter(|t| !t.is_expired()).collect()),
+        })
+    }
+
+    fn save(&self, tokens: &[Token]) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_private(&self.path, serde_json::to_string_pretty(tokens)?.as_bytes())
+    }
+
+    pub fn get_tokens(&self) -> Vec<Token> {
+        self.tokens.lock().unwrap().clone()
+    }
+
+    /// Adds `token` and stores it, replacing the token with the same label if there is one.
+    pub fn insert(&self, token: Token) -> io::Result<()> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let previous = tokens.clone();
+        if token.label.is_some() {
+            tokens.retain(|t| t.label != token.label);
+        }
+        tokens.push(token);
+        if let Err(e) = self.save(&tokens) {
+            *tokens = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Removes the token `value`, returning it if there was one.
+    pub fn remove(&self, value: &str) -> io::Result<Option<Token>> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let index = match tokens.iter().position(|t| t.value == value) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let removed = tokens.remove(index);
+        if let Err(e) = self.save(&tokens) {
+            tokens.insert(index, removed);
+            return Err(e);
+        }
+        Ok(Some(removed))
+    }
+
+    /// Finds the token `value` if it grants `scope`.
+    pub fn find(&self, value: &str, scope: Scope) -> Option<Token> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens
+            .iter()
+            .find(|t| t.value == value && t.grants(scope))
+            .cloned()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkState {
+    Unused,
+    Used,
+    Revoked,
+}
+
+#[derive(Debug)]
+pub struct Link {
+    pub token: Token,
+    used: AtomicBool,
+    revoked: AtomicBool,
+}
+
+/// A link as kept in the state file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedLink {
+    pub token: Token,
+    pub state: LinkState,
+}
+
+impl Link {
+    fn new(token: Token) -> Link {
+        Link {
+            token,
+            used: AtomicBool::new(false),
+            revoked: AtomicBool::new(false),
+        }
+    }
+
+    fn restore(saved: SavedLink) -> Link {
+        Link {
+            token: saved.token,
+            used: AtomicBool::new(saved.state == LinkState::Used),
+            revoked: AtomicBool::new(saved.state == LinkState::Revoked),
+        }
+    }
+
+    pub fn get_state(&self) -> LinkState {
+        if self.revoked.load(Ordering::SeqCst) {
+            LinkState::Revoked
+        } else if self.used.load(Ordering::SeqCst) {
+            LinkState::Used
+        } else {
+            LinkState::Unused
+        }
+    }
+
+    pub fn mark_used(&self) {
+        self.used.store(true, Ordering::SeqCst);
+    }
+
+    pub fn revoke(&self) {
+        self.revoked.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The links a file is shared with
+#[derive(Debug)]
+pub struct LinkSet {
+    links: Vec<Link>,
+}
+
+impl LinkSet {
+    pub fn new(count: usize) -> LinkSet {
+        LinkSet {
+            links: (0..count)
+                .map(|_| Link::new(Token::new(Scope::Download)))
+                .collect(),
+        }
+    }
+
+    /// Continues with the links of an earlier run.
+    pub fn restore(saved: Vec<SavedLink>) -> LinkSet {
+        LinkSet {
+            links: saved.into_iter().map(Link::restore).collect(),
+        }
+    }
+
+    pub fn get_links(&self) -> &[Link] {
+        &self.links
+    }
+
+    pub fn save(&self) -> Vec<SavedLink> {
+        self.links
+            .iter()
+            .map(|l| SavedLink {
+                token: l.token.clone(),
+                state: l.get_state(),
+            })
+            .collect()
+    }
+
+    /// Finds the link a request path belongs to and returns its index with the path below the
+    /// token.
+    pub fn find<'a>(&self, path: &'a str) -> Option<(usize, &'a str)> {
+        let (token, rest) = split_token(path)?;
+        let index = self
+            .links
+            .iter()
+            .position(|l| l.token.value == token && l.token.grants(Scope::Download))?;
+        Some((index, rest))
+    }
+
+    /// Whether no link can be used anymore
+    pub fn is_exhausted(&self) -> bool {
+        self.links
+            .iter()
+            .all(|l| l.get_state() != LinkState::Unused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    proptest! {
+        #[test]
+        fn test_find(count in 1usize..10, index in 0usize..10, rest in "(/[a-z]{1,5}){0,3}") {
+            let links = LinkSet::new(count);
+            let index = index % count;
+            let token = &links.get_links()[index].token.value;
+            let path = format!("/{}{}", token, rest);
+            let (found, found_rest) = links.find(&path).unwrap();
+            prop_assert_eq!(index, found);
+            prop_assert_eq!(if rest.is_empty() { "/" } else { rest.as_str() }, found_rest);
+        }
+    }
+
+    #[test]
+    fn test_generate_token() {
+        let token = generate_token();
+        assert_eq!(16, token.len());
+        assert!(token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_ne!(token, generate_token());
+    }
+
+    #[test]
+    fn test_link_states() {
+        let links = LinkSet::new(2);
+        assert!(links.find("/wrong/").is_none());
+        assert!(links.find("").is_none());
+        assert!(!links.is_exhausted());
+        links.get_links()[0].mark_used();
+        links.get_links()[1].revoke();
+        assert_eq!(LinkState::Used, links.get_links()[0].get_state());
+        assert_eq!(LinkState::Revoked, links.get_links()[1].get_state());
+        assert!(links.is_exhausted());
+
+        let restored = LinkSet::restore(links.save());
+        assert_eq!(links.get_links()[0].token, restored.get_links()[0].token);
+        assert_eq!(LinkState::Used, restored.get_links()[0].get_state());
+        assert_eq!(LinkState::Revoked, restored.get_links()[1].get_state());
+    }
+
+    #[test]
+    fn test_scopes_and_expiry() {
+        let upload = Token::new(Scope::Upload);
+        assert!(upload.grants(Scope::Upload));
+        assert!(!upload.grants(Scope::Download));
+        assert!(Token::new(Scope::Admin).grants(Scope::Download));
+        let expired = Token::new(Scope::Upload).with_lifetime(Duration::from_secs(0));
+        assert!(expired.is_expired());
+        assert!(!expired.grants(Scope::Upload));
+        let valid = Token::new(Scope::Upload).with_lifetime(Duration::from_secs(60));
+        assert!(valid.grants(Scope::Upload));
+        let lasting = Token::new(Scope::Upload).with_lifetime(Duration::from_secs(u64::MAX));
+        assert!(lasting.grants(Scope::Upload));
+        assert_eq!(
+            format!("http://10.0.0.1:8080/{}/", valid.value),
+            valid.get_url("http://10.0.0.1:8080")
+        );
+    }
+
+    #[test]
+    fn test_token_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.json");
+        let store = TokenStore::open(path.clone()).unwrap();
+        let alice = Token::new(Scope::Upload).with_label("Alice");
+        store.insert(alice.clone()).unwrap();
+        let bob = Token::new(Scope::Download).with_label("Bob");
+        store.insert(bob.clone()).unwrap();
+        store
+            .insert(Token::new(Scope::Upload).with_lifetime(Duration::from_secs(0)))
+            .unwrap();
+        assert_eq!(Some(alice.clone()), store.find(&alice.value, Scope::Upload));
+        assert_eq!(None, store.find(&alice.value, Scope::Download));
+        assert_eq!(Some(bob.clone()), store.remove(&bob.value).unwrap());
+        assert_eq!(None, store.remove(&bob.value).unwrap());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        let temp_path = dir.path().join("tokens.json.tmp");
+        fs::write(&temp_path, b"").unwrap();
+        fs::set_permissions(&temp_path, fs::Permissions::from_mode(0o644)).unwrap();
+        store.insert(bob.clone()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        assert!(!temp_path.exists());
+        store.remove(&bob.value).unwrap();
+
+        // Expired tokens are dropped when loading.
+        let reopened = TokenStore::open(path).unwrap();
+        assert_eq!(vec![alice.clone()], reopened.get_tokens());
+
+        let replacement = Token::new(Scope::Upload).with_label("Alice");
+        reopened.insert(replacement.clone()).unwrap();
+        assert_eq!(vec![replacement], reopened.get_tokens());
+    }
+}
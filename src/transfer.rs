@@ -0,0 +1,821 @@
+//! Streaming of shared files with byte counting, used to detect when a download has completed,
+//! limiting the number of downloads running at the same time and throttling their speed and that
+//! of uploads, and following the progress of running transfers, which shutting down waits for
+
+use crate::html;
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncRead;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunk size with `--low-memory`
+const SMALL_CHUNK_SIZE: usize = 16 * 1024;
+/// Size of the chunks files are read in by every `FileStream`
+static CURRENT_CHUNK_SIZE: AtomicUsize = AtomicUsize::new(CHUNK_SIZE);
+/// Largest JSON document accepted from another instance, like the manifest of a sync
+pub const MAX_JSON_SIZE: u64 = 64 * 1024 * 1024;
+/// Seconds a waiting client is asked to wait before trying again
+const RETRY_AFTER_SECONDS: u64 = 5;
+
+/// A stream reading a file, or any other reader, in chunks, suitable as a response body.
+pub struct FileStream<R = tokio::fs::File> {
+    file: R,
+    buffer: Vec<u8>,
+}
+
+impl<R> FileStream<R> {
+    pub fn new(file: R) -> FileStream<R> {
+        FileStream {
+            file,
+            buffer: vec![0; CURRENT_CHUNK_SIZE.load(Ordering::Relaxed)],
+        }
+    }
+}
+
+/// Makes every `FileStream` created from now on read in small chunks.
+pub fn use_small_chunks() {
+    CURRENT_CHUNK_SIZE.store(SMALL_CHUNK_SIZE, Ordering::Relaxed);
+}
+
+/// Reads a whole body, failing as soon as it is larger than `limit` bytes.
+pub async fn read_limited(mut body: Body, limit: u64) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        if (content.len() + chunk.len()) as u64 > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("The response is larger than {}", format_size(limit)),
+            ));
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Ok(content)
+}
+
+impl<R: AsyncRead + Unpin> Stream for FileStream<R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.file).poll_read(cx, &mut this.buffer) {
+            Poll::Ready(Ok(0)) => Poll::Ready(None),
+            Poll::Ready(Ok(n)) => Poll::Ready(Some(Ok(Bytes::copy_from_slice(&this.buffer[..n])))),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+type CompletionCallback = Box<dyn FnOnce() + Send + Sync>;
+
+/// Wraps a body stream and counts the bytes passing through it.
+///
+/// As soon as exactly the expected amount of bytes has been handed to the connection, the
+/// completion callback is called. hyper stops polling a body once its Content-Length is reached,
+/// so this can't wait for the end of the inner stream. A transfer aborted by the client drops the
+/// stream early and therefore never counts as completed.
+pub struct CountingStream<S> {
+    inner: S,
+    sent: u64,
+    expected: u64,
+    on_complete: Option<CompletionCallback>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new<F>(inner: S, expected: u64, on_complete: F) -> CountingStream<S>
+    where
+        F: FnOnce() + Send + Sync + 'static,
+    {
+        CountingStream {
+            inner,
+            sent: 0,
+            expected,
+            on_complete: Some(Box::new(on_complete)),
+        }
+    }
+
+    fn check_completion(&mut self) {
+        if self.sent == self.expected {
+            if let Some(on_complete) = self.on_complete.take() {
+                on_complete();
+            }
+        }
+    }
+}
+
+impl<S> Stream for CountingStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                this.sent += chunk.len() as u64;
+                this.check_completion();
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(None) => {
+                this.check_completion();
+                Poll::Ready(None)
+            }
+            other => other,
+        }
+    }
+}
+
+/// Limits the number of transfers running at the same time
+pub struct TransferLimit {
+    max: usize,
+    active: AtomicUsize,
+}
+
+impl TransferLimit {
+    pub fn new(max: usize) -> TransferLimit {
+        TransferLimit {
+            max,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a permit for another transfer, unless the limit is reached.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<TransferPermit> {
+        let mut active = self.active.load(Ordering::SeqCst);
+        while active < self.max {
+            match self.active.compare_exchange(
+                active,
+                active + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    return Some(TransferPermit {
+                        limit: self.clone(),
+                    })
+                }
+                Err(current) => active = current,
+            }
+        }
+        None
+    }
+}
+
+/// Counts as an active transfer until dropped
+pub struct TransferPermit {
+    limit: Arc<TransferLimit>,
+}
+
+impl Drop for TransferPermit {
+    fn drop(&mut self) {
+        self.limit.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A response body holding a permit until the body has been sent or the client went away
+struct PermitBody {
+    body: Body,
+    _permit: TransferPermit,
+}
+
+impl Stream for PermitBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().body).poll_next(cx)
+    }
+}
+
+/// Whether a response streams file content, as opposed to pages, listings and other small
+/// responses
+fn is_transfer(response: &Response<Body>) -> bool {
+    matches!(
+        response.status(),
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT
+    ) && response.headers().get(header::CONTENT_TYPE)
+        == Some(&header::HeaderValue::from_static(
+            "application/octet-stream",
+        ))
+}
+
+/// A body that breaks off as soon as its transfer has been revoked
+struct RevocableBody<F> {
+    body: Body,
+    is_revoked: F,
+}
+
+impl<F: Fn() -> bool + Unpin> Stream for RevocableBody<F> {
+    type Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if (this.is_revoked)() {
+            return Poll::Ready(Some(Err(Box::new(io::Error::other("Transfer revoked")))));
+        }
+        Pin::new(&mut this.body)
+            .poll_next(cx)
+            .map(|item| item.map(|chunk| chunk.map_err(|e| e.into())))
+    }
+}
+
+/// Makes a transfer stop as soon as `is_revoked` returns true, so revoking access also cuts off
+/// downloads that are already running.
+pub fn make_revocable<F>(response: Response<Body>, is_revoked: F) -> Response<Body>
+where
+    F: Fn() -> bool + Send + Sync + Unpin + 'static,
+{
+    if !is_transfer(&response) {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = Body::wrap_stream(RevocableBody { body, is_revoked });
+    Response::from_parts(parts, body)
+}
+
+/// Applies the limit to a response. Responses streaming file content count as transfers, while
+/// pages, listings and other small responses are always sent. Clients over the limit get a page
+/// that reloads itself after a few seconds.
+pub fn limit_response(limit: &Arc<TransferLimit>, response: Response<Body>) -> Response<Body> {
+    if !is_transfer(&response) {
+        return response;
+    }
+    match limit.try_acquire() {
+        Some(permit) => {
+            let (parts, body) = response.into_parts();
+            let body = Body::wrap_stream(PermitBody {
+                body,
+                _permit: permit,
+            });
+            Response::from_parts(parts, body)
+        }
+        None => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(header::RETRY_AFTER, RETRY_AFTER_SECONDS)
+            .header("Refresh", RETRY_AFTER_SECONDS)
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::from(html::create_message_page(
+                "Please wait",
+                "All transfers are busy right now. This page retries automatically in a few \
+                 seconds.",
+            )))
+            .unwrap(),
+    }
+}
+
+/// How far a running transfer got
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub client: Option<IpAddr>,
+    pub name: String,
+    pub upload: bool,
+    pub done: u64,
+    /// The Content-Length, if there is one
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    pub fn describe(&self) -> String {
+        let client = match self.client {
+            Some(ip) => ip.to_string(),
+            None => String::from("Someone"),
+        };
+        let verb = if self.upload {
+            "uploading"
+        } else {
+            "downloading"
+        };
+        match self.total.filter(|&t| t > 0) {
+            Some(total) => format!(
+                "{} {} {}: {}% of {}",
+                client,
+                verb,
+                self.name,
+                self.done.min(total) * 100 / total,
+                format_size(total)
+            ),
+            None => format!(
+                "{} {} {}: {} so far",
+                client,
+                verb,
+                self.name,
+                format_size(self.done)
+            ),
+        }
+    }
+}
+
+/// The transfers running right now
+#[derive(Default)]
+pub struct Transfers {
+    next: AtomicUsize,
+    running: Mutex<BTreeMap<usize, Progress>>,
+}
+
+impl Transfers {
+    /// Follows a download of `name` to `client`. Pages and other small responses aren't followed.
+    pub fn track_response(
+        self: &Arc<Self>,
+        client: Option<IpAddr>,
+        name: &str,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        if !is_transfer(&response) {
+            return response;
+        }
+        let total = get_content_length(response.headers());
+        let (parts, body) = response.into_parts();
+        let body = self.track(client, name, false, total, body);
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    /// Follows an upload from `client`. Requests that don't upload are left alone.
+    pub fn track_request(
+        self: &Arc<Self>,
+        client: Option<IpAddr>,
+        name: &str,
+        req: Request<Body>,
+    ) -> Request<Body> {
+        if !matches!(*req.method(), Method::PUT | Method::POST) {
+            return req;
+        }
+        let total = get_content_length(req.headers());
+        let (parts, body) = req.into_parts();
+        let body = self.track(client, name, true, total, body);
+        Request::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    fn track(
+        self: &Arc<Self>,
+        client: Option<IpAddr>,
+        name: &str,
+        upload: bool,
+        total: Option<u64>,
+        body: Body,
+    ) -> TrackedBody {
+        let id = self.next.fetch_add(1, Ordering::SeqCst);
+        let progress = Progress {
+            client,
+            name: name.to_string(),
+            upload,
+            done: 0,
+            total,
+        };
+        self.running.lock().unwrap().insert(id, progress);
+        TrackedBody {
+            body,
+            transfers: self.clone(),
+            id,
+        }
+    }
+
+    /// The running transfers, in the order they started
+    pub fn get_running(&self) -> Vec<Progress> {
+        self.running.lock().unwrap().values().cloned().collect()
+    }
+}
+
+fn get_content_length(headers: &header::HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse().ok())
+}
+
+/// A body counting its bytes into the progress of its transfer, which ends when the body ends or
+/// is dropped
+struct TrackedBody {
+    body: Body,
+    transfers: Arc<Transfers>,
+    id: usize,
+}
+
+impl Stream for TrackedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.body).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let mut running = this.transfers.running.lock().unwrap();
+                if let Some(progress) = running.get_mut(&this.id) {
+                    progress.done += chunk.len() as u64;
+                }
+            }
+            Poll::Ready(_) => {
+                this.transfers.running.lock().unwrap().remove(&this.id);
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+impl Drop for TrackedBody {
+    fn drop(&mut self) {
+        self.transfers.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Parses a positive number of bytes like `500K` or `20G`, with suffixes in multiples of 1024.
+fn parse_bytes(s: &str) -> Option<u64> {
+    let (number, factor) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .filter(|&n| n > 0)
+}
+
+/// Parses a speed like `500K` or `2M` in bytes per second, with suffixes in multiples of 1024.
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    parse_bytes(s).ok_or_else(|| format!("Invalid rate: {}, use for example 500K or 2M", s))
+}
+
+/// Formats a number of bytes with the largest unit that keeps it at least 1, like `3.5 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Parses a size like `500M` or `20G`, with suffixes in multiples of 1024.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    parse_bytes(s).ok_or_else(|| format!("Invalid size: {}, use for example 500M or 20G", s))
+}
+
+/// A speed limit shared by all transfers it is applied to
+pub struct RateLimit {
+    bytes_per_second: u64,
+    /// When the next bytes may be sent
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64) -> RateLimit {
+        RateLimit {
+            bytes_per_second,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Books sending `bytes` and returns when they may be sent.
+    fn reserve(&self, bytes: usize) -> Instant {
+        let mut next = self.next.lock().unwrap();
+        let start = (*next).max(Instant::now());
+        *next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+        start
+    }
+}
+
+/// A body holding back every chunk until all its rate limits allow passing it on. Holding back
+/// an upload stops reading from the connection, which makes the sender slow down.
+struct ThrottledBody {
+    body: Body,
+    limits: Vec<Arc<RateLimit>>,
+    delayed: Option<(tokio::time::Delay, Bytes)>,
+}
+
+impl Stream for ThrottledBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some((delay, _)) = &mut this.delayed {
+            if Pin::new(delay).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let (_, chunk) = this.delayed.take().unwrap();
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let start = this.limits.iter().map(|l| l.reserve(chunk.len())).max();
+                match start {
+                    Some(start) if start > Instant::now() => {
+                        let delay = tokio::time::delay_until(start.into());
+                        this.delayed = Some((delay, chunk));
+                        Pin::new(this).poll_next(cx)
+                    }
+                    _ => Poll::Ready(Some(Ok(chunk))),
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+/// Slows a transfer down to the given rate limits. Pages and other small responses are sent at
+/// full speed.
+pub fn throttle_response(response: Response<Body>, limits: Vec<Arc<RateLimit>>) -> Response<Body> {
+    if limits.is_empty() || !is_transfer(&response) {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = Body::wrap_stream(ThrottledBody {
+        body,
+        limits,
+        delayed: None,
+    });
+    Response::from_parts(parts, body)
+}
+
+/// Slows an upload down to the given rate limits. Requests that don't upload are left alone.
+pub fn throttle_request(req: Request<Body>, limits: Vec<Arc<RateLimit>>) -> Request<Body> {
+    if limits.is_empty() || !matches!(*req.method(), Method::PUT | Method::POST) {
+        return req;
+    }
+    let (parts, body) = req.into_parts();
+    let body = Body::wrap_stream(ThrottledBody {
+        body,
+        limits,
+        delayed: None,
+    });
+    Request::from_parts(parts, body)
+}
+
+/// The part of a file a request asks for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteRange {
+    Whole,
+    /// First and last byte, both included
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses the value of a Range header for a file of `length` bytes. Only single ranges are
+/// supported, others get the whole file, which is how a device continues an interrupted download.
+pub fn parse_range(value: &str, length: u64) -> ByteRange {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Whole,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Whole,
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, length.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (length.saturating_sub(suffix), length.saturating_sub(1))
+        }
+        _ => return ByteRange::Whole,
+    };
+    if start >= length {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Part(start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::{self, StreamExt};
+    use proptest::prelude::*;
+    use std::sync::atomic::AtomicBool;
+
+    fn chunks(data: &[&'static [u8]]) -> impl Stream<Item = io::Result<Bytes>> + Unpin {
+        stream::iter(
+            data.iter()
+                .map(|c| Ok(Bytes::from_static(c)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_open_range(length in 1u64..1_000_000, start in 0u64..1_000_000) {
+            let expected = if start < length {
+                ByteRange::Part(start, length - 1)
+            } else {
+                ByteRange::Unsatisfiable
+            };
+            prop_assert_eq!(expected, parse_range(&format!("bytes={}-", start), length));
+        }
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!("512 bytes", format_size(512));
+        assert_eq!("1.5 KiB", format_size(1536));
+        assert_eq!("20.0 GiB", format_size(20 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(ByteRange::Part(0, 99), parse_range("bytes=0-99", 1000));
+        assert_eq!(
+            ByteRange::Part(900, 999),
+            parse_range("bytes=900-5000", 1000)
+        );
+        assert_eq!(ByteRange::Part(990, 999), parse_range("bytes=-10", 1000));
+        assert_eq!(ByteRange::Part(0, 9), parse_range("bytes=-50", 10));
+        assert_eq!(ByteRange::Whole, parse_range("bytes=0-1,5-9", 1000));
+        assert_eq!(ByteRange::Whole, parse_range("bytes=9-5", 1000));
+        assert_eq!(ByteRange::Whole, parse_range("items=0-5", 1000));
+        assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=1000-", 1000));
+    }
+
+    #[test]
+    fn test_transfer_limit() {
+        let limit = Arc::new(TransferLimit::new(2));
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_read_limited() {
+        let body = Body::from(vec![1u8; 100]);
+        assert_eq!(100, read_limited(body, 100).await.unwrap().len());
+        let body = Body::from(vec![1u8; 101]);
+        let error = read_limited(body, 100).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[tokio::test]
+    async fn test_transfers_follow_progress() {
+        let transfers = Arc::new(Transfers::default());
+        let client = Some("10.0.0.2".parse().unwrap());
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, "8")
+            .body(Body::wrap_stream(stream::iter(vec![
+                Ok::<_, io::Error>(Bytes::from_static(b"data")),
+                Ok(Bytes::from_static(b"more")),
+            ])))
+            .unwrap();
+        let page = transfers.track_response(client, "a.txt", Response::new(Body::from("page")));
+        let mut body = transfers
+            .track_response(client, "a.txt", response)
+            .into_body();
+        assert_eq!(1, transfers.get_running().len());
+        body.next().await.unwrap().unwrap();
+        assert_eq!(
+            "10.0.0.2 downloading a.txt: 50% of 8 bytes",
+            transfers.get_running()[0].describe()
+        );
+        let req = Request::put("/b.txt").body(Body::from("up")).unwrap();
+        let upload = transfers.track_request(None, "b.txt", req);
+        assert_eq!(
+            "Someone uploading b.txt: 0 bytes so far",
+            transfers.get_running()[1].describe()
+        );
+        drop(upload);
+        body.next().await.unwrap().unwrap();
+        assert!(body.next().await.is_none());
+        assert!(transfers.get_running().is_empty());
+        drop(page);
+    }
+
+    #[tokio::test]
+    async fn test_limit_response() {
+        let limit = Arc::new(TransferLimit::new(1));
+        let transfer = || {
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(Body::from("data"))
+                .unwrap()
+        };
+        let first = limit_response(&limit, transfer());
+        assert_eq!(StatusCode::OK, first.status());
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            limit_response(&limit, transfer()).status()
+        );
+        assert_eq!(
+            StatusCode::OK,
+            limit_response(&limit, Response::new(Body::from("page"))).status()
+        );
+        let body = hyper::body::to_bytes(first.into_body()).await.unwrap();
+        assert_eq!(&b"data"[..], &body[..]);
+        assert_eq!(StatusCode::OK, limit_response(&limit, transfer()).status());
+    }
+
+    #[tokio::test]
+    async fn test_make_revocable() {
+        let transfer = || {
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(Body::from("data"))
+                .unwrap()
+        };
+        let open = make_revocable(transfer(), || false);
+        assert!(hyper::body::to_bytes(open.into_body()).await.is_ok());
+        let revoked = make_revocable(transfer(), || true);
+        assert!(hyper::body::to_bytes(revoked.into_body()).await.is_err());
+        let page = make_revocable(Response::new(Body::from("page")), || true);
+        assert!(hyper::body::to_bytes(page.into_body()).await.is_ok());
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_rate(n in 1u64..100_000, suffix in 0usize..4) {
+            let (text, factor) = [("", 1), ("K", 1024), ("m", 1024 * 1024), ("G", 1 << 30)][suffix];
+            prop_assert_eq!(Ok(n * factor), parse_rate(&format!("{}{}", n, text)));
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_rate() {
+        assert!(parse_rate("").is_err());
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("M").is_err());
+        assert!(parse_rate("2T").is_err());
+        assert!(parse_rate("-1K").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_throttle_response() {
+        let transfer = |body: Body| {
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(body)
+                .unwrap()
+        };
+        let limit = Arc::new(RateLimit::new(10_000));
+        let started = Instant::now();
+        // A single chunk is sent right away and books the following 200 ms.
+        let first = throttle_response(transfer(Body::from(vec![0u8; 2000])), vec![limit.clone()]);
+        assert_eq!(
+            2000,
+            hyper::body::to_bytes(first.into_body())
+                .await
+                .unwrap()
+                .len()
+        );
+        let page = throttle_response(Response::new(Body::from("page")), vec![limit.clone()]);
+        hyper::body::to_bytes(page.into_body()).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(200));
+        let second = throttle_response(transfer(Body::from("data")), vec![limit]);
+        hyper::body::to_bytes(second.into_body()).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_throttle_request() {
+        let upload = |body: Body| Request::put("/photo.jpg").body(body).unwrap();
+        let limit = Arc::new(RateLimit::new(10_000));
+        let started = Instant::now();
+        let first = throttle_request(upload(Body::from(vec![0u8; 2000])), vec![limit.clone()]);
+        hyper::body::to_bytes(first.into_body()).await.unwrap();
+        let read = throttle_request(Request::new(Body::from("query")), vec![limit.clone()]);
+        hyper::body::to_bytes(read.into_body()).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(200));
+        let second = throttle_request(upload(Body::from("data")), vec![limit]);
+        hyper::body::to_bytes(second.into_body()).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_counting_stream_complete() {
+        let completed = Arc::new(AtomicBool::new(false));
+        let flag = completed.clone();
+        let stream = CountingStream::new(chunks(&[b"abc", b"de"]), 5, move || {
+            flag.store(true, Ordering::SeqCst)
+        });
+        let collected = stream.collect::<Vec<_>>().await;
+        assert_eq!(2, collected.len());
+        assert!(completed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_counting_stream_short() {
+        let completed = Arc::new(AtomicBool::new(false));
+        let flag = completed.clone();
+        let stream = CountingStream::new(chunks(&[b"abc"]), 5, move || {
+            flag.store(true, Ordering::SeqCst)
+        });
+        stream.collect::<Vec<_>>().await;
+        assert!(!completed.load(Ordering::SeqCst));
+    }
+}
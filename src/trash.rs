@@ -0,0 +1,116 @@
+//! Moving files rustbelt removes to the trash, so they can be restored
+//!
+//! Rejected uploads and the sources of `--move` are trashed instead of being unlinked. `rustbelt
+//! trash list` shows what is in the trash and `rustbelt trash restore` puts an item back.
+
+use chrono::{Local, TimeZone};
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Moves `path` to the trash of the current user.
+pub fn move_to_trash(path: &Path) -> io::Result<()> {
+    ::trash::delete(path).map_err(io::Error::other)
+}
+
+#[derive(Debug)]
+struct NotInTrashError {
+    query: String,
+}
+
+impl error::Error for NotInTrashError {}
+
+impl fmt::Display for NotInTrashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Nothing named {} is in the trash", self.query)
+    }
+}
+
+impl NotInTrashError {
+    fn new(query: &str) -> NotInTrashError {
+        NotInTrashError {
+            query: query.to_string(),
+        }
+    }
+}
+
+/// Finds the index of the most recently trashed item whose original path or name is `query`.
+fn find_item(items: &[::trash::TrashItem], query: &str) -> Option<usize> {
+    let query_path = Path::new(query);
+    let absolute = std::env::current_dir()
+        .map(|d| d.join(query_path))
+        .unwrap_or_else(|_| query_path.to_path_buf());
+    (0..items.len())
+        .filter(|&i| items[i].name == query || items[i].original_path() == absolute)
+        .max_by_key(|&i| items[i].time_deleted)
+}
+
+fn format_time(timestamp: i64) -> String {
+    Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn run_trash(matches: &clap::ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let mut items = ::trash::os_limited::list()?;
+    match matches.subcommand() {
+        ("restore", Some(restore_matches)) => {
+            let query = restore_matches.value_of("ITEM").unwrap();
+            let index = find_item(&items, query).ok_or_else(|| NotInTrashError::new(query))?;
+            let item = items.swap_remove(index);
+            let path = item.original_path();
+            ::trash::os_limited::restore_all(vec![item])?;
+            println!("Restored {}", path.display());
+        }
+        _ => {
+            items.sort_by_key(|i| -i.time_deleted);
+            for item in items {
+                println!(
+                    "{}  {}",
+                    format_time(item.time_deleted),
+                    item.original_path().display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub fn run_trash(_matches: &clap::ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    Err(Box::new(io::Error::other(
+        "Listing and restoring the trash is not supported on macOS, use the Finder instead",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+
+    fn create_item(parent: &str, name: &str, time_deleted: i64) -> ::trash::TrashItem {
+        ::trash::TrashItem {
+            id: OsString::from(format!("{}{}", name, time_deleted)),
+            name: name.to_string(),
+            original_parent: PathBuf::from(parent),
+            time_deleted,
+        }
+    }
+
+    #[test]
+    fn test_find_item() {
+        let items = vec![
+            create_item("/a", "f.txt", 1),
+            create_item("/b", "f.txt", 3),
+            create_item("/a", "g.txt", 2),
+        ];
+        assert_eq!(Some(1), find_item(&items, "f.txt"));
+        assert_eq!(Some(0), find_item(&items, "/a/f.txt"));
+        assert!(find_item(&items, "h.txt").is_none());
+    }
+}
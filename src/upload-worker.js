@@ -0,0 +1,99 @@
+// Keeps uploads that failed because the connection dropped and sends them again once it is back.
+// The files wait in IndexedDB, so they survive the page being closed or reloaded.
+const DATABASE = "rustbelt-uploads";
+const STORE = "queue";
+const SYNC_TAG = "rustbelt-uploads";
+
+self.addEventListener("install", function () {
+  self.skipWaiting();
+});
+
+self.addEventListener("activate", function (event) {
+  event.waitUntil(self.clients.claim());
+});
+
+function openDatabase() {
+  return new Promise(function (resolve, reject) {
+    const request = indexedDB.open(DATABASE, 1);
+    request.onupgradeneeded = function () {
+      request.result.createObjectStore(STORE, { keyPath: "id", autoIncrement: true });
+    };
+    request.onsuccess = function () { resolve(request.result); };
+    request.onerror = function () { reject(request.error); };
+  });
+}
+
+async function withStore(mode, action) {
+  const database = await openDatabase();
+  return new Promise(function (resolve, reject) {
+    const transaction = database.transaction(STORE, mode);
+    const request = action(transaction.objectStore(STORE));
+    transaction.oncomplete = function () { resolve(request.result); };
+    transaction.onerror = function () { reject(transaction.error); };
+  });
+}
+
+async function tell(message) {
+  for (const client of await self.clients.matchAll({ includeUncontrolled: true, type: "window" })) {
+    client.postMessage(message);
+  }
+}
+
+async function reportQueue() {
+  const uploads = await withStore("readonly", function (store) { return store.getAll(); });
+  await tell({ type: "queue", uploads: uploads.map(function (u) { return { key: u.key, name: u.name }; }) });
+}
+
+// Sends the waiting uploads in the order they failed, and stops at the first one that still
+// can't reach the server. Resolves to whether all of them were sent.
+let sending = null;
+function sendQueue() {
+  if (!sending) {
+    sending = (async function () {
+      const uploads = await withStore("readonly", function (store) { return store.getAll(); });
+      let sent = true;
+      for (const upload of uploads) {
+        let response;
+        try {
+          response = await fetch(upload.url, { method: "PUT", body: upload.file });
+        } catch (e) {
+          sent = false;
+          break;
+        }
+        await withStore("readwrite", function (store) { return store.delete(upload.id); });
+        const text = response.status === 201 ? "done" : await response.text();
+        await tell({ type: "result", key: upload.key, name: upload.name, text: text });
+      }
+      await reportQueue();
+      return sent;
+    })().finally(function () { sending = null; });
+  }
+  return sending;
+}
+
+self.addEventListener("message", function (event) {
+  const message = event.data;
+  if (message.type === "queue") {
+    const upload = { key: message.key, name: message.name, url: message.url, file: message.file };
+    event.waitUntil(withStore("readwrite", function (store) { return store.add(upload); })
+      .then(reportQueue)
+      .then(function () { return self.registration.sync && self.registration.sync.register(SYNC_TAG); })
+      .catch(function () {}));
+  } else if (message.type === "retry") {
+    event.waitUntil(sendQueue());
+  } else if (message.type === "status") {
+    event.waitUntil(reportQueue());
+  }
+});
+
+// Background sync wakes the worker when the device is online again, even without an open page,
+// and tries again later if the worker fails.
+self.addEventListener("sync", function (event) {
+  if (event.tag === SYNC_TAG) {
+    event.waitUntil(sendQueue().then(function (sent) {
+      if (!sent) {
+        throw new Error("The server can't be reached yet");
+      }
+    }));
+  }
+});
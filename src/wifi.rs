@@ -0,0 +1,252 @@
+//! A QR code for joining the Wi-Fi, printed before the one for the share
+//!
+//! A recipient who isn't on the network yet scans the first code to join it and then the second
+//! to download. The credentials are given as `SSID:PASSWORD`, or taken from the active Wi-Fi
+//! connection of NetworkManager with `current`. The QR code uses the `WIFI:` format phone
+//! cameras understand.
+
+use std::error;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+/// The value of `--wifi` that looks up the active connection
+pub const CURRENT: &str = "current";
+/// The type NetworkManager gives Wi-Fi connections
+const WIRELESS_TYPE: &str = "802-11-wireless";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    /// The WPA passphrase, `None` for open networks
+    pub password: Option<String>,
+}
+
+impl FromStr for WifiNetwork {
+    type Err = String;
+
+    /// Parses `SSID:PASSWORD`. The password may contain colons, and is left empty for open
+    /// networks.
+    fn from_str(s: &str) -> Result<WifiNetwork, String> {
+        match s.split_once(':') {
+            Some((ssid, password)) if !ssid.is_empty() => Ok(WifiNetwork {
+                ssid: ssid.to_string(),
+                password: Some(password.to_string()).filter(|p| !p.is_empty()),
+            }),
+            _ => Err(format!("Expected SSID:PASSWORD or {}: {}", CURRENT, s)),
+        }
+    }
+}
+
+/// Escapes the characters with a meaning in `WIFI:` codes.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Splits a line of terse `nmcli` output into its fields, which have colons and backslashes
+/// escaped.
+fn split_nmcli(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    fields.last_mut().unwrap().push(next);
+                }
+            }
+            ':' => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// The name of the first Wi-Fi connection in the output of
+/// `nmcli -t -f NAME,TYPE connection show --active`.
+fn parse_active_connection(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| match &split_nmcli(line)[..] {
+            [name, kind] if kind == WIRELESS_TYPE => Some(name.clone()),
+            _ => None,
+        })
+}
+
+/// The network from the output of `nmcli -s -t -f 802-11-wireless.ssid,802-11-wireless-security
+/// connection show`. Each line holds a setting, its name and value separated by a colon.
+fn parse_connection(output: &str) -> Option<WifiNetwork> {
+    let mut ssid = None;
+    let mut key_management = None;
+    let mut password = None;
+    for line in output.lines() {
+        let (name, value) = match &split_nmcli(line)[..] {
+            [name, value] if !value.is_empty() => (name.clone(), value.clone()),
+            _ => continue,
+        };
+        match name.as_str() {
+            "802-11-wireless.ssid" => ssid = Some(value),
+            "802-11-wireless-security.key-mgmt" => key_management = Some(value),
+            "802-11-wireless-security.psk" => password = Some(value),
+            _ => {}
+        }
+    }
+    let password = match key_management.as_deref() {
+        None => None,
+        Some("wpa-psk") | Some("sae") => Some(password?),
+        // WEP and enterprise networks can't be joined with a passphrase.
+        Some(_) => return None,
+    };
+    Some(WifiNetwork {
+        ssid: ssid?,
+        password,
+    })
+}
+
+fn run_nmcli(args: &[&str]) -> Result<String, Box<dyn error::Error>> {
+    let output = Command::new("nmcli")
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| format!("Could not run nmcli to look up the Wi-Fi: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("nmcli {} failed", args.join(" ")).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The active Wi-Fi connection of NetworkManager, with its password
+fn get_current_network() -> Result<WifiNetwork, Box<dyn error::Error>> {
+    let active = run_nmcli(&["-t", "-f", "NAME,TYPE", "connection", "show", "--active"])?;
+    let name = parse_active_connection(&active)
+        .ok_or("NetworkManager has no active Wi-Fi connection, give it as --wifi SSID:PASSWORD")?;
+    let settings = run_nmcli(&[
+        "-s",
+        "-t",
+        "-f",
+        "802-11-wireless.ssid,802-11-wireless-security",
+        "connection",
+        "show",
+        "id",
+        &name,
+    ])?;
+    parse_connection(&settings).ok_or_else(|| {
+        format!(
+            "NetworkManager didn't tell the password of {}, give it as --wifi SSID:PASSWORD",
+            name
+        )
+        .into()
+    })
+}
+
+/// The network given to `--wifi`
+pub fn get_network(value: &str) -> Result<WifiNetwork, Box<dyn error::Error>> {
+    if value == CURRENT {
+        get_current_network()
+    } else {
+        Ok(value.parse()?)
+    }
+}
+
+impl WifiNetwork {
+    /// The text of the QR code, like `WIFI:T:WPA;S:home;P:secret;;`
+    pub fn get_qr_text(&self) -> String {
+        match &self.password {
+            Some(password) => format!(
+                "WIFI:T:WPA;S:{};P:{};;",
+                escape(&self.ssid),
+                escape(password)
+            ),
+            None => format!("WIFI:T:nopass;S:{};;", escape(&self.ssid)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_escape_leaves_no_separators(value in ".*") {
+            let escaped = escape(&value);
+            let mut chars = escaped.chars();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    prop_assert!(chars.next().is_some());
+                } else {
+                    prop_assert!(c != ';' && c != ':');
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_network() {
+        assert_eq!(
+            Ok(WifiNetwork {
+                ssid: String::from("home"),
+                password: Some(String::from("se:cret")),
+            }),
+            "home:se:cret".parse()
+        );
+        assert_eq!(
+            Ok(WifiNetwork {
+                ssid: String::from("café"),
+                password: None,
+            }),
+            "café:".parse()
+        );
+        assert!("home".parse::<WifiNetwork>().is_err());
+        assert!(":secret".parse::<WifiNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_qr_text() {
+        let network: WifiNetwork = "my;net:p\"w".parse().unwrap();
+        assert_eq!("WIFI:T:WPA;S:my\\;net;P:p\\\"w;;", network.get_qr_text());
+        let network: WifiNetwork = "guest:".parse().unwrap();
+        assert_eq!("WIFI:T:nopass;S:guest;;", network.get_qr_text());
+    }
+
+    #[test]
+    fn test_parse_nmcli() {
+        let active = "Wired connection 1:802-3-ethernet\nHome\\: 5G:802-11-wireless\n";
+        assert_eq!(
+            Some(String::from("Home: 5G")),
+            parse_active_connection(active)
+        );
+        assert_eq!(None, parse_active_connection("lo:loopback\n"));
+
+        let settings = "802-11-wireless.ssid:Home\\: 5G\n\
+                        802-11-wireless.mode:infrastructure\n\
+                        802-11-wireless-security.key-mgmt:wpa-psk\n\
+                        802-11-wireless-security.psk:pass\\\\word\n";
+        assert_eq!(
+            Some(WifiNetwork {
+                ssid: String::from("Home: 5G"),
+                password: Some(String::from("pass\\word")),
+            }),
+            parse_connection(settings)
+        );
+        // Without the rights to see secrets, the password is missing.
+        let hidden = "802-11-wireless.ssid:Home\n802-11-wireless-security.key-mgmt:sae\n";
+        assert_eq!(None, parse_connection(hidden));
+        let open = "802-11-wireless.ssid:Cafe\n";
+        assert_eq!(
+            Some(WifiNetwork {
+                ssid: String::from("Cafe"),
+                password: None,
+            }),
+            parse_connection(open)
+        );
+    }
+}
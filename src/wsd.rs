@@ -0,0 +1,513 @@
+//! Announcing the share over WS-Discovery, with `--wsd`
+//!
+//! Windows Explorer lists the devices answering WS-Discovery under "Network", which reaches people
+//! who would never scan a QR code or type a URL. rustbelt announces itself on the multicast group
+//! with a Hello, answers Probe and Resolve messages of the computers looking around, and says Bye
+//! when it stops. Explorer then asks for the metadata of the device over HTTP, which the share's
+//! own server answers at `WSD_PATH`. The device is announced as a generic device rather than a
+//! computer, so Explorer doesn't look for Windows file shares on it, with the share URL as its
+//! presentation URL, which a double click opens in the browser.
+
+use crate::html;
+use futures::stream::StreamExt;
+use hyper::{header, Body, Request, Response, StatusCode};
+use rand::RngCore;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+
+/// Where Explorer asks for the metadata of the device
+pub const WSD_PATH: &str = "/.wsd";
+const WSD_PORT: u16 = 3702;
+const WSD_IPV4: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const WSD_IPV6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc);
+/// Times every multicast message is sent, UDP may lose some
+const MULTICAST_REPEAT: usize = 4;
+const REPEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// Message IDs remembered to answer every repeated Probe only once
+const SEEN_MESSAGES: usize = 32;
+/// Largest metadata request read, Explorer's are far below
+const MAX_REQUEST: usize = 64 * 1024;
+
+const DISCOVERY: &str = "http://schemas.xmlsoap.org/ws/2005/04/discovery";
+const DISCOVERY_TO: &str = "urn:schemas-xmlsoap-org:ws:2005:04:discovery";
+const ANONYMOUS: &str = "http://schemas.xmlsoap.org/ws/2004/08/addressing/role/anonymous";
+const TRANSFER_GET: &str = "http://schemas.xmlsoap.org/ws/2004/09/transfer/Get";
+const DEVICE_PROFILE: &str = "http://schemas.xmlsoap.org/ws/2006/02/devprof";
+/// The types the device is announced with
+const TYPES: &str = "wsdp:Device";
+
+/// Creates a random UUID as URN.
+fn generate_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    // Version 4, variant 1
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The content of the first element named `name`, whatever its namespace prefix.
+fn get_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest.find(|c: char| c.is_whitespace() || c == '>' || c == '/')?;
+        let tag = &rest[..end];
+        if tag.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        let close = rest.find('>')?;
+        if rest[..close].ends_with('/') {
+            return Some("");
+        }
+        let content = &rest[close + 1..];
+        let end = content.find(&format!("</{}>", tag))?;
+        return Some(content[..end].trim());
+    }
+    None
+}
+
+/// A message of a computer looking for devices
+#[derive(Debug, PartialEq)]
+enum Query {
+    /// A Probe for the given types, all devices if there are none
+    Probe(String),
+    /// A Resolve of the endpoint address
+    Resolve(String),
+}
+
+/// Reads a discovery message, returning its message ID and what it asks for.
+fn parse_message(xml: &str) -> Option<(String, Query)> {
+    let action = get_element(xml, "Action")?;
+    let message_id = get_element(xml, "MessageID")?.to_string();
+    let query = match action.strip_prefix(DISCOVERY)? {
+        "/Probe" => Query::Probe(get_element(xml, "Types").unwrap_or("").to_string()),
+        "/Resolve" => {
+            let reference = get_element(xml, "EndpointReference")?;
+            Query::Resolve(get_element(reference, "Address")?.to_string())
+        }
+        _ => return None,
+    };
+    Some((message_id, query))
+}
+
+/// Whether a Probe for `types` finds a device, by the local names of the types
+fn matches_types(types: &str) -> bool {
+    types
+        .split_whitespace()
+        .all(|t| t.rsplit(':').next() == Some("Device"))
+}
+
+/// The device as announced to the network
+pub struct Announcer {
+    /// Endpoint address, stays the same for the whole run
+    endpoint: String,
+    name: String,
+    /// Opened by a double click in Explorer
+    presentation_url: String,
+    /// Where the metadata is fetched
+    metadata_url: String,
+    instance_id: u64,
+    message_number: AtomicU64,
+}
+
+impl Announcer {
+    /// Announces the share at `base_url` as `name`, opening `presentation_url` in the browser.
+    pub fn new(name: &str, base_url: &str, presentation_url: &str) -> Announcer {
+        Announcer {
+            endpoint: generate_uuid(),
+            name: name.to_string(),
+            presentation_url: presentation_url.to_string(),
+            metadata_url: format!("{}{}", base_url, WSD_PATH),
+            instance_id: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            message_number: AtomicU64::new(0),
+        }
+    }
+
+    fn create_envelope(&self, action: &str, to: &str, extra: &str, body: &str) -> String {
+        let number = self.message_number.fetch_add(1, Ordering::SeqCst) + 1;
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <soap:Envelope xmlns:soap=\"http://www.w3.org/2003/05/soap-envelope\" \
+             xmlns:wsa=\"http://schemas.xmlsoap.org/ws/2004/08/addressing\" \
+             xmlns:wsd=\"{}\" xmlns:wsdp=\"{}\" \
+             xmlns:wsx=\"http://schemas.xmlsoap.org/ws/2004/09/mex\" \
+             xmlns:pnpx=\"http://schemas.microsoft.com/windows/pnpx/2005/10\">\
+             <soap:Header><wsa:To>{}</wsa:To><wsa:Action>{}</wsa:Action>\
+             <wsa:MessageID>{}</wsa:MessageID>{}\
+             <wsd:AppSequence InstanceId=\"{}\" MessageNumber=\"{}\"/></soap:Header>\
+             <soap:Body>{}</soap:Body></soap:Envelope>",
+            DISCOVERY,
+            DEVICE_PROFILE,
+            to,
+            action,
+            generate_uuid(),
+            extra,
+            self.instance_id,
+            number,
+            body
+        )
+    }
+
+    fn create_endpoint_reference(&self) -> String {
+        format!(
+            "<wsa:EndpointReference><wsa:Address>{}</wsa:Address></wsa:EndpointReference>",
+            self.endpoint
+        )
+    }
+
+    /// The description of the device in Hello, ProbeMatch and ResolveMatch
+    fn create_description(&self) -> String {
+        format!(
+            "{}<wsd:Types>{}</wsd:Types><wsd:XAddrs>{}</wsd:XAddrs>\
+             <wsd:MetadataVersion>1</wsd:MetadataVersion>",
+            self.create_endpoint_reference(),
+            TYPES,
+            html::escape(&self.metadata_url)
+        )
+    }
+
+    fn create_hello(&self) -> String {
+        let body = format!("<wsd:Hello>{}</wsd:Hello>", self.create_description());
+        let action = format!("{}/Hello", DISCOVERY);
+        self.create_envelope(&action, DISCOVERY_TO, "", &body)
+    }
+
+    fn create_bye(&self) -> String {
+        let body = format!("<wsd:Bye>{}</wsd:Bye>", self.create_endpoint_reference());
+        let action = format!("{}/Bye", DISCOVERY);
+        self.create_envelope(&action, DISCOVERY_TO, "", &body)
+    }
+
+    /// The answer to a message, if it asks for this device
+    fn create_answer(&self, message_id: &str, request: &Query) -> Option<String> {
+        let (action, body) = match request {
+            Query::Probe(types) if matches_types(types) => (
+                "ProbeMatches",
+                format!(
+                    "<wsd:ProbeMatches><wsd:ProbeMatch>{}</wsd:ProbeMatch></wsd:ProbeMatches>",
+                    self.create_description()
+                ),
+            ),
+            Query::Resolve(endpoint) if *endpoint == self.endpoint => (
+                "ResolveMatches",
+                format!(
+                    "<wsd:ResolveMatches><wsd:ResolveMatch>{}</wsd:ResolveMatch>\
+                     </wsd:ResolveMatches>",
+                    self.create_description()
+                ),
+            ),
+            _ => return None,
+        };
+        let relates_to = format!(
+            "<wsa:RelatesTo>{}</wsa:RelatesTo>",
+            html::escape(message_id)
+        );
+        let action = format!("{}/{}", DISCOVERY, action);
+        Some(self.create_envelope(&action, ANONYMOUS, &relates_to, &body))
+    }
+
+    /// The answer to Explorer's request for the metadata of the device
+    fn create_metadata(&self, message_id: &str) -> String {
+        let body = format!(
+            "<wsx:Metadata>\
+             <wsx:MetadataSection Dialect=\"{0}/ThisDevice\"><wsdp:ThisDevice>\
+             <wsdp:FriendlyName>{1}</wsdp:FriendlyName>\
+             <wsdp:FirmwareVersion>{2}</wsdp:FirmwareVersion>\
+             <wsdp:SerialNumber>{3}</wsdp:SerialNumber>\
+             </wsdp:ThisDevice></wsx:MetadataSection>\
+             <wsx:MetadataSection Dialect=\"{0}/ThisModel\"><wsdp:ThisModel>\
+             <wsdp:Manufacturer>rustbelt</wsdp:Manufacturer>\
+             <wsdp:ModelName>rustbelt</wsdp:ModelName>\
+             <wsdp:PresentationUrl>{4}</wsdp:PresentationUrl>\
+             <pnpx:DeviceCategory>Other</pnpx:DeviceCategory>\
+             </wsdp:ThisModel></wsx:MetadataSection>\
+             <wsx:MetadataSection Dialect=\"{0}/Relationship\">\
+             <wsdp:Relationship Type=\"{0}/host\"><wsdp:Host>{5}\
+             <wsdp:Types>{6}</wsdp:Types><wsdp:ServiceId>{3}</wsdp:ServiceId>\
+             </wsdp:Host></wsdp:Relationship></wsx:MetadataSection>\
+             </wsx:Metadata>",
+            DEVICE_PROFILE,
+            html::escape(&self.name),
+            env!("CARGO_PKG_VERSION"),
+            self.endpoint,
+            html::escape(&self.presentation_url),
+            self.create_endpoint_reference(),
+            TYPES
+        );
+        let relates_to = format!(
+            "<wsa:RelatesTo>{}</wsa:RelatesTo>",
+            html::escape(message_id)
+        );
+        let action = "http://schemas.xmlsoap.org/ws/2004/09/transfer/GetResponse";
+        self.create_envelope(action, ANONYMOUS, &relates_to, &body)
+    }
+}
+
+/// Joins the WS-Discovery group on the interface of `ip` and returns the socket with the group.
+fn open_socket(ip: IpAddr, interface: &str) -> io::Result<(UdpSocket, SocketAddr)> {
+    let (domain, bind, group): (_, SocketAddr, SocketAddr) = match ip {
+        IpAddr::V4(_) => (
+            Domain::ipv4(),
+            (Ipv4Addr::UNSPECIFIED, WSD_PORT).into(),
+            (WSD_IPV4, WSD_PORT).into(),
+        ),
+        IpAddr::V6(_) => (
+            Domain::ipv6(),
+            (Ipv6Addr::UNSPECIFIED, WSD_PORT).into(),
+            (WSD_IPV6, WSD_PORT).into(),
+        ),
+    };
+    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    // wsdd or another rustbelt may listen on the port as well.
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    match ip {
+        IpAddr::V4(ip) => {
+            socket.join_multicast_v4(&WSD_IPV4, &ip)?;
+            socket.set_multicast_if_v4(&ip)?;
+        }
+        IpAddr::V6(_) => {
+            let name = std::ffi::CString::new(interface)?;
+            // Safe, if_nametoindex only reads the name.
+            let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+            socket.set_only_v6(true)?;
+            socket.join_multicast_v6(&WSD_IPV6, index)?;
+            socket.set_multicast_if_v6(index)?;
+        }
+    }
+    socket.bind(&SockAddr::from(bind))?;
+    let socket = socket.into_udp_socket();
+    socket.set_nonblocking(true)?;
+    Ok((UdpSocket::from_std(socket)?, group))
+}
+
+async fn send_multicast(socket: &mut UdpSocket, group: SocketAddr, message: &str) {
+    for i in 0..MULTICAST_REPEAT {
+        if i > 0 {
+            tokio::time::delay_for(REPEAT_INTERVAL).await;
+        }
+        if let Err(e) = socket.send_to(message.as_bytes(), group).await {
+            eprintln!("Could not send a WS-Discovery announcement: {}", e);
+            return;
+        }
+    }
+}
+
+/// Announces the device on the interface of `ip` until `shutdown`, answering the computers
+/// looking for devices in between.
+pub async fn run(
+    announcer: Arc<Announcer>,
+    ip: IpAddr,
+    interface: String,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (mut socket, group) = match open_socket(ip, &interface) {
+        Ok(opened) => opened,
+        Err(e) => {
+            eprintln!("Could not announce the share over WS-Discovery: {}", e);
+            return;
+        }
+    };
+    send_multicast(&mut socket, group, &announcer.create_hello()).await;
+    let mut seen = VecDeque::with_capacity(SEEN_MESSAGES);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buffer) => received,
+            stop = shutdown.recv() => match stop {
+                Some(false) => continue,
+                _ => break,
+            },
+        };
+        let (length, sender) = match received {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let message = String::from_utf8_lossy(&buffer[..length]);
+        let (message_id, request) = match parse_message(&message) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        if seen.contains(&message_id) {
+            continue;
+        }
+        if seen.len() == SEEN_MESSAGES {
+            seen.pop_front();
+        }
+        let answer = announcer.create_answer(&message_id, &request);
+        seen.push_back(message_id);
+        if let Some(answer) = answer {
+            let _ = socket.send_to(answer.as_bytes(), sender).await;
+        }
+    }
+    send_multicast(&mut socket, group, &announcer.create_bye()).await;
+}
+
+/// Answers Explorer's request for the metadata of the device.
+pub async fn handle_request(announcer: &Announcer, req: Request<Body>) -> Response<Body> {
+    let mut body = req.into_body();
+    let mut content = Vec::new();
+    while let Some(Ok(chunk)) = body.next().await {
+        content.extend_from_slice(&chunk);
+        if content.len() > MAX_REQUEST {
+            break;
+        }
+    }
+    let content = String::from_utf8_lossy(&content);
+    let message_id = match get_element(&content, "MessageID") {
+        Some(id) if get_element(&content, "Action") == Some(TRANSFER_GET) => id,
+        _ => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Expected a WS-Transfer Get request"))
+                .unwrap()
+        }
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/soap+xml; charset=utf-8")
+        .body(Body::from(announcer.create_metadata(message_id)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const PROBE: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <soap:Envelope xmlns:soap=\"http://www.w3.org/2003/05/soap-envelope\" \
+        xmlns:wsa=\"http://schemas.xmlsoap.org/ws/2004/08/addressing\" \
+        xmlns:wsd=\"http://schemas.xmlsoap.org/ws/2005/04/discovery\" \
+        xmlns:wsdp=\"http://schemas.xmlsoap.org/ws/2006/02/devprof\">\
+        <soap:Header><wsa:To>urn:schemas-xmlsoap-org:ws:2005:04:discovery</wsa:To>\
+        <wsa:Action>http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe</wsa:Action>\
+        <wsa:MessageID>urn:uuid:0f5d604c-81ac-4abc-8010-51dbffad55f2</wsa:MessageID>\
+        </soap:Header><soap:Body><wsd:Probe><wsd:Types>wsdp:Device</wsd:Types></wsd:Probe>\
+        </soap:Body></soap:Envelope>";
+
+    fn create_announcer() -> Announcer {
+        Announcer::new(
+            "Slides & notes (rustbelt)",
+            "http://192.168.1.2:8080",
+            "http://192.168.1.2:8080",
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn test_elements_are_found_with_any_prefix(
+            prefix in "([a-z]{1,5}:)?",
+            content in "[^<>&]*",
+        ) {
+            let xml = format!("<a><{0}Name attr=\"x\">{1}</{0}Name></a>", prefix, content);
+            prop_assert_eq!(Some(content.trim()), get_element(&xml, "Name"));
+            prop_assert_eq!(None, get_element(&xml, "Nam"));
+        }
+    }
+
+    #[test]
+    fn test_generate_uuid() {
+        let uuid = generate_uuid();
+        assert_eq!(45, uuid.len());
+        assert_eq!(Some('4'), uuid.chars().nth(23));
+        assert_ne!(uuid, generate_uuid());
+    }
+
+    #[test]
+    fn test_answers_probes_for_devices() {
+        let announcer = create_announcer();
+        let (message_id, request) = parse_message(PROBE).unwrap();
+        assert_eq!("urn:uuid:0f5d604c-81ac-4abc-8010-51dbffad55f2", message_id);
+        let answer = announcer.create_answer(&message_id, &request).unwrap();
+        assert_eq!(
+            Some("http://schemas.xmlsoap.org/ws/2005/04/discovery/ProbeMatches"),
+            get_element(&answer, "Action")
+        );
+        assert_eq!(Some(message_id.as_str()), get_element(&answer, "RelatesTo"));
+        assert_eq!(
+            Some("http://192.168.1.2:8080/.wsd"),
+            get_element(&answer, "XAddrs")
+        );
+
+        let printers = PROBE.replace("wsdp:Device", "wsdp:Device wprt:PrintDeviceType");
+        let (message_id, request) = parse_message(&printers).unwrap();
+        assert_eq!(None, announcer.create_answer(&message_id, &request));
+        let everything = PROBE.replace("<wsd:Types>wsdp:Device</wsd:Types>", "");
+        let (message_id, request) = parse_message(&everything).unwrap();
+        assert!(announcer.create_answer(&message_id, &request).is_some());
+    }
+
+    #[test]
+    fn test_answers_resolves_of_its_endpoint() {
+        let announcer = create_announcer();
+        let resolve = |endpoint: &str| {
+            PROBE.replace("/Probe<", "/Resolve<").replace(
+                "<wsd:Probe><wsd:Types>wsdp:Device</wsd:Types></wsd:Probe>",
+                &format!(
+                    "<wsd:Resolve><wsa:EndpointReference><wsa:Address>{}</wsa:Address>\
+                     </wsa:EndpointReference></wsd:Resolve>",
+                    endpoint
+                ),
+            )
+        };
+        let (message_id, request) = parse_message(&resolve(&announcer.endpoint)).unwrap();
+        let answer = announcer.create_answer(&message_id, &request).unwrap();
+        assert!(answer.contains("<wsd:ResolveMatch>"));
+        let (message_id, request) = parse_message(&resolve(&generate_uuid())).unwrap();
+        assert_eq!(None, announcer.create_answer(&message_id, &request));
+        assert!(parse_message(&announcer.create_hello()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let announcer = create_announcer();
+        let get = PROBE
+            .replace(
+                "http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe",
+                TRANSFER_GET,
+            )
+            .replace(
+                "<wsd:Probe><wsd:Types>wsdp:Device</wsd:Types></wsd:Probe>",
+                "",
+            );
+        let req = Request::post(WSD_PATH).body(Body::from(get)).unwrap();
+        let response = handle_request(&announcer, req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(
+            Some("Slides &amp; notes (rustbelt)"),
+            get_element(&body, "FriendlyName")
+        );
+        assert_eq!(
+            Some("http://192.168.1.2:8080"),
+            get_element(&body, "PresentationUrl")
+        );
+
+        let req = Request::post(WSD_PATH).body(Body::from(PROBE)).unwrap();
+        let response = handle_request(&announcer, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+}